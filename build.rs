@@ -0,0 +1,59 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Build script
+//!
+//! Generate the symbol table used by the backtrace printer.
+//! Set `HYPERVISOR_SYMBOL_MAP` to the output of `llvm-nm --defined-only -C <previous build>`
+//! to embed the symbols, otherwise an empty table is generated.
+//!
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const SYMBOL_MAP_ENV: &str = "HYPERVISOR_SYMBOL_MAP";
+
+fn main() {
+    println!("cargo:rerun-if-env-changed={SYMBOL_MAP_ENV}");
+    let mut symbols: Vec<(u64, String)> = Vec::new();
+
+    if let Ok(path) = env::var(SYMBOL_MAP_ENV) {
+        println!("cargo:rerun-if-changed={path}");
+        let map = fs::read_to_string(&path).expect("Failed to read the symbol map");
+        for line in map.lines() {
+            /* "<address> <type> <name>" */
+            let mut fields = line.splitn(3, ' ');
+            let (Some(address), Some(symbol_type), Some(name)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if !matches!(symbol_type, "T" | "t") {
+                continue;
+            }
+            if let Ok(address) = u64::from_str_radix(address, 16) {
+                symbols.push((address, name.to_string()));
+            }
+        }
+        symbols.sort_by_key(|(address, _)| *address);
+        symbols.dedup_by_key(|(address, _)| *address);
+    }
+
+    let mut output = String::new();
+    writeln!(output, "pub static SYMBOLS: [(usize, &str); {}] = [", symbols.len()).unwrap();
+    for (address, name) in &symbols {
+        writeln!(output, "    ({:#X}, {:?}),", address, name).unwrap();
+    }
+    writeln!(output, "];").unwrap();
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("symbols.rs"), output)
+        .expect("Failed to write the symbol table");
+}
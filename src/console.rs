@@ -6,52 +6,198 @@
 // http://opensource.org/licenses/mit-license.php
 
 //!
-//! Console with UEFI Output Protocol
+//! Console
+//!
+//! The output is written to the selected backend: the UEFI Output Protocol, the physical PL011,
+//! or the memory ring buffer. The backend is switched atomically between the outputs.
+//!
+//! Each output is serialized by the console lock. If the output is requested while the same CPU
+//! holds the lock, like the panic in the middle of the output, it is written to the physical
+//! PL011 directly instead of waiting the lock forever.
 //!
 
-use crate::uefi::{output::EfiOutputProtocol, EfiStatus};
+use crate::cpu::get_mpidr_el1;
+use crate::uefi::output::EfiOutputProtocol;
+use backend::{ConsoleBackend, MemoryConsole, Pl011Console, UefiConsole};
 
 use core::fmt;
-use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+const MPIDR_AFFINITY_MASK: u64 = 0xFF_00FF_FFFF;
+
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConsoleBackendType {
+    Uefi = 0,
+    Pl011 = 1,
+    Memory = 2,
+}
+
+impl ConsoleBackendType {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::Uefi,
+            1 => Self::Pl011,
+            _ => Self::Memory,
+        }
+    }
 
-pub struct Console {
-    uefi_output_console: MaybeUninit<&'static EfiOutputProtocol>,
-    //write_lock: SpinLockFlag, // Currently, Bootloader runs only BSP. Therefore the lock is not necessary.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Uefi, Self::Pl011, Self::Memory]
+            .into_iter()
+            .find(|t| backend_instance(*t).name() == name)
+    }
 }
 
-pub static mut DEFAULT_CONSOLE: Console = Console::new();
+static mut UEFI_CONSOLE: UefiConsole = UefiConsole::new();
+static mut PL011_CONSOLE: Pl011Console = Pl011Console::new();
+static mut MEMORY_CONSOLE: MemoryConsole = MemoryConsole::new();
 
-impl Console {
-    pub const fn new() -> Self {
-        Self {
-            uefi_output_console: MaybeUninit::uninit(),
+static CURRENT_BACKEND: AtomicU8 = AtomicU8::new(ConsoleBackendType::Uefi as u8);
+/// (The affinity of the CPU holding the console lock) + 1, or 0 if the lock is free
+static CONSOLE_LOCK_OWNER: AtomicU64 = AtomicU64::new(0);
+
+fn backend_instance(backend_type: ConsoleBackendType) -> &'static mut dyn ConsoleBackend {
+    unsafe {
+        match backend_type {
+            ConsoleBackendType::Uefi => &mut *core::ptr::addr_of_mut!(UEFI_CONSOLE),
+            ConsoleBackendType::Pl011 => &mut *core::ptr::addr_of_mut!(PL011_CONSOLE),
+            ConsoleBackendType::Memory => &mut *core::ptr::addr_of_mut!(MEMORY_CONSOLE),
         }
     }
+}
+
+struct ConsoleLockGuard;
 
-    pub fn init(&mut self, efi_output_protocol: *const EfiOutputProtocol) {
-        self.uefi_output_console = MaybeUninit::new(unsafe { &*efi_output_protocol });
+impl Drop for ConsoleLockGuard {
+    fn drop(&mut self) {
+        CONSOLE_LOCK_OWNER.store(0, Ordering::Release);
     }
 }
 
-impl fmt::Write for Console {
-    fn write_str(&mut self, string: &str) -> fmt::Result {
-        let result = unsafe { self.uefi_output_console.assume_init().output(string) };
-        if result == EfiStatus::EfiSuccess {
-            Ok(())
-        } else {
-            Err(fmt::Error)
+/// Acquire the console lock
+///
+/// # Result
+/// Some(guard) if acquired, or None if this CPU already holds it
+fn lock_console() -> Option<ConsoleLockGuard> {
+    let own_id = (get_mpidr_el1() & MPIDR_AFFINITY_MASK) + 1;
+    loop {
+        match CONSOLE_LOCK_OWNER.compare_exchange_weak(
+            0,
+            own_id,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return Some(ConsoleLockGuard),
+            Err(owner) if owner == own_id => return None,
+            Err(_) => core::hint::spin_loop(),
         }
     }
 }
 
+/// Set up the UEFI backend, it is selected at first
+pub fn init_uefi_console(efi_output_protocol: *const EfiOutputProtocol) {
+    unsafe { (*core::ptr::addr_of_mut!(UEFI_CONSOLE)).init(efi_output_protocol) };
+}
+
+/// Make the UEFI backend unavailable, called before returning to the firmware
+///
+/// # Result
+/// If the UEFI backend is selected now, returns Err(())
+pub fn disable_uefi_console() -> Result<(), ()> {
+    let _guard = lock_console();
+    if get_backend() == ConsoleBackendType::Uefi {
+        return Err(());
+    }
+    unsafe { (*core::ptr::addr_of_mut!(UEFI_CONSOLE)).disable() };
+    Ok(())
+}
+
+/// Set up the PL011 backend, it is also used for the reentrant output
+pub fn init_pl011_console(base_address: usize) {
+    unsafe { (*core::ptr::addr_of_mut!(PL011_CONSOLE)).init(base_address) };
+}
+
+/// Switch the backend, the output in progress is completed with the previous backend
+///
+/// # Result
+/// If the backend is not set up, returns Err(())
+pub fn set_backend(backend_type: ConsoleBackendType) -> Result<(), ()> {
+    if !backend_instance(backend_type).is_available() {
+        return Err(());
+    }
+    let _guard = lock_console();
+    CURRENT_BACKEND.store(backend_type as u8, Ordering::Release);
+    Ok(())
+}
+
+pub fn get_backend() -> ConsoleBackendType {
+    ConsoleBackendType::from_u8(CURRENT_BACKEND.load(Ordering::Acquire))
+}
+
+pub fn get_backend_name(backend_type: ConsoleBackendType) -> &'static str {
+    backend_instance(backend_type).name()
+}
+
+/// Print the contents of the memory backend to the current backend
+///
+/// # Result
+/// If the memory backend is selected now, returns Err(())
+pub fn dump_memory_console() -> Result<(), ()> {
+    if get_backend() == ConsoleBackendType::Memory {
+        return Err(());
+    }
+    unsafe { &*core::ptr::addr_of!(MEMORY_CONSOLE) }.for_each_byte(&mut |c| write_bytes(&[c]));
+    Ok(())
+}
+
+pub fn clear_memory_console() {
+    let _guard = lock_console();
+    unsafe { (*core::ptr::addr_of_mut!(MEMORY_CONSOLE)).clear() };
+}
+
+struct BackendWriter(&'static mut dyn ConsoleBackend);
+
+impl fmt::Write for BackendWriter {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        self.0.write(string).or(Err(fmt::Error))
+    }
+}
+
+/// Write to the physical PL011 without the console lock
+struct DirectWriter;
+
+impl fmt::Write for DirectWriter {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        unsafe { &*core::ptr::addr_of!(PL011_CONSOLE) }.write_direct(string.as_bytes());
+        Ok(())
+    }
+}
+
 pub fn print(args: fmt::Arguments) {
     use fmt::Write;
-    let result = unsafe { DEFAULT_CONSOLE.write_fmt(args) };
+    let Some(_guard) = lock_console() else {
+        let _ = DirectWriter.write_fmt(args);
+        return;
+    };
+    let result = BackendWriter(backend_instance(get_backend())).write_fmt(args);
     if result.is_err() {
         panic!("write_fmt was failed.");
     }
 }
 
+/// Write the bytes without converting them to characters
+///
+/// The bytes which are not valid UTF-8 by themselves, like the part of a multibyte character
+/// written by the guest one by one, are passed to the backend as they are.
+pub fn write_bytes(data: &[u8]) {
+    let Some(_guard) = lock_console() else {
+        unsafe { &*core::ptr::addr_of!(PL011_CONSOLE) }.write_direct(data);
+        return;
+    };
+    let _ = backend_instance(get_backend()).write_bytes(data);
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::console::print(format_args!($($arg)*)));
@@ -63,11 +209,15 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => ($crate::console::print(format_args!("{}\n", format_args!($fmt, $($arg)*))));
 }
 
+/// Record the message by `debug!` in the debug build, nothing is emitted in the release build
+///
+/// The message is recorded only when the level of the module is [`crate::log::LogLevel::Debug`]
+/// or Trace, the debug build sets it by default.
 #[cfg(debug_assertions)]
 #[macro_export]
 macro_rules! pr_debug {
-    ($fmt:expr) => (println!($fmt));
-    ($fmt:expr, $($arg:tt)*) => (println!($fmt, $($arg)*));
+    ($fmt:expr) => ($crate::debug!($fmt));
+    ($fmt:expr, $($arg:tt)*) => ($crate::debug!($fmt, $($arg)*));
 }
 
 #[cfg(not(debug_assertions))]
@@ -76,3 +226,6 @@ macro_rules! pr_debug {
     ($fmt:expr) => {};
     ($fmt:expr, $($arg:tt)*) => {};
 }
+
+pub mod backend;
+pub mod mux;
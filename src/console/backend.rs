@@ -0,0 +1,197 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Console Backends
+//!
+//! The devices which the console output is written to.
+//! Only [`ConsoleBackend::write`] of the selected backend is called, with the console lock held.
+//!
+
+use crate::uefi::{output::EfiOutputProtocol, EfiStatus};
+
+use core::ptr::{read_volatile, write_volatile};
+
+const UART_DR: usize = 0x000;
+const UART_FR: usize = 0x018;
+const UART_FR_TXFF: u32 = 1 << 5;
+
+pub const MEMORY_CONSOLE_SIZE: usize = 0x4000;
+
+pub trait ConsoleBackend {
+    fn name(&self) -> &'static str;
+
+    /// Check if the backend can be selected now
+    fn is_available(&self) -> bool;
+
+    /// Write the bytes as they are, they may be the part of a UTF-8 sequence
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), ()>;
+
+    fn write(&mut self, string: &str) -> Result<(), ()> {
+        self.write_bytes(string.as_bytes())
+    }
+}
+
+/// The console with EFI_SIMPLE_TEXT_OUTPUT_PROTOCOL
+///
+/// It must not be used after the hypervisor returned to the firmware, because the firmware
+/// is not reentrant from the exception handler.
+pub struct UefiConsole {
+    output: Option<&'static EfiOutputProtocol>,
+}
+
+impl UefiConsole {
+    pub const fn new() -> Self {
+        Self { output: None }
+    }
+
+    pub fn init(&mut self, efi_output_protocol: *const EfiOutputProtocol) {
+        self.output = unsafe { efi_output_protocol.as_ref() };
+    }
+
+    /// Stop using the firmware, [`ConsoleBackend::is_available`] returns false after this
+    pub fn disable(&mut self) {
+        self.output = None;
+    }
+}
+
+impl ConsoleBackend for UefiConsole {
+    fn name(&self) -> &'static str {
+        "uefi"
+    }
+
+    fn is_available(&self) -> bool {
+        self.output.is_some()
+    }
+
+    fn write(&mut self, string: &str) -> Result<(), ()> {
+        match self.output {
+            Some(output) if output.output(string) == EfiStatus::EfiSuccess => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    /// The protocol takes UCS-2 strings, so the invalid UTF-8 sequences are replaced by U+FFFD
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), ()> {
+        for chunk in data.utf8_chunks() {
+            self.write(chunk.valid())?;
+            if !chunk.invalid().is_empty() {
+                self.write(char::REPLACEMENT_CHARACTER.encode_utf8(&mut [0; 4]))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The console writing the physical PL011 directly
+///
+/// It does not depend on the firmware and each character is written by a single store,
+/// therefore it is usable from any context.
+pub struct Pl011Console {
+    base_address: Option<usize>,
+}
+
+impl Pl011Console {
+    pub const fn new() -> Self {
+        Self { base_address: None }
+    }
+
+    pub fn init(&mut self, base_address: usize) {
+        self.base_address = Some(base_address);
+    }
+
+    fn put_char(base_address: usize, c: u8) {
+        while (unsafe { read_volatile((base_address + UART_FR) as *const u32) } & UART_FR_TXFF) != 0
+        {
+            core::hint::spin_loop();
+        }
+        unsafe { write_volatile((base_address + UART_DR) as *mut u32, c as u32) };
+    }
+
+    /// Write the bytes without the console lock, used for the reentrant output
+    pub fn write_direct(&self, data: &[u8]) {
+        let Some(base_address) = self.base_address else {
+            return;
+        };
+        for &c in data {
+            if c == b'\n' {
+                Self::put_char(base_address, b'\r');
+            }
+            Self::put_char(base_address, c);
+        }
+    }
+}
+
+impl ConsoleBackend for Pl011Console {
+    fn name(&self) -> &'static str {
+        "pl011"
+    }
+
+    fn is_available(&self) -> bool {
+        self.base_address.is_some()
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), ()> {
+        if self.base_address.is_none() {
+            return Err(());
+        }
+        self.write_direct(data);
+        Ok(())
+    }
+}
+
+/// The console storing the output into the ring buffer, the oldest output is overwritten
+pub struct MemoryConsole {
+    data: [u8; MEMORY_CONSOLE_SIZE],
+    head: usize,
+    length: usize,
+}
+
+impl MemoryConsole {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; MEMORY_CONSOLE_SIZE],
+            head: 0,
+            length: 0,
+        }
+    }
+
+    /// Call `f` with each byte from the oldest
+    pub fn for_each_byte(&self, f: &mut dyn FnMut(u8)) {
+        for i in 0..self.length {
+            f(self.data[(self.head + i) % MEMORY_CONSOLE_SIZE]);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.length = 0;
+    }
+}
+
+impl ConsoleBackend for MemoryConsole {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), ()> {
+        for &c in data {
+            if self.length == MEMORY_CONSOLE_SIZE {
+                self.data[self.head] = c;
+                self.head = (self.head + 1) % MEMORY_CONSOLE_SIZE;
+            } else {
+                self.data[(self.head + self.length) % MEMORY_CONSOLE_SIZE] = c;
+                self.length += 1;
+            }
+        }
+        Ok(())
+    }
+}
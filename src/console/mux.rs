@@ -0,0 +1,308 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Console Multiplexer
+//!
+//! The hypervisor and the guest consoles share the physical serial port.
+//! The output of the consoles is written to the physical console, and the input is delivered
+//! to the focused console.
+//! The dedicated console, like the channel of the GDB stub, is not mixed with the others:
+//! its output is written only while it is focused, and the output of the other consoles is
+//! held then. The held output is written when the console becomes writable.
+//! The messages of the hypervisor printed by `println!` are not held. The console registered first, the console of the guest firmware,
+//! is focused at first. Type `Ctrl-A` and then the console number to switch the focus,
+//! `Ctrl-A` `a` sends `Ctrl-A` itself, and `Ctrl-A` `m` focuses the hypervisor monitor.
+//!
+
+/// The physical PL011 which the input is polled from
+const PHYSICAL_PL011: usize = 0x09000000;
+const UART_DR: usize = 0x000;
+const UART_FR: usize = 0x018;
+const UART_FR_RXFE: u32 = 1 << 4;
+
+pub const MAX_CONSOLES: usize = 8;
+const INPUT_BUFFER_SIZE: usize = 256;
+const OUTPUT_BUFFER_SIZE: usize = 0x2000;
+const ESCAPE_CHARACTER: u8 = 0x01; /* Ctrl-A */
+
+/// The console of the hypervisor itself, read by the monitor
+pub const HYPERVISOR_CONSOLE_ID: usize = 0;
+/// The id given to the console registered first, it is focused at first
+const FIRST_GUEST_CONSOLE_ID: usize = HYPERVISOR_CONSOLE_ID + 1;
+
+/// Called when the input is queued to the console
+///
+/// The argument is the console id returned by [`register_console`].
+pub type ConsoleInputNotifier = fn(usize);
+
+struct ByteBuffer<const SIZE: usize> {
+    data: [u8; SIZE],
+    head: usize,
+    length: usize,
+}
+
+impl<const SIZE: usize> ByteBuffer<SIZE> {
+    const fn new() -> Self {
+        Self {
+            data: [0; SIZE],
+            head: 0,
+            length: 0,
+        }
+    }
+
+    fn push(&mut self, c: u8) -> Result<(), ()> {
+        if self.length == SIZE {
+            return Err(());
+        }
+        self.data[(self.head + self.length) % SIZE] = c;
+        self.length += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.length == 0 {
+            return None;
+        }
+        let c = self.data[self.head];
+        self.head = (self.head + 1) % SIZE;
+        self.length -= 1;
+        Some(c)
+    }
+
+    /// Move the bytes into `buffer` from the oldest, returns the number of the bytes
+    fn pop_bytes(&mut self, buffer: &mut [u8]) -> usize {
+        let mut length = 0;
+        while length < buffer.len() {
+            let Some(c) = self.pop() else {
+                break;
+            };
+            buffer[length] = c;
+            length += 1;
+        }
+        length
+    }
+}
+
+struct ConsoleEntry {
+    name: &'static str,
+    input: ByteBuffer<INPUT_BUFFER_SIZE>,
+    /// The output held while the console is not writable, the overflow is discarded
+    output: ByteBuffer<OUTPUT_BUFFER_SIZE>,
+    notifier: Option<ConsoleInputNotifier>,
+    is_dedicated: bool,
+}
+
+impl ConsoleEntry {
+    const fn new(
+        name: &'static str,
+        notifier: Option<ConsoleInputNotifier>,
+        is_dedicated: bool,
+    ) -> Self {
+        Self {
+            name,
+            input: ByteBuffer::new(),
+            output: ByteBuffer::new(),
+            notifier,
+            is_dedicated,
+        }
+    }
+}
+
+struct ConsoleMux {
+    consoles: [Option<ConsoleEntry>; MAX_CONSOLES],
+    focused: usize,
+    is_escaped: bool,
+}
+
+static mut CONSOLE_MUX: ConsoleMux = ConsoleMux {
+    consoles: [const { None }; MAX_CONSOLES],
+    focused: FIRST_GUEST_CONSOLE_ID,
+    is_escaped: false,
+};
+
+fn console_mux() -> &'static mut ConsoleMux {
+    let mux = unsafe { &mut *core::ptr::addr_of_mut!(CONSOLE_MUX) };
+    if mux.consoles[HYPERVISOR_CONSOLE_ID].is_none() {
+        mux.consoles[HYPERVISOR_CONSOLE_ID] = Some(ConsoleEntry::new("hypervisor", None, false));
+    }
+    mux
+}
+
+impl ConsoleMux {
+    fn is_dedicated(&self, id: usize) -> bool {
+        matches!(self.consoles.get(id), Some(Some(e)) if e.is_dedicated)
+    }
+
+    /// Check if the output of the console can be written to the physical console now
+    fn is_writable(&self, id: usize) -> bool {
+        id == self.focused || (!self.is_dedicated(id) && !self.is_dedicated(self.focused))
+    }
+
+    /// Write the held output of the consoles which are writable now
+    fn flush_output(&mut self) {
+        for id in 0..MAX_CONSOLES {
+            if !self.is_writable(id) {
+                continue;
+            }
+            let Some(entry) = &mut self.consoles[id] else {
+                continue;
+            };
+            let mut buffer = [0u8; 64];
+            loop {
+                let length = entry.output.pop_bytes(&mut buffer);
+                if length == 0 {
+                    break;
+                }
+                crate::console::write_bytes(&buffer[..length]);
+            }
+        }
+    }
+}
+
+fn add_console(
+    name: &'static str,
+    notifier: Option<ConsoleInputNotifier>,
+    is_dedicated: bool,
+) -> Result<usize, ()> {
+    let (id, entry) = console_mux()
+        .consoles
+        .iter_mut()
+        .enumerate()
+        .find(|(_, e)| e.is_none())
+        .ok_or(())?;
+    *entry = Some(ConsoleEntry::new(name, notifier, is_dedicated));
+    Ok(id)
+}
+
+/// Register the console to the multiplexer
+///
+/// # Arguments
+/// * `name` - the name shown when the focus is switched
+/// * `notifier` - called when the input is available
+///
+/// # Result
+/// If succeeded, Ok(console id), otherwise Err(())
+pub fn register_console(
+    name: &'static str,
+    notifier: Option<ConsoleInputNotifier>,
+) -> Result<usize, ()> {
+    add_console(name, notifier, false)
+}
+
+/// Register the console which is not mixed with the others, see the module document
+///
+/// # Arguments
+/// * `name` - the name shown when the focus is switched
+/// * `notifier` - called when the input is available
+///
+/// # Result
+/// If succeeded, Ok(console id), otherwise Err(())
+pub fn register_dedicated_console(
+    name: &'static str,
+    notifier: Option<ConsoleInputNotifier>,
+) -> Result<usize, ()> {
+    add_console(name, notifier, true)
+}
+
+/// Write the output of the console
+///
+/// If the console is not writable now, the output is held until it becomes writable.
+pub fn write(id: usize, data: &[u8]) {
+    let mux = console_mux();
+    if mux.is_writable(id) {
+        crate::console::write_bytes(data);
+        return;
+    }
+    let Some(Some(entry)) = mux.consoles.get_mut(id) else {
+        return;
+    };
+    for c in data {
+        if entry.output.push(*c).is_err() {
+            break;
+        }
+    }
+}
+
+/// Take the input queued to the console
+///
+/// # Result
+/// The number of bytes stored in `buffer`
+pub fn read(id: usize, buffer: &mut [u8]) -> usize {
+    let Some(Some(entry)) = console_mux().consoles.get_mut(id) else {
+        return 0;
+    };
+    entry.input.pop_bytes(buffer)
+}
+
+pub fn has_input(id: usize) -> bool {
+    matches!(console_mux().consoles.get(id), Some(Some(e)) if e.input.length > 0)
+}
+
+pub fn get_focused_console() -> usize {
+    console_mux().focused
+}
+
+/// Move the input focus to the console
+pub fn focus_console(id: usize) -> Result<(), ()> {
+    let mux = console_mux();
+    let Some(Some(entry)) = mux.consoles.get(id) else {
+        return Err(());
+    };
+    println!("\n[console {}: {}]", id, entry.name);
+    mux.focused = id;
+    mux.flush_output();
+    Ok(())
+}
+
+/// Queue the input to the focused console
+pub fn input(c: u8) {
+    let mux = console_mux();
+    let c = if mux.is_escaped {
+        mux.is_escaped = false;
+        match c {
+            b'0'..=b'9' => {
+                let _ = focus_console((c - b'0') as usize);
+                return;
+            }
+            b'a' => ESCAPE_CHARACTER,
+            b'm' => {
+                let _ = focus_console(HYPERVISOR_CONSOLE_ID);
+                return;
+            }
+            _ => return,
+        }
+    } else if c == ESCAPE_CHARACTER {
+        mux.is_escaped = true;
+        return;
+    } else {
+        c
+    };
+    let id = mux.focused;
+    let Some(Some(entry)) = mux.consoles.get_mut(id) else {
+        return;
+    };
+    if entry.input.push(c).is_ok() {
+        if let Some(notifier) = entry.notifier {
+            notifier(id);
+        }
+    }
+}
+
+/// Read the physical serial port and dispatch the input
+///
+/// This is called on each VM exit by the physical interrupt.
+pub fn poll_input() {
+    loop {
+        let flags = unsafe { core::ptr::read_volatile((PHYSICAL_PL011 + UART_FR) as *const u32) };
+        if (flags & UART_FR_RXFE) != 0 {
+            break;
+        }
+        let c = unsafe { core::ptr::read_volatile((PHYSICAL_PL011 + UART_DR) as *const u32) } as u8;
+        input(c);
+    }
+}
@@ -20,6 +20,10 @@ pub struct InterruptFlag(u64);
 
 pub const AA64_INSTRUCTION_SIZE: usize = 4;
 
+/* ISR_EL1 */
+pub const ISR_EL1_I: u64 = 1 << 7;
+pub const ISR_EL1_F: u64 = 1 << 6;
+
 /* DAIF */
 pub const DAIF_IRQ_BIT: u64 = 7;
 pub const DAIF_FIQ_BIT: u64 = 6;
@@ -101,9 +105,28 @@ pub const HCR_EL2_APK: u64 = 1 << 40;
 //pub const HCR_EL2_TEA: u64 = 1 << 37;
 pub const HCR_EL2_E2H: u64 = 1 << 34;
 pub const HCR_EL2_RW: u64 = 1 << 31;
+pub const HCR_EL2_TSW: u64 = 1 << 22;
+pub const HCR_EL2_TACR: u64 = 1 << 21;
 pub const HCR_EL2_TSC: u64 = 1 << 19;
+pub const HCR_EL2_TID3: u64 = 1 << 18;
+pub const HCR_EL2_TID2: u64 = 1 << 17;
+pub const HCR_EL2_TID1: u64 = 1 << 16;
+pub const HCR_EL2_TWE: u64 = 1 << 14;
+pub const HCR_EL2_TWI: u64 = 1 << 13;
+pub const HCR_EL2_IMO: u64 = 1 << 4;
+pub const HCR_EL2_FMO: u64 = 1 << 3;
 pub const HCR_EL2_VM: u64 = 1 << 0;
 
+/* MDCR_EL2 */
+pub const MDCR_EL2_TDRA: u64 = 1 << 11;
+pub const MDCR_EL2_TDOSA: u64 = 1 << 10;
+pub const MDCR_EL2_TDA: u64 = 1 << 9;
+pub const MDCR_EL2_TDE: u64 = 1 << 8;
+pub const MDCR_EL2_HPME: u64 = 1 << 7;
+pub const MDCR_EL2_TPM: u64 = 1 << 6;
+pub const MDCR_EL2_TPMCR: u64 = 1 << 5;
+pub const MDCR_EL2_HPMN: u64 = 0b11111;
+
 /* VTCR_EL2 */
 pub const VTCR_EL2_SL2_BIT_OFFSET: u64 = 33;
 pub const VTCR_EL2_SL2: u64 = 1 << VTCR_EL2_SL2_BIT_OFFSET;
@@ -121,14 +144,66 @@ pub const VTCR_EL2_SL0: u64 = 0b11 << VTCR_EL2_SL0_BITS_OFFSET;
 pub const VTCR_EL2_T0SZ_BITS_OFFSET: u64 = 0;
 pub const VTCR_EL2_T0SZ: u64 = 0b111111 << VTCR_EL2_T0SZ_BITS_OFFSET;
 
+/* SCTLR_EL1 */
+pub const SCTLR_EL1_SPAN: u64 = 1 << 23;
+
+/* MDSCR_EL1 */
+pub const MDSCR_EL1_MDE: u64 = 1 << 15;
+pub const MDSCR_EL1_KDE: u64 = 1 << 13;
+pub const MDSCR_EL1_SS: u64 = 1 << 0;
+
+/* DBGBCR<n>_EL1 */
+pub const DBGBCR_EL1_BAS: u64 = 0b1111 << 5;
+pub const DBGBCR_EL1_PMC_EL1_EL0: u64 = 0b11 << 1;
+pub const DBGBCR_EL1_E: u64 = 1 << 0;
+
+/* DBGWCR<n>_EL1 */
+pub const DBGWCR_EL1_BAS_BITS_OFFSET: u64 = 5;
+pub const DBGWCR_EL1_LSC_LOAD: u64 = 0b01 << 3;
+pub const DBGWCR_EL1_LSC_STORE: u64 = 0b10 << 3;
+pub const DBGWCR_EL1_PAC_EL1_EL0: u64 = 0b11 << 1;
+pub const DBGWCR_EL1_E: u64 = 1 << 0;
+
 /* SPSR_EL2 */
+pub const SPSR_EL2_SS: u64 = 1 << 21;
 pub const SPSR_EL2_M: u64 = 0b1111;
 pub const SPSR_EL2_M_EL0T: u64 = 0b0000;
+pub const SPSR_EL2_M_EL1T: u64 = 0b0100;
+pub const SPSR_EL2_M_EL1H: u64 = 0b0101;
+pub const SPSR_EL2_M_AARCH32: u64 = 1 << 4;
+pub const SPSR_EL2_D: u64 = 1 << 9;
+pub const SPSR_EL2_A: u64 = 1 << 8;
+pub const SPSR_EL2_I: u64 = 1 << 7;
+pub const SPSR_EL2_F: u64 = 1 << 6;
+pub const SPSR_EL2_PAN: u64 = 1 << 22;
 
 /* ID_AA64PFR0_EL1 */
 pub const ID_AA64PFR0_EL1_SVE: u64 = 0b1111 << 32;
 pub const ID_AA64PFR0_EL1_GIC: u64 = 0b1111 << 24;
 
+/* ID_AA64DFR0_EL1 */
+pub const ID_AA64DFR0_EL1_WRPS_BITS_OFFSET: u64 = 20;
+pub const ID_AA64DFR0_EL1_WRPS: u64 = 0b1111 << ID_AA64DFR0_EL1_WRPS_BITS_OFFSET;
+pub const ID_AA64DFR0_EL1_BRPS_BITS_OFFSET: u64 = 12;
+pub const ID_AA64DFR0_EL1_BRPS: u64 = 0b1111 << ID_AA64DFR0_EL1_BRPS_BITS_OFFSET;
+
+/* ID_AA64ISAR0_EL1 */
+pub const ID_AA64ISAR0_EL1_RNDR_BITS_OFFSET: u64 = 60;
+pub const ID_AA64ISAR0_EL1_RNDR: u64 = 0b1111 << ID_AA64ISAR0_EL1_RNDR_BITS_OFFSET;
+
+/* ID_AA64PFR1_EL1 */
+pub const ID_AA64PFR1_EL1_SME: u64 = 0b1111 << 24;
+pub const ID_AA64PFR1_EL1_MTE: u64 = 0b1111 << 8;
+
+/* PMCR_EL0 */
+pub const PMCR_EL0_IMP: u64 = 0xff << 24;
+pub const PMCR_EL0_N_BITS_OFFSET: u64 = 11;
+pub const PMCR_EL0_N: u64 = 0b11111 << PMCR_EL0_N_BITS_OFFSET;
+pub const PMCR_EL0_LC: u64 = 1 << 6;
+pub const PMCR_EL0_C: u64 = 1 << 2;
+pub const PMCR_EL0_P: u64 = 1 << 1;
+pub const PMCR_EL0_E: u64 = 1 << 0;
+
 /* ID_AA64MMFR0_EL1 */
 pub const ID_AA64MMFR0_EL1_PARANGE: u64 = 0b1111;
 
@@ -279,6 +354,18 @@ pub fn set_hcr_el2(hcr_el2: u64) {
     unsafe { asm!("msr hcr_el2, {:x}", in(reg) hcr_el2) };
 }
 
+#[inline(always)]
+pub fn get_mdcr_el2() -> u64 {
+    let mdcr_el2: u64;
+    unsafe { asm!("mrs {:x}, mdcr_el2", out(reg) mdcr_el2) };
+    mdcr_el2
+}
+
+#[inline(always)]
+pub fn set_mdcr_el2(mdcr_el2: u64) {
+    unsafe { asm!("msr mdcr_el2, {:x}", in(reg) mdcr_el2) };
+}
+
 #[inline(always)]
 pub fn get_current_el() -> u64 {
     let current_el: u64;
@@ -361,6 +448,13 @@ pub fn set_icc_igrpen1_el1(icc_igrpen1_el1: u64) {
     unsafe { asm!("msr icc_igrpen1_el1, {:x}", in(reg) icc_igrpen1_el1) };
 }
 
+#[inline(always)]
+pub fn get_isr_el1() -> u64 {
+    let isr_el1: u64;
+    unsafe { asm!("mrs {:x}, isr_el1", out(reg) isr_el1) };
+    isr_el1
+}
+
 #[inline(always)]
 pub fn get_mair_el2() -> u64 {
     let mair_el2: u64;
@@ -392,6 +486,27 @@ pub fn set_cnthctl_el2(cnthctl_el2: u64) {
     unsafe { asm!("msr cnthctl_el2, {:x}", in(reg) cnthctl_el2) };
 }
 
+#[inline(always)]
+pub fn get_cntpct_el0() -> u64 {
+    let cntpct_el0: u64;
+    unsafe { asm!("isb; mrs {:x}, cntpct_el0", out(reg) cntpct_el0) };
+    cntpct_el0
+}
+
+#[inline(always)]
+pub fn get_cntvct_el0() -> u64 {
+    let cntvct_el0: u64;
+    unsafe { asm!("isb; mrs {:x}, cntvct_el0", out(reg) cntvct_el0) };
+    cntvct_el0
+}
+
+#[inline(always)]
+pub fn get_cntfrq_el0() -> u64 {
+    let cntfrq_el0: u64;
+    unsafe { asm!("mrs {:x}, cntfrq_el0", out(reg) cntfrq_el0) };
+    cntfrq_el0
+}
+
 #[inline(always)]
 pub fn set_cntvoff_el2(cntvoff_el2: u64) {
     unsafe { asm!("msr cntvoff_el2, {:x}", in(reg) cntvoff_el2) };
@@ -469,6 +584,54 @@ pub fn set_vbar_el1(vbar_el1: u64) {
     unsafe { asm!("msr vbar_el1, {:x}", in(reg) vbar_el1) };
 }
 
+#[inline(always)]
+pub fn get_esr_el1() -> u64 {
+    let esr_el1: u64;
+    unsafe { asm!("mrs {:x}, esr_el1", out(reg) esr_el1) };
+    esr_el1
+}
+
+#[inline(always)]
+pub fn set_esr_el1(esr_el1: u64) {
+    unsafe { asm!("msr esr_el1, {:x}", in(reg) esr_el1) };
+}
+
+#[inline(always)]
+pub fn get_far_el1() -> u64 {
+    let far_el1: u64;
+    unsafe { asm!("mrs {:x}, far_el1", out(reg) far_el1) };
+    far_el1
+}
+
+#[inline(always)]
+pub fn set_far_el1(far_el1: u64) {
+    unsafe { asm!("msr far_el1, {:x}", in(reg) far_el1) };
+}
+
+#[inline(always)]
+pub fn get_spsr_el1() -> u64 {
+    let spsr_el1: u64;
+    unsafe { asm!("mrs {:x}, spsr_el1", out(reg) spsr_el1) };
+    spsr_el1
+}
+
+#[inline(always)]
+pub fn set_spsr_el1(spsr_el1: u64) {
+    unsafe { asm!("msr spsr_el1, {:x}", in(reg) spsr_el1) };
+}
+
+#[inline(always)]
+pub fn get_elr_el1() -> u64 {
+    let elr_el1: u64;
+    unsafe { asm!("mrs {:x}, elr_el1", out(reg) elr_el1) };
+    elr_el1
+}
+
+#[inline(always)]
+pub fn set_elr_el1(elr_el1: u64) {
+    unsafe { asm!("msr elr_el1, {:x}", in(reg) elr_el1) };
+}
+
 #[inline(always)]
 pub fn get_esr_el2() -> u64 {
     let esr_el2: u64;
@@ -521,6 +684,18 @@ pub fn get_sp() -> u64 {
     sp
 }
 
+#[inline(always)]
+pub fn get_sp_el0() -> u64 {
+    let sp_el0: u64;
+    unsafe { asm!("mrs {:x}, sp_el0", out(reg) sp_el0) };
+    sp_el0
+}
+
+#[inline(always)]
+pub fn set_sp_el0(sp_el0: u64) {
+    unsafe { asm!("msr sp_el0, {:x}", in(reg) sp_el0) };
+}
+
 #[inline(always)]
 pub fn get_sp_el1() -> u64 {
     let sp_el1: u64;
@@ -540,6 +715,13 @@ pub fn get_id_aa64mmfr0_el1() -> u64 {
     id_aa64mmfr0_el1
 }
 
+#[inline(always)]
+pub fn get_id_aa64isar0_el1() -> u64 {
+    let id_aa64isar0_el1: u64;
+    unsafe { asm!("mrs {:x}, id_aa64isar0_el1", out(reg) id_aa64isar0_el1) };
+    id_aa64isar0_el1
+}
+
 #[inline(always)]
 pub fn get_id_aa64pfr0_el1() -> u64 {
     let id_aa64pfr0_el1: u64;
@@ -547,6 +729,98 @@ pub fn get_id_aa64pfr0_el1() -> u64 {
     id_aa64pfr0_el1
 }
 
+#[inline(always)]
+pub fn get_id_aa64pfr1_el1() -> u64 {
+    let id_aa64pfr1_el1: u64;
+    unsafe { asm!("mrs {:x}, id_aa64pfr1_el1", out(reg) id_aa64pfr1_el1) };
+    id_aa64pfr1_el1
+}
+
+#[inline(always)]
+pub fn get_id_aa64dfr0_el1() -> u64 {
+    let id_aa64dfr0_el1: u64;
+    unsafe { asm!("mrs {:x}, id_aa64dfr0_el1", out(reg) id_aa64dfr0_el1) };
+    id_aa64dfr0_el1
+}
+
+#[inline(always)]
+pub fn get_mdscr_el1() -> u64 {
+    let mdscr_el1: u64;
+    unsafe { asm!("mrs {:x}, mdscr_el1", out(reg) mdscr_el1) };
+    mdscr_el1
+}
+
+#[inline(always)]
+pub fn set_mdscr_el1(mdscr_el1: u64) {
+    unsafe { asm!("msr mdscr_el1, {:x}", in(reg) mdscr_el1) };
+}
+
+/// Unlock the OS Lock, the debug exceptions are not generated while it is locked
+#[inline(always)]
+pub fn unlock_os_lock() {
+    unsafe { asm!("msr oslar_el1, xzr") };
+}
+
+/// Define the writers of DBGBVR<n>_EL1/DBGBCR<n>_EL1/DBGWVR<n>_EL1/DBGWCR<n>_EL1
+///
+/// MSR needs the register number as an immediate value, therefore all numbers are listed.
+/// The write to the register which is not implemented is ignored.
+macro_rules! define_debug_register_writers {
+    ($($n:literal),*) => {
+        pub fn set_dbgbvr_el1(index: usize, value: u64) {
+            match index {
+                $($n => unsafe { asm!(concat!("msr dbgbvr", $n, "_el1, {:x}"), in(reg) value) },)*
+                _ => {}
+            }
+        }
+
+        pub fn set_dbgbcr_el1(index: usize, value: u64) {
+            match index {
+                $($n => unsafe { asm!(concat!("msr dbgbcr", $n, "_el1, {:x}"), in(reg) value) },)*
+                _ => {}
+            }
+        }
+
+        pub fn set_dbgwvr_el1(index: usize, value: u64) {
+            match index {
+                $($n => unsafe { asm!(concat!("msr dbgwvr", $n, "_el1, {:x}"), in(reg) value) },)*
+                _ => {}
+            }
+        }
+
+        pub fn set_dbgwcr_el1(index: usize, value: u64) {
+            match index {
+                $($n => unsafe { asm!(concat!("msr dbgwcr", $n, "_el1, {:x}"), in(reg) value) },)*
+                _ => {}
+            }
+        }
+    };
+}
+
+define_debug_register_writers!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+
+/// Read RNDR (FEAT_RNG)
+///
+/// # Result
+/// If the random number is generated, Some(number), otherwise None
+#[inline(always)]
+pub fn get_rndr() -> Option<u64> {
+    let rndr: u64;
+    let is_valid: u64;
+    unsafe {
+        asm!("mrs {r}, s3_3_c2_c4_0
+              cset {v}, ne", r = out(reg) rndr, v = out(reg) is_valid)
+    };
+    (is_valid != 0).then_some(rndr)
+}
+
+#[inline(always)]
+pub fn get_pmcr_el0() -> u64 {
+    let pmcr_el0: u64;
+    unsafe { asm!("mrs {:x}, pmcr_el0", out(reg) pmcr_el0) };
+    pmcr_el0
+}
+
 #[inline(always)]
 pub fn get_mpidr_el1() -> u64 {
     let mpidr_el1: u64;
@@ -790,6 +1064,36 @@ pub fn convert_virtual_address_to_intermediate_physical_address_el0_read(
     }
 }
 
+/// Convert virtual address of EL0 for write access to intermediate physical address
+///
+/// This function uses AT S1E0W instruction.
+///
+/// # Arguments
+/// * `virtual_address` - **the virtual address of EL0** to convert
+///
+/// # Result
+/// If succeeded, returns Ok(intermediate_physical_address),
+///  otherwise(the address is not accessible) returns Err(())
+pub fn convert_virtual_address_to_intermediate_physical_address_el0_write(
+    virtual_address: usize,
+) -> Result<usize, ()> {
+    let aligned_virtual_address = virtual_address & PAGE_MASK;
+    let offset = virtual_address & !PAGE_MASK;
+    let aligned_physical_address: usize;
+    unsafe {
+        asm!("  at S1E0W, {:x}
+                mrs {:x}, par_el1",
+        in(reg) (aligned_virtual_address),
+        out(reg) aligned_physical_address)
+    };
+
+    if (aligned_physical_address & 1) == 0 {
+        Ok((aligned_physical_address & bitmask!(51, PAGE_SHIFT)) + offset)
+    } else {
+        Err(())
+    }
+}
+
 /// Convert virtual address of EL1 for read access to intermediate physical address
 ///
 /// This function uses AT S1E1R instruction.
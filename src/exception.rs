@@ -19,7 +19,6 @@
 //!
 use crate::cpu::*;
 use core::arch::global_asm;
-use core::panic;
 
 global_asm!(
     "
@@ -70,7 +69,7 @@ irq_current_el_stack_pointer_x:
     stp  x0,  x1, [sp, #( 0 * 16)]
     mov  x0,  sp
     adr x30, exit_exception
-    b   irq_handler
+    b   irq_current_el_handler
 
 .balign 0x080
 fiq_current_el_stack_pointer_x:
@@ -172,9 +171,23 @@ exit_exception:
 "
 );
 
+pub mod esr;
+pub mod inject;
+pub mod load_store;
+
 use crate::asm;
-use crate::mmio::pl011;
-use crate::mmio::virt_mmio;
+use crate::gdb;
+use crate::hypercall;
+use crate::monitor;
+use crate::smc;
+use crate::stats;
+use crate::sysreg::{self, SystemRegister};
+use crate::unwind;
+use crate::vcpu;
+use crate::vgic;
+use crate::vm::{self, UnmappedAccessPolicy};
+
+use esr::{DataAbortIss, Esr, ExceptionClass, InstructionAbortIss, Iss, WfxType, ESR_EL2_ISS};
 
 #[repr(C)]
 pub struct Registers {
@@ -211,44 +224,158 @@ pub struct Registers {
     pub x30: u64,
     padding: u64,
 }
-/* ESR_EL2 */
-pub const ESR_EL2_EC_BITS_OFFSET: u64 = 26;
-pub const ESR_EL2_EC: u64 = 0b111111 << ESR_EL2_EC_BITS_OFFSET;
-pub const ESR_EL2_EC_DATA_ABORT: u64 = 0b100100 << 26;
-pub const ESR_EL2_ISS_ISV: u64 = 1 << 24;
-pub const ESR_EL2_ISS_SAS_BITS_OFFSET: u64 = 22;
-pub const ESR_EL2_ISS_SAS: u64 = 0b11 << ESR_EL2_ISS_SAS_BITS_OFFSET;
-pub const ESR_EL2_ISS_SRT_BITS_OFFSET: u64 = 16;
-pub const ESR_EL2_ISS_SRT: u64 = 0b11111 << ESR_EL2_ISS_SRT_BITS_OFFSET;
-pub const ESR_EL2_ISS_SF: u64 = 1 << 15;
-pub const ESR_EL2_ISS_WNR: u64 = 1 << 6;
-
-/* ESR_EL2 instruction abort */
-pub const ESR_EL2_EC_INSTRUCTION_ABORT: u64 = 0b100000 << 26;
-pub const ESR_EL2_ISS_IFSC_BITS_OFFSET: u64 = 0;
-pub const ESR_EL2_ISS_IFSC: u64 = 0b111111 << ESR_EL2_ISS_IFSC_BITS_OFFSET;
-pub const ESR_EL2_ISS_S1PTW_BITS_OFFSET: u64 = 7;
-pub const ESR_EL2_ISS_S1PTW: u64 = 1 << ESR_EL2_ISS_S1PTW_BITS_OFFSET;
 
+impl Registers {
+    /// Read the general purpose register, register number 31 is treated as XZR
+    pub fn read(&self, register_number: usize) -> u64 {
+        if register_number >= 31 {
+            return 0;
+        }
+        let registers = unsafe { &*(self as *const _ as usize as *const [u64; 32]) };
+        registers[register_number]
+    }
+
+    /// Write the general purpose register, writes to XZR(register number 31) are ignored
+    pub fn write(&mut self, register_number: usize, value: u64) {
+        if register_number >= 31 {
+            return;
+        }
+        let registers = unsafe { &mut *(self as *mut _ as usize as *mut [u64; 32]) };
+        registers[register_number] = value;
+    }
+}
 /* HPFAR_EL2 */
 pub const HPFAR_EL2_FIPA_BITS_OFFSET: u64 = 4;
 pub const HPFAR_EL2_FIPA: u64 = ((1 << 44) - 1) & !((1 << 4) - 1);
 
+/// The registers of EL1 saved by the exception vectors, valid while handling the exception
+static mut CURRENT_REGISTERS: *const Registers = core::ptr::null();
+
+/// Get the registers of EL1 saved at the entry of the exception being handled
+///
+/// # Result
+/// If the exception from EL1 is being handled, returns Some(registers), otherwise None
+pub fn current_registers() -> Option<&'static Registers> {
+    unsafe { CURRENT_REGISTERS.as_ref() }
+}
+
 #[no_mangle]
-extern "C" fn irq_handler() {}
+extern "C" fn irq_handler(registers: *mut Registers) {
+    let start = stats::get_timestamp();
+    let pc = get_elr_el2();
+    vgic::handle_physical_interrupt();
+    crate::console::mux::poll_input();
+    gdb::poll(unsafe { &mut *registers });
+    monitor::poll(unsafe { &mut *registers });
+    vgic::flush_pending_interrupts();
+    stats::record_irq_exit(pc, start);
+}
+
+/// Handle the IRQ taken while EL2 itself was running
+///
+/// The saved registers are of EL2, so they are not passed to the debugger or the monitor,
+/// and it is not counted as the exit of the guest.
+#[no_mangle]
+extern "C" fn irq_current_el_handler(_registers: *mut Registers) {
+    vgic::handle_physical_interrupt();
+    vgic::flush_pending_interrupts();
+}
 
 #[no_mangle]
 extern "C" fn synchronous_handler(registers: *mut Registers) {
+    let start = stats::get_timestamp();
+    let pc = get_elr_el2();
+    let esr = Esr::new(get_esr_el2());
+    /* The handler may change ELR_EL2, so take the trace information at the entry */
+    let detail = if esr.exception_class() == ExceptionClass::DataAbortLowerEl {
+        get_faulting_ipa() as u64
+    } else {
+        get_esr_el2() & ESR_EL2_ISS
+    };
+    unsafe { CURRENT_REGISTERS = registers };
+    _synchronous_handler(registers);
+    vgic::flush_pending_interrupts();
+    unsafe { CURRENT_REGISTERS = core::ptr::null() };
+    stats::record_synchronous_exit(esr.exception_class_number(), pc, detail, start);
+}
+
+fn _synchronous_handler(registers: *mut Registers) {
     /*println!("Synchronous Exception!");
     println!("Fault at {:#X}", get_elr_el2());*/
-    let esr_el2 = get_esr_el2();
-    //println!("ESR_EL2: {:#X}", esr_el2);
-    let ec = esr_el2 & ESR_EL2_EC;
-    match ec {
-        ESR_EL2_EC_DATA_ABORT => data_abort_handler(unsafe { &mut *registers }, esr_el2),
-        ESR_EL2_EC_INSTRUCTION_ABORT => instruction_abort_handler(unsafe { &mut *registers }, esr_el2),
+    let esr = Esr::new(get_esr_el2());
+    //println!("{}", esr);
+    match (esr.exception_class(), esr.decode()) {
+        (ExceptionClass::DataAbortLowerEl, Iss::DataAbort(iss)) => {
+            data_abort_handler(unsafe { &mut *registers }, iss)
+        }
+        (ExceptionClass::InstructionAbortLowerEl, Iss::InstructionAbort(iss)) => {
+            instruction_abort_handler(unsafe { &mut *registers }, iss)
+        }
+        (ExceptionClass::Brk64, Iss::Brk { comment }) => {
+            gdb::software_breakpoint_handler(unsafe { &mut *registers }, comment)
+        }
+        (
+            ExceptionClass::BreakpointLowerEl
+            | ExceptionClass::SoftwareStepLowerEl
+            | ExceptionClass::WatchpointLowerEl,
+            _,
+        ) => gdb::debug_exception_handler(unsafe { &mut *registers }, esr.exception_class()),
+        (ExceptionClass::Hvc64, Iss::Hvc { imm16 }) => {
+            hypercall::hypercall_handler(unsafe { &mut *registers }, imm16)
+        }
+        (ExceptionClass::Smc64, Iss::Smc { imm16 }) => {
+            smc::smc_handler(unsafe { &mut *registers }, imm16)
+        }
+        (
+            ExceptionClass::WfxTrap,
+            Iss::WfxTrap {
+                wfx_type,
+                register_number,
+                is_register_valid,
+                ..
+            },
+        ) => wfx_handler(
+            unsafe { &mut *registers },
+            wfx_type,
+            is_register_valid.then_some(register_number),
+        ),
+        (
+            ExceptionClass::MsrMrsSystemInstructionTrap,
+            Iss::SystemRegister {
+                op0,
+                op1,
+                crn,
+                crm,
+                op2,
+                register_number,
+                is_read,
+            },
+        ) => {
+            let register = SystemRegister::new(op0, op1, crn, crm, op2);
+            let start = stats::get_timestamp();
+            let result = sysreg::system_register_trap_handler(
+                unsafe { &mut *registers },
+                register,
+                register_number,
+                is_read,
+            );
+            stats::record_system_register_access(register, is_read, start);
+            if result.is_err() {
+                warn!(
+                    "Unhandled System Register Access at {:#X}, reflected as UNDEFINED: {}",
+                    get_elr_el2(),
+                    esr
+                );
+                inject::inject_undefined_instruction();
+            }
+        }
         _ => {
-            panic!("Unknown Exception: {}", ec >> ESR_EL2_EC_BITS_OFFSET);
+            warn!(
+                "Unhandled Exception at {:#X}, reflected as UNDEFINED: {}",
+                get_elr_el2(),
+                esr
+            );
+            inject::inject_undefined_instruction();
         }
     }
 }
@@ -260,94 +387,259 @@ pub fn setup_exception() {
     unsafe { set_vbar_el2(&exception_table as *const _ as usize as u64) }
 }
 
-// ページフォールトの原因を特定
-fn data_abort_handler(registers: &mut Registers, esr_el2: u64) {
-    if esr_el2 & ESR_EL2_ISS_ISV == 0 {
-        panic!("Data Abort Info is not available.");
-    }
-    let is_64bit_resigter = (esr_el2 & ESR_EL2_ISS_SF) != 0;
-    let access_width = match (esr_el2 & ESR_EL2_ISS_SAS) >> ESR_EL2_ISS_SAS_BITS_OFFSET {
-        0b00 => 8,
-        0b01 => 16,
-        0b10 => 32,
-        0b11 => 64,
-        _ => unreachable!(),
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GuestAccessFault {
+    /// Neither the stage 2 translation nor the MMIO devices back the address
+    Unmapped,
+    /// The device rejected the access
+    DeviceError,
+}
+
+/// Access the guest physical address trapped by the stage 2 translation
+///
+/// The access to the address without devices is handled by [`UnmappedAccessPolicy`].
+///
+/// # Arguments
+/// * `address` - the intermediate physical address
+/// * `access_width` - the width in bits
+/// * `value` - Some(the value to write) for the write access, None for the read access
+///
+/// # Result
+/// If succeeded, Ok(the read value, or 0 for the write access), otherwise Err(GuestAccessFault)
+pub fn access_guest_physical_address(
+    address: usize,
+    access_width: u64,
+    value: Option<u64>,
+) -> Result<u64, GuestAccessFault> {
+    let vm = vm::current();
+    let Some((device, offset)) = vm.mmio_bus.find_device(address) else {
+        return if vm.unmapped_access_policy == UnmappedAccessPolicy::ReadAsZeroWriteIgnore {
+            info!(
+                "{:#X} {} ({} Bits)(Value: {:#X}), no device",
+                address,
+                if value.is_some() { "<=" } else { "=>" },
+                access_width,
+                value.unwrap_or(0)
+            );
+            Ok(0)
+        } else {
+            Err(GuestAccessFault::Unmapped)
+        };
+    };
+    let start = stats::get_timestamp();
+    let result = match value {
+        Some(v) => device
+            .write(offset, access_width, v)
+            .and(Ok(0))
+            .or(Err(GuestAccessFault::DeviceError)),
+        None => device
+            .read(offset, access_width)
+            .or(Err(GuestAccessFault::DeviceError)),
     };
-    let is_write_access = (esr_el2 & ESR_EL2_ISS_WNR) != 0;
+    stats::record_mmio_access(address - offset, offset, value.is_some(), start);
+    /* The device is no longer borrowed, process the work which it requested for the others */
+    crate::mmio::virt_mmio::process_deferred_queue_notifications();
+    result
+}
+
+/// Handle the access to the unbacked address by [`UnmappedAccessPolicy`] of the VM
+///
+/// ELR_EL2 must point the faulting instruction.
+fn handle_unmapped_access(
+    registers: &Registers,
+    address: usize,
+    virtual_address: usize,
+    is_write_access: bool,
+) {
+    match vm::current().unmapped_access_policy {
+        UnmappedAccessPolicy::InjectAbort => {
+            inject::inject_synchronous_external_abort(virtual_address as u64, is_write_access)
+        }
+        UnmappedAccessPolicy::ReadAsZeroWriteIgnore => {
+            unreachable!("The access is completed by access_guest_physical_address")
+        }
+        UnmappedAccessPolicy::StopVm => {
+            error!(
+                "The guest {} the unbacked address {:#X} (VA: {:#X})",
+                if is_write_access { "wrote" } else { "read" },
+                address,
+                virtual_address
+            );
+            dump_guest_state(registers);
+            error!("The VM is stopped.");
+            halt_loop();
+        }
+    }
+}
 
-    let register_number = ((esr_el2 & ESR_EL2_ISS_SRT) >> ESR_EL2_ISS_SRT_BITS_OFFSET) as usize;
-    let register: &mut u64 =
-        &mut unsafe { &mut *(registers as *mut _ as usize as *mut [u64; 32]) }[register_number];
+/// Handle the access rejected by the MMIO device
+///
+/// The guest must not be able to stop the hypervisor by the access, so the access is
+/// reflected to the guest as the synchronous external abort.
+/// ELR_EL2 must point the faulting instruction.
+fn handle_device_error(address: usize, virtual_address: usize, is_write_access: bool) {
+    warn!(
+        "The device rejected the {} of {:#X} (VA: {:#X}), reflected as External Abort",
+        if is_write_access { "write" } else { "read" },
+        address,
+        virtual_address
+    );
+    inject::inject_synchronous_external_abort(virtual_address as u64, is_write_access);
+}
 
-    let address = (((get_hpfar_el2() & HPFAR_EL2_FIPA) >> HPFAR_EL2_FIPA_BITS_OFFSET)
-        << crate::paging::PAGE_SHIFT)
-        | (get_far_el2() & ((1 << crate::paging::PAGE_SHIFT) - 1));
-
-    if (0x09000000..0x09001000).contains(&address) {
-        // PL011
-        let offset = (address - 0x09000000) as usize;
-        if is_write_access {
-            let register_value = if is_64bit_resigter {
-                *register
-            } else {
-                *register & (u32::MAX as u64)
-            };
-            pl011::mmio_write(offset, access_width, register_value).expect("Failed to handle MMIO");
+/// Print the syndrome, the registers and the call stack of the guest
+fn dump_guest_state(registers: &Registers) {
+    println!("{}", Esr::new(get_esr_el2()));
+    dump_guest_registers(registers);
+    unwind::print_guest_backtrace(registers, get_elr_el2());
+}
+
+/// Print the general purpose registers and the state saved at the exit of the guest
+pub fn dump_guest_registers(registers: &Registers) {
+    for i in (0..31).step_by(2) {
+        if i == 30 {
+            println!("X30: {:#018X}", registers.read(30));
         } else {
-            *register = pl011::mmio_read(offset, access_width).expect("Failed to handle MMIO");
+            println!(
+                "X{:<2}: {:#018X} X{:<2}: {:#018X}",
+                i,
+                registers.read(i),
+                i + 1,
+                registers.read(i + 1)
+            );
         }
-    } else if (0xa000000..0xa0001ff).contains(&address) {
-        // virtio mmio
-        let offset = (address - 0xa000000) as usize;
-        if is_write_access {
-            let register_value = if is_64bit_resigter {
-                *register
-            } else {
-                *register & (u32::MAX as u64)
-            };
-            virt_mmio::virt_mmio_write(0x1, offset, access_width, register_value as u32)
-                .expect("Failed to handle VIRTIO MMIO");
-        } else {
-            *register = virt_mmio::virt_mmio_read(0x1, offset, access_width)
-                .expect("Failed to handle VIRTIO MMIO") as u64;
+    }
+    println!(
+        "SP_EL0: {:#018X} SP_EL1: {:#018X}",
+        get_sp_el0(),
+        get_sp_el1()
+    );
+    println!(
+        "SPSR_EL2: {:#018X} ELR_EL2: {:#018X}",
+        get_spsr_el2(),
+        get_elr_el2()
+    );
+}
+
+// ページフォールトの原因を特定
+fn data_abort_handler(registers: &mut Registers, iss: DataAbortIss) {
+    if !iss.is_valid() {
+        /* The syndrome does not describe the access, decode the instruction */
+        match load_store::emulate(registers) {
+            Ok(()) => unsafe { advance_elr_el2() },
+            Err(load_store::EmulationError::AccessFault {
+                address,
+                virtual_address,
+                is_write_access,
+                fault: GuestAccessFault::Unmapped,
+            }) => handle_unmapped_access(registers, address, virtual_address, is_write_access),
+            Err(load_store::EmulationError::Unsupported) => {
+                warn!(
+                    "Unsupported MMIO instruction at {:#X}, reflected as UNDEFINED: {}",
+                    get_elr_el2(),
+                    Esr::new(get_esr_el2())
+                );
+                inject::inject_undefined_instruction();
+            }
+            Err(load_store::EmulationError::AccessFault {
+                address,
+                virtual_address,
+                is_write_access,
+                fault: GuestAccessFault::DeviceError,
+            }) => handle_device_error(address, virtual_address, is_write_access),
+        }
+        return;
+    }
+    let is_64bit_register = iss.is_64bit_register();
+    let access_width = iss.access_width();
+    let is_write_access = iss.is_write_access();
+    let register_number = iss.register_number();
+    let access_mask = if access_width == 64 {
+        u64::MAX
+    } else {
+        (1 << access_width) - 1
+    };
+    let address = get_faulting_ipa();
+
+    let result = if is_write_access {
+        if iss.is_acquire_release() {
+            /* Store-Release: the preceding accesses must be observed before this write */
+            dsb();
         }
+        /* Register number 31 is XZR */
+        let register_value = registers.read(register_number) & access_mask;
+        access_guest_physical_address(address, access_width, Some(register_value))
     } else {
-        println!(
-            "{:#X} {} {}{} ({} Bits)(Value: {:#X})",
-            address,
-            if is_write_access { "<=" } else { "=>" },
-            if is_64bit_resigter { "X" } else { "W" },
-            register_number,
-            access_width,
-            *register
-        );
+        access_guest_physical_address(address, access_width, None).map(|value| {
+            let mut value = value & access_mask;
+            if iss.is_sign_extended() && access_width < 64 {
+                value = (((value << (64 - access_width)) as i64) >> (64 - access_width)) as u64;
+            }
+            if !is_64bit_register {
+                /* The upper 32 bits of the X register are cleared by the W register load */
+                value &= u32::MAX as u64;
+            }
+            registers.write(register_number, value);
+            if iss.is_acquire_release() {
+                /* Load-Acquire: the following accesses must be observed after this read */
+                dsb();
+            }
+            value
+        })
+    };
+    match result {
+        Ok(_) => unsafe { advance_elr_el2() },
+        Err(GuestAccessFault::Unmapped) => {
+            handle_unmapped_access(registers, address, get_far_el2() as usize, is_write_access)
+        }
+        Err(GuestAccessFault::DeviceError) => {
+            handle_device_error(address, get_far_el2() as usize, is_write_access)
+        }
+    }
+}
+
+/// Get the intermediate physical address of the stage 2 fault from HPFAR_EL2 and FAR_EL2
+fn get_faulting_ipa() -> usize {
+    ((((get_hpfar_el2() & HPFAR_EL2_FIPA) >> HPFAR_EL2_FIPA_BITS_OFFSET)
+        << crate::paging::PAGE_SHIFT)
+        | (get_far_el2() & ((1 << crate::paging::PAGE_SHIFT) - 1))) as usize
+}
+
+/// Handle trapped WFI/WFE/WFIT/WFET
+///
+/// WFI blocks the vCPU until an interrupt becomes pending.
+/// WFE is treated as a hint to yield, and returns immediately.
+fn wfx_handler(registers: &mut Registers, wfx_type: WfxType, timeout_register: Option<usize>) {
+    let deadline = timeout_register.map(|r| registers.read(r));
+    match wfx_type {
+        WfxType::Wfi | WfxType::Wfit => vcpu::current().block(deadline),
+        WfxType::Wfe | WfxType::Wfet => { /* Only one vCPU exists, nothing to yield to */ }
     }
     unsafe { advance_elr_el2() };
 }
 
-pub fn instruction_abort_handler(registers: &mut Registers, esr_el2: u64) {
-    let ifsc = esr_el2 & ESR_EL2_ISS_IFSC;
-    match ifsc {
-        0b000000 => println!("Address size fault, level 0 of translation or translation table base register."),
-        0b000001 => println!("Address size fault, level 1."),
-        0b000010 => println!("Address size fault, level 2."),
-        0b000011 => println!("Address size fault, level 3."),
-        0b000100 => println!("Translation fault, level 0."),
-        0b000101 => println!("Translation fault, level 1."),
-        0b000110 => {
-            println!("Translation fault, level 2.");
-            if esr_el2 & ESR_EL2_ISS_S1PTW == 0 {
-                println!("Fault not on a stage 2 translation for a stage 1 translation table walk.");
-            } else {
-                println!("Fault on the stage 2 translation of an access for a stage 1 translation table walk.");
-            }
-        },
-        0b000111 => println!("Translation fault, level 3."),
-        _ => {
-            panic!("Unknown Instruction Abort");
-        }
+/// Handle the instruction fetch from the address which is not mapped by stage 2
+///
+/// The fetch cannot be completed by any device, so the guest takes the External Abort
+/// unless [`UnmappedAccessPolicy::StopVm`] is selected.
+pub fn instruction_abort_handler(registers: &mut Registers, iss: InstructionAbortIss) {
+    let virtual_address = if iss.is_far_not_valid() {
+        get_elr_el2()
+    } else {
+        get_far_el2()
+    };
+    warn!(
+        "Instruction Abort at {:#X} (HPFAR_EL2: {:#X}): {}",
+        virtual_address,
+        get_hpfar_el2(),
+        Esr::new(get_esr_el2())
+    );
+    if vm::current().unmapped_access_policy == UnmappedAccessPolicy::StopVm {
+        dump_guest_state(registers);
+        error!("The VM is stopped.");
+        halt_loop();
     }
-    panic!();
+    inject::inject_instruction_abort(virtual_address, inject::FSC_SYNCHRONOUS_EXTERNAL_ABORT);
 }
 
 pub unsafe fn advance_elr_el2() {
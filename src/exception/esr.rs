@@ -0,0 +1,666 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Exception Syndrome Register Decoder
+//!
+//! Decode ESR_EL2 into the exception class and the instruction specific syndrome.
+//! (ARM DDI 0487 D17.2.37 ESR_EL2, Exception Syndrome Register (EL2))
+//!
+
+use core::fmt;
+
+pub const ESR_EL2_EC_BITS_OFFSET: u64 = 26;
+pub const ESR_EL2_EC: u64 = 0b111111 << ESR_EL2_EC_BITS_OFFSET;
+pub const ESR_EL2_IL: u64 = 1 << 25;
+pub const ESR_EL2_ISS: u64 = (1 << 25) - 1;
+
+/// Exception Class, ESR_EL2[31:26]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExceptionClass {
+    Unknown,
+    WfxTrap,
+    Mcr32Cp15Trap,
+    Mcrr32Cp15Trap,
+    Mcr32Cp14Trap,
+    LdcStcTrap,
+    SveSimdFpTrap,
+    Ld64bTrap,
+    Mrrc32Cp14Trap,
+    BranchTargetException,
+    IllegalExecutionState,
+    Svc32,
+    Hvc32,
+    Smc32,
+    Svc64,
+    Hvc64,
+    Smc64,
+    MsrMrsSystemInstructionTrap,
+    SveTrap,
+    EretTrap,
+    TstartAccess,
+    PointerAuthenticationFailure,
+    SmeTrap,
+    InstructionAbortLowerEl,
+    InstructionAbortCurrentEl,
+    PcAlignmentFault,
+    DataAbortLowerEl,
+    DataAbortCurrentEl,
+    SpAlignmentFault,
+    MemoryOperationException,
+    FpException32,
+    FpException64,
+    SError,
+    BreakpointLowerEl,
+    BreakpointCurrentEl,
+    SoftwareStepLowerEl,
+    SoftwareStepCurrentEl,
+    WatchpointLowerEl,
+    WatchpointCurrentEl,
+    Bkpt32,
+    VectorCatch32,
+    Brk64,
+    Reserved(u8),
+}
+
+impl ExceptionClass {
+    pub const fn from_ec(ec: u8) -> Self {
+        match ec {
+            0x00 => Self::Unknown,
+            0x01 => Self::WfxTrap,
+            0x03 => Self::Mcr32Cp15Trap,
+            0x04 => Self::Mcrr32Cp15Trap,
+            0x05 => Self::Mcr32Cp14Trap,
+            0x06 => Self::LdcStcTrap,
+            0x07 => Self::SveSimdFpTrap,
+            0x0a => Self::Ld64bTrap,
+            0x0c => Self::Mrrc32Cp14Trap,
+            0x0d => Self::BranchTargetException,
+            0x0e => Self::IllegalExecutionState,
+            0x11 => Self::Svc32,
+            0x12 => Self::Hvc32,
+            0x13 => Self::Smc32,
+            0x15 => Self::Svc64,
+            0x16 => Self::Hvc64,
+            0x17 => Self::Smc64,
+            0x18 => Self::MsrMrsSystemInstructionTrap,
+            0x19 => Self::SveTrap,
+            0x1a => Self::EretTrap,
+            0x1b => Self::TstartAccess,
+            0x1c => Self::PointerAuthenticationFailure,
+            0x1d => Self::SmeTrap,
+            0x20 => Self::InstructionAbortLowerEl,
+            0x21 => Self::InstructionAbortCurrentEl,
+            0x22 => Self::PcAlignmentFault,
+            0x24 => Self::DataAbortLowerEl,
+            0x25 => Self::DataAbortCurrentEl,
+            0x26 => Self::SpAlignmentFault,
+            0x27 => Self::MemoryOperationException,
+            0x28 => Self::FpException32,
+            0x2c => Self::FpException64,
+            0x2f => Self::SError,
+            0x30 => Self::BreakpointLowerEl,
+            0x31 => Self::BreakpointCurrentEl,
+            0x32 => Self::SoftwareStepLowerEl,
+            0x33 => Self::SoftwareStepCurrentEl,
+            0x34 => Self::WatchpointLowerEl,
+            0x35 => Self::WatchpointCurrentEl,
+            0x38 => Self::Bkpt32,
+            0x3a => Self::VectorCatch32,
+            0x3c => Self::Brk64,
+            _ => Self::Reserved(ec),
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Unknown => "Unknown reason",
+            Self::WfxTrap => "Trapped WFI/WFE/WFIT/WFET",
+            Self::Mcr32Cp15Trap => "Trapped MCR/MRC (coproc=0b1111)",
+            Self::Mcrr32Cp15Trap => "Trapped MCRR/MRRC (coproc=0b1111)",
+            Self::Mcr32Cp14Trap => "Trapped MCR/MRC (coproc=0b1110)",
+            Self::LdcStcTrap => "Trapped LDC/STC",
+            Self::SveSimdFpTrap => "Trapped SVE/Advanced SIMD/Floating-point",
+            Self::Ld64bTrap => "Trapped LD64B/ST64B/ST64BV/ST64BV0",
+            Self::Mrrc32Cp14Trap => "Trapped MRRC (coproc=0b1110)",
+            Self::BranchTargetException => "Branch Target Exception",
+            Self::IllegalExecutionState => "Illegal Execution state",
+            Self::Svc32 => "SVC (AArch32)",
+            Self::Hvc32 => "HVC (AArch32)",
+            Self::Smc32 => "SMC (AArch32)",
+            Self::Svc64 => "SVC (AArch64)",
+            Self::Hvc64 => "HVC (AArch64)",
+            Self::Smc64 => "SMC (AArch64)",
+            Self::MsrMrsSystemInstructionTrap => "Trapped MSR/MRS/System instruction",
+            Self::SveTrap => "Trapped SVE",
+            Self::EretTrap => "Trapped ERET/ERETAA/ERETAB",
+            Self::TstartAccess => "TSTART access",
+            Self::PointerAuthenticationFailure => "Pointer Authentication failure",
+            Self::SmeTrap => "Trapped SME",
+            Self::InstructionAbortLowerEl => "Instruction Abort from a lower Exception level",
+            Self::InstructionAbortCurrentEl => "Instruction Abort taken without a change in Exception level",
+            Self::PcAlignmentFault => "PC alignment fault",
+            Self::DataAbortLowerEl => "Data Abort from a lower Exception level",
+            Self::DataAbortCurrentEl => "Data Abort taken without a change in Exception level",
+            Self::SpAlignmentFault => "SP alignment fault",
+            Self::MemoryOperationException => "Memory Operation Exception",
+            Self::FpException32 => "Trapped floating-point exception (AArch32)",
+            Self::FpException64 => "Trapped floating-point exception (AArch64)",
+            Self::SError => "SError interrupt",
+            Self::BreakpointLowerEl => "Breakpoint from a lower Exception level",
+            Self::BreakpointCurrentEl => "Breakpoint taken without a change in Exception level",
+            Self::SoftwareStepLowerEl => "Software Step from a lower Exception level",
+            Self::SoftwareStepCurrentEl => "Software Step taken without a change in Exception level",
+            Self::WatchpointLowerEl => "Watchpoint from a lower Exception level",
+            Self::WatchpointCurrentEl => "Watchpoint taken without a change in Exception level",
+            Self::Bkpt32 => "BKPT (AArch32)",
+            Self::VectorCatch32 => "Vector Catch (AArch32)",
+            Self::Brk64 => "BRK (AArch64)",
+            Self::Reserved(_) => "Reserved",
+        }
+    }
+}
+
+/// Fault Status Code, DFSC/IFSC of Data Abort, Instruction Abort and SError
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultStatusCode {
+    AddressSizeFault(i8),
+    TranslationFault(i8),
+    AccessFlagFault(i8),
+    PermissionFault(i8),
+    SynchronousExternalAbort,
+    SynchronousTagCheckFault,
+    SynchronousExternalAbortOnTableWalk(i8),
+    SynchronousParityOrEccError,
+    SynchronousParityOrEccErrorOnTableWalk(i8),
+    AlignmentFault,
+    GranuleProtectionFaultOnTableWalk(i8),
+    GranuleProtectionFault,
+    TlbConflictAbort,
+    UnsupportedAtomicHardwareUpdateFault,
+    ImplementationDefinedLockdown,
+    ImplementationDefinedUnsupportedExclusiveOrAtomic,
+    Unknown(u8),
+}
+
+impl FaultStatusCode {
+    pub const fn from_fsc(fsc: u8) -> Self {
+        let level = (fsc & 0b11) as i8;
+        match fsc {
+            0b000000..=0b000011 => Self::AddressSizeFault(level),
+            0b101001 => Self::AddressSizeFault(-1),
+            0b000100..=0b000111 => Self::TranslationFault(level),
+            0b101011 => Self::TranslationFault(-1),
+            0b001000..=0b001011 => Self::AccessFlagFault(level),
+            0b001100..=0b001111 => Self::PermissionFault(level),
+            0b010000 => Self::SynchronousExternalAbort,
+            0b010001 => Self::SynchronousTagCheckFault,
+            0b010100..=0b010111 => Self::SynchronousExternalAbortOnTableWalk(level),
+            0b010011 => Self::SynchronousExternalAbortOnTableWalk(-1),
+            0b011000 => Self::SynchronousParityOrEccError,
+            0b011100..=0b011111 => Self::SynchronousParityOrEccErrorOnTableWalk(level),
+            0b011011 => Self::SynchronousParityOrEccErrorOnTableWalk(-1),
+            0b100001 => Self::AlignmentFault,
+            0b100100..=0b100111 => Self::GranuleProtectionFaultOnTableWalk(level),
+            0b100011 => Self::GranuleProtectionFaultOnTableWalk(-1),
+            0b101000 => Self::GranuleProtectionFault,
+            0b110000 => Self::TlbConflictAbort,
+            0b110001 => Self::UnsupportedAtomicHardwareUpdateFault,
+            0b110100 => Self::ImplementationDefinedLockdown,
+            0b110101 => Self::ImplementationDefinedUnsupportedExclusiveOrAtomic,
+            _ => Self::Unknown(fsc),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddressSizeFault(l) => write!(f, "Address size fault, level {l}"),
+            Self::TranslationFault(l) => write!(f, "Translation fault, level {l}"),
+            Self::AccessFlagFault(l) => write!(f, "Access flag fault, level {l}"),
+            Self::PermissionFault(l) => write!(f, "Permission fault, level {l}"),
+            Self::SynchronousExternalAbort => {
+                write!(f, "Synchronous External abort, not on translation table walk")
+            }
+            Self::SynchronousTagCheckFault => write!(f, "Synchronous Tag Check Fault"),
+            Self::SynchronousExternalAbortOnTableWalk(l) => write!(
+                f,
+                "Synchronous External abort on translation table walk, level {l}"
+            ),
+            Self::SynchronousParityOrEccError => write!(
+                f,
+                "Synchronous parity or ECC error, not on translation table walk"
+            ),
+            Self::SynchronousParityOrEccErrorOnTableWalk(l) => write!(
+                f,
+                "Synchronous parity or ECC error on translation table walk, level {l}"
+            ),
+            Self::AlignmentFault => write!(f, "Alignment fault"),
+            Self::GranuleProtectionFaultOnTableWalk(l) => write!(
+                f,
+                "Granule Protection Fault on translation table walk, level {l}"
+            ),
+            Self::GranuleProtectionFault => {
+                write!(f, "Granule Protection Fault, not on translation table walk")
+            }
+            Self::TlbConflictAbort => write!(f, "TLB conflict abort"),
+            Self::UnsupportedAtomicHardwareUpdateFault => {
+                write!(f, "Unsupported atomic hardware update fault")
+            }
+            Self::ImplementationDefinedLockdown => write!(f, "IMPLEMENTATION DEFINED (Lockdown)"),
+            Self::ImplementationDefinedUnsupportedExclusiveOrAtomic => write!(
+                f,
+                "IMPLEMENTATION DEFINED (Unsupported Exclusive or Atomic access)"
+            ),
+            Self::Unknown(fsc) => write!(f, "Unknown fault status code: {:#08b}", fsc),
+        }
+    }
+}
+
+/// The type of the trapped WFx instruction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WfxType {
+    Wfi,
+    Wfe,
+    Wfit,
+    Wfet,
+}
+
+/// ISS of Data Abort
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DataAbortIss(u32);
+
+impl DataAbortIss {
+    pub const ISV: u32 = 1 << 24;
+    pub const SAS_BITS_OFFSET: u32 = 22;
+    pub const SAS: u32 = 0b11 << Self::SAS_BITS_OFFSET;
+    pub const SSE: u32 = 1 << 21;
+    pub const SRT_BITS_OFFSET: u32 = 16;
+    pub const SRT: u32 = 0b11111 << Self::SRT_BITS_OFFSET;
+    pub const SF: u32 = 1 << 15;
+    pub const AR: u32 = 1 << 14;
+    pub const FNV: u32 = 1 << 10;
+    pub const EA: u32 = 1 << 9;
+    pub const CM: u32 = 1 << 8;
+    pub const S1PTW: u32 = 1 << 7;
+    pub const WNR: u32 = 1 << 6;
+    pub const DFSC: u32 = 0b111111;
+
+    /// Instruction Syndrome Valid, SAS/SSE/SRT/SF/AR are valid only if this is true
+    pub const fn is_valid(&self) -> bool {
+        (self.0 & Self::ISV) != 0
+    }
+
+    /// Syndrome Access Size in bits
+    pub const fn access_width(&self) -> u64 {
+        8 << ((self.0 & Self::SAS) >> Self::SAS_BITS_OFFSET)
+    }
+
+    /// Syndrome Sign Extend
+    pub const fn is_sign_extended(&self) -> bool {
+        (self.0 & Self::SSE) != 0
+    }
+
+    /// Syndrome Register Transfer
+    pub const fn register_number(&self) -> usize {
+        ((self.0 & Self::SRT) >> Self::SRT_BITS_OFFSET) as usize
+    }
+
+    /// Sixty Four bit general-purpose register transfer
+    pub const fn is_64bit_register(&self) -> bool {
+        (self.0 & Self::SF) != 0
+    }
+
+    /// Acquire/Release
+    pub const fn is_acquire_release(&self) -> bool {
+        (self.0 & Self::AR) != 0
+    }
+
+    /// FAR not Valid
+    pub const fn is_far_not_valid(&self) -> bool {
+        (self.0 & Self::FNV) != 0
+    }
+
+    /// External abort type
+    pub const fn is_external_abort(&self) -> bool {
+        (self.0 & Self::EA) != 0
+    }
+
+    /// Cache maintenance
+    pub const fn is_cache_maintenance(&self) -> bool {
+        (self.0 & Self::CM) != 0
+    }
+
+    /// The fault was on the stage 2 translation for a stage 1 translation table walk
+    pub const fn is_stage1_page_table_walk(&self) -> bool {
+        (self.0 & Self::S1PTW) != 0
+    }
+
+    /// Write not Read
+    pub const fn is_write_access(&self) -> bool {
+        (self.0 & Self::WNR) != 0
+    }
+
+    pub const fn fault_status_code(&self) -> FaultStatusCode {
+        FaultStatusCode::from_fsc((self.0 & Self::DFSC) as u8)
+    }
+}
+
+/// ISS of Instruction Abort
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InstructionAbortIss(u32);
+
+impl InstructionAbortIss {
+    pub const FNV: u32 = 1 << 10;
+    pub const EA: u32 = 1 << 9;
+    pub const S1PTW: u32 = 1 << 7;
+    pub const IFSC: u32 = 0b111111;
+
+    pub const fn is_far_not_valid(&self) -> bool {
+        (self.0 & Self::FNV) != 0
+    }
+
+    pub const fn is_external_abort(&self) -> bool {
+        (self.0 & Self::EA) != 0
+    }
+
+    pub const fn is_stage1_page_table_walk(&self) -> bool {
+        (self.0 & Self::S1PTW) != 0
+    }
+
+    pub const fn fault_status_code(&self) -> FaultStatusCode {
+        FaultStatusCode::from_fsc((self.0 & Self::IFSC) as u8)
+    }
+}
+
+/// Decoded Instruction Specific Syndrome
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Iss {
+    WfxTrap {
+        wfx_type: WfxType,
+        /// The register number of WFIT/WFET, valid only if `is_register_valid` is true
+        register_number: usize,
+        is_register_valid: bool,
+        condition: Option<u8>,
+    },
+    /// Trapped MSR/MRS/System instruction
+    SystemRegister {
+        op0: u8,
+        op1: u8,
+        crn: u8,
+        crm: u8,
+        op2: u8,
+        register_number: usize,
+        /// true: MRS(read from the system register), false: MSR(write to the system register)
+        is_read: bool,
+    },
+    Svc { imm16: u16 },
+    Hvc { imm16: u16 },
+    Smc { imm16: u16 },
+    Brk { comment: u16 },
+    SveSimdFp { condition: Option<u8> },
+    Sve,
+    DataAbort(DataAbortIss),
+    InstructionAbort(InstructionAbortIss),
+    SError {
+        is_implementation_defined: bool,
+        is_implicit_error_synchronization_barrier: bool,
+        error_type: u8,
+        is_external_abort: bool,
+        fault_status_code: FaultStatusCode,
+    },
+    SoftwareStep { is_valid: bool, is_exclusive: bool },
+    Watchpoint { is_write_access: bool, is_cache_maintenance: bool },
+    FpException { trapped_fault: bool, flags: u8 },
+    Raw(u32),
+}
+
+/// ESR_EL2
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Esr(u64);
+
+impl Esr {
+    pub const fn new(esr_el2: u64) -> Self {
+        Self(esr_el2)
+    }
+
+    pub const fn exception_class(&self) -> ExceptionClass {
+        ExceptionClass::from_ec(self.exception_class_number())
+    }
+
+    /// The raw value of ESR_EL2.EC
+    pub const fn exception_class_number(&self) -> u8 {
+        ((self.0 & ESR_EL2_EC) >> ESR_EL2_EC_BITS_OFFSET) as u8
+    }
+
+    pub const fn iss(&self) -> u32 {
+        (self.0 & ESR_EL2_ISS) as u32
+    }
+
+    /// Decode ISS according to the exception class
+    pub fn decode(&self) -> Iss {
+        let iss = self.iss();
+        let bits = |high: u32, low: u32| (iss >> low) & ((1 << (high - low + 1)) - 1);
+        let condition = if bits(24, 24) != 0 {
+            Some(bits(23, 20) as u8)
+        } else {
+            None
+        };
+        match self.exception_class() {
+            ExceptionClass::WfxTrap => Iss::WfxTrap {
+                wfx_type: match bits(1, 0) {
+                    0b00 => WfxType::Wfi,
+                    0b01 => WfxType::Wfe,
+                    0b10 => WfxType::Wfit,
+                    _ => WfxType::Wfet,
+                },
+                register_number: bits(9, 5) as usize,
+                is_register_valid: bits(2, 2) != 0,
+                condition,
+            },
+            ExceptionClass::MsrMrsSystemInstructionTrap => Iss::SystemRegister {
+                op0: bits(21, 20) as u8,
+                op2: bits(19, 17) as u8,
+                op1: bits(16, 14) as u8,
+                crn: bits(13, 10) as u8,
+                register_number: bits(9, 5) as usize,
+                crm: bits(4, 1) as u8,
+                is_read: bits(0, 0) != 0,
+            },
+            ExceptionClass::Svc64 | ExceptionClass::Svc32 => Iss::Svc {
+                imm16: bits(15, 0) as u16,
+            },
+            ExceptionClass::Hvc64 | ExceptionClass::Hvc32 => Iss::Hvc {
+                imm16: bits(15, 0) as u16,
+            },
+            ExceptionClass::Smc64 | ExceptionClass::Smc32 => Iss::Smc {
+                imm16: bits(15, 0) as u16,
+            },
+            ExceptionClass::Brk64 => Iss::Brk {
+                comment: bits(15, 0) as u16,
+            },
+            ExceptionClass::SveSimdFpTrap => Iss::SveSimdFp { condition },
+            ExceptionClass::SveTrap => Iss::Sve,
+            ExceptionClass::DataAbortLowerEl | ExceptionClass::DataAbortCurrentEl => {
+                Iss::DataAbort(DataAbortIss(iss))
+            }
+            ExceptionClass::InstructionAbortLowerEl | ExceptionClass::InstructionAbortCurrentEl => {
+                Iss::InstructionAbort(InstructionAbortIss(iss))
+            }
+            ExceptionClass::SError => Iss::SError {
+                is_implementation_defined: bits(24, 24) != 0,
+                is_implicit_error_synchronization_barrier: bits(13, 13) != 0,
+                error_type: bits(12, 10) as u8,
+                is_external_abort: bits(9, 9) != 0,
+                fault_status_code: FaultStatusCode::from_fsc(bits(5, 0) as u8),
+            },
+            ExceptionClass::SoftwareStepLowerEl | ExceptionClass::SoftwareStepCurrentEl => {
+                Iss::SoftwareStep {
+                    is_valid: bits(24, 24) != 0,
+                    is_exclusive: bits(6, 6) != 0,
+                }
+            }
+            ExceptionClass::WatchpointLowerEl | ExceptionClass::WatchpointCurrentEl => {
+                Iss::Watchpoint {
+                    is_write_access: bits(6, 6) != 0,
+                    is_cache_maintenance: bits(8, 8) != 0,
+                }
+            }
+            ExceptionClass::FpException64 | ExceptionClass::FpException32 => Iss::FpException {
+                trapped_fault: bits(23, 23) != 0,
+                flags: (bits(7, 7) << 5 | bits(4, 0)) as u8,
+            },
+            _ => Iss::Raw(iss),
+        }
+    }
+}
+
+impl fmt::Display for Esr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ec = self.exception_class();
+        write!(
+            f,
+            "ESR_EL2: {:#X} (EC: {:#04X} {})",
+            self.0,
+            self.exception_class_number(),
+            ec.name()
+        )?;
+        match self.decode() {
+            Iss::WfxTrap {
+                wfx_type,
+                register_number,
+                is_register_valid,
+                condition,
+            } => {
+                write!(f, ", {:?}", wfx_type)?;
+                if is_register_valid {
+                    write!(f, ", X{register_number}")?;
+                }
+                if let Some(c) = condition {
+                    write!(f, ", COND: {:#X}", c)?;
+                }
+                Ok(())
+            }
+            Iss::SystemRegister {
+                op0,
+                op1,
+                crn,
+                crm,
+                op2,
+                register_number,
+                is_read,
+            } => write!(
+                f,
+                ", {} S{op0}_{op1}_C{crn}_C{crm}_{op2} {} X{register_number}",
+                if is_read { "MRS" } else { "MSR" },
+                if is_read { "=>" } else { "<=" },
+            ),
+            Iss::Svc { imm16 } | Iss::Hvc { imm16 } | Iss::Smc { imm16 } => {
+                write!(f, ", #{:#X}", imm16)
+            }
+            Iss::Brk { comment } => write!(f, ", comment: {:#X}", comment),
+            Iss::SveSimdFp { condition } => match condition {
+                Some(c) => write!(f, ", COND: {:#X}", c),
+                None => Ok(()),
+            },
+            Iss::Sve => Ok(()),
+            Iss::DataAbort(iss) => {
+                write!(f, ", {}", iss.fault_status_code())?;
+                write!(f, ", {}", if iss.is_write_access() { "Write" } else { "Read" })?;
+                if iss.is_valid() {
+                    write!(
+                        f,
+                        ", {}{} ({} Bits{}{})",
+                        if iss.is_64bit_register() { "X" } else { "W" },
+                        iss.register_number(),
+                        iss.access_width(),
+                        if iss.is_sign_extended() { ", Sign Extended" } else { "" },
+                        if iss.is_acquire_release() { ", Acquire/Release" } else { "" }
+                    )?;
+                } else {
+                    write!(f, ", Instruction Syndrome is not valid")?;
+                }
+                if iss.is_stage1_page_table_walk() {
+                    write!(f, ", on stage 1 translation table walk")?;
+                }
+                if iss.is_external_abort() {
+                    write!(f, ", IMPLEMENTATION DEFINED External abort type")?;
+                }
+                if iss.is_cache_maintenance() {
+                    write!(f, ", by cache maintenance")?;
+                }
+                if iss.is_far_not_valid() {
+                    write!(f, ", FAR is not valid")?;
+                }
+                Ok(())
+            }
+            Iss::InstructionAbort(iss) => {
+                write!(f, ", {}", iss.fault_status_code())?;
+                if iss.is_stage1_page_table_walk() {
+                    write!(f, ", on stage 1 translation table walk")?;
+                }
+                if iss.is_external_abort() {
+                    write!(f, ", IMPLEMENTATION DEFINED External abort type")?;
+                }
+                if iss.is_far_not_valid() {
+                    write!(f, ", FAR is not valid")?;
+                }
+                Ok(())
+            }
+            Iss::SError {
+                is_implementation_defined,
+                is_implicit_error_synchronization_barrier: _,
+                error_type,
+                is_external_abort,
+                fault_status_code,
+            } => {
+                if is_implementation_defined {
+                    write!(f, ", IMPLEMENTATION DEFINED syndrome")
+                } else {
+                    write!(
+                        f,
+                        ", {}, AET: {:#05b}{}",
+                        fault_status_code,
+                        error_type,
+                        if is_external_abort { ", External abort" } else { "" }
+                    )
+                }
+            }
+            Iss::SoftwareStep {
+                is_valid,
+                is_exclusive,
+            } => {
+                if is_valid && is_exclusive {
+                    write!(f, ", stepped a Load-Exclusive")
+                } else {
+                    Ok(())
+                }
+            }
+            Iss::Watchpoint {
+                is_write_access,
+                is_cache_maintenance,
+            } => write!(
+                f,
+                ", {}{}",
+                if is_write_access { "Write" } else { "Read" },
+                if is_cache_maintenance { ", by cache maintenance" } else { "" }
+            ),
+            Iss::FpException {
+                trapped_fault,
+                flags,
+            } => {
+                if trapped_fault {
+                    write!(f, ", flags: {:#08b}", flags)
+                } else {
+                    Ok(())
+                }
+            }
+            Iss::Raw(iss) => write!(f, ", ISS: {:#X}", iss),
+        }
+    }
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Exception Injection
+//!
+//! Make EL1 take the exception as if the hardware generated it.
+//! (ARM DDI 0487 D1.10 Exception entry)
+//!
+
+use super::esr::{ESR_EL2_EC_BITS_OFFSET, ESR_EL2_IL, ESR_EL2_ISS};
+use crate::cpu::*;
+
+/* The offsets from VBAR_EL1 */
+const VECTOR_CURRENT_EL_SP_EL0: u64 = 0x000;
+const VECTOR_CURRENT_EL_SP_ELX: u64 = 0x200;
+const VECTOR_LOWER_EL_AARCH64: u64 = 0x400;
+const VECTOR_LOWER_EL_AARCH32: u64 = 0x600;
+
+/* Exception Classes of ESR_EL1 */
+const EC_UNKNOWN: u64 = 0x00;
+const EC_INSTRUCTION_ABORT_LOWER_EL: u64 = 0x20;
+const EC_INSTRUCTION_ABORT_CURRENT_EL: u64 = 0x21;
+const EC_DATA_ABORT_LOWER_EL: u64 = 0x24;
+const EC_DATA_ABORT_CURRENT_EL: u64 = 0x25;
+const EC_SERROR: u64 = 0x2f;
+const EC_BRK64: u64 = 0x3c;
+
+/// Fault Status Code: Synchronous External abort, not on translation table walk
+pub const FSC_SYNCHRONOUS_EXTERNAL_ABORT: u64 = 0b010000;
+const DATA_ABORT_ISS_WNR: u64 = 1 << 6;
+
+/// SError ISS: Implementation defined syndrome is not valid, Uncontainable
+pub const SERROR_ISS_UNCONTAINABLE: u64 = 0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExceptionType {
+    Synchronous,
+    Irq,
+    Fiq,
+    SError,
+}
+
+impl ExceptionType {
+    const fn vector_offset(&self) -> u64 {
+        match self {
+            Self::Synchronous => 0x000,
+            Self::Irq => 0x080,
+            Self::Fiq => 0x100,
+            Self::SError => 0x180,
+        }
+    }
+
+    /// The bit of PSTATE which masks the exception, None if it cannot be masked
+    const fn mask_bit(&self) -> Option<u64> {
+        match self {
+            Self::Synchronous => None,
+            Self::Irq => Some(SPSR_EL2_I),
+            Self::Fiq => Some(SPSR_EL2_F),
+            Self::SError => Some(SPSR_EL2_A),
+        }
+    }
+}
+
+/// Check if the guest was running at EL0
+pub fn is_from_el0(spsr_el2: u64) -> bool {
+    (spsr_el2 & SPSR_EL2_M_AARCH32) != 0 || (spsr_el2 & SPSR_EL2_M) == SPSR_EL2_M_EL0T
+}
+
+/// Get the IL bit of the instruction trapped now, used for the synchronous exception by it
+fn trapped_instruction_length() -> u64 {
+    get_esr_el2() & ESR_EL2_IL
+}
+
+/// Make EL1 take the exception at the return from the current exception
+///
+/// The state of the guest is saved into SPSR_EL1 and ELR_EL1 from SPSR_EL2 and ELR_EL2,
+/// and ELR_EL2 is redirected to the vector of VBAR_EL1.
+/// The general purpose registers are not changed by the exception entry.
+///
+/// For the synchronous exception, ELR_EL2 must point the instruction which causes it.
+///
+/// # Arguments
+/// * `exception_type` - the type of the exception
+/// * `esr_el1` - the syndrome of the synchronous exception or SError, None for IRQ/FIQ
+/// * `far_el1` - the faulting virtual address, None if the exception does not report it
+///
+/// # Result
+/// If the exception is masked by PSTATE of the guest, returns Err(()) and nothing is changed
+pub fn inject_exception(
+    exception_type: ExceptionType,
+    esr_el1: Option<u64>,
+    far_el1: Option<u64>,
+) -> Result<(), ()> {
+    let spsr_el2 = get_spsr_el2();
+    if exception_type
+        .mask_bit()
+        .is_some_and(|mask| (spsr_el2 & mask) != 0)
+    {
+        return Err(());
+    }
+    let vector_offset = if (spsr_el2 & SPSR_EL2_M_AARCH32) != 0 {
+        VECTOR_LOWER_EL_AARCH32
+    } else {
+        match spsr_el2 & SPSR_EL2_M {
+            SPSR_EL2_M_EL0T => VECTOR_LOWER_EL_AARCH64,
+            SPSR_EL2_M_EL1T => VECTOR_CURRENT_EL_SP_EL0,
+            _ => VECTOR_CURRENT_EL_SP_ELX,
+        }
+    };
+    if let Some(esr_el1) = esr_el1 {
+        set_esr_el1(esr_el1);
+    }
+    if let Some(far_el1) = far_el1 {
+        set_far_el1(far_el1);
+    }
+    set_spsr_el1(spsr_el2);
+    set_elr_el1(get_elr_el2());
+
+    let mut pstate = SPSR_EL2_D | SPSR_EL2_A | SPSR_EL2_I | SPSR_EL2_F | SPSR_EL2_M_EL1H;
+    if (get_sctlr_el1() & SCTLR_EL1_SPAN) == 0 {
+        pstate |= SPSR_EL2_PAN;
+    } else {
+        pstate |= spsr_el2 & SPSR_EL2_PAN;
+    }
+    set_spsr_el2(pstate);
+    set_elr_el2(get_vbar_el1() + vector_offset + exception_type.vector_offset());
+    Ok(())
+}
+
+fn inject_synchronous_exception(exception_class: u64, iss: u64, far_el1: Option<u64>) {
+    let esr_el1 = (exception_class << ESR_EL2_EC_BITS_OFFSET)
+        | trapped_instruction_length()
+        | (iss & ESR_EL2_ISS);
+    inject_exception(ExceptionType::Synchronous, Some(esr_el1), far_el1)
+        .expect("The synchronous exception cannot be masked");
+}
+
+/// Make the instruction at ELR_EL2 UNDEFINED for the guest
+///
+/// This is used to reflect the trapped instruction which the hypervisor does not support.
+pub fn inject_undefined_instruction() {
+    inject_synchronous_exception(EC_UNKNOWN, 0, None);
+}
+
+/// Make EL1 take the exception of BRK at ELR_EL2, used when BRK is trapped by MDCR_EL2.TDE
+///
+/// # Arguments
+/// * `comment` - the immediate value of BRK
+pub fn inject_breakpoint_instruction(comment: u16) {
+    inject_synchronous_exception(EC_BRK64, comment as u64, None);
+}
+
+/// Inject Data Abort for the access by the instruction at ELR_EL2
+///
+/// # Arguments
+/// * `virtual_address` - the address accessed by the instruction
+/// * `is_write_access` - true if the instruction wrote the memory
+/// * `fault_status_code` - DFSC of the abort
+pub fn inject_data_abort(virtual_address: u64, is_write_access: bool, fault_status_code: u64) {
+    let exception_class = if is_from_el0(get_spsr_el2()) {
+        EC_DATA_ABORT_LOWER_EL
+    } else {
+        EC_DATA_ABORT_CURRENT_EL
+    };
+    let mut iss = fault_status_code & 0b111111;
+    if is_write_access {
+        iss |= DATA_ABORT_ISS_WNR;
+    }
+    inject_synchronous_exception(exception_class, iss, Some(virtual_address));
+}
+
+/// Inject Instruction Abort for the fetch of the instruction at ELR_EL2
+///
+/// # Arguments
+/// * `virtual_address` - the address of the instruction
+/// * `fault_status_code` - IFSC of the abort
+pub fn inject_instruction_abort(virtual_address: u64, fault_status_code: u64) {
+    let exception_class = if is_from_el0(get_spsr_el2()) {
+        EC_INSTRUCTION_ABORT_LOWER_EL
+    } else {
+        EC_INSTRUCTION_ABORT_CURRENT_EL
+    };
+    inject_synchronous_exception(
+        exception_class,
+        fault_status_code & 0b111111,
+        Some(virtual_address),
+    );
+}
+
+/// Inject Synchronous External abort for the data access
+///
+/// ELR_EL2 must point the faulting instruction.
+///
+/// # Arguments
+/// * `virtual_address` - the address accessed by the instruction
+/// * `is_write_access` - true if the instruction wrote the memory
+pub fn inject_synchronous_external_abort(virtual_address: u64, is_write_access: bool) {
+    inject_data_abort(
+        virtual_address,
+        is_write_access,
+        FSC_SYNCHRONOUS_EXTERNAL_ABORT,
+    );
+}
+
+/// Make EL1 take the physical IRQ exception before the instruction at ELR_EL2
+///
+/// Usually the interrupts should be delivered by the virtual GIC, this is for the guest
+/// which does not use it.
+pub fn inject_irq() -> Result<(), ()> {
+    inject_exception(ExceptionType::Irq, None, None)
+}
+
+/// Make EL1 take the FIQ exception before the instruction at ELR_EL2
+pub fn inject_fiq() -> Result<(), ()> {
+    inject_exception(ExceptionType::Fiq, None, None)
+}
+
+/// Make EL1 take SError before the instruction at ELR_EL2
+///
+/// # Arguments
+/// * `iss` - ISS of ESR_EL1, see [`SERROR_ISS_UNCONTAINABLE`]
+pub fn inject_serror(iss: u64) -> Result<(), ()> {
+    inject_exception(
+        ExceptionType::SError,
+        Some((EC_SERROR << ESR_EL2_EC_BITS_OFFSET) | ESR_EL2_IL | (iss & ESR_EL2_ISS)),
+        None,
+    )
+}
@@ -0,0 +1,410 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Load/Store Instruction Emulation
+//!
+//! When ESR_EL2.ISV is 0, the syndrome does not describe the access (load/store pair,
+//! pre/post-index, SIMD&FP registers, ...), so the instruction at ELR_EL2 is decoded and emulated.
+//!
+//! The SIMD&FP registers of EL1 are not saved by the exception vectors and the hypervisor itself
+//! uses them, so the accesses with them are decoded but not emulated.
+//!
+
+use super::{access_guest_physical_address, GuestAccessFault, Registers};
+use crate::cpu::*;
+use crate::paging::convert_intermediate_physical_address_to_physical_address;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegisterType {
+    /// General-purpose register, `is_64bit` is false for W registers
+    General {
+        is_64bit: bool,
+        is_sign_extended: bool,
+    },
+    /// SIMD&FP register
+    Simd,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressingMode {
+    /// `[Xn, #imm]`
+    Offset(i64),
+    /// `[Xn, #imm]!`
+    PreIndex(i64),
+    /// `[Xn], #imm`
+    PostIndex(i64),
+    /// `[Xn, Xm{, extend {#amount}}]`
+    Register { rm: usize, option: u32, shift: u32 },
+}
+
+/// The decoded load/store instruction
+#[derive(Clone, Copy, Debug)]
+pub struct LoadStore {
+    pub is_load: bool,
+    pub register_type: RegisterType,
+    /// The bytes transferred by one register
+    pub size: usize,
+    pub rt: usize,
+    /// The second register of the load/store pair
+    pub rt2: Option<usize>,
+    /// The base register, 31 means SP
+    pub rn: usize,
+    pub addressing_mode: AddressingMode,
+}
+
+const fn sign_extend(value: u64, bits: u32) -> i64 {
+    ((value << (64 - bits)) as i64) >> (64 - bits)
+}
+
+/// Decode the load/store instruction
+///
+/// # Result
+/// If the instruction is a supported load/store, Ok(decoded instruction), otherwise Err(())
+pub fn decode(instruction: u32) -> Result<LoadStore, ()> {
+    let is_simd = ((instruction >> 26) & 1) != 0;
+    let rt = (instruction & 0b11111) as usize;
+    let rn = ((instruction >> 5) & 0b11111) as usize;
+
+    match (instruction >> 27) & 0b111 {
+        /* Load/store register pair */
+        0b101 => {
+            let opc = instruction >> 30;
+            let is_load = ((instruction >> 22) & 1) != 0;
+            let (size, register_type) = match (is_simd, opc, is_load) {
+                (false, 0b00, _) => (
+                    4,
+                    RegisterType::General {
+                        is_64bit: false,
+                        is_sign_extended: false,
+                    },
+                ),
+                /* LDPSW */
+                (false, 0b01, true) => (
+                    4,
+                    RegisterType::General {
+                        is_64bit: true,
+                        is_sign_extended: true,
+                    },
+                ),
+                (false, 0b10, _) => (
+                    8,
+                    RegisterType::General {
+                        is_64bit: true,
+                        is_sign_extended: false,
+                    },
+                ),
+                (true, 0b00..=0b10, _) => (4 << opc, RegisterType::Simd),
+                _ => return Err(()),
+            };
+            let offset = sign_extend(((instruction >> 15) & 0x7f) as u64, 7) * size as i64;
+            let addressing_mode = match (instruction >> 23) & 0b111 {
+                0b000 | 0b010 => AddressingMode::Offset(offset),
+                0b001 => AddressingMode::PostIndex(offset),
+                0b011 => AddressingMode::PreIndex(offset),
+                _ => return Err(()),
+            };
+            Ok(LoadStore {
+                is_load,
+                register_type,
+                size,
+                rt,
+                rt2: Some(((instruction >> 10) & 0b11111) as usize),
+                rn,
+                addressing_mode,
+            })
+        }
+        /* Load/store register */
+        0b111 => {
+            let size_field = instruction >> 30;
+            let opc = (instruction >> 22) & 0b11;
+            let (is_load, size, register_type) = if is_simd {
+                let size = match (size_field, opc & 0b10) {
+                    (s, 0) => 1 << s,
+                    (0, _) => 16,
+                    _ => return Err(()),
+                };
+                ((opc & 1) != 0, size, RegisterType::Simd)
+            } else {
+                let size = 1 << size_field;
+                match (opc, size_field) {
+                    (0b00, _) => (
+                        false,
+                        size,
+                        RegisterType::General {
+                            is_64bit: size_field == 3,
+                            is_sign_extended: false,
+                        },
+                    ),
+                    (0b01, _) => (
+                        true,
+                        size,
+                        RegisterType::General {
+                            is_64bit: size_field == 3,
+                            is_sign_extended: false,
+                        },
+                    ),
+                    /* PRFM and the unallocated encodings are not accesses */
+                    (0b10, 0..=2) => (
+                        true,
+                        size,
+                        RegisterType::General {
+                            is_64bit: true,
+                            is_sign_extended: true,
+                        },
+                    ),
+                    (0b11, 0..=1) => (
+                        true,
+                        size,
+                        RegisterType::General {
+                            is_64bit: false,
+                            is_sign_extended: true,
+                        },
+                    ),
+                    _ => return Err(()),
+                }
+            };
+            let addressing_mode = match ((instruction >> 24) & 0b11, (instruction >> 21) & 1) {
+                (0b01, _) => {
+                    AddressingMode::Offset((((instruction >> 10) & 0xfff) as usize * size) as i64)
+                }
+                (0b00, 0) => {
+                    let offset = sign_extend(((instruction >> 12) & 0x1ff) as u64, 9);
+                    match (instruction >> 10) & 0b11 {
+                        /* LDUR/STUR and LDTR/STTR */
+                        0b00 | 0b10 => AddressingMode::Offset(offset),
+                        0b01 => AddressingMode::PostIndex(offset),
+                        _ => AddressingMode::PreIndex(offset),
+                    }
+                }
+                (0b00, 1) if ((instruction >> 10) & 0b11) == 0b10 => {
+                    let option = (instruction >> 13) & 0b111;
+                    if (option & 0b010) == 0 {
+                        return Err(());
+                    }
+                    let shift = if ((instruction >> 12) & 1) != 0 {
+                        size.trailing_zeros()
+                    } else {
+                        0
+                    };
+                    AddressingMode::Register {
+                        rm: ((instruction >> 16) & 0b11111) as usize,
+                        option,
+                        shift,
+                    }
+                }
+                _ => return Err(()),
+            };
+            Ok(LoadStore {
+                is_load,
+                register_type,
+                size,
+                rt,
+                rt2: None,
+                rn,
+                addressing_mode,
+            })
+        }
+        _ => Err(()),
+    }
+}
+
+fn is_el0(spsr_el2: u64) -> bool {
+    (spsr_el2 & SPSR_EL2_M) == SPSR_EL2_M_EL0T
+}
+
+/// Read the base register, register number 31 is the stack pointer selected by SPSR_EL2.M
+fn read_base_register(registers: &Registers, register_number: usize, spsr_el2: u64) -> u64 {
+    if register_number != 31 {
+        registers.read(register_number)
+    } else if (spsr_el2 & SPSR_EL2_M) == SPSR_EL2_M_EL1H {
+        get_sp_el1()
+    } else {
+        get_sp_el0()
+    }
+}
+
+fn write_base_register(
+    registers: &mut Registers,
+    register_number: usize,
+    value: u64,
+    spsr_el2: u64,
+) {
+    if register_number != 31 {
+        registers.write(register_number, value);
+    } else if (spsr_el2 & SPSR_EL2_M) == SPSR_EL2_M_EL1H {
+        set_sp_el1(value);
+    } else {
+        set_sp_el0(value);
+    }
+}
+
+fn extend_register(value: u64, option: u32) -> u64 {
+    match option {
+        /* UXTW */
+        0b010 => value as u32 as u64,
+        /* SXTW */
+        0b110 => value as u32 as i32 as i64 as u64,
+        /* LSL(UXTX), SXTX */
+        _ => value,
+    }
+}
+
+fn translate_data_address(
+    virtual_address: usize,
+    is_write: bool,
+    spsr_el2: u64,
+) -> Result<usize, ()> {
+    match (is_el0(spsr_el2), is_write) {
+        (true, false) => {
+            convert_virtual_address_to_intermediate_physical_address_el0_read(virtual_address)
+        }
+        (true, true) => {
+            convert_virtual_address_to_intermediate_physical_address_el0_write(virtual_address)
+        }
+        (false, false) => {
+            convert_virtual_address_to_intermediate_physical_address_el1_read(virtual_address)
+        }
+        (false, true) => {
+            convert_virtual_address_to_intermediate_physical_address_el1_write(virtual_address)
+        }
+    }
+}
+
+/// Read the instruction of EL1/EL0 through the stage 1 and stage 2 translation
+pub fn fetch_instruction(virtual_address: usize, spsr_el2: u64) -> Result<u32, ()> {
+    if (virtual_address & 0b11) != 0 {
+        return Err(());
+    }
+    let intermediate_physical_address = if is_el0(spsr_el2) {
+        convert_virtual_address_to_intermediate_physical_address_el0_read(virtual_address)?
+    } else {
+        convert_virtual_address_to_intermediate_physical_address_el1_read(virtual_address)?
+    };
+    let (physical_address, _) =
+        convert_intermediate_physical_address_to_physical_address(intermediate_physical_address)?;
+    Ok(unsafe { core::ptr::read_volatile(physical_address as *const u32) })
+}
+
+/// Access the register of one transfer, the 128-bit access is split into two 64-bit accesses
+fn access_memory(
+    virtual_address: usize,
+    size: usize,
+    value: Option<u128>,
+    spsr_el2: u64,
+) -> Result<u128, EmulationError> {
+    let mut result = 0u128;
+    let chunk_size = size.min(8);
+    for i in 0..(size / chunk_size) {
+        let chunk_address = virtual_address + i * chunk_size;
+        let address = translate_data_address(chunk_address, value.is_some(), spsr_el2)
+            .or(Err(EmulationError::Unsupported))?;
+        let access_width = (chunk_size * 8) as u64;
+        let chunk = value.map(|v| {
+            let chunk = (v >> (i * 64)) as u64;
+            if chunk_size == 8 {
+                chunk
+            } else {
+                chunk & ((1 << access_width) - 1)
+            }
+        });
+        match access_guest_physical_address(address, access_width, chunk) {
+            Ok(v) => result |= (v as u128) << (i * 64),
+            Err(fault) => {
+                return Err(EmulationError::AccessFault {
+                    address,
+                    virtual_address: chunk_address,
+                    is_write_access: value.is_some(),
+                    fault,
+                })
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum EmulationError {
+    /// The instruction could not be fetched, decoded, or translated
+    Unsupported,
+    /// The access to the guest physical address failed, no register was updated
+    AccessFault {
+        address: usize,
+        virtual_address: usize,
+        is_write_access: bool,
+        fault: GuestAccessFault,
+    },
+}
+
+/// Emulate the load/store instruction at ELR_EL2 against the MMIO bus
+///
+/// ELR_EL2 is not advanced by this function.
+pub fn emulate(registers: &mut Registers) -> Result<(), EmulationError> {
+    let spsr_el2 = get_spsr_el2();
+    let instruction =
+        fetch_instruction(get_elr_el2() as usize, spsr_el2).or(Err(EmulationError::Unsupported))?;
+    let load_store = decode(instruction).or(Err(EmulationError::Unsupported))?;
+    let RegisterType::General {
+        is_64bit,
+        is_sign_extended,
+    } = load_store.register_type
+    else {
+        return Err(EmulationError::Unsupported);
+    };
+
+    let base = read_base_register(registers, load_store.rn, spsr_el2);
+    let (address, new_base) = match load_store.addressing_mode {
+        AddressingMode::Offset(offset) => (base.wrapping_add(offset as u64), None),
+        AddressingMode::PreIndex(offset) => {
+            let address = base.wrapping_add(offset as u64);
+            (address, Some(address))
+        }
+        AddressingMode::PostIndex(offset) => (base, Some(base.wrapping_add(offset as u64))),
+        AddressingMode::Register { rm, option, shift } => (
+            base.wrapping_add(extend_register(registers.read(rm), option) << shift),
+            None,
+        ),
+    };
+
+    let mut loaded_values = [0u128; 2];
+    for (i, register_number) in [Some(load_store.rt), load_store.rt2]
+        .into_iter()
+        .flatten()
+        .enumerate()
+    {
+        let virtual_address = (address as usize).wrapping_add(i * load_store.size);
+        if load_store.is_load {
+            loaded_values[i] = access_memory(virtual_address, load_store.size, None, spsr_el2)?;
+        } else {
+            let value =
+                registers.read(register_number) as u128 & ((1u128 << (load_store.size * 8)) - 1);
+            access_memory(virtual_address, load_store.size, Some(value), spsr_el2)?;
+        }
+    }
+
+    /* The registers are updated after all accesses succeeded */
+    if load_store.is_load {
+        for (i, register_number) in [Some(load_store.rt), load_store.rt2]
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let mut value = loaded_values[i] as u64;
+            if is_sign_extended {
+                value = sign_extend(value, (load_store.size * 8) as u32) as u64;
+            }
+            if !is_64bit {
+                value &= u32::MAX as u64;
+            }
+            registers.write(register_number, value);
+        }
+    }
+    if let Some(new_base) = new_base {
+        write_base_register(registers, load_store.rn, new_base, spsr_el2);
+    }
+    Ok(())
+}
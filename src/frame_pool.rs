@@ -0,0 +1,76 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Physical Frame Pool
+//!
+//! The pool of 4KiB frames owned by the hypervisor, available after ExitBootServices.
+//! The free frames are linked by the pointer stored at the head of each frame.
+//!
+
+use crate::paging::{PAGE_SHIFT, PAGE_SIZE};
+
+struct FramePool {
+    head: usize,
+    number_of_free_frames: usize,
+}
+
+static mut FRAME_POOL: FramePool = FramePool {
+    head: 0,
+    number_of_free_frames: 0,
+};
+
+fn frame_pool() -> &'static mut FramePool {
+    unsafe { &mut *core::ptr::addr_of_mut!(FRAME_POOL) }
+}
+
+/// Add the frames to the pool
+///
+/// # Arguments
+/// * `physical_address` - the start address, must be page aligned
+/// * `pages` - the number of the frames
+pub fn add_frames(physical_address: usize, pages: usize) {
+    assert_eq!(physical_address & (PAGE_SIZE - 1), 0);
+    for i in 0..pages {
+        free_frame(physical_address + (i << PAGE_SHIFT));
+    }
+}
+
+/// Reserve the frames with UEFI, this must be called before ExitBootServices
+pub fn reserve_frames(pages: usize) -> Result<(), ()> {
+    let address = crate::allocate_memory(pages, None)?;
+    add_frames(address, pages);
+    Ok(())
+}
+
+/// Take a frame from the pool
+///
+/// # Result
+/// If succeeded, Ok(physical address of the zero-filled frame), otherwise Err(())
+pub fn allocate_frame() -> Result<usize, ()> {
+    let pool = frame_pool();
+    if pool.number_of_free_frames == 0 {
+        return Err(());
+    }
+    let frame = pool.head;
+    pool.head = unsafe { *(frame as *const usize) };
+    pool.number_of_free_frames -= 1;
+    unsafe { core::ptr::write_bytes(frame as *mut u8, 0, PAGE_SIZE) };
+    Ok(frame)
+}
+
+/// Return the frame to the pool
+pub fn free_frame(physical_address: usize) {
+    let pool = frame_pool();
+    unsafe { *(physical_address as *mut usize) = pool.head };
+    pool.head = physical_address;
+    pool.number_of_free_frames += 1;
+}
+
+pub fn get_number_of_free_frames() -> usize {
+    frame_pool().number_of_free_frames
+}
@@ -0,0 +1,951 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! GDB Remote Serial Protocol Stub
+//!
+//! The stub is reachable over the dedicated console "gdb" of the console multiplexer, focus it
+//! by `Ctrl-A` and its console number before connecting GDB to the serial port.
+//! When GDB sends a packet or Ctrl-C, the guest is stopped in the exception handler
+//! and the packets are processed until GDB resumes it.
+//! While GDB is attached, MDCR_EL2.TDE routes BRK, the breakpoints, the watchpoints and
+//! the software step of the guest to EL2. The debug registers of the guest are virtualized by
+//! [`crate::sysreg`], therefore the physical debug registers are owned by the stub until it
+//! detaches, then the values written by the guest are restored.
+//!
+
+use crate::console::mux;
+use crate::cpu::*;
+use crate::exception::esr::ExceptionClass;
+use crate::exception::{inject, Registers};
+use crate::paging::convert_intermediate_physical_address_to_physical_address;
+
+use core::fmt::{self, Write};
+
+/// The maximum length of the packet data, advertised by qSupported
+const PACKET_SIZE: usize = 0x1000;
+const MAX_SOFTWARE_BREAKPOINTS: usize = 32;
+const MAX_HARDWARE_BREAKPOINTS: usize = 16;
+const MAX_WATCHPOINTS: usize = 16;
+
+const INTERRUPT_CHARACTER: u8 = 0x03; /* Ctrl-C */
+const BRK_INSTRUCTION: u32 = 0xd4200000; /* BRK #0 */
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/* The register numbers of GDB */
+const NUMBER_OF_GENERAL_REGISTERS: usize = 31;
+const REGISTER_SP: usize = 31;
+const REGISTER_PC: usize = 32;
+const REGISTER_CPSR: usize = 33;
+const FIRST_SYSTEM_REGISTER: usize = 34;
+
+/// The name, the getter and the setter of the system register
+type SystemRegisterAccessor = (&'static str, fn() -> u64, fn(u64));
+
+/// The system registers of EL1 shown to GDB after the core registers
+const SYSTEM_REGISTERS: [SystemRegisterAccessor; 11] = [
+    ("sp_el0", get_sp_el0, set_sp_el0),
+    ("sp_el1", get_sp_el1, set_sp_el1),
+    ("elr_el1", get_elr_el1, set_elr_el1),
+    ("spsr_el1", get_spsr_el1, set_spsr_el1),
+    ("esr_el1", get_esr_el1, set_esr_el1),
+    ("far_el1", get_far_el1, set_far_el1),
+    ("vbar_el1", get_vbar_el1, set_vbar_el1),
+    ("sctlr_el1", get_sctlr_el1, set_sctlr_el1),
+    ("tcr_el1", get_tcr_el1, set_tcr_el1),
+    ("ttbr0_el1", get_ttbr0_el1, set_ttbr0_el1),
+    ("mair_el1", get_mair_el1, set_mair_el1),
+];
+const NUMBER_OF_REGISTERS: usize = FIRST_SYSTEM_REGISTER + SYSTEM_REGISTERS.len();
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum ReceiveState {
+    Idle,
+    Data,
+    Checksum1,
+    Checksum2,
+}
+
+enum InputEvent {
+    Interrupt,
+    Packet,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum WatchpointType {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Clone, Copy)]
+struct Watchpoint {
+    address: u64,
+    length: u64,
+    watchpoint_type: WatchpointType,
+}
+
+#[derive(Clone, Copy)]
+struct SoftwareBreakpoint {
+    address: u64,
+    original_instruction: u32,
+}
+
+#[derive(Clone, Copy)]
+enum StopReason {
+    Signal(u8),
+    SoftwareBreakpoint,
+    HardwareBreakpoint,
+    Watchpoint(Watchpoint),
+}
+
+struct GdbStub {
+    console_id: Option<usize>,
+    is_attached: bool,
+    is_stepping: bool,
+    last_stop_reason: StopReason,
+    receive_state: ReceiveState,
+    packet: [u8; PACKET_SIZE],
+    packet_length: usize,
+    checksum: u8,
+    received_checksum: u8,
+    software_breakpoints: [Option<SoftwareBreakpoint>; MAX_SOFTWARE_BREAKPOINTS],
+    hardware_breakpoints: [Option<u64>; MAX_HARDWARE_BREAKPOINTS],
+    watchpoints: [Option<Watchpoint>; MAX_WATCHPOINTS],
+}
+
+static mut GDB_STUB: GdbStub = GdbStub {
+    console_id: None,
+    is_attached: false,
+    is_stepping: false,
+    last_stop_reason: StopReason::Signal(SIGTRAP),
+    receive_state: ReceiveState::Idle,
+    packet: [0; PACKET_SIZE],
+    packet_length: 0,
+    checksum: 0,
+    received_checksum: 0,
+    software_breakpoints: [None; MAX_SOFTWARE_BREAKPOINTS],
+    hardware_breakpoints: [None; MAX_HARDWARE_BREAKPOINTS],
+    watchpoints: [None; MAX_WATCHPOINTS],
+};
+
+fn gdb_stub() -> &'static mut GdbStub {
+    unsafe { &mut *core::ptr::addr_of_mut!(GDB_STUB) }
+}
+
+/// The packet data being built
+struct Response {
+    buffer: [u8; PACKET_SIZE],
+    length: usize,
+}
+
+impl Response {
+    fn new() -> Self {
+        Self {
+            buffer: [0; PACKET_SIZE],
+            length: 0,
+        }
+    }
+
+    fn push(&mut self, c: u8) {
+        if self.length < PACKET_SIZE {
+            self.buffer[self.length] = c;
+            self.length += 1;
+        }
+    }
+
+    fn push_str(&mut self, s: &str) {
+        for c in s.bytes() {
+            self.push(c);
+        }
+    }
+
+    fn push_hex_byte(&mut self, b: u8) {
+        const HEX: &[u8; 16] = b"0123456789abcdef";
+        self.push(HEX[(b >> 4) as usize]);
+        self.push(HEX[(b & 0xf) as usize]);
+    }
+
+    /// Push the value as the little endian bytes in the target byte order
+    fn push_hex_le(&mut self, value: u64, size: usize) {
+        for b in &value.to_le_bytes()[..size] {
+            self.push_hex_byte(*b);
+        }
+    }
+
+    /// Push the binary data with the escape of the special characters
+    fn push_binary(&mut self, data: &[u8]) {
+        for c in data {
+            if matches!(*c, b'#' | b'$' | b'}' | b'*') {
+                self.push(b'}');
+                self.push(*c ^ 0x20);
+            } else {
+                self.push(*c);
+            }
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+}
+
+impl Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(data: &[u8]) -> Result<u64, ()> {
+    if data.is_empty() || data.len() > 16 {
+        return Err(());
+    }
+    data.iter().try_fold(0u64, |value, c| {
+        Ok((value << 4) | hex_digit(*c).ok_or(())? as u64)
+    })
+}
+
+/// Parse the hex string of the little endian bytes
+fn parse_hex_le(data: &[u8]) -> Result<u64, ()> {
+    if data.is_empty() || data.len() > 16 || (data.len() & 1) != 0 {
+        return Err(());
+    }
+    let mut value = 0u64;
+    for (i, pair) in data.chunks(2).enumerate() {
+        value |= parse_hex(pair)? << (i * 8);
+    }
+    Ok(value)
+}
+
+/// Split "address,length" of the packets
+fn parse_address_and_length(data: &[u8]) -> Result<(u64, usize), ()> {
+    let separator = data.iter().position(|c| *c == b',').ok_or(())?;
+    Ok((
+        parse_hex(&data[..separator])?,
+        parse_hex(&data[(separator + 1)..])? as usize,
+    ))
+}
+
+/// Parse "address,length:XX..." of the 'M' packet
+///
+/// # Result
+/// If the data has `length` bytes and the range does not overflow, Ok((address, length, data)),
+/// otherwise Err(())
+fn parse_write_memory_arguments(arguments: &[u8]) -> Result<(u64, usize, &[u8]), ()> {
+    let separator = arguments.iter().position(|c| *c == b':').ok_or(())?;
+    let (address, length) = parse_address_and_length(&arguments[..separator])?;
+    let data = &arguments[(separator + 1)..];
+    if length.checked_mul(2) != Some(data.len()) || address.checked_add(length as u64).is_none() {
+        return Err(());
+    }
+    Ok((address, length, data))
+}
+
+/// Translate the virtual address of EL1 through the stage 1 and stage 2 translation
+///
+/// The read permission is used for the write access too because the software breakpoints are
+/// written into the read-only code.
+fn translate_guest_address(virtual_address: u64) -> Result<usize, ()> {
+    let intermediate_physical_address =
+        convert_virtual_address_to_intermediate_physical_address_el1_read(
+            virtual_address as usize,
+        )?;
+    let (physical_address, _) =
+        convert_intermediate_physical_address_to_physical_address(intermediate_physical_address)?;
+    Ok(physical_address)
+}
+
+fn read_guest_memory(virtual_address: u64, buffer: &mut [u8]) -> Result<(), ()> {
+    for (i, b) in buffer.iter_mut().enumerate() {
+        let physical_address = translate_guest_address(virtual_address + i as u64)?;
+        *b = unsafe { core::ptr::read_volatile(physical_address as *const u8) };
+    }
+    Ok(())
+}
+
+/// Write the guest memory and synchronize the instruction cache
+fn write_guest_memory(virtual_address: u64, data: &[u8]) -> Result<(), ()> {
+    let mut result = Ok(());
+    for (i, b) in data.iter().enumerate() {
+        let Ok(physical_address) = translate_guest_address(virtual_address + i as u64) else {
+            result = Err(());
+            break;
+        };
+        unsafe { core::ptr::write_volatile(physical_address as *mut u8, *b) };
+        clean_and_invalidate_data_cache(physical_address);
+    }
+    dsb();
+    clear_instruction_cache_all();
+    dsb();
+    isb();
+    result
+}
+
+fn get_number_of_hardware_breakpoints() -> usize {
+    ((((get_id_aa64dfr0_el1() & ID_AA64DFR0_EL1_BRPS) >> ID_AA64DFR0_EL1_BRPS_BITS_OFFSET) + 1)
+        as usize)
+        .min(MAX_HARDWARE_BREAKPOINTS)
+}
+
+fn get_number_of_watchpoints() -> usize {
+    ((((get_id_aa64dfr0_el1() & ID_AA64DFR0_EL1_WRPS) >> ID_AA64DFR0_EL1_WRPS_BITS_OFFSET) + 1)
+        as usize)
+        .min(MAX_WATCHPOINTS)
+}
+
+/// Register the console of the stub
+pub fn setup_gdb_stub() -> Result<(), ()> {
+    let stub = gdb_stub();
+    if stub.console_id.is_some() {
+        return Err(());
+    }
+    stub.console_id = Some(mux::register_dedicated_console("gdb", None)?);
+    Ok(())
+}
+
+/// Check the input from GDB, and stop the guest if GDB requested
+///
+/// This is called on each VM exit by the physical interrupt.
+pub fn poll(registers: &mut Registers) {
+    let stub = gdb_stub();
+    match stub.read_input() {
+        Some(InputEvent::Interrupt) => stub.debug_loop(registers, Some(StopReason::Signal(SIGINT))),
+        Some(InputEvent::Packet) => stub.debug_loop(registers, None),
+        None => {}
+    }
+}
+
+/// Handle BRK trapped by MDCR_EL2.TDE
+///
+/// If BRK is not inserted by GDB, it is reflected to the guest.
+pub fn software_breakpoint_handler(registers: &mut Registers, comment: u16) {
+    let stub = gdb_stub();
+    let elr_el2 = get_elr_el2();
+    if stub.is_attached
+        && stub
+            .software_breakpoints
+            .iter()
+            .flatten()
+            .any(|b| b.address == elr_el2)
+    {
+        stub.debug_loop(registers, Some(StopReason::SoftwareBreakpoint));
+    } else {
+        inject::inject_breakpoint_instruction(comment);
+    }
+}
+
+/// Handle Breakpoint, Watchpoint and Software Step exceptions from EL1/EL0
+pub fn debug_exception_handler(registers: &mut Registers, exception_class: ExceptionClass) {
+    let stub = gdb_stub();
+    let reason = match exception_class {
+        ExceptionClass::BreakpointLowerEl => StopReason::HardwareBreakpoint,
+        ExceptionClass::SoftwareStepLowerEl => {
+            stub.is_stepping = false;
+            StopReason::Signal(SIGTRAP)
+        }
+        ExceptionClass::WatchpointLowerEl => {
+            let address = get_far_el2();
+            let watchpoint = stub
+                .watchpoints
+                .iter()
+                .flatten()
+                .find(|w| (address & !0b111) == (w.address & !0b111))
+                .or(stub.watchpoints.iter().flatten().next())
+                .copied();
+            match watchpoint {
+                Some(w) => StopReason::Watchpoint(w),
+                None => StopReason::Signal(SIGTRAP),
+            }
+        }
+        _ => return,
+    };
+    if !stub.is_attached {
+        /* The debug state was left by the detached session */
+        stub.apply_debug_state();
+        crate::sysreg::set_debugger_attached(false);
+        return;
+    }
+    stub.debug_loop(registers, Some(reason));
+}
+
+impl GdbStub {
+    fn send_packet(&self, data: &[u8]) {
+        let Some(id) = self.console_id else {
+            return;
+        };
+        let checksum = data.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+        let mut trailer = Response::new();
+        trailer.push(b'#');
+        trailer.push_hex_byte(checksum);
+        mux::write(id, b"$");
+        mux::write(id, data);
+        mux::write(id, trailer.as_bytes());
+    }
+
+    /// Feed the byte into the packet parser
+    fn receive_byte(&mut self, c: u8) -> Option<InputEvent> {
+        match self.receive_state {
+            ReceiveState::Idle => match c {
+                b'$' => {
+                    self.receive_state = ReceiveState::Data;
+                    self.packet_length = 0;
+                    self.checksum = 0;
+                }
+                INTERRUPT_CHARACTER => return Some(InputEvent::Interrupt),
+                /* Acknowledgments are ignored, the channel does not lose the data */
+                _ => {}
+            },
+            ReceiveState::Data => {
+                if c == b'#' {
+                    self.receive_state = ReceiveState::Checksum1;
+                } else {
+                    self.checksum = self.checksum.wrapping_add(c);
+                    if self.packet_length < PACKET_SIZE {
+                        self.packet[self.packet_length] = c;
+                        self.packet_length += 1;
+                    }
+                }
+            }
+            ReceiveState::Checksum1 => {
+                self.received_checksum = hex_digit(c).unwrap_or(0) << 4;
+                self.receive_state = ReceiveState::Checksum2;
+            }
+            ReceiveState::Checksum2 => {
+                self.receive_state = ReceiveState::Idle;
+                let id = self.console_id?;
+                if self.received_checksum | hex_digit(c).unwrap_or(0) == self.checksum {
+                    mux::write(id, b"+");
+                    return Some(InputEvent::Packet);
+                }
+                mux::write(id, b"-");
+            }
+        }
+        None
+    }
+
+    /// Read the queued input until an event occurs
+    fn read_input(&mut self) -> Option<InputEvent> {
+        let id = self.console_id?;
+        let mut c = [0u8; 1];
+        while mux::read(id, &mut c) == 1 {
+            if let Some(event) = self.receive_byte(c[0]) {
+                return Some(event);
+            }
+        }
+        None
+    }
+
+    /// Stop the guest and process the packets until GDB resumes it
+    ///
+    /// # Arguments
+    /// * `registers` - the registers of the guest
+    /// * `reason` - the stop reason to report, None if the guest is stopped by the received packet
+    fn debug_loop(&mut self, registers: &mut Registers, reason: Option<StopReason>) {
+        if !self.is_attached {
+            self.is_attached = true;
+            crate::sysreg::set_debugger_attached(true);
+            unlock_os_lock();
+            set_mdcr_el2(get_mdcr_el2() | MDCR_EL2_TDE);
+            isb();
+        }
+        let mut has_packet = reason.is_none();
+        if let Some(reason) = reason {
+            self.last_stop_reason = reason;
+            self.send_stop_reply();
+        }
+        loop {
+            if !has_packet {
+                mux::poll_input();
+                if !matches!(self.read_input(), Some(InputEvent::Packet)) {
+                    core::hint::spin_loop();
+                    continue;
+                }
+            }
+            has_packet = false;
+            let mut packet = [0u8; PACKET_SIZE];
+            let length = self.packet_length;
+            packet[..length].copy_from_slice(&self.packet[..length]);
+            if self.handle_packet(registers, &packet[..length]) {
+                break;
+            }
+        }
+        self.apply_debug_state();
+        if !self.is_attached {
+            crate::sysreg::set_debugger_attached(false);
+        }
+    }
+
+    fn send_stop_reply(&self) {
+        let mut response = Response::new();
+        match self.last_stop_reason {
+            StopReason::Signal(signal) => {
+                response.push(b'S');
+                response.push_hex_byte(signal);
+            }
+            StopReason::SoftwareBreakpoint => response.push_str("T05swbreak:;"),
+            StopReason::HardwareBreakpoint => response.push_str("T05hwbreak:;"),
+            StopReason::Watchpoint(w) => {
+                let _ = write!(
+                    response,
+                    "T05{}:{:x};",
+                    match w.watchpoint_type {
+                        WatchpointType::Write => "watch",
+                        WatchpointType::Read => "rwatch",
+                        WatchpointType::Access => "awatch",
+                    },
+                    w.address
+                );
+            }
+        }
+        self.send_packet(response.as_bytes());
+    }
+
+    /// Process one packet
+    ///
+    /// # Result
+    /// true if the guest should be resumed
+    fn handle_packet(&mut self, registers: &mut Registers, packet: &[u8]) -> bool {
+        let mut response = Response::new();
+        let Some((&command, arguments)) = packet.split_first() else {
+            self.send_packet(&[]);
+            return false;
+        };
+        match command {
+            b'?' => {
+                self.send_stop_reply();
+                return false;
+            }
+            b'q' => self.handle_query(arguments, &mut response),
+            b'g' => {
+                for n in 0..NUMBER_OF_REGISTERS {
+                    let (value, size) = read_register(registers, n).unwrap();
+                    response.push_hex_le(value, size);
+                }
+            }
+            b'G' => {
+                let mut offset = 0;
+                let mut result = Ok(());
+                for n in 0..NUMBER_OF_REGISTERS {
+                    let size = read_register(registers, n).unwrap().1 * 2;
+                    let Some(data) = arguments.get(offset..(offset + size)) else {
+                        break;
+                    };
+                    result = result
+                        .and(parse_hex_le(data).and_then(|v| write_register(registers, n, v)));
+                    offset += size;
+                }
+                response.push_str(if result.is_ok() { "OK" } else { "E01" });
+            }
+            b'p' => match parse_hex(arguments)
+                .ok()
+                .and_then(|n| read_register(registers, n as usize))
+            {
+                Some((value, size)) => response.push_hex_le(value, size),
+                None => response.push_str("E01"),
+            },
+            b'P' => {
+                let result = arguments
+                    .iter()
+                    .position(|c| *c == b'=')
+                    .ok_or(())
+                    .and_then(|separator| {
+                        let n = parse_hex(&arguments[..separator])? as usize;
+                        let value = parse_hex_le(&arguments[(separator + 1)..])?;
+                        write_register(registers, n, value)
+                    });
+                response.push_str(if result.is_ok() { "OK" } else { "E01" });
+            }
+            b'm' => self.handle_read_memory(arguments, &mut response),
+            b'M' => match parse_write_memory_arguments(arguments) {
+                Ok((address, length, data)) => {
+                    let mut buffer = [0u8; PACKET_SIZE / 2];
+                    let result = data
+                        .chunks(2)
+                        .enumerate()
+                        .try_for_each(|(i, pair)| {
+                            buffer[i] = parse_hex(pair)? as u8;
+                            Ok(())
+                        })
+                        .and_then(|_| write_guest_memory(address, &buffer[..length]));
+                    response.push_str(if result.is_ok() { "OK" } else { "E14" });
+                }
+                Err(()) => response.push_str("E01"),
+            },
+            b'c' | b's' => {
+                if let Ok(address) = parse_hex(arguments) {
+                    set_elr_el2(address);
+                }
+                self.is_stepping = command == b's';
+                return true;
+            }
+            b'Z' | b'z' => {
+                let result = self.handle_breakpoint_packet(command == b'Z', arguments);
+                match result {
+                    Some(Ok(())) => response.push_str("OK"),
+                    Some(Err(())) => response.push_str("E01"),
+                    /* Not supported */
+                    None => {}
+                }
+            }
+            b'D' => {
+                self.detach();
+                self.send_packet(b"OK");
+                return true;
+            }
+            b'k' => {
+                /* The VM cannot be killed, keep it running */
+                self.detach();
+                return true;
+            }
+            b'H' | b'T' => response.push_str("OK"),
+            _ => {}
+        }
+        self.send_packet(response.as_bytes());
+        false
+    }
+
+    fn handle_query(&self, arguments: &[u8], response: &mut Response) {
+        const XFER_TARGET_XML: &[u8] = b"Xfer:features:read:target.xml:";
+        if arguments.starts_with(b"Supported") {
+            let _ = write!(
+                response,
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            );
+        } else if arguments == b"Attached" {
+            response.push_str("1");
+        } else if arguments == b"C" {
+            response.push_str("QC1");
+        } else if arguments == b"fThreadInfo" {
+            response.push_str("m1");
+        } else if arguments == b"sThreadInfo" {
+            response.push_str("l");
+        } else if let Some(range) = arguments.strip_prefix(XFER_TARGET_XML) {
+            let Ok((offset, length)) = parse_address_and_length(range) else {
+                response.push_str("E01");
+                return;
+            };
+            let mut xml = Response::new();
+            write_target_description(&mut xml);
+            let xml = xml.as_bytes();
+            let offset = (offset as usize).min(xml.len());
+            /* The escaped characters may double the size */
+            let end = (offset + length.min(PACKET_SIZE / 2 - 1)).min(xml.len());
+            response.push(if end == xml.len() { b'l' } else { b'm' });
+            response.push_binary(&xml[offset..end]);
+        }
+    }
+
+    fn handle_read_memory(&self, arguments: &[u8], response: &mut Response) {
+        let Ok((address, length)) = parse_address_and_length(arguments) else {
+            response.push_str("E01");
+            return;
+        };
+        let mut buffer = [0u8; PACKET_SIZE / 2];
+        let length = length.min(buffer.len());
+        if address.checked_add(length as u64).is_none() {
+            response.push_str("E01");
+            return;
+        }
+        let mut read_length = 0;
+        while read_length < length {
+            if read_guest_memory(
+                address + read_length as u64,
+                &mut buffer[read_length..][..1],
+            )
+            .is_err()
+            {
+                break;
+            }
+            read_length += 1;
+        }
+        if read_length == 0 && length != 0 {
+            response.push_str("E14");
+            return;
+        }
+        /* Show the original instructions instead of the inserted BRK */
+        for b in self.software_breakpoints.iter().flatten() {
+            for (i, original) in b.original_instruction.to_le_bytes().iter().enumerate() {
+                let Some(target) = b.address.checked_add(i as u64) else {
+                    continue;
+                };
+                if target >= address && target < address + read_length as u64 {
+                    buffer[(target - address) as usize] = *original;
+                }
+            }
+        }
+        for b in &buffer[..read_length] {
+            response.push_hex_byte(*b);
+        }
+    }
+
+    /// Handle "Z/z type,address,kind"
+    ///
+    /// # Result
+    /// None if the type is not supported, otherwise the result of the operation
+    fn handle_breakpoint_packet(
+        &mut self,
+        is_insert: bool,
+        arguments: &[u8],
+    ) -> Option<Result<(), ()>> {
+        let (&breakpoint_type, rest) = arguments.split_first()?;
+        /* The conditions after ';' are not supported */
+        let rest = rest.split(|c| *c == b';').next().unwrap_or(&[]);
+        let Some(Ok((address, kind))) = rest.strip_prefix(b",").map(parse_address_and_length)
+        else {
+            return Some(Err(()));
+        };
+        let watchpoint_type = match breakpoint_type {
+            b'0' => return Some(self.set_software_breakpoint(is_insert, address)),
+            b'1' => return Some(self.set_hardware_breakpoint(is_insert, address)),
+            b'2' => WatchpointType::Write,
+            b'3' => WatchpointType::Read,
+            b'4' => WatchpointType::Access,
+            _ => return None,
+        };
+        Some(self.set_watchpoint(
+            is_insert,
+            Watchpoint {
+                address,
+                length: kind as u64,
+                watchpoint_type,
+            },
+        ))
+    }
+
+    fn set_software_breakpoint(&mut self, is_insert: bool, address: u64) -> Result<(), ()> {
+        let existing = self
+            .software_breakpoints
+            .iter_mut()
+            .find(|b| b.is_some_and(|b| b.address == address));
+        if !is_insert {
+            if let Some(entry) = existing {
+                let b = entry.take().unwrap();
+                write_guest_memory(address, &b.original_instruction.to_le_bytes())?;
+            }
+            return Ok(());
+        }
+        if existing.is_some() {
+            return Ok(());
+        }
+        let entry = self
+            .software_breakpoints
+            .iter_mut()
+            .find(|b| b.is_none())
+            .ok_or(())?;
+        let mut original = [0u8; 4];
+        read_guest_memory(address, &mut original)?;
+        write_guest_memory(address, &BRK_INSTRUCTION.to_le_bytes())?;
+        *entry = Some(SoftwareBreakpoint {
+            address,
+            original_instruction: u32::from_le_bytes(original),
+        });
+        Ok(())
+    }
+
+    fn set_hardware_breakpoint(&mut self, is_insert: bool, address: u64) -> Result<(), ()> {
+        let breakpoints = &mut self.hardware_breakpoints[..get_number_of_hardware_breakpoints()];
+        if !is_insert {
+            for b in breakpoints.iter_mut().filter(|b| **b == Some(address)) {
+                *b = None;
+            }
+            return Ok(());
+        }
+        if (address & 0b11) != 0 {
+            return Err(());
+        }
+        *breakpoints.iter_mut().find(|b| b.is_none()).ok_or(())? = Some(address);
+        Ok(())
+    }
+
+    fn set_watchpoint(&mut self, is_insert: bool, watchpoint: Watchpoint) -> Result<(), ()> {
+        let watchpoints = &mut self.watchpoints[..get_number_of_watchpoints()];
+        let is_same = |w: &Watchpoint| {
+            w.address == watchpoint.address
+                && w.length == watchpoint.length
+                && w.watchpoint_type == watchpoint.watchpoint_type
+        };
+        if !is_insert {
+            for w in watchpoints
+                .iter_mut()
+                .filter(|w| w.is_some_and(|w| is_same(&w)))
+            {
+                *w = None;
+            }
+            return Ok(());
+        }
+        /* Only the bytes in one double word can be watched without MASK */
+        if watchpoint.length == 0 || (watchpoint.address & 0b111) + watchpoint.length > 8 {
+            return Err(());
+        }
+        *watchpoints.iter_mut().find(|w| w.is_none()).ok_or(())? = Some(watchpoint);
+        Ok(())
+    }
+
+    /// Remove all breakpoints and return the debug exceptions to the guest
+    fn detach(&mut self) {
+        for i in 0..MAX_SOFTWARE_BREAKPOINTS {
+            if let Some(b) = self.software_breakpoints[i] {
+                let _ = self.set_software_breakpoint(false, b.address);
+            }
+        }
+        self.hardware_breakpoints = [None; MAX_HARDWARE_BREAKPOINTS];
+        self.watchpoints = [None; MAX_WATCHPOINTS];
+        self.is_stepping = false;
+        self.is_attached = false;
+        set_mdcr_el2(get_mdcr_el2() & !MDCR_EL2_TDE);
+    }
+
+    /// Write the breakpoints, the watchpoints and the step state into the physical registers
+    fn apply_debug_state(&self) {
+        for (i, breakpoint) in self
+            .hardware_breakpoints
+            .iter()
+            .enumerate()
+            .take(get_number_of_hardware_breakpoints())
+        {
+            match *breakpoint {
+                Some(address) => {
+                    set_dbgbvr_el1(i, address);
+                    set_dbgbcr_el1(i, DBGBCR_EL1_BAS | DBGBCR_EL1_PMC_EL1_EL0 | DBGBCR_EL1_E);
+                }
+                None => set_dbgbcr_el1(i, 0),
+            }
+        }
+        for (i, watchpoint) in self
+            .watchpoints
+            .iter()
+            .enumerate()
+            .take(get_number_of_watchpoints())
+        {
+            match *watchpoint {
+                Some(w) => {
+                    let byte_address_select = ((1u64 << w.length) - 1) << (w.address & 0b111);
+                    let load_store_control = match w.watchpoint_type {
+                        WatchpointType::Write => DBGWCR_EL1_LSC_STORE,
+                        WatchpointType::Read => DBGWCR_EL1_LSC_LOAD,
+                        WatchpointType::Access => DBGWCR_EL1_LSC_LOAD | DBGWCR_EL1_LSC_STORE,
+                    };
+                    set_dbgwvr_el1(i, w.address & !0b111);
+                    set_dbgwcr_el1(
+                        i,
+                        (byte_address_select << DBGWCR_EL1_BAS_BITS_OFFSET)
+                            | load_store_control
+                            | DBGWCR_EL1_PAC_EL1_EL0
+                            | DBGWCR_EL1_E,
+                    );
+                }
+                None => set_dbgwcr_el1(i, 0),
+            }
+        }
+
+        let mut mdscr_el1 = get_mdscr_el1() & !(MDSCR_EL1_MDE | MDSCR_EL1_SS);
+        if self.hardware_breakpoints.iter().any(|b| b.is_some())
+            || self.watchpoints.iter().any(|w| w.is_some())
+        {
+            mdscr_el1 |= MDSCR_EL1_MDE;
+        }
+        let mut spsr_el2 = get_spsr_el2() & !SPSR_EL2_SS;
+        if self.is_stepping {
+            mdscr_el1 |= MDSCR_EL1_SS;
+            spsr_el2 |= SPSR_EL2_SS;
+        }
+        set_mdscr_el1(mdscr_el1);
+        set_spsr_el2(spsr_el2);
+        isb();
+    }
+}
+
+/// The stack pointer selected by PSTATE of the guest
+fn is_sp_el1_selected() -> bool {
+    (get_spsr_el2() & (SPSR_EL2_M | SPSR_EL2_M_AARCH32)) == SPSR_EL2_M_EL1H
+}
+
+/// Read the register by the register number of GDB
+///
+/// # Result
+/// Some((value, size in bytes)), or None if the register does not exist
+fn read_register(registers: &Registers, n: usize) -> Option<(u64, usize)> {
+    match n {
+        0..NUMBER_OF_GENERAL_REGISTERS => Some((registers.read(n), 8)),
+        REGISTER_SP => Some((
+            if is_sp_el1_selected() {
+                get_sp_el1()
+            } else {
+                get_sp_el0()
+            },
+            8,
+        )),
+        REGISTER_PC => Some((get_elr_el2(), 8)),
+        REGISTER_CPSR => Some((get_spsr_el2() & (u32::MAX as u64), 4)),
+        _ => SYSTEM_REGISTERS
+            .get(n - FIRST_SYSTEM_REGISTER)
+            .map(|(_, get, _)| (get(), 8)),
+    }
+}
+
+fn write_register(registers: &mut Registers, n: usize, value: u64) -> Result<(), ()> {
+    match n {
+        0..NUMBER_OF_GENERAL_REGISTERS => registers.write(n, value),
+        REGISTER_SP => {
+            if is_sp_el1_selected() {
+                set_sp_el1(value)
+            } else {
+                set_sp_el0(value)
+            }
+        }
+        REGISTER_PC => set_elr_el2(value),
+        REGISTER_CPSR => set_spsr_el2(value & (u32::MAX as u64)),
+        _ => (SYSTEM_REGISTERS.get(n - FIRST_SYSTEM_REGISTER).ok_or(())?.2)(value),
+    }
+    Ok(())
+}
+
+/// Write target.xml which describes the registers
+fn write_target_description(xml: &mut Response) {
+    xml.push_str(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><architecture>aarch64</architecture>\
+         <feature name=\"org.gnu.gdb.aarch64.core\">",
+    );
+    for n in 0..NUMBER_OF_GENERAL_REGISTERS {
+        let _ = write!(
+            xml,
+            "<reg name=\"x{}\" bitsize=\"64\" regnum=\"{}\"/>",
+            n, n
+        );
+    }
+    let _ = write!(
+        xml,
+        "<reg name=\"sp\" bitsize=\"64\" type=\"data_ptr\" regnum=\"{}\"/>\
+         <reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\" regnum=\"{}\"/>\
+         <reg name=\"cpsr\" bitsize=\"32\" regnum=\"{}\"/></feature>\
+         <feature name=\"org.gnu.gdb.aarch64.el1\">",
+        REGISTER_SP, REGISTER_PC, REGISTER_CPSR
+    );
+    for (i, (name, _, _)) in SYSTEM_REGISTERS.iter().enumerate() {
+        let _ = write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"64\" regnum=\"{}\" group=\"system\"/>",
+            name,
+            FIRST_SYSTEM_REGISTER + i
+        );
+    }
+    xml.push_str("</feature></target>");
+}
@@ -0,0 +1,314 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Hypercall Interface
+//!
+//! The hypercalls are issued by `HVC #0` following SMC Calling Convention.
+//! They are placed in the Vendor Specific Hypervisor Service range (Owning Entity Number = 6).
+//!
+//! | Function ID  | Name            | Arguments                             | Results                 |
+//! |--------------|-----------------|---------------------------------------|-------------------------|
+//! | `0xC6000000` | VERSION         | -                                     | x0: (major << 16) minor |
+//! | `0xC6000001` | FEATURES        | -                                     | x0: SUCCESS, x1: bitmap of available function numbers |
+//! | `0xC6000002` | LOG_WRITE       | x1: IPA of the string, x2: length     | x0: SUCCESS, x1: written bytes |
+//! | `0xC6000003` | MEMORY_SHARE    | x1: IPA, x2: number of pages, x3: flags(bit0: writable) | x0: SUCCESS, x1: grant handle |
+//! | `0xC6000004` | MEMORY_UNSHARE  | x1: grant handle                      | x0: SUCCESS             |
+//! | `0xC6000005` | GET_STATISTICS  | x1: statistics id                     | x0: SUCCESS, x1: value  |
+//! | `0xC6000006` | TRACE_CONTROL   | x1: 0: stop, 1: start, 2: clear       | x0: SUCCESS, x1: stored records, x2: lost records |
+//! | `0xC6000007` | TRACE_READ      | x1: IPA of the buffer, x2: size       | x0: SUCCESS, x1: read records |
+//! | `0xC600FF00` | CALL_COUNT      | -                                     | x0: number of functions |
+//! | `0xC600FF01` | CALL_UID        | -                                     | x0~x3: UID              |
+//! | `0xC600FF03` | REVISION        | -                                     | x0: major, x1: minor    |
+//!
+//! The general service queries (`0xFF00`~`0xFF03`) and VERSION/FEATURES are also available
+//! with the SMC32 function IDs (`0x86000000`~).
+//!
+//! Statistics ID of GET_STATISTICS:
+//! * `0x000`: the number of total VM exits
+//! * `0x001`: the number of VM exits by the physical interrupts
+//! * `0x002`: the ticks spent in the handler of the physical interrupts
+//! * `0x003`: the frequency of the ticks (CNTFRQ_EL0)
+//! * `0x100 + EC`: the number of VM exits by the synchronous exception with the exception class
+//! * `0x200 + EC`: the ticks spent in the handler of the synchronous exception
+//!
+//! MEMORY_SHARE pins the pages: they keep the same frames and virtio-balloon does not reclaim
+//! them until MEMORY_UNSHARE. With `writable`, the pages must be writable by the guest.
+//!
+//! TRACE_READ moves the oldest records of the exit trace into the buffer,
+//! see [`stats::TraceRecord`] for the format of each 32 bytes record.
+//!
+//! Any other function, including the yielding calls, returns NOT_SUPPORTED(-1).
+//!
+
+use crate::exception::Registers;
+use crate::paging::{
+    convert_intermediate_physical_address_range_to_physical_address as translate_guest_range,
+    PAGE_SHIFT,
+};
+use crate::smccc::*;
+use crate::stats;
+
+pub const HYPERVISOR_VERSION_MAJOR: u64 = 0;
+pub const HYPERVISOR_VERSION_MINOR: u64 = 1;
+
+pub const HYPERCALL_VERSION: u32 = 0x0000;
+pub const HYPERCALL_FEATURES: u32 = 0x0001;
+pub const HYPERCALL_LOG_WRITE: u32 = 0x0002;
+pub const HYPERCALL_MEMORY_SHARE: u32 = 0x0003;
+pub const HYPERCALL_MEMORY_UNSHARE: u32 = 0x0004;
+pub const HYPERCALL_GET_STATISTICS: u32 = 0x0005;
+pub const HYPERCALL_TRACE_CONTROL: u32 = 0x0006;
+pub const HYPERCALL_TRACE_READ: u32 = 0x0007;
+const NUMBER_OF_HYPERCALLS: u64 = 8;
+
+pub const HYPERCALL_MEMORY_SHARE_WRITABLE: u64 = 1 << 0;
+
+pub const STATISTICS_TOTAL_EXITS: u64 = 0x000;
+pub const STATISTICS_IRQ_EXITS: u64 = 0x001;
+pub const STATISTICS_IRQ_EXIT_TICKS: u64 = 0x002;
+pub const STATISTICS_TICK_FREQUENCY: u64 = 0x003;
+pub const STATISTICS_SYNCHRONOUS_EXITS_BASE: u64 = 0x100;
+pub const STATISTICS_SYNCHRONOUS_EXIT_TICKS_BASE: u64 = 0x200;
+
+pub const TRACE_CONTROL_STOP: u64 = 0;
+pub const TRACE_CONTROL_START: u64 = 1;
+pub const TRACE_CONTROL_CLEAR: u64 = 2;
+
+/// UID of this hypervisor, returned in w0~w3 by CALL_UID
+pub const HYPERVISOR_UID: [u32; 4] = [0x7a3b9c1e, 0x4e865d2f, 0x68791b10, 0x7276736f];
+
+const MAX_LOG_WRITE_SIZE: usize = 0x1000;
+const MAX_MEMORY_GRANTS: usize = 32;
+/// A grant cannot be larger than the guest RAM window
+const MAX_MEMORY_SHARE_PAGES: usize = crate::GUEST_RAM_SIZE >> PAGE_SHIFT;
+
+/// The memory region which the guest shared with the hypervisor
+#[derive(Clone, Copy, Debug)]
+struct MemoryGrant {
+    handle: u64,
+    intermediate_physical_address: usize,
+    number_of_pages: usize,
+}
+
+static mut MEMORY_GRANTS: [Option<MemoryGrant>; MAX_MEMORY_GRANTS] = [None; MAX_MEMORY_GRANTS];
+static mut NEXT_GRANT_HANDLE: u64 = 1;
+
+/// Check if the page is shared by MEMORY_SHARE, the shared pages must not be unmapped
+///
+/// # Arguments
+/// * `intermediate_physical_address` - the address in the page
+pub fn is_shared_page(intermediate_physical_address: usize) -> bool {
+    unsafe { &*core::ptr::addr_of!(MEMORY_GRANTS) }
+        .iter()
+        .flatten()
+        .any(|g| {
+            intermediate_physical_address
+                .checked_sub(g.intermediate_physical_address)
+                .is_some_and(|offset| (offset >> PAGE_SHIFT) < g.number_of_pages)
+        })
+}
+
+/// Handle HVC from EL1
+///
+/// ELR_EL2 already points the next instruction of HVC.
+pub fn hypercall_handler(registers: &mut Registers, imm16: u16) {
+    let function_id = FunctionId::new(registers.x0);
+    if imm16 != 0
+        || !function_id.is_fast_call()
+        || function_id.service_range() != ServiceRange::VendorHypervisor
+    {
+        debug!(
+            "Unsupported HVC #{:#X}: {:#X} ({})",
+            imm16,
+            function_id.0,
+            function_id.service_range().name()
+        );
+        registers.x0 = SMCCC_NOT_SUPPORTED;
+        return;
+    }
+    let is_64bit_call = function_id.is_64bit_call();
+    match function_id.function_number() {
+        HYPERCALL_VERSION => {
+            registers.x0 = (HYPERVISOR_VERSION_MAJOR << 16) | HYPERVISOR_VERSION_MINOR;
+        }
+        HYPERCALL_FEATURES => {
+            registers.x0 = SMCCC_SUCCESS;
+            registers.x1 = (1 << NUMBER_OF_HYPERCALLS) - 1;
+        }
+        HYPERCALL_LOG_WRITE if is_64bit_call => {
+            log_write(registers);
+        }
+        HYPERCALL_MEMORY_SHARE if is_64bit_call => {
+            memory_share(registers);
+        }
+        HYPERCALL_MEMORY_UNSHARE if is_64bit_call => {
+            memory_unshare(registers);
+        }
+        HYPERCALL_GET_STATISTICS if is_64bit_call => {
+            get_statistics(registers);
+        }
+        HYPERCALL_TRACE_CONTROL if is_64bit_call => {
+            trace_control(registers);
+        }
+        HYPERCALL_TRACE_READ if is_64bit_call => {
+            trace_read(registers);
+        }
+        SMCCC_CALL_COUNT => {
+            registers.x0 = NUMBER_OF_HYPERCALLS;
+        }
+        SMCCC_CALL_UID => {
+            registers.x0 = HYPERVISOR_UID[0] as u64;
+            registers.x1 = HYPERVISOR_UID[1] as u64;
+            registers.x2 = HYPERVISOR_UID[2] as u64;
+            registers.x3 = HYPERVISOR_UID[3] as u64;
+        }
+        SMCCC_REVISION => {
+            registers.x0 = HYPERVISOR_VERSION_MAJOR;
+            registers.x1 = HYPERVISOR_VERSION_MINOR;
+        }
+        _ => {
+            registers.x0 = SMCCC_NOT_SUPPORTED;
+        }
+    }
+}
+
+fn log_write(registers: &mut Registers) {
+    let size = (registers.x2 as usize).min(MAX_LOG_WRITE_SIZE);
+    let Ok(physical_address) = translate_guest_range(registers.x1 as usize, size, false) else {
+        registers.x0 = SMCCC_INVALID_PARAMETER;
+        return;
+    };
+    let buffer = unsafe { core::slice::from_raw_parts(physical_address as *const u8, size) };
+    crate::console::write_bytes(buffer);
+    registers.x0 = SMCCC_SUCCESS;
+    registers.x1 = size as u64;
+}
+
+fn memory_share(registers: &mut Registers) {
+    let intermediate_physical_address = registers.x1 as usize;
+    let number_of_pages = registers.x2 as usize;
+    let is_writable = (registers.x3 & HYPERCALL_MEMORY_SHARE_WRITABLE) != 0;
+    if (intermediate_physical_address & ((1 << PAGE_SHIFT) - 1)) != 0
+        || number_of_pages == 0
+        || number_of_pages > MAX_MEMORY_SHARE_PAGES
+    {
+        registers.x0 = SMCCC_INVALID_PARAMETER;
+        return;
+    }
+    let Some(size) = number_of_pages.checked_mul(1 << PAGE_SHIFT) else {
+        registers.x0 = SMCCC_INVALID_PARAMETER;
+        return;
+    };
+    if translate_guest_range(intermediate_physical_address, size, is_writable).is_err() {
+        registers.x0 = SMCCC_INVALID_PARAMETER;
+        return;
+    }
+    let grants = unsafe { &mut *core::ptr::addr_of_mut!(MEMORY_GRANTS) };
+    let Some(entry) = grants.iter_mut().find(|g| g.is_none()) else {
+        registers.x0 = SMCCC_NOT_SUPPORTED;
+        return;
+    };
+    let handle = unsafe { NEXT_GRANT_HANDLE };
+    unsafe { NEXT_GRANT_HANDLE += 1 };
+    *entry = Some(MemoryGrant {
+        handle,
+        intermediate_physical_address,
+        number_of_pages,
+    });
+    registers.x0 = SMCCC_SUCCESS;
+    registers.x1 = handle;
+}
+
+fn memory_unshare(registers: &mut Registers) {
+    let handle = registers.x1;
+    match unsafe { &mut *core::ptr::addr_of_mut!(MEMORY_GRANTS) }
+        .iter_mut()
+        .find(|g| matches!(g, Some(g) if g.handle == handle))
+    {
+        Some(entry) => {
+            *entry = None;
+            registers.x0 = SMCCC_SUCCESS;
+        }
+        None => registers.x0 = SMCCC_INVALID_PARAMETER,
+    }
+}
+
+fn get_statistics(registers: &mut Registers) {
+    let statistics = stats::get_exit_statistics();
+    let id = registers.x1;
+    let value = match id {
+        STATISTICS_TOTAL_EXITS => statistics.total_exits,
+        STATISTICS_IRQ_EXITS => statistics.irq_exits,
+        STATISTICS_IRQ_EXIT_TICKS => statistics.irq_exit_ticks,
+        STATISTICS_TICK_FREQUENCY => crate::cpu::get_cntfrq_el0(),
+        _ if (STATISTICS_SYNCHRONOUS_EXITS_BASE
+            ..STATISTICS_SYNCHRONOUS_EXITS_BASE + stats::NUMBER_OF_EXCEPTION_CLASSES as u64)
+            .contains(&id) =>
+        {
+            statistics.synchronous_exits[(id - STATISTICS_SYNCHRONOUS_EXITS_BASE) as usize]
+        }
+        _ if (STATISTICS_SYNCHRONOUS_EXIT_TICKS_BASE
+            ..STATISTICS_SYNCHRONOUS_EXIT_TICKS_BASE
+                + stats::NUMBER_OF_EXCEPTION_CLASSES as u64)
+            .contains(&id) =>
+        {
+            statistics.synchronous_exit_ticks
+                [(id - STATISTICS_SYNCHRONOUS_EXIT_TICKS_BASE) as usize]
+        }
+        _ => {
+            registers.x0 = SMCCC_INVALID_PARAMETER;
+            return;
+        }
+    };
+    registers.x0 = SMCCC_SUCCESS;
+    registers.x1 = value;
+}
+
+fn trace_control(registers: &mut Registers) {
+    match registers.x1 {
+        TRACE_CONTROL_STOP => stats::set_trace_enabled(false),
+        TRACE_CONTROL_START => stats::set_trace_enabled(true),
+        TRACE_CONTROL_CLEAR => stats::clear_trace(),
+        _ => {
+            registers.x0 = SMCCC_INVALID_PARAMETER;
+            return;
+        }
+    }
+    let (length, lost_records) = stats::get_trace_status();
+    registers.x0 = SMCCC_SUCCESS;
+    registers.x1 = length as u64;
+    registers.x2 = lost_records;
+}
+
+fn trace_read(registers: &mut Registers) {
+    let number_of_records = registers.x2 as usize / stats::TRACE_RECORD_SIZE;
+    if number_of_records == 0 {
+        registers.x0 = SMCCC_INVALID_PARAMETER;
+        return;
+    }
+    let Ok(physical_address) = translate_guest_range(
+        registers.x1 as usize,
+        number_of_records * stats::TRACE_RECORD_SIZE,
+        true,
+    ) else {
+        registers.x0 = SMCCC_INVALID_PARAMETER;
+        return;
+    };
+    let buffer = physical_address as *mut stats::TraceRecord;
+    let mut read_records = 0;
+    while read_records < number_of_records {
+        let Some(record) = stats::pop_trace_record() else {
+            break;
+        };
+        /* The buffer of the guest may not be aligned */
+        unsafe { core::ptr::write_unaligned(buffer.add(read_records), record) };
+        read_records += 1;
+    }
+    registers.x0 = SMCCC_SUCCESS;
+    registers.x1 = read_records as u64;
+}
@@ -0,0 +1,362 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Leveled Logging
+//!
+//! `error!`, `warn!`, `info!`, `debug!` and `trace!` record the message with the timestamp of
+//! the generic timer and the module path.
+//! Whether the message is recorded is decided by the level of the longest matching module
+//! filter, or the default level. The recorded messages are stored into the in-memory ring buffer,
+//! which is printed by the panic handler, and the messages at or above the console level are
+//! also printed to the console.
+//!
+//! The levels are set by [`init`] and can be changed by the load options of the image,
+//! see [`parse_options`].
+//!
+
+use crate::cpu::{get_cntfrq_el0, get_cntpct_el0};
+
+use core::fmt;
+
+pub const LOG_BUFFER_SIZE: usize = 0x4000;
+pub const MAX_LOG_FILTERS: usize = 16;
+pub const MAX_MODULE_NAME_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum LogLevel {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+            Self::Trace => "trace",
+        }
+    }
+
+    const fn mark(&self) -> char {
+        match self {
+            Self::Off => ' ',
+            Self::Error => 'E',
+            Self::Warn => 'W',
+            Self::Info => 'I',
+            Self::Debug => 'D',
+            Self::Trace => 'T',
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Self::Off,
+            Self::Error,
+            Self::Warn,
+            Self::Info,
+            Self::Debug,
+            Self::Trace,
+        ]
+        .into_iter()
+        .find(|l| l.name() == name)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct LogFilter {
+    module: [u8; MAX_MODULE_NAME_LENGTH],
+    module_length: usize,
+    level: LogLevel,
+}
+
+impl LogFilter {
+    fn module(&self) -> &str {
+        core::str::from_utf8(&self.module[..self.module_length]).unwrap_or("")
+    }
+
+    /// Check if `module` is the module of the filter or its submodule
+    fn matches(&self, module: &str) -> bool {
+        let name = self.module();
+        module
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+}
+
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    head: usize,
+    length: usize,
+}
+
+struct Logger {
+    default_level: LogLevel,
+    console_level: LogLevel,
+    filters: [Option<LogFilter>; MAX_LOG_FILTERS],
+    buffer: LogBuffer,
+}
+
+static mut LOGGER: Logger = Logger {
+    default_level: LogLevel::Info,
+    console_level: LogLevel::Info,
+    filters: [None; MAX_LOG_FILTERS],
+    buffer: LogBuffer {
+        data: [0; LOG_BUFFER_SIZE],
+        head: 0,
+        length: 0,
+    },
+};
+
+fn logger() -> &'static mut Logger {
+    unsafe { &mut *core::ptr::addr_of_mut!(LOGGER) }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.bytes() {
+            if self.length == LOG_BUFFER_SIZE {
+                self.data[self.head] = c;
+                self.head = (self.head + 1) % LOG_BUFFER_SIZE;
+            } else {
+                self.data[(self.head + self.length) % LOG_BUFFER_SIZE] = c;
+                self.length += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Remove the crate name from the module path
+fn strip_crate_name(module_path: &str) -> &str {
+    module_path
+        .split_once("::")
+        .map(|(_, module)| module)
+        .unwrap_or(module_path)
+}
+
+/// Set up the levels from the boot configuration
+///
+/// # Arguments
+/// * `default_level` - the level of the modules without the filter
+/// * `console_level` - the lowest level printed to the console
+/// * `filters` - the pairs of the module path (without the crate name) and its level
+pub fn init(
+    default_level: LogLevel,
+    console_level: LogLevel,
+    filters: &[(&str, LogLevel)],
+) -> Result<(), ()> {
+    let l = logger();
+    l.default_level = default_level;
+    l.console_level = console_level;
+    l.filters = [None; MAX_LOG_FILTERS];
+    for (module, level) in filters {
+        set_module_level(module, *level)?;
+    }
+    Ok(())
+}
+
+/// Apply the log levels in the load options
+///
+/// The options are separated by spaces and the following ones are recognized, the others
+/// are ignored:
+/// * `log=<entry>[,<entry>...]` - `<entry>` is `<level>` for the default level, or
+///   `<module>:<level>` for the module and its submodules, like `log=info,paging:trace`
+/// * `console_log=<level>` - the lowest level printed to the console
+///
+/// # Result
+/// If a level is unknown or the filter cannot be added, returns Err(()) after applying the
+/// other entries
+pub fn parse_options(options: &str) -> Result<(), ()> {
+    let mut result = Ok(());
+    for option in options.split_ascii_whitespace() {
+        if let Some(entries) = option.strip_prefix("log=") {
+            for entry in entries.split(',') {
+                let r = match entry.split_once(':') {
+                    Some((module, level)) => LogLevel::from_name(level)
+                        .ok_or(())
+                        .and_then(|level| set_module_level(module, level)),
+                    None => LogLevel::from_name(entry).map(set_default_level).ok_or(()),
+                };
+                result = result.and(r);
+            }
+        } else if let Some(level) = option.strip_prefix("console_log=") {
+            result = result.and(LogLevel::from_name(level).map(set_console_level).ok_or(()));
+        }
+    }
+    result
+}
+
+/// Set the level of the module and its submodules
+///
+/// # Arguments
+/// * `module` - the module path without the crate name, like "mmio::pl011"
+/// * `level` - the level, [`LogLevel::Off`] drops all messages of the module
+///
+/// # Result
+/// If the module name is too long or the filter table is full, returns Err(())
+pub fn set_module_level(module: &str, level: LogLevel) -> Result<(), ()> {
+    if module.len() > MAX_MODULE_NAME_LENGTH {
+        return Err(());
+    }
+    let filters = &mut logger().filters;
+    let entry = if let Some(f) = filters.iter_mut().flatten().find(|f| f.module() == module) {
+        f
+    } else if let Some(e) = filters.iter_mut().find(|f| f.is_none()) {
+        e.insert(LogFilter {
+            module: [0; MAX_MODULE_NAME_LENGTH],
+            module_length: 0,
+            level,
+        })
+    } else {
+        return Err(());
+    };
+    entry.module[..module.len()].copy_from_slice(module.as_bytes());
+    entry.module_length = module.len();
+    entry.level = level;
+    Ok(())
+}
+
+/// Remove the filter of the module, then the default level is applied to it
+pub fn remove_module_level(module: &str) -> Result<(), ()> {
+    let entry = logger()
+        .filters
+        .iter_mut()
+        .find(|f| f.is_some_and(|f| f.module() == module))
+        .ok_or(())?;
+    *entry = None;
+    Ok(())
+}
+
+pub fn set_default_level(level: LogLevel) {
+    logger().default_level = level;
+}
+
+pub fn set_console_level(level: LogLevel) {
+    logger().console_level = level;
+}
+
+/// Call `f` with the default level, the console level and each filter
+pub fn for_each_filter(f: &mut dyn FnMut(&str, LogLevel)) {
+    let l = logger();
+    f("(default)", l.default_level);
+    f("(console)", l.console_level);
+    for filter in l.filters.iter().flatten() {
+        f(filter.module(), filter.level);
+    }
+}
+
+/// Get the level applied to the module
+///
+/// # Arguments
+/// * `module_path` - the result of `module_path!()`
+pub fn get_module_level(module_path: &str) -> LogLevel {
+    let l = logger();
+    let module = strip_crate_name(module_path);
+    l.filters
+        .iter()
+        .flatten()
+        .filter(|f| f.matches(module))
+        .max_by_key(|f| f.module_length)
+        .map(|f| f.level)
+        .unwrap_or(l.default_level)
+}
+
+/// Record the message, use the macros instead of calling this directly
+pub fn write_log(level: LogLevel, module_path: &str, args: fmt::Arguments) {
+    use fmt::Write;
+    if level == LogLevel::Off || level > get_module_level(module_path) {
+        return;
+    }
+    let ticks = get_cntpct_el0();
+    let frequency = get_cntfrq_el0().max(1);
+    let seconds = ticks / frequency;
+    let microseconds = (ticks % frequency) * 1000000 / frequency;
+    let module = strip_crate_name(module_path);
+    let l = logger();
+    let _ = writeln!(
+        l.buffer,
+        "[{:5}.{:06}] {} {}: {}",
+        seconds,
+        microseconds,
+        level.mark(),
+        module,
+        args
+    );
+    if level <= l.console_level {
+        println!(
+            "[{:5}.{:06}] {} {}: {}",
+            seconds,
+            microseconds,
+            level.mark(),
+            module,
+            args
+        );
+    }
+}
+
+/// Print the contents of the log buffer from the oldest
+///
+/// If the buffer wrapped around, the first partial line is skipped.
+pub fn dump_log_buffer() {
+    let b = &logger().buffer;
+    let mut is_line_head = b.length < LOG_BUFFER_SIZE;
+    for i in 0..b.length {
+        let c = b.data[(b.head + i) % LOG_BUFFER_SIZE];
+        if is_line_head {
+            crate::console::write_bytes(&[c]);
+        } else if c == b'\n' {
+            is_line_head = true;
+        }
+    }
+}
+
+/// Discard the contents of the log buffer
+pub fn clear_log_buffer() {
+    let b = &mut logger().buffer;
+    b.head = 0;
+    b.length = 0;
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => (
+        $crate::log::write_log($level, module_path!(), format_args!($($arg)*))
+    );
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::LogLevel::Trace, $($arg)*));
+}
@@ -20,49 +20,44 @@ use crate::EfiMemoryType::*;
 
 #[macro_use]
 mod console;
+#[macro_use]
+mod log;
 mod cpu;
 mod exception;
+mod frame_pool;
+mod gdb;
+mod hypercall;
+mod monitor;
+mod net_switch;
 mod paging;
+mod random;
+mod smc;
+mod smccc;
+mod stats;
+mod sysreg;
 mod uefi;
+mod unwind;
+mod vcpu;
+mod vgic;
+mod vm;
+mod vsock;
 mod mmio {
+    pub mod bus;
     pub mod pl011;
     pub mod virt_mmio;
+    pub mod virtio_balloon;
+    pub mod virtio_blk;
+    pub mod virtio_console;
+    pub mod virtio_net;
+    pub mod virtio_rng;
+    pub mod virtio_vsock;
+    pub mod virtqueue;
 }
 
 use core::ptr::write_volatile;
 
 const PL011: usize = 0x09000000;
 
-/*
-pub struct PL011Reg {
-    dr: u32,
-    rsr: u32,
-    reserved1: [u8; 0x10],
-    fr: u32,
-    reserved2: u32,
-    ilpr: u32,
-    ibrd: u32,
-    fbrd: u32,
-    lcr_h: u32,
-    cr: u32,
-    ifls: u32,
-    imsc: u32,
-    ris: u32,
-    mis: u32,
-    icr: u32,
-    dmacr: u32,
-    reserved3: [u8; 0xf60],
-    periphid0: u32,
-    periphid1: u32,
-    periphid2: u32,
-    periphid3: u32,
-    pcellid0: u32,
-    pcellid1: u32,
-    pcellid2: u32,
-    pcellid3: u32,
-}
-*/ 
-
 static mut INTERRUPT_FLAG: MaybeUninit<InterruptFlag> = MaybeUninit::uninit();
 
 static mut IMAGE_HANDLE: EfiHandle = 0;
@@ -72,6 +67,74 @@ static mut SYSTEM_TABLE: *const EfiSystemTable = core::ptr::null();
 pub const ALLOC_SIZE: usize = 256 * 1024 * 1024; /* 256 MB */
 pub const MAX_PHYSICAL_ADDRESS: usize = (1 << (48 + 1)) - 1;
 pub const STACK_PAGES: usize = 16;
+/// The guest RAM window, mapped to the same physical address
+pub const GUEST_RAM_ADDRESS: usize = 0x40000000;
+pub const GUEST_RAM_SIZE: usize = 0x80000000; /* 2 GB */
+const GUEST_RAM_PAGES: usize = GUEST_RAM_SIZE >> PAGE_SHIFT;
+
+/// The pages of the guest RAM window used by the hypervisor, bit n is the page n of the window
+static mut HYPERVISOR_PAGE_BITMAP: [u64; GUEST_RAM_PAGES / u64::BITS as usize] =
+    [0; GUEST_RAM_PAGES / u64::BITS as usize];
+
+/// The virtio-mmio slot of the emulated virtio-blk (QEMU assigns its devices from the last slot)
+const VIRTIO_BLK_SLOT: usize = 0;
+/// The disk image on the volume which the hypervisor was loaded from
+const VIRTIO_BLK_IMAGE_FILE: &str = "\\disk.img";
+/// The size of the RAM disk used when the disk image does not exist
+const VIRTIO_BLK_RAM_DISK_SIZE: usize = 64 * 1024 * 1024; /* 64 MB */
+/// The virtio-mmio slot of the emulated virtio-console
+const VIRTIO_CONSOLE_SLOT: usize = 1;
+const VIRTIO_CONSOLE_NUMBER_OF_PORTS: usize = 2;
+/// The virtio-mmio slot of the emulated virtio-net connected to the software switch
+const VIRTIO_NET_SLOT: usize = 2;
+const VIRTIO_NET_MAC_ADDRESS: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+/// The second virtio-net connected to the same switch, the frames between them are switched
+const VIRTIO_NET_SECOND_SLOT: usize = 7;
+const VIRTIO_NET_SECOND_MAC_ADDRESS: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x57];
+/// The virtio-mmio slot of the emulated virtio-rng
+const VIRTIO_RNG_SLOT: usize = 3;
+/// The virtio-mmio slot of the emulated virtio-balloon
+const VIRTIO_BALLOON_SLOT: usize = 4;
+/// The frames reserved for the page tables allocated after ExitBootServices
+const FRAME_POOL_RESERVED_PAGES: usize = 64;
+/// The virtio-mmio slot of the emulated virtio-vsock
+const VIRTIO_VSOCK_SLOT: usize = 5;
+const VIRTIO_VSOCK_GUEST_CID: u64 = 3;
+/// The second virtio-vsock, the packets between the two CIDs are forwarded by the hypervisor
+const VIRTIO_VSOCK_SECOND_SLOT: usize = 6;
+const VIRTIO_VSOCK_SECOND_GUEST_CID: u64 = 4;
+/// The port of the echo service on the hypervisor CID
+const VSOCK_ECHO_PORT: u32 = 7;
+/// The behavior on the accesses to the guest physical addresses backed by nothing
+///
+/// The firmware accesses the platform devices not mapped by stage 2, so they are ignored
+/// by default. InjectAbort is for the guests which use only the emulated devices.
+/// The load option `unmapped=<abort|ignore|stop>` overrides this.
+const UNMAPPED_ACCESS_POLICY: vm::UnmappedAccessPolicy =
+    vm::UnmappedAccessPolicy::ReadAsZeroWriteIgnore;
+/// The policies of SMC per SMCCC service range, overriding the defaults in [`smc`]
+///
+/// The SiP services are passed to EL3 for the platform firmware, e.g. the power domains.
+const SMC_POLICIES: [(smccc::ServiceRange, smc::SmcPolicy); 1] =
+    [(smccc::ServiceRange::SiliconPartner, smc::SmcPolicy::Forward)];
+/// The console backend used after returning to the firmware, UEFI cannot be called from there
+const CONSOLE_BACKEND_AFTER_BOOT: console::ConsoleBackendType = console::ConsoleBackendType::Pl011;
+/// The log level of the modules without the filter, and the lowest level printed to the console
+///
+/// The debug build shows `debug!` and `pr_debug!` by default.
+const DEFAULT_LOG_LEVEL: log::LogLevel = if cfg!(debug_assertions) {
+    log::LogLevel::Debug
+} else {
+    log::LogLevel::Info
+};
+const CONSOLE_LOG_LEVEL: log::LogLevel = DEFAULT_LOG_LEVEL;
+/// The log levels per module (without the crate name), the longest matching one is applied,
+/// e.g. `[("paging", log::LogLevel::Trace)]` shows each table updated by the stage 2 mapping
+///
+/// They can be overridden by the load options, see [`log::parse_options`].
+const LOG_FILTERS: [(&str, log::LogLevel); 0] = [];
+/// The maximum length of the load options in UTF-8
+const MAX_LOAD_OPTIONS_LENGTH: usize = 256;
 
 #[macro_export]
 macro_rules! bitmask {
@@ -86,8 +149,15 @@ extern "C" fn efi_main(image_handle: EfiHandle, system_table: *mut EfiSystemTabl
     unsafe {
         IMAGE_HANDLE = image_handle;
         SYSTEM_TABLE = system_table;
-        console::DEFAULT_CONSOLE.init((*system_table).console_output_protocol);
     }
+    if let Ok((image_base, image_size)) = uefi::file::get_image_range(image_handle, unsafe {
+        &*system_table.efi_boot_services
+    }) {
+        mark_hypervisor_pages(image_base, image_size.div_ceil(1 << PAGE_SHIFT));
+    }
+    console::init_uefi_console(system_table.console_output_protocol);
+    console::init_pl011_console(PL011);
+    setup_log();
     
     assert_eq!(get_current_el() >> 2, 2, "Expected CurrentEL is EL2");
     
@@ -133,7 +203,7 @@ extern "C" fn efi_main(image_handle: EfiHandle, system_table: *mut EfiSystemTabl
             },
         };
 
-        println!("{:#08X} ~ {:#08X} : {}",
+        debug!("{:#08X} ~ {:#08X} : {}",
                     physical_start,
                     physical_start + number_of_pages as usize * 0x1000,
                     // memory_type_str,
@@ -154,15 +224,54 @@ extern "C" fn efi_main(image_handle: EfiHandle, system_table: *mut EfiSystemTabl
     //     }
     // }
 
-    paging::map_address_stage2(0x40000000, 0x40000000, 0x80000000, true, true);
+    paging::map_address_stage2(GUEST_RAM_ADDRESS, GUEST_RAM_ADDRESS, GUEST_RAM_SIZE, true, true);
+
+    /* Seed DRBG while EFI_RNG_PROTOCOL is available */
+    let mut seed = [0u8; 32];
+    if uefi::rng::get_random_bytes(unsafe { &*((*SYSTEM_TABLE).efi_boot_services) }, &mut seed)
+        .is_err()
+    {
+        warn!("EFI_RNG_PROTOCOL is not available, DRBG is seeded by the counter");
+    }
+    random::init(&seed);
+
+    setup_unmapped_access_policy();
+    for (range, policy) in SMC_POLICIES {
+        smc::set_smc_policy(range, policy);
+    }
+    mmio::pl011::setup_pl011(PL011, mmio::pl011::UART_INTERRUPT_ID, "pl011")
+        .expect("Failed to setup PL011");
+    mmio::virt_mmio::register_virt_mmio_slots(&mut vm::current().mmio_bus)
+        .expect("Failed to register virtio-mmio");
+    setup_virtio_blk();
+    mmio::virtio_console::setup_virtio_console(VIRTIO_CONSOLE_SLOT, VIRTIO_CONSOLE_NUMBER_OF_PORTS)
+        .expect("Failed to setup virtio-console");
+    mmio::virtio_net::setup_virtio_net(VIRTIO_NET_SLOT, VIRTIO_NET_MAC_ADDRESS)
+        .expect("Failed to setup virtio-net");
+    mmio::virtio_net::setup_virtio_net(VIRTIO_NET_SECOND_SLOT, VIRTIO_NET_SECOND_MAC_ADDRESS)
+        .expect("Failed to setup virtio-net");
+    mmio::virtio_rng::setup_virtio_rng(VIRTIO_RNG_SLOT).expect("Failed to setup virtio-rng");
+    frame_pool::reserve_frames(FRAME_POOL_RESERVED_PAGES).expect("Failed to reserve frames");
+    mmio::virtio_balloon::setup_virtio_balloon(VIRTIO_BALLOON_SLOT)
+        .expect("Failed to setup virtio-balloon");
+    mmio::virtio_vsock::setup_virtio_vsock(VIRTIO_VSOCK_SLOT, VIRTIO_VSOCK_GUEST_CID)
+        .expect("Failed to setup virtio-vsock");
+    mmio::virtio_vsock::setup_virtio_vsock(VIRTIO_VSOCK_SECOND_SLOT, VIRTIO_VSOCK_SECOND_GUEST_CID)
+        .expect("Failed to setup virtio-vsock");
+    vsock::register_vsock_service(VSOCK_ECHO_PORT, vsock::echo_service)
+        .expect("Failed to register the echo service");
+    gdb::setup_gdb_stub().expect("Failed to setup the GDB stub");
 
     /* Stack for BSP */
     let stack_address = allocate_memory(STACK_PAGES, None).expect("Failed to alloc stack")
         + (STACK_PAGES << PAGE_SHIFT);
 
-    println!("Setup EL1");
+    info!("Setup EL1");
     isb();
 
+    console::set_backend(CONSOLE_BACKEND_AFTER_BOOT).expect("Failed to switch the console");
+    console::disable_uefi_console().expect("Failed to disable the UEFI console");
+
     /* Disable IRQ/FIQ */
     /* After disabling IRQ/FIQ, we should avoid calling UEFI functions */
     unsafe { local_irq_fiq_save() };
@@ -193,10 +302,122 @@ pub fn allocate_memory(pages: usize, align: Option<usize>) -> Result<usize, ()>
         if (address & ((1 << align) - 1)) != 0 {
             continue;
         }
+        mark_hypervisor_pages(address, pages);
         return Ok(address);
     }
 }
 
+/// Record the pages used by the hypervisor, the pages out of the guest RAM window are ignored
+fn mark_hypervisor_pages(address: usize, pages: usize) {
+    let bitmap = unsafe { &mut *core::ptr::addr_of_mut!(HYPERVISOR_PAGE_BITMAP) };
+    let first_page = address >> PAGE_SHIFT;
+    for page in first_page..(first_page + pages) {
+        if let Some(index) = page
+            .checked_sub(GUEST_RAM_ADDRESS >> PAGE_SHIFT)
+            .filter(|i| *i < GUEST_RAM_PAGES)
+        {
+            bitmap[index / u64::BITS as usize] |= 1 << (index % u64::BITS as usize);
+        }
+    }
+}
+
+/// Check if the page of the guest RAM window is used by the hypervisor
+///
+/// The guest RAM window is mapped to the same physical address, therefore the memory allocated
+/// for the hypervisor is also reachable from the guest.
+///
+/// # Arguments
+/// * `address` - the intermediate physical address in the page
+pub fn is_hypervisor_page(address: usize) -> bool {
+    let Some(index) = (address >> PAGE_SHIFT)
+        .checked_sub(GUEST_RAM_ADDRESS >> PAGE_SHIFT)
+        .filter(|i| *i < GUEST_RAM_PAGES)
+    else {
+        return false;
+    };
+    let bitmap = unsafe { &*core::ptr::addr_of!(HYPERVISOR_PAGE_BITMAP) };
+    (bitmap[index / u64::BITS as usize] & (1 << (index % u64::BITS as usize))) != 0
+}
+
+/// Get the load options of this image in UTF-8
+///
+/// The load options are given by the shell, like `hypervisor.efi log=info,paging:trace`.
+///
+/// # Arguments
+/// * `buffer` - the buffer to store the options, the rest of them are truncated
+///
+/// # Result
+/// The options, or the empty string if this image has no load options
+fn get_load_options(buffer: &mut [u8; MAX_LOAD_OPTIONS_LENGTH]) -> &str {
+    let Ok(load_options) = uefi::file::get_load_options(unsafe { IMAGE_HANDLE }, unsafe {
+        &*((*SYSTEM_TABLE).efi_boot_services)
+    }) else {
+        return "";
+    };
+    let mut length = 0;
+    for c in char::decode_utf16(load_options.iter().copied()) {
+        let c = c.unwrap_or(char::REPLACEMENT_CHARACTER);
+        if length + c.len_utf8() > buffer.len() {
+            break;
+        }
+        length += c.encode_utf8(&mut buffer[length..]).len();
+    }
+    core::str::from_utf8(&buffer[..length]).unwrap_or("")
+}
+
+/// Set up the log levels from the constants and the load options of this image
+fn setup_log() {
+    log::init(DEFAULT_LOG_LEVEL, CONSOLE_LOG_LEVEL, &LOG_FILTERS).expect("Invalid log filters");
+    let mut buffer = [0u8; MAX_LOAD_OPTIONS_LENGTH];
+    let options = get_load_options(&mut buffer);
+    if log::parse_options(options).is_err() {
+        warn!("Invalid log options: {}", options);
+    }
+}
+
+/// Set up [`vm::UnmappedAccessPolicy`] from the constant and the load options of this image
+fn setup_unmapped_access_policy() {
+    let mut policy = UNMAPPED_ACCESS_POLICY;
+    let mut buffer = [0u8; MAX_LOAD_OPTIONS_LENGTH];
+    for option in get_load_options(&mut buffer).split_ascii_whitespace() {
+        if let Some(name) = option.strip_prefix("unmapped=") {
+            match vm::UnmappedAccessPolicy::from_name(name) {
+                Some(p) => policy = p,
+                None => warn!("Unknown unmapped access policy: {}", name),
+            }
+        }
+    }
+    info!("Unmapped access policy: {}", policy.name());
+    vm::current().unmapped_access_policy = policy;
+}
+
+/// Load the disk image for virtio-blk, this must be called before disabling UEFI
+fn setup_virtio_blk() {
+    let ram_disk = match uefi::file::open_root_dir(unsafe { IMAGE_HANDLE }, unsafe {
+        &*((*SYSTEM_TABLE).efi_boot_services)
+    })
+    .and_then(|root| {
+        let file = root.open_file(VIRTIO_BLK_IMAGE_FILE);
+        let _ = root.close();
+        file
+    }) {
+        Ok(file) => {
+            info!("Load {} as virtio-blk", VIRTIO_BLK_IMAGE_FILE);
+            let disk = mmio::virtio_blk::RamDisk::load_file(file, false)
+                .expect("Failed to load the disk image");
+            let _ = file.close();
+            disk
+        }
+        Err(_) => {
+            info!("Use RAM disk as virtio-blk");
+            mmio::virtio_blk::RamDisk::new(VIRTIO_BLK_RAM_DISK_SIZE)
+                .expect("Failed to allocate RAM disk")
+        }
+    };
+    mmio::virtio_blk::setup_virtio_blk(VIRTIO_BLK_SLOT, ram_disk, "hypervisor-virtio-blk")
+        .expect("Failed to setup virtio-blk");
+}
+
 fn set_up_el1() {
     /* CNTHCTL_EL2 & CNTVOFF_EL2 */
     set_cnthctl_el2(CNTHCTL_EL2_EL1PCEN | CNTHCTL_EL2_EL1PCTEN);
@@ -273,7 +494,10 @@ fn set_up_el1() {
     set_vbar_el1(get_vbar_el2());
 
     /* HCR_EL2 */
-    let hcr_el2 = HCR_EL2_FIEN | HCR_EL2_API | HCR_EL2_APK | HCR_EL2_RW | HCR_EL2_TSC | HCR_EL2_VM;
+    let mut hcr_el2 = HCR_EL2_FIEN | HCR_EL2_API | HCR_EL2_APK | HCR_EL2_RW | HCR_EL2_TSC | HCR_EL2_VM;
+    hcr_el2 |= HCR_EL2_TWI | HCR_EL2_TWE;
+    hcr_el2 |= sysreg::setup_system_register_trap(&sysreg::DEFAULT_TRAP_CONFIGURATION);
+    hcr_el2 |= vgic::init();
     set_hcr_el2(hcr_el2);
     isb();
     set_cptr_el2(cptr_el2);
@@ -342,6 +566,16 @@ fn el2_to_el1(el1_entry_point: usize, el1_stack_pointer: usize) {
 
 #[panic_handler]
 pub fn panic(info: &core::panic::PanicInfo) -> ! {
+    if console::get_backend() == console::ConsoleBackendType::Memory {
+        let _ = console::set_backend(console::ConsoleBackendType::Pl011);
+        let _ = console::dump_memory_console();
+    }
+    println!("\n\nLog Buffer:");
+    log::dump_log_buffer();
     println!("\n\nBoot Loader Panic: {}", info);
+    unwind::print_backtrace();
+    if let Some(registers) = exception::current_registers() {
+        unwind::print_guest_backtrace(registers, get_elr_el2());
+    }
     halt_loop()
 }
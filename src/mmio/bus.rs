@@ -0,0 +1,173 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! MMIO Bus
+//!
+//! The emulated devices are registered with their address range, and the data aborts from EL1
+//! are dispatched to the device which owns the faulting address.
+//!
+//! The bus does not keep the references to the devices. Each region holds the handle of the
+//! device, and the device is looked up by it only while the access is handled.
+//!
+
+pub const MAX_MMIO_REGIONS: usize = 64;
+
+/// The device emulated by trapping the accesses of EL1
+pub trait MmioDevice {
+    /// Handle the read access
+    ///
+    /// # Arguments
+    /// * `offset` - the offset from the base address of the region
+    /// * `access_width` - 8, 16, 32, or 64
+    ///
+    /// # Result
+    /// If succeeded, Ok(value), otherwise Err(())
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, ()>;
+
+    /// Handle the write access
+    ///
+    /// # Arguments
+    /// * `offset` - the offset from the base address of the region
+    /// * `access_width` - 8, 16, 32, or 64
+    /// * `value` - the value truncated to `access_width`
+    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), ()>;
+
+    /// Return to the power-on state
+    fn reset(&mut self) {}
+
+    /// The name shown by the monitor
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Print the internal state for the monitor
+    fn dump_state(&self) {}
+}
+
+/// Look up the device model by the index in its module
+///
+/// The returned reference is used only until the access is completed.
+pub type MmioDeviceResolver = fn(index: usize) -> Option<&'static mut dyn MmioDevice>;
+
+/// The handle of the device registered to the bus
+#[derive(Clone, Copy)]
+pub struct MmioDeviceHandle {
+    pub resolver: MmioDeviceResolver,
+    pub index: usize,
+}
+
+impl MmioDeviceHandle {
+    fn resolve(&self) -> Option<&'static mut dyn MmioDevice> {
+        (self.resolver)(self.index)
+    }
+}
+
+struct MmioRegion {
+    base_address: usize,
+    size: usize,
+    device: MmioDeviceHandle,
+}
+
+/// The address map of the emulated devices of a VM
+pub struct MmioBus {
+    regions: [Option<MmioRegion>; MAX_MMIO_REGIONS],
+}
+
+impl MmioBus {
+    pub const fn new() -> Self {
+        Self {
+            regions: [const { None }; MAX_MMIO_REGIONS],
+        }
+    }
+
+    /// Map the device to the address range
+    ///
+    /// # Arguments
+    /// * `base_address` - the intermediate physical address of the region
+    /// * `size` - the size of the region, it must not overlap with the others
+    /// * `device` - the handle to look up the device model
+    ///
+    /// # Result
+    /// If succeeded, Ok(region id), otherwise Err(())
+    pub fn add_device(
+        &mut self,
+        base_address: usize,
+        size: usize,
+        device: MmioDeviceHandle,
+    ) -> Result<usize, ()> {
+        let end_address = base_address.checked_add(size).ok_or(())?;
+        if size == 0
+            || self
+                .regions
+                .iter()
+                .flatten()
+                .any(|r| base_address < r.base_address + r.size && r.base_address < end_address)
+        {
+            return Err(());
+        }
+        let (id, entry) = self
+            .regions
+            .iter_mut()
+            .enumerate()
+            .find(|(_, r)| r.is_none())
+            .ok_or(())?;
+        *entry = Some(MmioRegion {
+            base_address,
+            size,
+            device,
+        });
+        Ok(id)
+    }
+
+    /// Unmap the device
+    ///
+    /// # Result
+    /// The handle of the device which was mapped by the region id
+    pub fn remove_device(&mut self, id: usize) -> Option<MmioDeviceHandle> {
+        self.regions.get_mut(id)?.take().map(|r| r.device)
+    }
+
+    /// Find the device which owns the address
+    ///
+    /// The device must not be used after the access is completed.
+    ///
+    /// # Result
+    /// If found, Some((device, offset from the base address)), otherwise None
+    pub fn find_device(&mut self, address: usize) -> Option<(&mut dyn MmioDevice, usize)> {
+        let region = self
+            .regions
+            .iter()
+            .flatten()
+            .find(|r| (r.base_address..(r.base_address + r.size)).contains(&address))?;
+        Some((region.device.resolve()?, address - region.base_address))
+    }
+
+    /// Call `f` with (region id, base address, size, device) of each region
+    pub fn for_each_device(&self, f: &mut dyn FnMut(usize, usize, usize, &dyn MmioDevice)) {
+        for (id, r) in self.regions.iter().enumerate() {
+            let Some(r) = r else {
+                continue;
+            };
+            if let Some(device) = r.device.resolve() {
+                f(id, r.base_address, r.size, device);
+            }
+        }
+    }
+
+    /// Return all devices to the power-on state
+    pub fn reset_devices(&mut self) {
+        for device in self
+            .regions
+            .iter()
+            .flatten()
+            .filter_map(|r| r.device.resolve())
+        {
+            device.reset();
+        }
+    }
+}
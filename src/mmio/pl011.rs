@@ -17,23 +17,349 @@
 //!
 //! PL011のMMIO Driver
 //!
+//! (PrimeCell UART (PL011) Technical Reference Manual r1p5)
+//!
+//! The transmitted characters are written to the console multiplexer immediately, so the
+//! transmit FIFO is always empty. The receive FIFO is fed from the input of the multiplexer.
+//!
 
+use super::bus::{MmioDevice, MmioDeviceHandle};
+use crate::console::mux;
+use crate::vgic;
+
+/* Registers */
 const UART_DR: usize = 0x000;
+const UART_RSR: usize = 0x004;
 const UART_FR: usize = 0x018;
+const UART_ILPR: usize = 0x020;
+const UART_IBRD: usize = 0x024;
+const UART_FBRD: usize = 0x028;
+const UART_LCR_H: usize = 0x02c;
+const UART_CR: usize = 0x030;
+const UART_IFLS: usize = 0x034;
+const UART_IMSC: usize = 0x038;
+const UART_RIS: usize = 0x03c;
+const UART_MIS: usize = 0x040;
+const UART_ICR: usize = 0x044;
+const UART_DMACR: usize = 0x048;
+const UART_PERIPH_ID0: usize = 0xfe0;
+const UART_PCELL_ID3: usize = 0xffc;
+
+/* UART_FR */
+const UART_FR_RXFE: u32 = 1 << 4;
+const UART_FR_RXFF: u32 = 1 << 6;
+const UART_FR_TXFE: u32 = 1 << 7;
+
+/* UART_LCR_H */
+const UART_LCR_H_FEN: u32 = 1 << 4;
+
+/* UART_CR */
+const UART_CR_TXE: u32 = 1 << 8;
+const UART_CR_RXE: u32 = 1 << 9;
+
+/* UART_IFLS */
+const UART_IFLS_RXIFLSEL_BITS_OFFSET: u32 = 3;
+const UART_IFLS_RXIFLSEL: u32 = 0b111 << UART_IFLS_RXIFLSEL_BITS_OFFSET;
+
+/* Interrupt Bits (UART_IMSC, UART_RIS, UART_MIS and UART_ICR) */
+const UART_INT_RX: u32 = 1 << 4;
+const UART_INT_TX: u32 = 1 << 5;
+const UART_INT_RT: u32 = 1 << 6;
+const UART_INT_ALL: u32 = 0x7ff;
 
-pub fn mmio_read(offset: usize, _access_width: u64) -> Result<u64, ()> {
-    match offset {
-        UART_FR => Ok(0),
-        _ => Err(()),
+/// UARTPeriphID0~3 and UARTPCellID0~3
+const UART_ID: [u8; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+/// The size of the register region
+pub const PL011_MMIO_SIZE: usize = 0x1000;
+/// The INTID of the UART on QEMU virt (SPI 1)
+pub const UART_INTERRUPT_ID: u32 = 33;
+const UART_FIFO_SIZE: usize = 32;
+pub const MAX_PL011_DEVICES: usize = 4;
+
+pub struct Pl011 {
+    interrupt_id: u32,
+    console_id: Option<usize>,
+    rx_fifo: [u8; UART_FIFO_SIZE],
+    rx_fifo_head: usize,
+    rx_fifo_length: usize,
+    ilpr: u32,
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: u32,
+    /// The interrupt bits latched until cleared by UART_ICR
+    latched_interrupts: u32,
+    dmacr: u32,
+    /// The level of the interrupt line seen by the vGIC
+    is_interrupt_asserted: bool,
+}
+
+const PL011_RESET_STATE: Pl011 = Pl011 {
+    interrupt_id: UART_INTERRUPT_ID,
+    console_id: None,
+    rx_fifo: [0; UART_FIFO_SIZE],
+    rx_fifo_head: 0,
+    rx_fifo_length: 0,
+    ilpr: 0,
+    ibrd: 0,
+    fbrd: 0,
+    lcr_h: 0,
+    cr: UART_CR_RXE | UART_CR_TXE,
+    ifls: 0x12,
+    imsc: 0,
+    latched_interrupts: 0,
+    dmacr: 0,
+    is_interrupt_asserted: false,
+};
+
+static mut PL011_DEVICES: [Option<Pl011>; MAX_PL011_DEVICES] = [const { None }; MAX_PL011_DEVICES];
+
+fn pl011(index: usize) -> Option<&'static mut Pl011> {
+    if index >= MAX_PL011_DEVICES {
+        return None;
     }
+    unsafe { &mut *core::ptr::addr_of_mut!(PL011_DEVICES[index]) }.as_mut()
+}
+
+fn resolve_pl011(index: usize) -> Option<&'static mut dyn MmioDevice> {
+    pl011(index).map(|u| u as &mut dyn MmioDevice)
+}
+
+/// Create the UART connected to the console multiplexer and map it to the MMIO bus of the VM
+///
+/// # Arguments
+/// * `base_address` - the intermediate physical address of the registers
+/// * `interrupt_id` - the INTID of the UART, like [`UART_INTERRUPT_ID`]
+/// * `name` - the name of the console shown by the multiplexer
+///
+/// # Result
+/// If succeeded, Ok(index of the UART), otherwise Err(())
+pub fn setup_pl011(
+    base_address: usize,
+    interrupt_id: u32,
+    name: &'static str,
+) -> Result<usize, ()> {
+    let index = (0..MAX_PL011_DEVICES)
+        .find(|i| unsafe { (*core::ptr::addr_of!(PL011_DEVICES[*i])).is_none() })
+        .ok_or(())?;
+    let console_id = mux::register_console(name, Some(input_notifier))?;
+    unsafe {
+        *core::ptr::addr_of_mut!(PL011_DEVICES[index]) = Some(Pl011 {
+            interrupt_id,
+            console_id: Some(console_id),
+            ..PL011_RESET_STATE
+        })
+    };
+    crate::vm::current().mmio_bus.add_device(
+        base_address,
+        PL011_MMIO_SIZE,
+        MmioDeviceHandle {
+            resolver: resolve_pl011,
+            index,
+        },
+    )?;
+    Ok(index)
+}
+
+fn input_notifier(console_id: usize) {
+    let Some(uart) = (0..MAX_PL011_DEVICES)
+        .filter_map(pl011)
+        .find(|u| u.console_id == Some(console_id))
+    else {
+        return;
+    };
+    uart.fill_rx_fifo();
+    uart.update_interrupt();
 }
 
-pub fn mmio_write(offset: usize, _access_width: u64, value: u64) -> Result<(), ()> {
-    match offset {
-        UART_DR => {
-            print!("{}", (value as u8 as char));
-            Ok(())
+impl Pl011 {
+    fn rx_fifo_depth(&self) -> usize {
+        if (self.lcr_h & UART_LCR_H_FEN) != 0 {
+            UART_FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if (self.lcr_h & UART_LCR_H_FEN) == 0 {
+            return 1;
         }
-        _ => Err(()),
+        let eighths = match (self.ifls & UART_IFLS_RXIFLSEL) >> UART_IFLS_RXIFLSEL_BITS_OFFSET {
+            0 => 1,
+            1 => 2,
+            2 => 4,
+            3 => 6,
+            _ => 7,
+        };
+        UART_FIFO_SIZE * eighths / 8
+    }
+
+    fn fill_rx_fifo(&mut self) {
+        let Some(console_id) = self.console_id else {
+            return;
+        };
+        while self.rx_fifo_length < self.rx_fifo_depth() {
+            let mut c = [0u8; 1];
+            if mux::read(console_id, &mut c) == 0 {
+                break;
+            }
+            self.rx_fifo[(self.rx_fifo_head + self.rx_fifo_length) % UART_FIFO_SIZE] = c[0];
+            self.rx_fifo_length += 1;
+        }
+    }
+
+    fn pop_rx_fifo(&mut self) -> u8 {
+        if self.rx_fifo_length == 0 {
+            return 0;
+        }
+        let c = self.rx_fifo[self.rx_fifo_head];
+        self.rx_fifo_head = (self.rx_fifo_head + 1) % UART_FIFO_SIZE;
+        self.rx_fifo_length -= 1;
+        self.fill_rx_fifo();
+        c
+    }
+
+    fn raw_interrupt_status(&self) -> u32 {
+        let mut status = self.latched_interrupts;
+        if self.rx_fifo_length >= self.rx_trigger_level() {
+            status |= UART_INT_RX;
+        }
+        /* The receive timeout occurs immediately because no more characters arrive */
+        if self.rx_fifo_length > 0 {
+            status |= UART_INT_RT;
+        }
+        status
+    }
+
+    fn flags(&self) -> u32 {
+        let mut flags = UART_FR_TXFE;
+        if self.rx_fifo_length == 0 {
+            flags |= UART_FR_RXFE;
+        }
+        if self.rx_fifo_length >= self.rx_fifo_depth() {
+            flags |= UART_FR_RXFF;
+        }
+        flags
+    }
+
+    /// Inject the interrupt when the masked interrupt status becomes non-zero
+    fn update_interrupt(&mut self) {
+        let is_asserted = (self.raw_interrupt_status() & self.imsc) != 0;
+        if is_asserted
+            && !self.is_interrupt_asserted
+            && vgic::inject_interrupt(self.interrupt_id, vgic::DEFAULT_INTERRUPT_PRIORITY).is_err()
+        {
+            return;
+        }
+        self.is_interrupt_asserted = is_asserted;
+    }
+
+    /// The registers are 32-bit wide, the narrower accesses read/write the lower bits
+    ///
+    /// The other accesses and the accesses to the reserved offsets read as zero and the writes
+    /// are ignored, like the unimplemented registers of the real device.
+    fn is_valid_access(offset: usize, access_width: u64) -> bool {
+        (offset & 0b11) == 0 && matches!(access_width, 8 | 16 | 32)
+    }
+
+    fn transmit(&mut self, c: u8) {
+        match self.console_id {
+            Some(id) => mux::write(id, &[c]),
+            None => crate::console::write_bytes(&[c]),
+        }
+        /* The transmit FIFO becomes empty immediately */
+        self.latched_interrupts |= UART_INT_TX;
+    }
+}
+
+impl MmioDevice for Pl011 {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, ()> {
+        if !Self::is_valid_access(offset, access_width) {
+            return Ok(0);
+        }
+        let value = match offset {
+            UART_DR => self.pop_rx_fifo() as u32,
+            UART_RSR => 0,
+            UART_FR => self.flags(),
+            UART_ILPR => self.ilpr,
+            UART_IBRD => self.ibrd,
+            UART_FBRD => self.fbrd,
+            UART_LCR_H => self.lcr_h,
+            UART_CR => self.cr,
+            UART_IFLS => self.ifls,
+            UART_IMSC => self.imsc,
+            UART_RIS => self.raw_interrupt_status(),
+            UART_MIS => self.raw_interrupt_status() & self.imsc,
+            UART_DMACR => self.dmacr,
+            UART_PERIPH_ID0..=UART_PCELL_ID3 if (offset & 0b11) == 0 => {
+                UART_ID[(offset - UART_PERIPH_ID0) >> 2] as u32
+            }
+            _ => 0,
+        };
+        self.update_interrupt();
+        Ok(value as u64)
+    }
+
+    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), ()> {
+        if !Self::is_valid_access(offset, access_width) {
+            return Ok(());
+        }
+        let value = value as u32;
+        match offset {
+            UART_DR => self.transmit(value as u8),
+            /* UART_ECR: clear the errors, no error occurs */
+            UART_RSR => {}
+            UART_ILPR => self.ilpr = value & 0xff,
+            UART_IBRD => self.ibrd = value & 0xffff,
+            UART_FBRD => self.fbrd = value & 0x3f,
+            UART_LCR_H => {
+                if ((self.lcr_h ^ value) & UART_LCR_H_FEN) != 0 {
+                    /* The FIFO is flushed when it is enabled or disabled */
+                    self.rx_fifo_length = 0;
+                }
+                self.lcr_h = value & 0xff;
+                self.fill_rx_fifo();
+            }
+            UART_CR => self.cr = value & 0xffff,
+            UART_IFLS => self.ifls = value & 0x3f,
+            UART_IMSC => self.imsc = value & UART_INT_ALL,
+            UART_ICR => self.latched_interrupts &= !value,
+            UART_DMACR => self.dmacr = value & 0b111,
+            _ => {}
+        }
+        self.update_interrupt();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        *self = Pl011 {
+            interrupt_id: self.interrupt_id,
+            console_id: self.console_id,
+            ..PL011_RESET_STATE
+        };
+    }
+
+    fn name(&self) -> &'static str {
+        "pl011"
+    }
+
+    fn dump_state(&self) {
+        println!(
+            "  CR: {:#X} LCR_H: {:#X} IMSC: {:#X} RIS: {:#X} RX FIFO: {} bytes, IRQ: {}",
+            self.cr,
+            self.lcr_h,
+            self.imsc,
+            self.raw_interrupt_status(),
+            self.rx_fifo_length,
+            if self.is_interrupt_asserted {
+                "asserted"
+            } else {
+                "deasserted"
+            }
+        );
     }
 }
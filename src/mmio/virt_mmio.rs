@@ -2,17 +2,29 @@
 // virt mmio 仮想デバイス
 //
 
-const VIRT_MMIO: usize = 0xa000000;
-const VIRT_MMIO_SIZE: usize = 0x200;
+use super::bus::{MmioBus, MmioDevice, MmioDeviceHandle};
+use crate::vgic;
+
+pub const VIRT_MMIO: usize = 0xa000000;
+pub const VIRT_MMIO_SIZE: usize = 0x200;
+/// The number of virtio-mmio slots, same as QEMU virt machine
+pub const VIRT_MMIO_NUMBER_OF_SLOTS: usize = 32;
+/// INTID of the interrupt of the first slot (SPI 16)
+pub const VIRT_MMIO_INTERRUPT_ID_BASE: u32 = 32 + 16;
 
 const VIRT_MMIO_MAGIC_VALUE: u32 = 0x74726976;
 const VIRT_MMIO_VERSION: u32 = 0x2;
+const VIRT_MMIO_VENDOR_ID: u32 = 0x0;
 
-const VIRT_MMIO_QUEUE_NUM_MAX: u32 = 1024;
+pub const VIRT_MMIO_QUEUE_NUM_MAX: u32 = 1024;
+pub const VIRT_MMIO_MAX_QUEUES: usize = 8;
 
-const VIRTIO_RESERVED: u32 = 0x00;
-const VIRTIO_NETWORK_CARD: u32 = 0x01;
-const VIRTIO_BLOCK_DEVICE: u32 = 0x02;
+pub const VIRTIO_NETWORK_CARD: u32 = 0x01;
+pub const VIRTIO_BLOCK_DEVICE: u32 = 0x02;
+pub const VIRTIO_CONSOLE_DEVICE: u32 = 0x03;
+pub const VIRTIO_ENTROPY_SOURCE: u32 = 0x04;
+pub const VIRTIO_MEMORY_BALLOON: u32 = 0x05;
+pub const VIRTIO_SOCKET_DEVICE: u32 = 0x13;
 
 const VIRT_MMIO_MAGIC_OFFSET: usize = 0x00;
 const VIRT_MMIO_VERSION_OFFSET: usize = 0x04;
@@ -26,6 +38,10 @@ const VIRT_MMIO_QUEUE_SEL_OFFSET: usize = 0x30;
 const VIRT_MMIO_QUEUE_NUM_MAX_OFFSET: usize = 0x34;
 const VIRT_MMIO_QUEUE_NUM_OFFSET: usize = 0x38;
 const VIRT_MMIO_QUEUE_READY_OFFSET: usize = 0x44;
+const VIRT_MMIO_QUEUE_NOTIFY_OFFSET: usize = 0x50;
+const VIRT_MMIO_INTERRUPT_STATUS_OFFSET: usize = 0x60;
+const VIRT_MMIO_INTERRUPT_ACK_OFFSET: usize = 0x64;
+const VIRT_MMIO_STATUS_OFFSET: usize = 0x70;
 
 const VIRT_MMIO_QUEUE_DESC_LOW_OFFSET: usize = 0x80;
 const VIRT_MMIO_QUEUE_DESC_HIGH_OFFSET: usize = 0x84;
@@ -33,28 +49,474 @@ const VIRT_MMIO_QUEUE_DRIVER_LOW_OFFSET: usize = 0x90;
 const VIRT_MMIO_QUEUE_DRIVER_HIGH_OFFSET: usize = 0x94;
 const VIRT_MMIO_QUEUE_DEVICE_LOW_OFFSET: usize = 0xa0;
 const VIRT_MMIO_QUEUE_DEVICE_HIGH_OFFSET: usize = 0xa4;
+const VIRT_MMIO_CONFIG_GENERATION_OFFSET: usize = 0xfc;
+const VIRT_MMIO_CONFIG_OFFSET: usize = 0x100;
+
+/* InterruptStatus */
+pub const VIRT_MMIO_INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+pub const VIRT_MMIO_INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+/* Device Status */
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+pub const VIRTIO_STATUS_FAILED: u32 = 128;
+
+/* Reserved Feature Bits */
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The device model behind the virtio-mmio transport
+pub trait VirtioDevice {
+    /// Virtio Device ID
+    fn device_id(&self) -> u32;
+
+    /// The features offered by the device, VIRTIO_F_VERSION_1 is added by the transport
+    fn device_features(&self) -> u64;
+
+    /// The number of the virtqueues
+    fn number_of_queues(&self) -> usize;
+
+    /// Read the device specific configuration space
+    ///
+    /// # Arguments
+    /// * `offset` - the offset from the start of the configuration space
+    /// * `access_width` - 8, 16, or 32
+    fn read_config(&mut self, offset: usize, access_width: u64) -> Result<u32, ()>;
+
+    /// Write the device specific configuration space
+    fn write_config(&mut self, _offset: usize, _access_width: u64, _value: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    /// The driver notified that there are new buffers in the virtqueue
+    fn queue_notify(&mut self, transport: &mut VirtioMmioTransport, queue_index: usize);
+
+    /// The driver set DRIVER_OK, the negotiated features are in `transport.driver_features`
+    fn driver_ok(&mut self, _transport: &mut VirtioMmioTransport) {}
+
+    /// Reset the device state, called when the driver writes 0 to Status
+    fn reset(&mut self) {}
+
+    /// Print the state of the device model for the monitor, after the state of the transport
+    fn dump_state(&self) {}
+}
+
+/// The configuration of a virtqueue written by the driver
+#[derive(Clone, Copy, Default)]
+pub struct VirtQueueConfig {
+    pub num: u32,
+    pub ready: bool,
+    pub descriptor_area: u64,
+    pub driver_area: u64,
+    pub device_area: u64,
+    /// The index of the next available ring entry to be processed by the device
+    pub last_available_index: u16,
+    /// The index of the next used ring entry to be written by the device
+    pub used_index: u16,
+}
+
+/// The state of the virtio-mmio transport
+pub struct VirtioMmioTransport {
+    pub slot: usize,
+    pub device_features_sel: u32,
+    pub driver_features_sel: u32,
+    pub driver_features: u64,
+    pub queue_sel: u32,
+    pub queues: [VirtQueueConfig; VIRT_MMIO_MAX_QUEUES],
+    pub interrupt_status: u32,
+    pub status: u32,
+    pub config_generation: u32,
+}
+
+pub struct VirtioMmio {
+    pub transport: VirtioMmioTransport,
+    pub device: Option<&'static mut dyn VirtioDevice>,
+}
 
-pub fn virt_mmio_read(device_type: u32, offset: usize, _access_width: u64) -> Result<u32, ()> {
-    match offset {
-        VIRT_MMIO_MAGIC_OFFSET => Ok(VIRT_MMIO_MAGIC_VALUE),
-        VIRT_MMIO_VERSION_OFFSET => Ok(0x2),
-        VIRT_MMIO_DEVICE_ID_OFFSET => Ok(device_type),
-        VIRT_MMIO_QUEUE_NUM_MAX_OFFSET => Ok(VIRT_MMIO_QUEUE_NUM_MAX),
-        _ => Err(()),
+static mut VIRT_MMIO_DEVICES: [VirtioMmio; VIRT_MMIO_NUMBER_OF_SLOTS] = {
+    let mut devices = [const {
+        VirtioMmio {
+            transport: VirtioMmioTransport::new(0),
+            device: None,
+        }
+    }; VIRT_MMIO_NUMBER_OF_SLOTS];
+    let mut i = 0;
+    while i < VIRT_MMIO_NUMBER_OF_SLOTS {
+        devices[i].transport.slot = i;
+        i += 1;
+    }
+    devices
+};
+
+/// The queues to be notified after the current MMIO access, the bitmap of queues per slot
+static mut DEFERRED_QUEUE_NOTIFICATIONS: [u8; VIRT_MMIO_NUMBER_OF_SLOTS] =
+    [0; VIRT_MMIO_NUMBER_OF_SLOTS];
+
+impl VirtioMmioTransport {
+    pub const fn new(slot: usize) -> Self {
+        Self {
+            slot,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues: [VirtQueueConfig {
+                num: 0,
+                ready: false,
+                descriptor_area: 0,
+                driver_area: 0,
+                device_area: 0,
+                last_available_index: 0,
+                used_index: 0,
+            }; VIRT_MMIO_MAX_QUEUES],
+            interrupt_status: 0,
+            status: 0,
+            config_generation: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.slot);
+    }
+
+    pub fn interrupt_id(&self) -> u32 {
+        VIRT_MMIO_INTERRUPT_ID_BASE + self.slot as u32
+    }
+
+    pub fn is_feature_negotiated(&self, feature: u64) -> bool {
+        (self.driver_features & feature) != 0
+    }
+
+    /// Notify the driver that the used ring was updated
+    pub fn raise_used_buffer_interrupt(&mut self) {
+        self.interrupt_status |= VIRT_MMIO_INTERRUPT_USED_BUFFER;
+        let _ = vgic::inject_interrupt(self.interrupt_id(), vgic::DEFAULT_INTERRUPT_PRIORITY);
+    }
+
+    /// Notify the driver that the configuration space was changed
+    pub fn raise_config_change_interrupt(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt_status |= VIRT_MMIO_INTERRUPT_CONFIG_CHANGE;
+        let _ = vgic::inject_interrupt(self.interrupt_id(), vgic::DEFAULT_INTERRUPT_PRIORITY);
+    }
+
+    fn selected_queue(&mut self, number_of_queues: usize) -> Option<&mut VirtQueueConfig> {
+        let index = self.queue_sel as usize;
+        if index < number_of_queues.min(VIRT_MMIO_MAX_QUEUES) {
+            Some(&mut self.queues[index])
+        } else {
+            None
+        }
     }
 }
 
-pub fn virt_mmio_write(
-    device_type: u32,
-    offset: usize,
-    _access_width: u64,
-    value: u32,
-) -> Result<(), ()> {
-    match offset {
-        VIRT_MMIO_QUEUE_READY => {
-            // TODO: 対象のvirt queueを有効にする
-            Ok(())
+/// Read the device specific configuration in little endian
+///
+/// # Arguments
+/// * `config` - the whole configuration space of the device
+/// * `offset` - the offset from the start of the configuration space
+/// * `access_width` - 8, 16, or 32
+pub fn read_config_bytes(config: &[u8], offset: usize, access_width: u64) -> Result<u32, ()> {
+    let size = match access_width {
+        8 | 16 | 32 => (access_width / 8) as usize,
+        _ => return Err(()),
+    };
+    let bytes = config.get(offset..offset + size).ok_or(())?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0u32, |value, b| (value << 8) | *b as u32))
+}
+
+/// Call [`VirtioDevice::queue_notify`] if the queue is ready
+fn notify_queue(
+    transport: &mut VirtioMmioTransport,
+    device: &mut dyn VirtioDevice,
+    queue_index: usize,
+) {
+    if (transport.status & VIRTIO_STATUS_DRIVER_OK) != 0
+        && queue_index < device.number_of_queues().min(VIRT_MMIO_MAX_QUEUES)
+        && transport.queues[queue_index].ready
+    {
+        device.queue_notify(transport, queue_index);
+    }
+}
+
+/// Request [`VirtioDevice::queue_notify`] of the slot after the current MMIO access
+///
+/// The callbacks of [`VirtioDevice`] are called with the transport of their slot borrowed,
+/// so they must not call the callbacks of another slot, which may be on the call stack.
+/// Use this function instead to process the queue of another slot.
+///
+/// # Arguments
+/// * `slot` - the virtio-mmio slot
+/// * `queue_index` - the index of the queue, less than [`VIRT_MMIO_MAX_QUEUES`]
+pub fn defer_queue_notify(slot: usize, queue_index: usize) {
+    let notifications = unsafe { &mut *core::ptr::addr_of_mut!(DEFERRED_QUEUE_NOTIFICATIONS) };
+    if let Some(n) = notifications.get_mut(slot) {
+        if queue_index < VIRT_MMIO_MAX_QUEUES {
+            *n |= 1 << queue_index;
+        }
+    }
+}
+
+/// Call the notifications requested by [`defer_queue_notify`]
+///
+/// This must be called when no virtio-mmio slot is borrowed, after the MMIO access returned.
+/// The notifications requested while processing are also called.
+pub fn process_deferred_queue_notifications() {
+    let notifications = core::ptr::addr_of_mut!(DEFERRED_QUEUE_NOTIFICATIONS);
+    while let Some(slot) =
+        (0..VIRT_MMIO_NUMBER_OF_SLOTS).find(|s| unsafe { (*notifications)[*s] } != 0)
+    {
+        let queues = unsafe { core::mem::take(&mut (*notifications)[slot]) };
+        let virt_mmio = get_virtio_mmio(slot).unwrap();
+        let Some(device) = virt_mmio.device.as_mut() else {
+            continue;
+        };
+        for queue_index in (0..VIRT_MMIO_MAX_QUEUES).filter(|q| (queues & (1 << q)) != 0) {
+            notify_queue(&mut virt_mmio.transport, &mut **device, queue_index);
+        }
+    }
+}
+
+/// Attach the device model to the virtio-mmio slot
+pub fn attach_virtio_device(slot: usize, device: &'static mut dyn VirtioDevice) -> Result<(), ()> {
+    let virt_mmio = get_virtio_mmio(slot).ok_or(())?;
+    if virt_mmio.device.is_some() {
+        return Err(());
+    }
+    virt_mmio.transport.reset();
+    virt_mmio.device = Some(device);
+    Ok(())
+}
+
+/// Get the virtio-mmio slot
+///
+/// The reference must not be kept after returning to the guest, the MMIO bus looks up the slot
+/// on each access instead of holding it.
+pub fn get_virtio_mmio(slot: usize) -> Option<&'static mut VirtioMmio> {
+    if slot >= VIRT_MMIO_NUMBER_OF_SLOTS {
+        return None;
+    }
+    Some(unsafe { &mut *core::ptr::addr_of_mut!(VIRT_MMIO_DEVICES[slot]) })
+}
+
+fn resolve_virtio_mmio(slot: usize) -> Option<&'static mut dyn MmioDevice> {
+    get_virtio_mmio(slot).map(|v| v as &mut dyn MmioDevice)
+}
+
+/// Register all virtio-mmio slots to the MMIO bus
+pub fn register_virt_mmio_slots(bus: &mut MmioBus) -> Result<(), ()> {
+    for slot in 0..VIRT_MMIO_NUMBER_OF_SLOTS {
+        bus.add_device(
+            VIRT_MMIO + slot * VIRT_MMIO_SIZE,
+            VIRT_MMIO_SIZE,
+            MmioDeviceHandle {
+                resolver: resolve_virtio_mmio,
+                index: slot,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+impl MmioDevice for VirtioMmio {
+    fn read(&mut self, offset: usize, access_width: u64) -> Result<u64, ()> {
+        self.read_register(offset, access_width).map(|v| v as u64)
+    }
+
+    fn write(&mut self, offset: usize, access_width: u64, value: u64) -> Result<(), ()> {
+        self.write_register(offset, access_width, value as u32)
+    }
+
+    fn reset(&mut self) {
+        self.transport.reset();
+        if let Some(device) = self.device.as_mut() {
+            device.reset();
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "virtio-mmio"
+    }
+
+    fn dump_state(&self) {
+        let transport = &self.transport;
+        let Some(device) = self.device.as_ref() else {
+            println!("  Slot {}: no device", transport.slot);
+            return;
+        };
+        println!(
+            "  Slot {}: Device ID: {} Status: {:#X} Driver Features: {:#X} Interrupt Status: {:#X}",
+            transport.slot,
+            device.device_id(),
+            transport.status,
+            transport.driver_features,
+            transport.interrupt_status
+        );
+        for (i, q) in transport
+            .queues
+            .iter()
+            .enumerate()
+            .take(device.number_of_queues().min(VIRT_MMIO_MAX_QUEUES))
+            .filter(|(_, q)| q.ready)
+        {
+            println!(
+                "    Queue {}: Size: {} Available: {} Used: {}",
+                i, q.num, q.last_available_index, q.used_index
+            );
+        }
+        device.dump_state();
+    }
+}
+
+impl VirtioMmio {
+    fn read_register(&mut self, offset: usize, access_width: u64) -> Result<u32, ()> {
+        let transport = &mut self.transport;
+        let Some(device) = self.device.as_mut() else {
+            /* No device is attached: DeviceID 0 */
+            return match offset {
+                VIRT_MMIO_MAGIC_OFFSET => Ok(VIRT_MMIO_MAGIC_VALUE),
+                VIRT_MMIO_VERSION_OFFSET => Ok(VIRT_MMIO_VERSION),
+                _ => Ok(0),
+            };
+        };
+        /* The configuration outside of the device and the reserved registers read as zero */
+        if offset >= VIRT_MMIO_CONFIG_OFFSET {
+            return Ok(device
+                .read_config(offset - VIRT_MMIO_CONFIG_OFFSET, access_width)
+                .unwrap_or(0));
+        }
+        if access_width != 32 {
+            return Ok(0);
+        }
+        let number_of_queues = device.number_of_queues();
+        match offset {
+            VIRT_MMIO_MAGIC_OFFSET => Ok(VIRT_MMIO_MAGIC_VALUE),
+            VIRT_MMIO_VERSION_OFFSET => Ok(VIRT_MMIO_VERSION),
+            VIRT_MMIO_DEVICE_ID_OFFSET => Ok(device.device_id()),
+            VIRT_MMIO_VENDOR_ID_OFFSET => Ok(VIRT_MMIO_VENDOR_ID),
+            VIRT_MMIO_DEVICE_FEATURES_OFFSET => {
+                let features = device.device_features() | VIRTIO_F_VERSION_1;
+                Ok(match transport.device_features_sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                })
+            }
+            VIRT_MMIO_QUEUE_NUM_MAX_OFFSET => Ok(transport
+                .selected_queue(number_of_queues)
+                .map_or(0, |_| VIRT_MMIO_QUEUE_NUM_MAX)),
+            VIRT_MMIO_QUEUE_READY_OFFSET => Ok(transport
+                .selected_queue(number_of_queues)
+                .map_or(0, |q| q.ready as u32)),
+            VIRT_MMIO_INTERRUPT_STATUS_OFFSET => Ok(transport.interrupt_status),
+            VIRT_MMIO_STATUS_OFFSET => Ok(transport.status),
+            VIRT_MMIO_CONFIG_GENERATION_OFFSET => Ok(transport.config_generation),
+            _ => Ok(0),
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, access_width: u64, value: u32) -> Result<(), ()> {
+        let transport = &mut self.transport;
+        let Some(device) = self.device.as_mut() else {
+            /* No device is attached: ignore */
+            return Ok(());
+        };
+        /* The writes to the read-only or reserved registers are ignored */
+        if offset >= VIRT_MMIO_CONFIG_OFFSET {
+            let _ = device.write_config(offset - VIRT_MMIO_CONFIG_OFFSET, access_width, value);
+            return Ok(());
+        }
+        if access_width != 32 {
+            return Ok(());
+        }
+        let number_of_queues = device.number_of_queues();
+        let set_low = |target: &mut u64| *target = (*target & !(u32::MAX as u64)) | value as u64;
+        let set_high =
+            |target: &mut u64| *target = (*target & u32::MAX as u64) | ((value as u64) << 32);
+        match offset {
+            VIRT_MMIO_DEVICE_FEATURE_SEL_OFFSET => transport.device_features_sel = value,
+            VIRT_MMIO_DRIVER_FEATURES_SEL_OFFSET => transport.driver_features_sel = value,
+            VIRT_MMIO_DRIVER_FEATURES_OFFSET => {
+                if (transport.status & VIRTIO_STATUS_FEATURES_OK) == 0 {
+                    match transport.driver_features_sel {
+                        0 => set_low(&mut transport.driver_features),
+                        1 => set_high(&mut transport.driver_features),
+                        _ => {}
+                    }
+                }
+            }
+            VIRT_MMIO_QUEUE_SEL_OFFSET => transport.queue_sel = value,
+            VIRT_MMIO_QUEUE_NUM_OFFSET => {
+                if let Some(q) = transport.selected_queue(number_of_queues) {
+                    if value <= VIRT_MMIO_QUEUE_NUM_MAX && value.is_power_of_two() {
+                        q.num = value;
+                    }
+                }
+            }
+            VIRT_MMIO_QUEUE_READY_OFFSET => {
+                if let Some(q) = transport.selected_queue(number_of_queues) {
+                    q.ready = (value & 1) != 0;
+                }
+            }
+            VIRT_MMIO_QUEUE_DESC_LOW_OFFSET
+            | VIRT_MMIO_QUEUE_DESC_HIGH_OFFSET
+            | VIRT_MMIO_QUEUE_DRIVER_LOW_OFFSET
+            | VIRT_MMIO_QUEUE_DRIVER_HIGH_OFFSET
+            | VIRT_MMIO_QUEUE_DEVICE_LOW_OFFSET
+            | VIRT_MMIO_QUEUE_DEVICE_HIGH_OFFSET => {
+                if let Some(q) = transport.selected_queue(number_of_queues) {
+                    /* The addresses must not be changed while the queue is ready */
+                    if !q.ready {
+                        match offset {
+                            VIRT_MMIO_QUEUE_DESC_LOW_OFFSET => set_low(&mut q.descriptor_area),
+                            VIRT_MMIO_QUEUE_DESC_HIGH_OFFSET => set_high(&mut q.descriptor_area),
+                            VIRT_MMIO_QUEUE_DRIVER_LOW_OFFSET => set_low(&mut q.driver_area),
+                            VIRT_MMIO_QUEUE_DRIVER_HIGH_OFFSET => set_high(&mut q.driver_area),
+                            VIRT_MMIO_QUEUE_DEVICE_LOW_OFFSET => set_low(&mut q.device_area),
+                            _ => set_high(&mut q.device_area),
+                        }
+                    }
+                }
+            }
+            VIRT_MMIO_QUEUE_NOTIFY_OFFSET => notify_queue(transport, &mut **device, value as usize),
+            VIRT_MMIO_INTERRUPT_ACK_OFFSET => transport.interrupt_status &= !value,
+            VIRT_MMIO_STATUS_OFFSET => {
+                if value == 0 {
+                    transport.reset();
+                    device.reset();
+                    return Ok(());
+                }
+                let mut status = value;
+                if (status & VIRTIO_STATUS_FEATURES_OK) != 0
+                    && (transport.status & VIRTIO_STATUS_FEATURES_OK) == 0
+                {
+                    let offered = device.device_features() | VIRTIO_F_VERSION_1;
+                    if (transport.driver_features & !offered) != 0
+                        || (transport.driver_features & VIRTIO_F_VERSION_1) == 0
+                    {
+                        /* Reject the features, the driver will read back Status */
+                        status &= !VIRTIO_STATUS_FEATURES_OK;
+                    }
+                }
+                let is_driver_ok = (status & VIRTIO_STATUS_DRIVER_OK) != 0
+                    && (transport.status & VIRTIO_STATUS_DRIVER_OK) == 0;
+                if (status & VIRTIO_STATUS_FAILED) != 0
+                    && (transport.status & VIRTIO_STATUS_FAILED) == 0
+                {
+                    warn!("Slot {}: the driver gave up the device", transport.slot);
+                }
+                transport.status = status;
+                if is_driver_ok {
+                    device.driver_ok(transport);
+                }
+            }
+            _ => {}
         }
-        _ => Err(()),
+        Ok(())
     }
 }
@@ -0,0 +1,393 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtio Traditional Memory Balloon Device
+//!
+//! (Virtual I/O Device Version 1.2, 5.5 Traditional Memory Balloon Device)
+//!
+//! The pages inflated by the guest are removed from the stage 2 translation and given to
+//! [`crate::frame_pool`]. The deflated pages are mapped again with the frames from the pool,
+//! so their contents are lost as the specification allows.
+//! Only the guest RAM window can be inflated, except the pages used by the hypervisor,
+//! the pages shared by the hypercall and the pages inflated already.
+//!
+
+use super::virt_mmio::{
+    attach_virtio_device, get_virtio_mmio, read_config_bytes, VirtioDevice, VirtioMmioTransport,
+    VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_MEMORY_BALLOON,
+};
+use super::virtqueue::{DescriptorChain, VirtQueue};
+use crate::frame_pool;
+use crate::paging::{map_page_stage2, unmap_page_stage2, PAGE_SHIFT};
+
+/* Feature Bits */
+pub const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 1 << 0;
+pub const VIRTIO_BALLOON_F_STATS_VQ: u64 = 1 << 1;
+pub const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u64 = 1 << 3;
+
+/* Queues */
+const VIRTIO_BALLOON_INFLATE_QUEUE: usize = 0;
+const VIRTIO_BALLOON_DEFLATE_QUEUE: usize = 1;
+const VIRTIO_BALLOON_STATISTICS_QUEUE: usize = 2;
+const VIRTIO_BALLOON_FREE_PAGE_QUEUE: usize = 3;
+
+/* Memory Statistics Tags */
+pub const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+pub const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+pub const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+pub const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+pub const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+pub const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+pub const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+pub const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+pub const VIRTIO_BALLOON_S_HTLB_PGALLOC: u16 = 8;
+pub const VIRTIO_BALLOON_S_HTLB_PGFAIL: u16 = 9;
+pub const VIRTIO_BALLOON_S_NR: usize = 10;
+
+/* Free Page Hint Command IDs */
+pub const VIRTIO_BALLOON_CMD_ID_STOP: u32 = 0;
+pub const VIRTIO_BALLOON_CMD_ID_DONE: u32 = 1;
+
+/// The page size of PFNs in the inflate/deflate queue, independent from the guest page size
+const VIRTIO_BALLOON_PFN_SHIFT: usize = 12;
+/// struct virtio_balloon_stat (le16 tag, le64 val)
+const VIRTIO_BALLOON_STAT_SIZE: usize = 10;
+const VIRTIO_BALLOON_CONFIG_NUM_PAGES: usize = 0x00;
+const VIRTIO_BALLOON_CONFIG_ACTUAL: usize = 0x04;
+const VIRTIO_BALLOON_CONFIG_FREE_PAGE_HINT_CMD_ID: usize = 0x08;
+const VIRTIO_BALLOON_CONFIG_POISON_VAL: usize = 0x0c;
+const VIRTIO_BALLOON_CONFIG_SIZE: usize = 0x10;
+/// The PFNs read from the descriptor chain at once
+const PFN_BUFFER_ENTRIES: usize = 64;
+
+/// The state of the balloon visible from the hypervisor
+#[derive(Clone, Copy, Default)]
+pub struct BalloonStatistics {
+    /// The number of pages requested by [`set_balloon_target`]
+    pub target_pages: u32,
+    /// The number of pages which the driver reported in `actual`
+    pub actual_pages: u32,
+    /// The number of pages currently removed from the guest
+    pub inflated_pages: u64,
+    /// The pages which could not be inflated or deflated
+    pub failed_pages: u64,
+    /// The pages hinted as free during the last free page reporting
+    pub hinted_pages: u64,
+    /// The command id of the last completed free page reporting
+    pub completed_hint_command_id: u32,
+    /// The latest memory statistics indexed by VIRTIO_BALLOON_S_*
+    pub memory_statistics: [Option<u64>; VIRTIO_BALLOON_S_NR],
+}
+
+pub struct VirtioBalloon {
+    slot: usize,
+    statistics: BalloonStatistics,
+    /// The statistics buffer held until the next request
+    statistics_head: Option<u16>,
+    free_page_hint_command_id: u32,
+    next_free_page_hint_command_id: u32,
+    /// The command id which the driver is reporting with
+    reporting_command_id: Option<u32>,
+}
+
+static mut VIRTIO_BALLOON: Option<VirtioBalloon> = None;
+
+/// The PFNs which the balloon can hold, only the guest RAM window can be inflated
+const INFLATABLE_PAGES: usize = crate::GUEST_RAM_SIZE >> VIRTIO_BALLOON_PFN_SHIFT;
+const INFLATABLE_FIRST_PFN: usize = crate::GUEST_RAM_ADDRESS >> VIRTIO_BALLOON_PFN_SHIFT;
+/// The bitmap of the inflated PFNs, bit n is PFN (INFLATABLE_FIRST_PFN + n)
+static mut INFLATED_PAGE_BITMAP: [u64; INFLATABLE_PAGES / u64::BITS as usize] =
+    [0; INFLATABLE_PAGES / u64::BITS as usize];
+
+/// Get the word and the mask of the PFN in [`INFLATED_PAGE_BITMAP`]
+///
+/// # Result
+/// If the PFN is in the guest RAM window, Some((word, mask)), otherwise None
+fn inflated_page_bit(pfn: usize) -> Option<(&'static mut u64, u64)> {
+    let index = pfn.checked_sub(INFLATABLE_FIRST_PFN)?;
+    if index >= INFLATABLE_PAGES {
+        return None;
+    }
+    let bitmap = unsafe { &mut *core::ptr::addr_of_mut!(INFLATED_PAGE_BITMAP) };
+    Some((
+        &mut bitmap[index / u64::BITS as usize],
+        1 << (index % u64::BITS as usize),
+    ))
+}
+
+fn virtio_balloon() -> Option<&'static mut VirtioBalloon> {
+    unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_BALLOON) }.as_mut()
+}
+
+/// Create virtio-balloon and attach it to the virtio-mmio slot
+pub fn setup_virtio_balloon(slot: usize) -> Result<(), ()> {
+    let device = unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_BALLOON) };
+    if device.is_some() {
+        return Err(());
+    }
+    *device = Some(VirtioBalloon {
+        slot,
+        statistics: BalloonStatistics::default(),
+        statistics_head: None,
+        free_page_hint_command_id: VIRTIO_BALLOON_CMD_ID_STOP,
+        next_free_page_hint_command_id: VIRTIO_BALLOON_CMD_ID_DONE + 1,
+        reporting_command_id: None,
+    });
+    attach_virtio_device(slot, device.as_mut().unwrap())
+}
+
+/// Get the name of the memory statistics tag
+pub const fn get_memory_statistics_name(tag: u16) -> &'static str {
+    match tag {
+        VIRTIO_BALLOON_S_SWAP_IN => "Swap In",
+        VIRTIO_BALLOON_S_SWAP_OUT => "Swap Out",
+        VIRTIO_BALLOON_S_MAJFLT => "Major Faults",
+        VIRTIO_BALLOON_S_MINFLT => "Minor Faults",
+        VIRTIO_BALLOON_S_MEMFREE => "Free Memory",
+        VIRTIO_BALLOON_S_MEMTOT => "Total Memory",
+        VIRTIO_BALLOON_S_AVAIL => "Available Memory",
+        VIRTIO_BALLOON_S_CACHES => "Disk Caches",
+        VIRTIO_BALLOON_S_HTLB_PGALLOC => "Hugetlb Allocations",
+        VIRTIO_BALLOON_S_HTLB_PGFAIL => "Hugetlb Failures",
+        _ => "Unknown",
+    }
+}
+
+/// Request the guest to change the size of the balloon
+///
+/// # Arguments
+/// * `pages` - the number of 4KiB pages which the guest should give to the hypervisor
+pub fn set_balloon_target(pages: u32) -> Result<(), ()> {
+    let device = virtio_balloon().ok_or(())?;
+    let virt_mmio = get_virtio_mmio(device.slot).ok_or(())?;
+    device.statistics.target_pages = pages;
+    virt_mmio.transport.raise_config_change_interrupt();
+    Ok(())
+}
+
+pub fn get_balloon_statistics() -> Option<BalloonStatistics> {
+    virtio_balloon().map(|d| d.statistics)
+}
+
+/// Return the statistics buffer to the guest so that it sends the latest values
+///
+/// The result will be available from [`get_balloon_statistics`] after the guest responds.
+pub fn request_memory_statistics() -> Result<(), ()> {
+    let device = virtio_balloon().ok_or(())?;
+    let virt_mmio = get_virtio_mmio(device.slot).ok_or(())?;
+    let head = device.statistics_head.take().ok_or(())?;
+    let mut queue = VirtQueue::new(&mut virt_mmio.transport, VIRTIO_BALLOON_STATISTICS_QUEUE)?;
+    queue.push(head, 0);
+    queue.notify();
+    Ok(())
+}
+
+/// Start the free page reporting with the new command id
+///
+/// # Result
+/// If succeeded, Ok(command id), otherwise Err(())
+pub fn request_free_page_hints() -> Result<u32, ()> {
+    let device = virtio_balloon().ok_or(())?;
+    let virt_mmio = get_virtio_mmio(device.slot).ok_or(())?;
+    if !virt_mmio
+        .transport
+        .is_feature_negotiated(VIRTIO_BALLOON_F_FREE_PAGE_HINT)
+    {
+        return Err(());
+    }
+    let command_id = device.next_free_page_hint_command_id;
+    device.next_free_page_hint_command_id = if command_id == u32::MAX {
+        VIRTIO_BALLOON_CMD_ID_DONE + 1
+    } else {
+        command_id + 1
+    };
+    device.free_page_hint_command_id = command_id;
+    device.statistics.hinted_pages = 0;
+    virt_mmio.transport.raise_config_change_interrupt();
+    Ok(command_id)
+}
+
+impl VirtioBalloon {
+    fn for_each_pfn(chain: &DescriptorChain, mut f: impl FnMut(usize)) {
+        let mut offset = 0;
+        loop {
+            let mut buffer = [0u8; PFN_BUFFER_ENTRIES * core::mem::size_of::<u32>()];
+            let read = chain.read_bytes(offset, &mut buffer);
+            if read < core::mem::size_of::<u32>() {
+                return;
+            }
+            let (pfns, _) = buffer[..read].as_chunks::<{ core::mem::size_of::<u32>() }>();
+            for pfn in pfns {
+                f(u32::from_le_bytes(*pfn) as usize);
+            }
+            offset += read;
+        }
+    }
+
+    fn inflate_page(&mut self, pfn: usize) {
+        let intermediate_physical_address = pfn << VIRTIO_BALLOON_PFN_SHIFT;
+        let Some((word, mask)) = inflated_page_bit(pfn).filter(|(word, mask)| (**word & mask) == 0)
+        else {
+            /* Out of the guest RAM window, or inflated already */
+            self.statistics.failed_pages += 1;
+            return;
+        };
+        if crate::is_hypervisor_page(intermediate_physical_address)
+            || crate::hypercall::is_shared_page(intermediate_physical_address)
+        {
+            /* The frame is used by the hypervisor, or pinned by MEMORY_SHARE */
+            self.statistics.failed_pages += 1;
+            return;
+        }
+        match unmap_page_stage2(intermediate_physical_address) {
+            Ok(physical_address) => {
+                frame_pool::free_frame(physical_address);
+                *word |= mask;
+                self.statistics.inflated_pages += 1;
+            }
+            Err(_) => self.statistics.failed_pages += 1,
+        }
+    }
+
+    fn deflate_page(&mut self, pfn: usize) {
+        let intermediate_physical_address = pfn << VIRTIO_BALLOON_PFN_SHIFT;
+        let Some((word, mask)) = inflated_page_bit(pfn).filter(|(word, mask)| (**word & mask) != 0)
+        else {
+            /* The page was not given to the hypervisor, it may be MMIO or still mapped */
+            self.statistics.failed_pages += 1;
+            return;
+        };
+        let Ok(physical_address) = frame_pool::allocate_frame() else {
+            self.statistics.failed_pages += 1;
+            return;
+        };
+        if map_page_stage2(physical_address, intermediate_physical_address, 0b11).is_err() {
+            frame_pool::free_frame(physical_address);
+            self.statistics.failed_pages += 1;
+            return;
+        }
+        *word &= !mask;
+        self.statistics.inflated_pages -= 1;
+    }
+
+    fn process_statistics(&mut self, chain: &DescriptorChain) {
+        let mut offset = 0;
+        let mut stat = [0u8; VIRTIO_BALLOON_STAT_SIZE];
+        while chain.read_bytes(offset, &mut stat) == VIRTIO_BALLOON_STAT_SIZE {
+            let tag = u16::from_le_bytes(stat[0..2].try_into().unwrap()) as usize;
+            let value = u64::from_le_bytes(stat[2..10].try_into().unwrap());
+            if let Some(s) = self.statistics.memory_statistics.get_mut(tag) {
+                *s = Some(value);
+            }
+            offset += VIRTIO_BALLOON_STAT_SIZE;
+        }
+    }
+
+    fn process_free_page_hint(&mut self, chain: &DescriptorChain) {
+        if chain.readable_length() >= core::mem::size_of::<u32>() {
+            let mut command_id = [0u8; 4];
+            chain.read_bytes(0, &mut command_id);
+            let command_id = u32::from_le_bytes(command_id);
+            if command_id == VIRTIO_BALLOON_CMD_ID_STOP {
+                if let Some(id) = self.reporting_command_id.take() {
+                    self.statistics.completed_hint_command_id = id;
+                }
+                self.free_page_hint_command_id = VIRTIO_BALLOON_CMD_ID_DONE;
+            } else if command_id == self.free_page_hint_command_id {
+                self.reporting_command_id = Some(command_id);
+            }
+        } else if self.reporting_command_id.is_some() {
+            self.statistics.hinted_pages += (chain.writable_length() >> PAGE_SHIFT) as u64;
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_id(&self) -> u32 {
+        VIRTIO_MEMORY_BALLOON
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_BALLOON_F_MUST_TELL_HOST
+            | VIRTIO_BALLOON_F_STATS_VQ
+            | VIRTIO_BALLOON_F_FREE_PAGE_HINT
+            | VIRTIO_F_INDIRECT_DESC
+            | VIRTIO_F_EVENT_IDX
+    }
+
+    fn number_of_queues(&self) -> usize {
+        4
+    }
+
+    fn read_config(&mut self, offset: usize, access_width: u64) -> Result<u32, ()> {
+        let mut config = [0u8; VIRTIO_BALLOON_CONFIG_SIZE];
+        config[VIRTIO_BALLOON_CONFIG_NUM_PAGES..(VIRTIO_BALLOON_CONFIG_NUM_PAGES + 4)]
+            .copy_from_slice(&self.statistics.target_pages.to_le_bytes());
+        config[VIRTIO_BALLOON_CONFIG_ACTUAL..(VIRTIO_BALLOON_CONFIG_ACTUAL + 4)]
+            .copy_from_slice(&self.statistics.actual_pages.to_le_bytes());
+        config[VIRTIO_BALLOON_CONFIG_FREE_PAGE_HINT_CMD_ID
+            ..(VIRTIO_BALLOON_CONFIG_FREE_PAGE_HINT_CMD_ID + 4)]
+            .copy_from_slice(&self.free_page_hint_command_id.to_le_bytes());
+        read_config_bytes(&config, offset, access_width)
+    }
+
+    fn write_config(&mut self, offset: usize, access_width: u64, value: u32) -> Result<(), ()> {
+        match (offset, access_width) {
+            (VIRTIO_BALLOON_CONFIG_ACTUAL, 32) => {
+                self.statistics.actual_pages = value;
+                Ok(())
+            }
+            (VIRTIO_BALLOON_CONFIG_POISON_VAL, 32) => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    fn queue_notify(&mut self, transport: &mut VirtioMmioTransport, queue_index: usize) {
+        let Ok(mut queue) = VirtQueue::new(transport, queue_index) else {
+            return;
+        };
+        match queue_index {
+            VIRTIO_BALLOON_INFLATE_QUEUE | VIRTIO_BALLOON_DEFLATE_QUEUE => {
+                while let Some(chain) = queue.pop() {
+                    Self::for_each_pfn(&chain, |pfn| {
+                        if queue_index == VIRTIO_BALLOON_INFLATE_QUEUE {
+                            self.inflate_page(pfn)
+                        } else {
+                            self.deflate_page(pfn)
+                        }
+                    });
+                    queue.push(chain.head, 0);
+                }
+            }
+            VIRTIO_BALLOON_STATISTICS_QUEUE => {
+                while let Some(chain) = queue.pop() {
+                    self.process_statistics(&chain);
+                    if let Some(head) = self.statistics_head.replace(chain.head) {
+                        /* The driver should not queue multiple buffers */
+                        queue.push(head, 0);
+                    }
+                }
+            }
+            VIRTIO_BALLOON_FREE_PAGE_QUEUE => {
+                while let Some(chain) = queue.pop() {
+                    self.process_free_page_hint(&chain);
+                    queue.push(chain.head, 0);
+                }
+            }
+            _ => return,
+        }
+        queue.notify();
+    }
+
+    fn reset(&mut self) {
+        self.statistics.actual_pages = 0;
+        self.statistics_head = None;
+        self.free_page_hint_command_id = VIRTIO_BALLOON_CMD_ID_STOP;
+        self.reporting_command_id = None;
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtio Block Device
+//!
+//! (Virtual I/O Device Version 1.2, 5.2 Block Device)
+//!
+
+use super::virt_mmio::{
+    attach_virtio_device, read_config_bytes, VirtioDevice, VirtioMmioTransport,
+    VIRTIO_BLOCK_DEVICE, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC,
+};
+use super::virtqueue::{DescriptorChain, VirtQueue, MAX_DESCRIPTOR_CHAIN_LENGTH};
+use crate::paging::{PAGE_SHIFT, PAGE_SIZE};
+use crate::uefi::file::EfiFileProtocol;
+
+/* Feature Bits */
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+pub const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+/* Request Types */
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+/* Request Status */
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub const VIRTIO_BLK_SECTOR_SIZE: u64 = 512;
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+const VIRTIO_BLK_REQUEST_HEADER_SIZE: usize = 16;
+/// struct virtio_blk_discard_write_zeroes
+const VIRTIO_BLK_DISCARD_SEGMENT_SIZE: usize = 16;
+const VIRTIO_BLK_MAX_DISCARD_SEGMENTS: u32 = 16;
+const VIRTIO_BLK_CONFIG_SIZE: usize = 60;
+/// The size of the bounce buffer for IN/OUT
+const VIRTIO_BLK_TRANSFER_SIZE: usize = 0x1000;
+
+/// The storage behind virtio-blk
+pub trait BlockBackend {
+    /// The size of the disk in bytes, must be a multiple of the sector size
+    fn size(&self) -> u64;
+
+    fn is_read_only(&self) -> bool;
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<(), ()>;
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), ()>;
+
+    fn flush(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> Result<(), ()>;
+}
+
+/// The disk image on the memory allocated by the hypervisor
+pub struct RamDisk {
+    address: usize,
+    size: u64,
+    is_read_only: bool,
+}
+
+impl RamDisk {
+    /// Allocate the zero-filled disk
+    ///
+    /// # Arguments
+    /// * `size` - the size of the disk in bytes, rounded up to the page size
+    pub fn new(size: usize) -> Result<Self, ()> {
+        let pages = (size + PAGE_SIZE - 1) >> PAGE_SHIFT;
+        let address = crate::allocate_memory(pages, None)?;
+        unsafe { core::ptr::write_bytes(address as *mut u8, 0, pages << PAGE_SHIFT) };
+        Ok(Self {
+            address,
+            size: (size as u64) & !(VIRTIO_BLK_SECTOR_SIZE - 1),
+            is_read_only: false,
+        })
+    }
+
+    /// Load the disk image from the file
+    ///
+    /// This must be called before ExitBootServices. The writes from the guest are not written back
+    /// to the file.
+    pub fn load_file(file: &EfiFileProtocol, is_read_only: bool) -> Result<Self, ()> {
+        let file_size = file.get_file_size().map_err(|_| ())? as usize;
+        let disk_size = (file_size + VIRTIO_BLK_SECTOR_SIZE as usize - 1)
+            & !(VIRTIO_BLK_SECTOR_SIZE as usize - 1);
+        let mut disk = Self::new(disk_size)?;
+        disk.size = disk_size as u64;
+        disk.is_read_only = is_read_only;
+        let buffer = unsafe { core::slice::from_raw_parts_mut(disk.address as *mut u8, file_size) };
+        let mut loaded = 0;
+        while loaded < file_size {
+            let size = file.read(&mut buffer[loaded..]).map_err(|_| ())?;
+            if size == 0 {
+                return Err(());
+            }
+            loaded += size;
+        }
+        Ok(disk)
+    }
+
+    fn check_range(&self, offset: u64, length: u64) -> Result<(), ()> {
+        if offset.checked_add(length).ok_or(())? > self.size {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockBackend for RamDisk {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.is_read_only
+    }
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) -> Result<(), ()> {
+        self.check_range(offset, data.len() as u64)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (self.address + offset as usize) as *const u8,
+                data.as_mut_ptr(),
+                data.len(),
+            )
+        };
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), ()> {
+        if self.is_read_only {
+            return Err(());
+        }
+        self.check_range(offset, data.len() as u64)?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                (self.address + offset as usize) as *mut u8,
+                data.len(),
+            )
+        };
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: u64, length: u64) -> Result<(), ()> {
+        if self.is_read_only {
+            return Err(());
+        }
+        self.check_range(offset, length)?;
+        unsafe {
+            core::ptr::write_bytes(
+                (self.address + offset as usize) as *mut u8,
+                0,
+                length as usize,
+            )
+        };
+        Ok(())
+    }
+}
+
+pub struct VirtioBlk {
+    backend: &'static mut dyn BlockBackend,
+    id: [u8; VIRTIO_BLK_ID_BYTES],
+}
+
+static mut RAM_DISK: Option<RamDisk> = None;
+static mut VIRTIO_BLK: Option<VirtioBlk> = None;
+
+/// Create virtio-blk backed by the RAM disk and attach it to the virtio-mmio slot
+pub fn setup_virtio_blk(slot: usize, ram_disk: RamDisk, id: &str) -> Result<(), ()> {
+    let backend = unsafe { &mut *core::ptr::addr_of_mut!(RAM_DISK) }.insert(ram_disk);
+    let mut device_id = [0u8; VIRTIO_BLK_ID_BYTES];
+    let length = id.len().min(VIRTIO_BLK_ID_BYTES);
+    device_id[..length].copy_from_slice(&id.as_bytes()[..length]);
+    let device = unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_BLK) }.insert(VirtioBlk {
+        backend,
+        id: device_id,
+    });
+    attach_virtio_device(slot, device)
+}
+
+impl VirtioBlk {
+    fn config(&self) -> [u8; VIRTIO_BLK_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_BLK_CONFIG_SIZE];
+        let capacity = self.backend.size() / VIRTIO_BLK_SECTOR_SIZE;
+        config[0..8].copy_from_slice(&capacity.to_le_bytes());
+        /* seg_max */
+        config[12..16].copy_from_slice(&((MAX_DESCRIPTOR_CHAIN_LENGTH - 2) as u32).to_le_bytes());
+        /* blk_size */
+        config[20..24].copy_from_slice(&(VIRTIO_BLK_SECTOR_SIZE as u32).to_le_bytes());
+        /* max_discard_sectors */
+        config[36..40].copy_from_slice(&(capacity.min(u32::MAX as u64) as u32).to_le_bytes());
+        /* max_discard_seg */
+        config[40..44].copy_from_slice(&VIRTIO_BLK_MAX_DISCARD_SEGMENTS.to_le_bytes());
+        /* discard_sector_alignment */
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+        config
+    }
+
+    fn process_request(&mut self, chain: &DescriptorChain) -> (u8, u32) {
+        let mut header = [0u8; VIRTIO_BLK_REQUEST_HEADER_SIZE];
+        if chain.read_bytes(0, &mut header) != header.len() || chain.writable_length() == 0 {
+            return (VIRTIO_BLK_S_IOERR, 0);
+        }
+        let request_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        /* The last writable byte is the status */
+        let data_length = chain.writable_length() - 1;
+
+        match request_type {
+            VIRTIO_BLK_T_IN => match self.read_sectors(chain, sector, data_length) {
+                Ok(()) => (VIRTIO_BLK_S_OK, data_length as u32),
+                Err(()) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_OUT => match self.write_sectors(chain, sector) {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(()) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_FLUSH => match self.backend.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(()) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                let written =
+                    chain.write_bytes(0, &self.id[..data_length.min(VIRTIO_BLK_ID_BYTES)]);
+                (VIRTIO_BLK_S_OK, written as u32)
+            }
+            VIRTIO_BLK_T_DISCARD => match self.discard(chain) {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(()) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        }
+    }
+
+    fn read_sectors(
+        &mut self,
+        chain: &DescriptorChain,
+        sector: u64,
+        length: usize,
+    ) -> Result<(), ()> {
+        let offset = sector.checked_mul(VIRTIO_BLK_SECTOR_SIZE).ok_or(())?;
+        let mut buffer = [0u8; VIRTIO_BLK_TRANSFER_SIZE];
+        let mut transferred = 0;
+        while transferred < length {
+            let size = (length - transferred).min(buffer.len());
+            self.backend
+                .read(offset + transferred as u64, &mut buffer[..size])?;
+            chain.write_bytes(transferred, &buffer[..size]);
+            transferred += size;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, chain: &DescriptorChain, sector: u64) -> Result<(), ()> {
+        let offset = sector.checked_mul(VIRTIO_BLK_SECTOR_SIZE).ok_or(())?;
+        let length = chain.readable_length() - VIRTIO_BLK_REQUEST_HEADER_SIZE;
+        let mut buffer = [0u8; VIRTIO_BLK_TRANSFER_SIZE];
+        let mut transferred = 0;
+        while transferred < length {
+            let size = (length - transferred).min(buffer.len());
+            chain.read_bytes(
+                VIRTIO_BLK_REQUEST_HEADER_SIZE + transferred,
+                &mut buffer[..size],
+            );
+            self.backend
+                .write(offset + transferred as u64, &buffer[..size])?;
+            transferred += size;
+        }
+        Ok(())
+    }
+
+    fn discard(&mut self, chain: &DescriptorChain) -> Result<(), ()> {
+        let length = chain.readable_length() - VIRTIO_BLK_REQUEST_HEADER_SIZE;
+        if !length.is_multiple_of(VIRTIO_BLK_DISCARD_SEGMENT_SIZE)
+            || length / VIRTIO_BLK_DISCARD_SEGMENT_SIZE > VIRTIO_BLK_MAX_DISCARD_SEGMENTS as usize
+        {
+            return Err(());
+        }
+        for i in 0..(length / VIRTIO_BLK_DISCARD_SEGMENT_SIZE) {
+            let mut segment = [0u8; VIRTIO_BLK_DISCARD_SEGMENT_SIZE];
+            chain.read_bytes(
+                VIRTIO_BLK_REQUEST_HEADER_SIZE + i * VIRTIO_BLK_DISCARD_SEGMENT_SIZE,
+                &mut segment,
+            );
+            let sector = u64::from_le_bytes(segment[0..8].try_into().unwrap());
+            let number_of_sectors = u32::from_le_bytes(segment[8..12].try_into().unwrap());
+            self.backend.discard(
+                sector.checked_mul(VIRTIO_BLK_SECTOR_SIZE).ok_or(())?,
+                number_of_sectors as u64 * VIRTIO_BLK_SECTOR_SIZE,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_BLOCK_DEVICE
+    }
+
+    fn device_features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_SEG_MAX
+            | VIRTIO_BLK_F_BLK_SIZE
+            | VIRTIO_BLK_F_FLUSH
+            | VIRTIO_F_INDIRECT_DESC
+            | VIRTIO_F_EVENT_IDX;
+        if self.backend.is_read_only() {
+            features |= VIRTIO_BLK_F_RO;
+        } else {
+            features |= VIRTIO_BLK_F_DISCARD;
+        }
+        features
+    }
+
+    fn number_of_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&mut self, offset: usize, access_width: u64) -> Result<u32, ()> {
+        read_config_bytes(&self.config(), offset, access_width)
+    }
+
+    fn queue_notify(&mut self, transport: &mut VirtioMmioTransport, queue_index: usize) {
+        let Ok(mut queue) = VirtQueue::new(transport, queue_index) else {
+            return;
+        };
+        while let Some(chain) = queue.pop() {
+            if chain.writable_length() == 0 {
+                /* No room for the status */
+                queue.push(chain.head, 0);
+                continue;
+            }
+            let (status, written_length) = self.process_request(&chain);
+            chain.write_bytes(chain.writable_length() - 1, &[status]);
+            queue.push(chain.head, written_length + 1);
+        }
+        queue.notify();
+    }
+}
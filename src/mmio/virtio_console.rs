@@ -0,0 +1,354 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtio Console Device
+//!
+//! (Virtual I/O Device Version 1.2, 5.3 Console Device)
+//!
+//! Each port is registered to the console multiplexer. Port 0 is the console port (hvc0).
+//!
+
+use super::virt_mmio::{
+    attach_virtio_device, get_virtio_mmio, read_config_bytes, VirtioDevice, VirtioMmioTransport,
+    VIRTIO_CONSOLE_DEVICE, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRT_MMIO_MAX_QUEUES,
+};
+use super::virtqueue::VirtQueue;
+use crate::console::mux;
+
+/* Feature Bits */
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+pub const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+/* Control Events */
+pub const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+pub const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+pub const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+pub const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+pub const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+pub const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// port0 receiveq, port0 transmitq, control receiveq, control transmitq, port1 receiveq, ...
+pub const VIRTIO_CONSOLE_MAX_PORTS: usize = VIRT_MMIO_MAX_QUEUES / 2 - 1;
+const VIRTIO_CONSOLE_CONTROL_RECEIVE_QUEUE: usize = 2;
+const VIRTIO_CONSOLE_CONTROL_TRANSMIT_QUEUE: usize = 3;
+const VIRTIO_CONSOLE_CONFIG_SIZE: usize = 12;
+const VIRTIO_CONSOLE_EMERG_WR_OFFSET: usize = 8;
+/// struct virtio_console_control
+const VIRTIO_CONSOLE_CONTROL_SIZE: usize = 8;
+const MAX_PENDING_CONTROL_MESSAGES: usize = 16;
+const TRANSFER_SIZE: usize = 256;
+
+const PORT_NAMES: [&str; VIRTIO_CONSOLE_MAX_PORTS] =
+    ["virtio-console0", "virtio-console1", "virtio-console2"];
+
+#[derive(Clone, Copy)]
+struct ControlMessage {
+    id: u32,
+    event: u16,
+    value: u16,
+    name: Option<&'static str>,
+}
+
+#[derive(Clone, Copy)]
+struct Port {
+    console_id: usize,
+    is_ready: bool,
+    is_open: bool,
+}
+
+pub struct VirtioConsole {
+    slot: usize,
+    ports: [Port; VIRTIO_CONSOLE_MAX_PORTS],
+    number_of_ports: usize,
+    pending_control_messages: [Option<ControlMessage>; MAX_PENDING_CONTROL_MESSAGES],
+}
+
+static mut VIRTIO_CONSOLE: Option<VirtioConsole> = None;
+
+/// Create virtio-console and attach it to the virtio-mmio slot
+///
+/// # Arguments
+/// * `slot` - the virtio-mmio slot
+/// * `number_of_ports` - 1 ~ [`VIRTIO_CONSOLE_MAX_PORTS`]
+pub fn setup_virtio_console(slot: usize, number_of_ports: usize) -> Result<(), ()> {
+    if number_of_ports == 0 || number_of_ports > VIRTIO_CONSOLE_MAX_PORTS {
+        return Err(());
+    }
+    let mut ports = [Port {
+        console_id: 0,
+        is_ready: false,
+        is_open: false,
+    }; VIRTIO_CONSOLE_MAX_PORTS];
+    for (port, name) in ports.iter_mut().zip(PORT_NAMES).take(number_of_ports) {
+        port.console_id = mux::register_console(name, Some(input_notifier))?;
+    }
+    let device = unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_CONSOLE) }.insert(VirtioConsole {
+        slot,
+        ports,
+        number_of_ports,
+        pending_control_messages: [None; MAX_PENDING_CONTROL_MESSAGES],
+    });
+    attach_virtio_device(slot, device)
+}
+
+/// Deliver the input from the console multiplexer
+fn input_notifier(console_id: usize) {
+    let Some(device) = unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_CONSOLE) }.as_mut() else {
+        return;
+    };
+    let Some(virt_mmio) = get_virtio_mmio(device.slot) else {
+        return;
+    };
+    if let Some(port) = device.ports[..device.number_of_ports]
+        .iter()
+        .position(|p| p.console_id == console_id)
+    {
+        device.deliver_input(&mut virt_mmio.transport, port);
+    }
+}
+
+impl VirtioConsole {
+    const fn receive_queue(port: usize) -> usize {
+        if port == 0 {
+            0
+        } else {
+            2 + 2 * port
+        }
+    }
+
+    const fn transmit_queue(port: usize) -> usize {
+        Self::receive_queue(port) + 1
+    }
+
+    fn config(&self) -> [u8; VIRTIO_CONSOLE_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_CONSOLE_CONFIG_SIZE];
+        /* max_nr_ports */
+        config[4..8].copy_from_slice(&(self.number_of_ports as u32).to_le_bytes());
+        config
+    }
+
+    fn deliver_input(&mut self, transport: &mut VirtioMmioTransport, port: usize) {
+        let console_id = self.ports[port].console_id;
+        if port != 0 && !transport.is_feature_negotiated(VIRTIO_CONSOLE_F_MULTIPORT) {
+            return;
+        }
+        let Ok(mut queue) = VirtQueue::new(transport, Self::receive_queue(port)) else {
+            return;
+        };
+        while mux::has_input(console_id) {
+            let Some(chain) = queue.pop() else {
+                break;
+            };
+            let mut buffer = [0u8; TRANSFER_SIZE];
+            let length = mux::read(
+                console_id,
+                &mut buffer[..chain.writable_length().min(TRANSFER_SIZE)],
+            );
+            let written = chain.write_bytes(0, &buffer[..length]);
+            queue.push(chain.head, written as u32);
+        }
+        queue.notify();
+    }
+
+    fn transmit(&mut self, transport: &mut VirtioMmioTransport, port: usize) {
+        let console_id = self.ports[port].console_id;
+        let Ok(mut queue) = VirtQueue::new(transport, Self::transmit_queue(port)) else {
+            return;
+        };
+        while let Some(chain) = queue.pop() {
+            let mut buffer = [0u8; TRANSFER_SIZE];
+            let mut offset = 0;
+            loop {
+                let length = chain.read_bytes(offset, &mut buffer);
+                if length == 0 {
+                    break;
+                }
+                mux::write(console_id, &buffer[..length]);
+                offset += length;
+            }
+            queue.push(chain.head, 0);
+        }
+        queue.notify();
+    }
+
+    fn queue_control_message(
+        &mut self,
+        id: u32,
+        event: u16,
+        value: u16,
+        name: Option<&'static str>,
+    ) {
+        if let Some(entry) = self
+            .pending_control_messages
+            .iter_mut()
+            .find(|m| m.is_none())
+        {
+            *entry = Some(ControlMessage {
+                id,
+                event,
+                value,
+                name,
+            });
+        } else {
+            warn!("The control message is dropped");
+        }
+    }
+
+    fn flush_control_messages(&mut self, transport: &mut VirtioMmioTransport) {
+        let Ok(mut queue) = VirtQueue::new(transport, VIRTIO_CONSOLE_CONTROL_RECEIVE_QUEUE) else {
+            return;
+        };
+        for entry in self.pending_control_messages.iter_mut() {
+            let Some(message) = entry else {
+                continue;
+            };
+            let Some(chain) = queue.pop() else {
+                break;
+            };
+            let mut buffer = [0u8; VIRTIO_CONSOLE_CONTROL_SIZE];
+            buffer[0..4].copy_from_slice(&message.id.to_le_bytes());
+            buffer[4..6].copy_from_slice(&message.event.to_le_bytes());
+            buffer[6..8].copy_from_slice(&message.value.to_le_bytes());
+            let mut written = chain.write_bytes(0, &buffer);
+            if let Some(name) = message.name {
+                written += chain.write_bytes(written, name.as_bytes());
+            }
+            queue.push(chain.head, written as u32);
+            *entry = None;
+        }
+        /* Keep the order of the remaining messages */
+        let mut index = 0;
+        for i in 0..MAX_PENDING_CONTROL_MESSAGES {
+            if let Some(m) = self.pending_control_messages[i].take() {
+                self.pending_control_messages[index] = Some(m);
+                index += 1;
+            }
+        }
+        queue.notify();
+    }
+
+    fn handle_control_message(&mut self, id: u32, event: u16, value: u16) {
+        let port = id as usize;
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                if value == 1 {
+                    for p in 0..self.number_of_ports {
+                        self.queue_control_message(p as u32, VIRTIO_CONSOLE_DEVICE_ADD, 0, None);
+                    }
+                } else {
+                    warn!("The driver failed to initialize");
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if port < self.number_of_ports => {
+                self.ports[port].is_ready = value == 1;
+                if value == 1 {
+                    if port == 0 {
+                        self.queue_control_message(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, None);
+                    } else {
+                        self.queue_control_message(
+                            id,
+                            VIRTIO_CONSOLE_PORT_NAME,
+                            0,
+                            Some(PORT_NAMES[port]),
+                        );
+                    }
+                    self.queue_control_message(id, VIRTIO_CONSOLE_PORT_OPEN, 1, None);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN if port < self.number_of_ports => {
+                self.ports[port].is_open = value == 1;
+            }
+            _ => {
+                warn!(
+                    "Unknown control message(id: {}, event: {}, value: {})",
+                    id, event, value
+                );
+            }
+        }
+    }
+
+    fn process_control_messages(&mut self, transport: &mut VirtioMmioTransport) {
+        {
+            let Ok(mut queue) = VirtQueue::new(transport, VIRTIO_CONSOLE_CONTROL_TRANSMIT_QUEUE)
+            else {
+                return;
+            };
+            while let Some(chain) = queue.pop() {
+                let mut buffer = [0u8; VIRTIO_CONSOLE_CONTROL_SIZE];
+                if chain.read_bytes(0, &mut buffer) == VIRTIO_CONSOLE_CONTROL_SIZE {
+                    self.handle_control_message(
+                        u32::from_le_bytes(buffer[0..4].try_into().unwrap()),
+                        u16::from_le_bytes(buffer[4..6].try_into().unwrap()),
+                        u16::from_le_bytes(buffer[6..8].try_into().unwrap()),
+                    );
+                }
+                queue.push(chain.head, 0);
+            }
+            queue.notify();
+        }
+        self.flush_control_messages(transport);
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_CONSOLE_DEVICE
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+            | VIRTIO_CONSOLE_F_EMERG_WRITE
+            | VIRTIO_F_INDIRECT_DESC
+            | VIRTIO_F_EVENT_IDX
+    }
+
+    fn number_of_queues(&self) -> usize {
+        2 * (self.number_of_ports + 1)
+    }
+
+    fn read_config(&mut self, offset: usize, access_width: u64) -> Result<u32, ()> {
+        read_config_bytes(&self.config(), offset, access_width)
+    }
+
+    fn write_config(&mut self, offset: usize, _access_width: u64, value: u32) -> Result<(), ()> {
+        if offset == VIRTIO_CONSOLE_EMERG_WR_OFFSET {
+            mux::write(self.ports[0].console_id, &[value as u8]);
+        }
+        Ok(())
+    }
+
+    fn queue_notify(&mut self, transport: &mut VirtioMmioTransport, queue_index: usize) {
+        match queue_index {
+            VIRTIO_CONSOLE_CONTROL_RECEIVE_QUEUE => self.flush_control_messages(transport),
+            VIRTIO_CONSOLE_CONTROL_TRANSMIT_QUEUE => self.process_control_messages(transport),
+            _ => {
+                let port = if queue_index < 2 {
+                    0
+                } else {
+                    (queue_index - 2) / 2
+                };
+                if port >= self.number_of_ports {
+                    return;
+                }
+                if (queue_index & 1) == 0 {
+                    self.deliver_input(transport, port);
+                } else {
+                    self.transmit(transport, port);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        for p in self.ports.iter_mut() {
+            p.is_ready = false;
+            p.is_open = false;
+        }
+        self.pending_control_messages = [None; MAX_PENDING_CONTROL_MESSAGES];
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtio Network Device
+//!
+//! (Virtual I/O Device Version 1.2, 5.1 Network Device)
+//!
+//! Each device is connected to a port of [`crate::net_switch`].
+//! The frames forwarded by the switch are received when the receive queue is notified by the
+//! driver or by [`defer_queue_notify`] from the switch port.
+//!
+
+use super::virt_mmio::{
+    attach_virtio_device, defer_queue_notify, read_config_bytes, VirtioDevice, VirtioMmioTransport,
+    VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_NETWORK_CARD,
+};
+use super::virtqueue::{DescriptorChain, VirtQueue};
+use crate::net_switch::{
+    self, MacAddress, PartialChecksum, ReceiveStatus, ETHERNET_ADDRESS_LENGTH, MAX_FRAME_LENGTH,
+};
+
+/* Feature Bits */
+pub const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+pub const VIRTIO_NET_F_GUEST_CSUM: u64 = 1 << 1;
+pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+pub const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+pub const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/* virtio_net_hdr */
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1 << 0;
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_SIZE: usize = 12;
+const VIRTIO_NET_HDR_CSUM_START_OFFSET: usize = 6;
+const VIRTIO_NET_HDR_CSUM_OFFSET_OFFSET: usize = 8;
+const VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
+
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+const VIRTIO_NET_RECEIVE_QUEUE: usize = 0;
+const VIRTIO_NET_TRANSMIT_QUEUE: usize = 1;
+const VIRTIO_NET_CONFIG_SIZE: usize = 12;
+
+pub const MAX_VIRTIO_NET_DEVICES: usize = 4;
+/// The maximum number of the receive buffers merged for one frame
+const MAX_MERGEABLE_BUFFERS: usize = 16;
+
+pub struct VirtioNet {
+    slot: usize,
+    mac_address: MacAddress,
+    switch_port: usize,
+}
+
+static mut VIRTIO_NET_DEVICES: [Option<VirtioNet>; MAX_VIRTIO_NET_DEVICES] =
+    [const { None }; MAX_VIRTIO_NET_DEVICES];
+
+/// Create virtio-net connected to the software switch and attach it to the virtio-mmio slot
+pub fn setup_virtio_net(slot: usize, mac_address: MacAddress) -> Result<(), ()> {
+    let entry = unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_NET_DEVICES) }
+        .iter_mut()
+        .find(|d| d.is_none())
+        .ok_or(())?;
+    let switch_port = net_switch::register_switch_port(switch_port_notifier)?;
+    let device = entry.insert(VirtioNet {
+        slot,
+        mac_address,
+        switch_port,
+    });
+    attach_virtio_device(slot, device)
+}
+
+/// Receive the queued frames after the sender returned, the sender's transport is borrowed now
+fn switch_port_notifier(port_id: usize) {
+    if let Some(device) = unsafe { &*core::ptr::addr_of!(VIRTIO_NET_DEVICES) }
+        .iter()
+        .flatten()
+        .find(|d| d.switch_port == port_id)
+    {
+        defer_queue_notify(device.slot, VIRTIO_NET_RECEIVE_QUEUE);
+    }
+}
+
+/// Calculate the checksum left by the sender
+fn complete_checksum(frame: &mut [u8], checksum: PartialChecksum) -> Result<(), ()> {
+    let start = checksum.start as usize;
+    let field = start + checksum.offset as usize;
+    if field + 2 > frame.len() {
+        return Err(());
+    }
+    let mut sum: u32 = frame[start..]
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+        .sum();
+    while (sum >> 16) != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    frame[field..(field + 2)].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    Ok(())
+}
+
+impl VirtioNet {
+    fn config(&self) -> [u8; VIRTIO_NET_CONFIG_SIZE] {
+        let mut config = [0u8; VIRTIO_NET_CONFIG_SIZE];
+        config[0..ETHERNET_ADDRESS_LENGTH].copy_from_slice(&self.mac_address);
+        /* status */
+        config[6..8].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        /* max_virtqueue_pairs */
+        config[8..10].copy_from_slice(&1u16.to_le_bytes());
+        config
+    }
+
+    /// Write `header` followed by `frame` into the chain
+    fn write_packet(
+        chain: &DescriptorChain,
+        header: &[u8],
+        frame: &[u8],
+        offset: usize,
+        length: usize,
+    ) {
+        let end = offset + length;
+        if offset < header.len() {
+            let header_end = end.min(header.len());
+            chain.write_bytes(0, &header[offset..header_end]);
+        }
+        if end > header.len() {
+            let frame_start = offset.max(header.len()) - header.len();
+            chain.write_bytes(
+                frame_start + header.len() - offset,
+                &frame[frame_start..(end - header.len())],
+            );
+        }
+    }
+
+    /// Get the writable length of the largest receive buffer available now, 0 if none
+    fn get_largest_receive_buffer(queue: &mut VirtQueue) -> usize {
+        let mut count = 0u16;
+        let mut largest = 0;
+        while let Some(chain) = queue.pop() {
+            largest = largest.max(chain.writable_length());
+            count += 1;
+        }
+        queue.rewind(count);
+        largest
+    }
+
+    fn receive(
+        &mut self,
+        transport: &mut VirtioMmioTransport,
+        frame: &[u8],
+        checksum: Option<PartialChecksum>,
+    ) -> ReceiveStatus {
+        if frame.len() > MAX_FRAME_LENGTH {
+            return ReceiveStatus::Dropped;
+        }
+        let mut header = [0u8; VIRTIO_NET_HDR_SIZE];
+        let mut completed_frame = [0u8; MAX_FRAME_LENGTH];
+        let frame = match checksum {
+            Some(c) if transport.is_feature_negotiated(VIRTIO_NET_F_GUEST_CSUM) => {
+                header[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+                header[VIRTIO_NET_HDR_CSUM_START_OFFSET..(VIRTIO_NET_HDR_CSUM_START_OFFSET + 2)]
+                    .copy_from_slice(&c.start.to_le_bytes());
+                header[VIRTIO_NET_HDR_CSUM_OFFSET_OFFSET..(VIRTIO_NET_HDR_CSUM_OFFSET_OFFSET + 2)]
+                    .copy_from_slice(&c.offset.to_le_bytes());
+                frame
+            }
+            Some(c) => {
+                let completed_frame = &mut completed_frame[..frame.len()];
+                completed_frame.copy_from_slice(frame);
+                if complete_checksum(completed_frame, c).is_err() {
+                    return ReceiveStatus::Dropped;
+                }
+                completed_frame
+            }
+            None => frame,
+        };
+        header[1] = VIRTIO_NET_HDR_GSO_NONE;
+        let is_mergeable = transport.is_feature_negotiated(VIRTIO_NET_F_MRG_RXBUF);
+
+        let Ok(mut queue) = VirtQueue::new(transport, VIRTIO_NET_RECEIVE_QUEUE) else {
+            return ReceiveStatus::Dropped;
+        };
+        let total_length = VIRTIO_NET_HDR_SIZE + frame.len();
+        let mut used = [(0u16, 0u32); MAX_MERGEABLE_BUFFERS];
+        let mut number_of_buffers = 0;
+        let mut written = 0;
+        let mut first_chain = None;
+        while written < total_length {
+            let chain = if number_of_buffers == MAX_MERGEABLE_BUFFERS
+                || (!is_mergeable && number_of_buffers == 1)
+            {
+                None
+            } else {
+                queue.pop()
+            };
+            let Some(chain) = chain else {
+                queue.rewind(number_of_buffers as u16);
+                let buffers_per_frame = if is_mergeable {
+                    MAX_MERGEABLE_BUFFERS
+                } else {
+                    1
+                };
+                let capacity = Self::get_largest_receive_buffer(&mut queue) * buffers_per_frame;
+                if capacity != 0 && capacity < total_length {
+                    /* The frame never fits, it must not block the following frames */
+                    return ReceiveStatus::Dropped;
+                }
+                /* Not enough buffers, retry when the driver supplies them */
+                return ReceiveStatus::NoBuffer;
+            };
+            let length = chain.writable_length().min(total_length - written);
+            Self::write_packet(&chain, &header, frame, written, length);
+            used[number_of_buffers] = (chain.head, length as u32);
+            number_of_buffers += 1;
+            written += length;
+            if first_chain.is_none() {
+                first_chain = Some(chain);
+            }
+        }
+        if let Some(chain) = first_chain {
+            chain.write_bytes(
+                VIRTIO_NET_HDR_NUM_BUFFERS_OFFSET,
+                &(number_of_buffers as u16).to_le_bytes(),
+            );
+        }
+        for (head, length) in &used[..number_of_buffers] {
+            queue.push(*head, *length);
+        }
+        queue.notify();
+        ReceiveStatus::Received
+    }
+
+    fn receive_queued_frames(&mut self, transport: &mut VirtioMmioTransport) {
+        let switch_port = self.switch_port;
+        net_switch::receive(switch_port, &mut |frame, checksum| {
+            self.receive(transport, frame, checksum)
+        });
+    }
+
+    fn transmit(&mut self, transport: &mut VirtioMmioTransport) {
+        let is_checksum_offloaded = transport.is_feature_negotiated(VIRTIO_NET_F_CSUM);
+        let Ok(mut queue) = VirtQueue::new(transport, VIRTIO_NET_TRANSMIT_QUEUE) else {
+            return;
+        };
+        while let Some(chain) = queue.pop() {
+            let mut header = [0u8; VIRTIO_NET_HDR_SIZE];
+            let length = chain.readable_length().saturating_sub(VIRTIO_NET_HDR_SIZE);
+            if chain.read_bytes(0, &mut header) == VIRTIO_NET_HDR_SIZE && length <= MAX_FRAME_LENGTH
+            {
+                let mut frame = [0u8; MAX_FRAME_LENGTH];
+                chain.read_bytes(VIRTIO_NET_HDR_SIZE, &mut frame[..length]);
+                let checksum =
+                    if is_checksum_offloaded && (header[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM) != 0 {
+                        Some(PartialChecksum {
+                            start: u16::from_le_bytes(
+                                header[VIRTIO_NET_HDR_CSUM_START_OFFSET
+                                    ..(VIRTIO_NET_HDR_CSUM_START_OFFSET + 2)]
+                                    .try_into()
+                                    .unwrap(),
+                            ),
+                            offset: u16::from_le_bytes(
+                                header[VIRTIO_NET_HDR_CSUM_OFFSET_OFFSET
+                                    ..(VIRTIO_NET_HDR_CSUM_OFFSET_OFFSET + 2)]
+                                    .try_into()
+                                    .unwrap(),
+                            ),
+                        })
+                    } else {
+                        None
+                    };
+                net_switch::transmit(self.switch_port, &frame[..length], checksum);
+            }
+            queue.push(chain.head, 0);
+        }
+        queue.notify();
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_NETWORK_CARD
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_NET_F_CSUM
+            | VIRTIO_NET_F_GUEST_CSUM
+            | VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_MRG_RXBUF
+            | VIRTIO_NET_F_STATUS
+            | VIRTIO_F_INDIRECT_DESC
+            | VIRTIO_F_EVENT_IDX
+    }
+
+    fn number_of_queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&mut self, offset: usize, access_width: u64) -> Result<u32, ()> {
+        read_config_bytes(&self.config(), offset, access_width)
+    }
+
+    fn queue_notify(&mut self, transport: &mut VirtioMmioTransport, queue_index: usize) {
+        match queue_index {
+            VIRTIO_NET_RECEIVE_QUEUE => self.receive_queued_frames(transport),
+            VIRTIO_NET_TRANSMIT_QUEUE => self.transmit(transport),
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        net_switch::flush_port(self.switch_port);
+    }
+
+    fn dump_state(&self) {
+        if let Some(s) = net_switch::get_switch_port_statistics(self.switch_port) {
+            println!(
+                "    Switch Port {}: TX: {} RX: {} Dropped: {}",
+                self.switch_port, s.transmitted_frames, s.received_frames, s.dropped_frames
+            );
+        }
+    }
+}
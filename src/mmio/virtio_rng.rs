@@ -0,0 +1,73 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtio Entropy Device
+//!
+//! (Virtual I/O Device Version 1.2, 5.4 Entropy Device)
+//!
+
+use super::virt_mmio::{
+    attach_virtio_device, VirtioDevice, VirtioMmioTransport, VIRTIO_ENTROPY_SOURCE,
+    VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC,
+};
+use super::virtqueue::VirtQueue;
+use crate::random;
+
+const VIRTIO_RNG_REQUEST_QUEUE: usize = 0;
+const TRANSFER_SIZE: usize = 256;
+/// The maximum bytes provided for one request
+const MAX_REQUEST_SIZE: usize = 0x1000;
+
+pub struct VirtioRng {}
+
+static mut VIRTIO_RNG: VirtioRng = VirtioRng {};
+
+/// Create virtio-rng and attach it to the virtio-mmio slot
+pub fn setup_virtio_rng(slot: usize) -> Result<(), ()> {
+    attach_virtio_device(slot, unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_RNG) })
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ENTROPY_SOURCE
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX
+    }
+
+    fn number_of_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&mut self, _offset: usize, _access_width: u64) -> Result<u32, ()> {
+        /* No configuration space */
+        Ok(0)
+    }
+
+    fn queue_notify(&mut self, transport: &mut VirtioMmioTransport, queue_index: usize) {
+        if queue_index != VIRTIO_RNG_REQUEST_QUEUE {
+            return;
+        }
+        let Ok(mut queue) = VirtQueue::new(transport, queue_index) else {
+            return;
+        };
+        while let Some(chain) = queue.pop() {
+            let length = chain.writable_length().min(MAX_REQUEST_SIZE);
+            let mut written = 0;
+            while written < length {
+                let mut buffer = [0u8; TRANSFER_SIZE];
+                let size = (length - written).min(TRANSFER_SIZE);
+                random::fill_random_bytes(&mut buffer[..size]);
+                written += chain.write_bytes(written, &buffer[..size]);
+            }
+            queue.push(chain.head, written as u32);
+        }
+        queue.notify();
+    }
+}
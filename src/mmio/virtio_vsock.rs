@@ -0,0 +1,303 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtio Socket Device
+//!
+//! (Virtual I/O Device Version 1.2, 5.10 Socket Device)
+//!
+//! The packets to [`VMADDR_CID_HOST`] are handled by [`crate::vsock`], and the packets to
+//! the CID of another virtio-vsock are forwarded to its receive queue.
+//! The credit information is forwarded as it is, so the guests control the flow by themselves.
+//!
+
+use super::virt_mmio::{
+    attach_virtio_device, defer_queue_notify, get_virtio_mmio, read_config_bytes, VirtioDevice,
+    VirtioMmioTransport, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC, VIRTIO_SOCKET_DEVICE,
+    VIRT_MMIO_NUMBER_OF_SLOTS,
+};
+use super::virtqueue::{DescriptorChain, VirtQueue};
+use crate::vsock::{
+    self, VsockHeader, VIRTIO_VSOCK_OP_RST, VMADDR_CID_HOST, VSOCK_MAX_MESSAGE_SIZE,
+};
+
+/* Feature Bits */
+pub const VIRTIO_VSOCK_F_STREAM: u64 = 1 << 0;
+
+const VIRTIO_VSOCK_RECEIVE_QUEUE: usize = 0;
+const VIRTIO_VSOCK_TRANSMIT_QUEUE: usize = 1;
+const VIRTIO_VSOCK_EVENT_QUEUE: usize = 2;
+const VIRTIO_VSOCK_CONFIG_SIZE: usize = 8;
+
+pub const MAX_VIRTIO_VSOCK_DEVICES: usize = 4;
+/// The packets from the hypervisor waiting for the receive buffers
+const MAX_PENDING_PACKETS: usize = 16;
+/// The size of the bounce buffer for forwarding
+const TRANSFER_SIZE: usize = 0x400;
+
+#[derive(Clone, Copy)]
+struct PendingPacket {
+    header: VsockHeader,
+    payload: [u8; VSOCK_MAX_MESSAGE_SIZE],
+}
+
+pub struct VirtioVsock {
+    slot: usize,
+    guest_cid: u64,
+    pending_packets: [Option<PendingPacket>; MAX_PENDING_PACKETS],
+    pending_head: usize,
+    /// The payload of the first transmit packet already forwarded
+    transmit_offset: usize,
+    /// The virtio-mmio slots of the devices waiting for the receive buffers of this device
+    blocked_sources: u32,
+}
+
+static mut VIRTIO_VSOCK_DEVICES: [Option<VirtioVsock>; MAX_VIRTIO_VSOCK_DEVICES] =
+    [const { None }; MAX_VIRTIO_VSOCK_DEVICES];
+
+fn virtio_vsock_devices() -> &'static mut [Option<VirtioVsock>; MAX_VIRTIO_VSOCK_DEVICES] {
+    unsafe { &mut *core::ptr::addr_of_mut!(VIRTIO_VSOCK_DEVICES) }
+}
+
+/// Create virtio-vsock and attach it to the virtio-mmio slot
+///
+/// # Arguments
+/// * `slot` - the virtio-mmio slot
+/// * `guest_cid` - the CID of the guest, must be greater than [`VMADDR_CID_HOST`] and unique
+pub fn setup_virtio_vsock(slot: usize, guest_cid: u64) -> Result<(), ()> {
+    let devices = virtio_vsock_devices();
+    if guest_cid <= VMADDR_CID_HOST
+        || guest_cid > u32::MAX as u64
+        || devices.iter().flatten().any(|d| d.guest_cid == guest_cid)
+    {
+        return Err(());
+    }
+    let entry = devices.iter_mut().find(|d| d.is_none()).ok_or(())?;
+    let device = entry.insert(VirtioVsock {
+        slot,
+        guest_cid,
+        pending_packets: [None; MAX_PENDING_PACKETS],
+        pending_head: 0,
+        transmit_offset: 0,
+        blocked_sources: 0,
+    });
+    attach_virtio_device(slot, device)
+}
+
+fn find_device_index(guest_cid: u64) -> Option<usize> {
+    virtio_vsock_devices()
+        .iter()
+        .position(|d| d.as_ref().is_some_and(|d| d.guest_cid == guest_cid))
+}
+
+impl VirtioVsock {
+    fn queue_packet(&mut self, header: &VsockHeader, payload: &[u8]) {
+        let length = payload.len().min(VSOCK_MAX_MESSAGE_SIZE);
+        for i in 0..MAX_PENDING_PACKETS {
+            let entry = &mut self.pending_packets[(self.pending_head + i) % MAX_PENDING_PACKETS];
+            if entry.is_none() {
+                let mut packet = PendingPacket {
+                    header: *header,
+                    payload: [0; VSOCK_MAX_MESSAGE_SIZE],
+                };
+                packet.header.len = length as u32;
+                packet.payload[..length].copy_from_slice(&payload[..length]);
+                *entry = Some(packet);
+                return;
+            }
+        }
+        warn!("The packet is dropped, too many packets are pending");
+    }
+
+    fn deliver_pending_packets(&mut self, transport: &mut VirtioMmioTransport) {
+        let Ok(mut queue) = VirtQueue::new(transport, VIRTIO_VSOCK_RECEIVE_QUEUE) else {
+            return;
+        };
+        while let Some(packet) = self.pending_packets[self.pending_head] {
+            let Some(chain) = queue.pop() else {
+                break;
+            };
+            let length = packet.header.len as usize;
+            if chain.writable_length() < VsockHeader::SIZE + length {
+                /* The driver must supply the buffers which can hold the small packets */
+                queue.push(chain.head, 0);
+                continue;
+            }
+            chain.write_bytes(0, &packet.header.to_bytes());
+            chain.write_bytes(VsockHeader::SIZE, &packet.payload[..length]);
+            queue.push(chain.head, (VsockHeader::SIZE + length) as u32);
+            self.pending_packets[self.pending_head] = None;
+            self.pending_head = (self.pending_head + 1) % MAX_PENDING_PACKETS;
+        }
+        queue.notify();
+    }
+
+    fn read_header(chain: &DescriptorChain) -> Option<VsockHeader> {
+        let mut header = [0u8; VsockHeader::SIZE];
+        if chain.read_bytes(0, &mut header) != VsockHeader::SIZE {
+            return None;
+        }
+        Some(VsockHeader::from_bytes(&header))
+    }
+
+    /// Copy the payload to the receive queue of the destination device
+    ///
+    /// # Result
+    /// If all of the payload was forwarded, Ok(()), otherwise Err(()) and
+    /// `self.transmit_offset` holds the forwarded bytes
+    fn forward_packet(
+        &mut self,
+        destination: &mut VirtioVsock,
+        header: &VsockHeader,
+        chain: &DescriptorChain,
+    ) -> Result<(), ()> {
+        let Some(virt_mmio) = get_virtio_mmio(destination.slot) else {
+            return Ok(());
+        };
+        let Ok(mut queue) = VirtQueue::new(&mut virt_mmio.transport, VIRTIO_VSOCK_RECEIVE_QUEUE)
+        else {
+            /* The destination is not running, the packet is dropped */
+            return Ok(());
+        };
+        let length = (header.len as usize).min(chain.readable_length() - VsockHeader::SIZE);
+        let mut result = Ok(());
+        loop {
+            let Some(receive_chain) = queue.pop() else {
+                result = Err(());
+                break;
+            };
+            let capacity = receive_chain
+                .writable_length()
+                .saturating_sub(VsockHeader::SIZE);
+            if receive_chain.writable_length() < VsockHeader::SIZE || (capacity == 0 && length != 0)
+            {
+                queue.push(receive_chain.head, 0);
+                continue;
+            }
+            let size = (length - self.transmit_offset).min(capacity);
+            let mut h = *header;
+            h.len = size as u32;
+            receive_chain.write_bytes(0, &h.to_bytes());
+            let mut written = 0;
+            while written < size {
+                let mut buffer = [0u8; TRANSFER_SIZE];
+                let n = chain.read_bytes(
+                    VsockHeader::SIZE + self.transmit_offset + written,
+                    &mut buffer[..(size - written).min(TRANSFER_SIZE)],
+                );
+                receive_chain.write_bytes(VsockHeader::SIZE + written, &buffer[..n]);
+                written += n;
+            }
+            queue.push(receive_chain.head, (VsockHeader::SIZE + size) as u32);
+            self.transmit_offset += size;
+            if self.transmit_offset >= length {
+                break;
+            }
+        }
+        queue.notify();
+        result
+    }
+
+    fn process_transmit_queue(&mut self, transport: &mut VirtioMmioTransport, index: usize) {
+        let Ok(mut queue) = VirtQueue::new(transport, VIRTIO_VSOCK_TRANSMIT_QUEUE) else {
+            return;
+        };
+        while let Some(chain) = queue.pop() {
+            let Some(mut header) = Self::read_header(&chain) else {
+                queue.push(chain.head, 0);
+                continue;
+            };
+            /* The guest cannot impersonate the others */
+            header.src_cid = self.guest_cid;
+
+            if header.dst_cid == VMADDR_CID_HOST {
+                let mut payload = [0u8; VSOCK_MAX_MESSAGE_SIZE];
+                let length = chain.read_bytes(
+                    VsockHeader::SIZE,
+                    &mut payload[..(header.len as usize).min(VSOCK_MAX_MESSAGE_SIZE)],
+                );
+                vsock::receive(&header, &payload[..length], &mut |h, p| {
+                    self.queue_packet(h, p)
+                });
+            } else if let Some(destination_index) =
+                find_device_index(header.dst_cid).filter(|i| *i != index)
+            {
+                let destination = virtio_vsock_devices()[destination_index].as_mut().unwrap();
+                if self.forward_packet(destination, &header, &chain).is_err() {
+                    /* Retry when the destination supplies the receive buffers */
+                    destination.blocked_sources |= 1 << self.slot;
+                    queue.rewind(1);
+                    break;
+                }
+            } else if header.op != VIRTIO_VSOCK_OP_RST {
+                self.queue_packet(&header.reply(VIRTIO_VSOCK_OP_RST), &[]);
+            }
+            self.transmit_offset = 0;
+            queue.push(chain.head, 0);
+        }
+        queue.notify();
+        self.deliver_pending_packets(queue.transport());
+    }
+
+    /// Request to process the transmit queues of the devices blocked by this device
+    ///
+    /// The queues are processed after this device returned, because the forwarding accesses
+    /// this device and its transport.
+    fn resume_blocked_sources(&mut self) {
+        let blocked_sources = core::mem::take(&mut self.blocked_sources);
+        for slot in (0..VIRT_MMIO_NUMBER_OF_SLOTS).filter(|s| (blocked_sources & (1 << s)) != 0) {
+            defer_queue_notify(slot, VIRTIO_VSOCK_TRANSMIT_QUEUE);
+        }
+    }
+
+    fn index(&self) -> usize {
+        find_device_index(self.guest_cid).unwrap()
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_id(&self) -> u32 {
+        VIRTIO_SOCKET_DEVICE
+    }
+
+    fn device_features(&self) -> u64 {
+        VIRTIO_VSOCK_F_STREAM | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_EVENT_IDX
+    }
+
+    fn number_of_queues(&self) -> usize {
+        3
+    }
+
+    fn read_config(&mut self, offset: usize, access_width: u64) -> Result<u32, ()> {
+        let config: [u8; VIRTIO_VSOCK_CONFIG_SIZE] = self.guest_cid.to_le_bytes();
+        read_config_bytes(&config, offset, access_width)
+    }
+
+    fn queue_notify(&mut self, transport: &mut VirtioMmioTransport, queue_index: usize) {
+        match queue_index {
+            VIRTIO_VSOCK_RECEIVE_QUEUE => {
+                self.deliver_pending_packets(transport);
+                self.resume_blocked_sources();
+            }
+            VIRTIO_VSOCK_TRANSMIT_QUEUE => {
+                let index = self.index();
+                self.process_transmit_queue(transport, index);
+            }
+            /* No event is sent, the buffers are held */
+            VIRTIO_VSOCK_EVENT_QUEUE => {}
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending_packets = [None; MAX_PENDING_PACKETS];
+        self.pending_head = 0;
+        self.transmit_offset = 0;
+        self.blocked_sources = 0;
+        vsock::reset_connections(self.guest_cid);
+    }
+}
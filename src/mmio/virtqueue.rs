@@ -0,0 +1,408 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Split Virtqueue
+//!
+//! (Virtual I/O Device Version 1.2, 2.7 Split Virtqueues)
+//!
+
+use super::virt_mmio::{
+    VirtioMmioTransport, VIRTIO_F_EVENT_IDX, VIRTIO_F_INDIRECT_DESC,
+    VIRTIO_STATUS_DEVICE_NEEDS_RESET,
+};
+use crate::paging::convert_intermediate_physical_address_range_to_physical_address;
+
+use core::sync::atomic::{fence, Ordering};
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1 << 0;
+pub const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 1 << 2;
+
+pub const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1 << 0;
+
+const VIRTQ_DESC_SIZE: usize = 16;
+const VIRTQ_USED_ELEM_SIZE: usize = 8;
+/// The size of flags and idx of the available and used rings
+const VIRTQ_RING_HEADER_SIZE: usize = 4;
+
+/// The maximum number of the buffers in one descriptor chain
+pub const MAX_DESCRIPTOR_CHAIN_LENGTH: usize = 64;
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct VirtqDesc {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// The guest buffer described by a descriptor
+#[derive(Clone, Copy, Default)]
+pub struct VirtQueueBuffer {
+    /// Physical address translated by stage 2
+    pub physical_address: usize,
+    pub length: u32,
+    pub is_writable: bool,
+}
+
+/// The buffers of a descriptor chain, the readable buffers precede the writable ones
+pub struct DescriptorChain {
+    pub head: u16,
+    buffers: [VirtQueueBuffer; MAX_DESCRIPTOR_CHAIN_LENGTH],
+    number_of_buffers: usize,
+}
+
+impl DescriptorChain {
+    const fn new(head: u16) -> Self {
+        Self {
+            head,
+            buffers: [VirtQueueBuffer {
+                physical_address: 0,
+                length: 0,
+                is_writable: false,
+            }; MAX_DESCRIPTOR_CHAIN_LENGTH],
+            number_of_buffers: 0,
+        }
+    }
+
+    fn add_buffer(&mut self, descriptor: &VirtqDesc) -> Result<(), ()> {
+        let is_writable = (descriptor.flags & VIRTQ_DESC_F_WRITE) != 0;
+        if self.number_of_buffers >= MAX_DESCRIPTOR_CHAIN_LENGTH
+            || (!is_writable && self.buffers().iter().any(|b| b.is_writable))
+        {
+            return Err(());
+        }
+        let physical_address = if descriptor.length == 0 {
+            0
+        } else {
+            convert_intermediate_physical_address_range_to_physical_address(
+                descriptor.address as usize,
+                descriptor.length as usize,
+                is_writable,
+            )?
+        };
+        self.buffers[self.number_of_buffers] = VirtQueueBuffer {
+            physical_address,
+            length: descriptor.length,
+            is_writable,
+        };
+        self.number_of_buffers += 1;
+        Ok(())
+    }
+
+    pub fn buffers(&self) -> &[VirtQueueBuffer] {
+        &self.buffers[..self.number_of_buffers]
+    }
+
+    pub fn readable_buffers(&self) -> impl Iterator<Item = &VirtQueueBuffer> {
+        self.buffers().iter().filter(|b| !b.is_writable)
+    }
+
+    pub fn writable_buffers(&self) -> impl Iterator<Item = &VirtQueueBuffer> {
+        self.buffers().iter().filter(|b| b.is_writable)
+    }
+
+    pub fn readable_length(&self) -> usize {
+        self.readable_buffers().map(|b| b.length as usize).sum()
+    }
+
+    pub fn writable_length(&self) -> usize {
+        self.writable_buffers().map(|b| b.length as usize).sum()
+    }
+
+    /// Copy the data from the readable buffers
+    ///
+    /// # Arguments
+    /// * `offset` - the offset from the start of the readable buffers
+    /// * `data` - the destination
+    ///
+    /// # Result
+    /// The number of copied bytes
+    pub fn read_bytes(&self, mut offset: usize, data: &mut [u8]) -> usize {
+        let mut copied = 0;
+        for b in self.readable_buffers() {
+            if copied == data.len() {
+                break;
+            }
+            let length = b.length as usize;
+            if offset >= length {
+                offset -= length;
+                continue;
+            }
+            let size = (length - offset).min(data.len() - copied);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    (b.physical_address + offset) as *const u8,
+                    data[copied..].as_mut_ptr(),
+                    size,
+                )
+            };
+            copied += size;
+            offset = 0;
+        }
+        copied
+    }
+
+    /// Copy the data into the writable buffers
+    ///
+    /// # Arguments
+    /// * `offset` - the offset from the start of the writable buffers
+    /// * `data` - the source
+    ///
+    /// # Result
+    /// The number of copied bytes
+    pub fn write_bytes(&self, mut offset: usize, data: &[u8]) -> usize {
+        let mut copied = 0;
+        for b in self.writable_buffers() {
+            if copied == data.len() {
+                break;
+            }
+            let length = b.length as usize;
+            if offset >= length {
+                offset -= length;
+                continue;
+            }
+            let size = (length - offset).min(data.len() - copied);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[copied..].as_ptr(),
+                    (b.physical_address + offset) as *mut u8,
+                    size,
+                )
+            };
+            copied += size;
+            offset = 0;
+        }
+        copied
+    }
+}
+
+/// The split virtqueue which the device processes
+///
+/// It is created from the transport state each time the device accesses the queue.
+/// Call [`VirtQueue::notify`] after publishing the used buffers.
+pub struct VirtQueue<'a> {
+    transport: &'a mut VirtioMmioTransport,
+    queue_index: usize,
+    queue_size: u16,
+    descriptor_table: usize,
+    available_ring: usize,
+    used_ring: usize,
+    signalled_used_index: u16,
+}
+
+impl<'a> VirtQueue<'a> {
+    /// Translate the areas of the queue configured by the driver
+    pub fn new(transport: &'a mut VirtioMmioTransport, queue_index: usize) -> Result<Self, ()> {
+        let q = transport.queues.get(queue_index).ok_or(())?;
+        let queue_size = q.num as usize;
+        if !q.ready || queue_size == 0 {
+            return Err(());
+        }
+        let descriptor_table = convert_intermediate_physical_address_range_to_physical_address(
+            q.descriptor_area as usize,
+            VIRTQ_DESC_SIZE * queue_size,
+            false,
+        )?;
+        let available_ring = convert_intermediate_physical_address_range_to_physical_address(
+            q.driver_area as usize,
+            VIRTQ_RING_HEADER_SIZE + 2 * queue_size + 2,
+            false,
+        )?;
+        let used_ring = convert_intermediate_physical_address_range_to_physical_address(
+            q.device_area as usize,
+            VIRTQ_RING_HEADER_SIZE + VIRTQ_USED_ELEM_SIZE * queue_size + 2,
+            true,
+        )?;
+        let signalled_used_index = q.used_index;
+        Ok(Self {
+            transport,
+            queue_index,
+            queue_size: queue_size as u16,
+            descriptor_table,
+            available_ring,
+            used_ring,
+            signalled_used_index,
+        })
+    }
+
+    pub fn transport(&mut self) -> &mut VirtioMmioTransport {
+        self.transport
+    }
+
+    fn is_event_index_enabled(&self) -> bool {
+        self.transport.is_feature_negotiated(VIRTIO_F_EVENT_IDX)
+    }
+
+    fn read_u16(address: usize) -> u16 {
+        unsafe { core::ptr::read_volatile(address as *const u16) }
+    }
+
+    fn write_u16(address: usize, value: u16) {
+        unsafe { core::ptr::write_volatile(address as *mut u16, value) }
+    }
+
+    fn available_flags(&self) -> u16 {
+        Self::read_u16(self.available_ring)
+    }
+
+    fn available_index(&self) -> u16 {
+        Self::read_u16(self.available_ring + 2)
+    }
+
+    fn available_entry(&self, index: u16) -> u16 {
+        Self::read_u16(
+            self.available_ring + VIRTQ_RING_HEADER_SIZE + 2 * (index % self.queue_size) as usize,
+        )
+    }
+
+    /// used_event of the available ring (VIRTIO_F_EVENT_IDX)
+    fn used_event(&self) -> u16 {
+        Self::read_u16(self.available_ring + VIRTQ_RING_HEADER_SIZE + 2 * self.queue_size as usize)
+    }
+
+    /// avail_event of the used ring (VIRTIO_F_EVENT_IDX)
+    fn set_available_event(&self, index: u16) {
+        Self::write_u16(
+            self.used_ring
+                + VIRTQ_RING_HEADER_SIZE
+                + VIRTQ_USED_ELEM_SIZE * self.queue_size as usize,
+            index,
+        );
+    }
+
+    /// Take the next descriptor chain from the available ring
+    ///
+    /// If the driver has put a malformed chain, DEVICE_NEEDS_RESET is set and `None` is returned.
+    pub fn pop(&mut self) -> Option<DescriptorChain> {
+        let available_index = self.available_index();
+        let last_available_index = self.transport.queues[self.queue_index].last_available_index;
+        if available_index == last_available_index {
+            return None;
+        }
+        if available_index.wrapping_sub(last_available_index) > self.queue_size {
+            self.set_needs_reset();
+            return None;
+        }
+        /* Read the ring entry after idx */
+        fence(Ordering::Acquire);
+        let head = self.available_entry(last_available_index);
+        let next_available_index = last_available_index.wrapping_add(1);
+        self.transport.queues[self.queue_index].last_available_index = next_available_index;
+        if self.is_event_index_enabled() {
+            self.set_available_event(next_available_index);
+        }
+        match self.read_descriptor_chain(head) {
+            Ok(chain) => Some(chain),
+            Err(()) => {
+                self.set_needs_reset();
+                None
+            }
+        }
+    }
+
+    /// Give back the descriptor chains taken by [`VirtQueue::pop`] and not pushed yet
+    ///
+    /// # Arguments
+    /// * `count` - the number of the chains, they will be taken again in the same order
+    pub fn rewind(&mut self, count: u16) {
+        let q = &mut self.transport.queues[self.queue_index];
+        q.last_available_index = q.last_available_index.wrapping_sub(count);
+        if self.is_event_index_enabled() {
+            self.set_available_event(self.transport.queues[self.queue_index].last_available_index);
+        }
+    }
+
+    fn read_descriptor_chain(&self, head: u16) -> Result<DescriptorChain, ()> {
+        let mut chain = DescriptorChain::new(head);
+        let mut table = self.descriptor_table;
+        let mut table_size = self.queue_size as usize;
+        let mut index = head as usize;
+        let mut is_indirect = false;
+        loop {
+            if index >= table_size {
+                return Err(());
+            }
+            let descriptor = unsafe {
+                core::ptr::read_volatile((table + index * VIRTQ_DESC_SIZE) as *const VirtqDesc)
+            };
+            if (descriptor.flags & VIRTQ_DESC_F_INDIRECT) != 0 {
+                let length = descriptor.length as usize;
+                if is_indirect
+                    || (descriptor.flags & VIRTQ_DESC_F_NEXT) != 0
+                    || !self.transport.is_feature_negotiated(VIRTIO_F_INDIRECT_DESC)
+                    || length == 0
+                    || !length.is_multiple_of(VIRTQ_DESC_SIZE)
+                {
+                    return Err(());
+                }
+                table = convert_intermediate_physical_address_range_to_physical_address(
+                    descriptor.address as usize,
+                    length,
+                    false,
+                )?;
+                table_size = length / VIRTQ_DESC_SIZE;
+                index = 0;
+                is_indirect = true;
+                continue;
+            }
+            chain.add_buffer(&descriptor)?;
+            if (descriptor.flags & VIRTQ_DESC_F_NEXT) == 0 {
+                return Ok(chain);
+            }
+            index = descriptor.next as usize;
+        }
+    }
+
+    /// Return the descriptor chain to the driver
+    ///
+    /// # Arguments
+    /// * `head` - the head index of the chain
+    /// * `written_length` - the number of bytes written into the writable buffers
+    pub fn push(&mut self, head: u16, written_length: u32) {
+        let q = &mut self.transport.queues[self.queue_index];
+        let element = self.used_ring
+            + VIRTQ_RING_HEADER_SIZE
+            + VIRTQ_USED_ELEM_SIZE * (q.used_index % self.queue_size) as usize;
+        unsafe {
+            core::ptr::write_volatile(element as *mut u32, head as u32);
+            core::ptr::write_volatile((element + 4) as *mut u32, written_length);
+        }
+        /* Publish the element before idx */
+        fence(Ordering::Release);
+        q.used_index = q.used_index.wrapping_add(1);
+        Self::write_u16(self.used_ring + 2, q.used_index);
+    }
+
+    /// Raise the used buffer interrupt if the driver requires it
+    pub fn notify(&mut self) {
+        let new_index = self.transport.queues[self.queue_index].used_index;
+        let old_index = self.signalled_used_index;
+        if new_index == old_index {
+            return;
+        }
+        self.signalled_used_index = new_index;
+        fence(Ordering::SeqCst);
+        let need_interrupt = if self.is_event_index_enabled() {
+            new_index.wrapping_sub(self.used_event()).wrapping_sub(1)
+                < new_index.wrapping_sub(old_index)
+        } else {
+            (self.available_flags() & VIRTQ_AVAIL_F_NO_INTERRUPT) == 0
+        };
+        if need_interrupt {
+            self.transport.raise_used_buffer_interrupt();
+        }
+    }
+
+    fn set_needs_reset(&mut self) {
+        warn!("virtqueue {}: malformed descriptor chain", self.queue_index);
+        self.transport.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+        self.transport.raise_config_change_interrupt();
+    }
+}
@@ -0,0 +1,566 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Hypervisor Monitor
+//!
+//! The command shell on the hypervisor console, type `Ctrl-A` `m` to focus it.
+//! The commands are executed at the exit by the physical interrupt, and `pause` keeps the guest
+//! in the exit until `resume` is entered.
+//!
+
+use crate::console::mux::{self, HYPERVISOR_CONSOLE_ID};
+use crate::console::{self, ConsoleBackendType};
+use crate::cpu;
+use crate::exception::{self, esr::ExceptionClass, inject, Registers};
+use crate::frame_pool;
+use crate::log::{self, LogLevel};
+use crate::mmio::virtio_balloon;
+use crate::paging;
+use crate::stats;
+use crate::vm;
+
+const LINE_BUFFER_SIZE: usize = 128;
+const MAX_ARGUMENTS: usize = 4;
+const PROMPT: &str = "(monitor) ";
+/// The default and the maximum length of `read`
+const DEFAULT_DUMP_LENGTH: usize = 64;
+const MAX_DUMP_LENGTH: usize = 4096;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+struct Monitor {
+    line: [u8; LINE_BUFFER_SIZE],
+    line_length: usize,
+    is_paused: bool,
+    is_focused: bool,
+}
+
+static mut MONITOR: Monitor = Monitor {
+    line: [0; LINE_BUFFER_SIZE],
+    line_length: 0,
+    is_paused: false,
+    is_focused: false,
+};
+
+fn monitor() -> &'static mut Monitor {
+    unsafe { &mut *core::ptr::addr_of_mut!(MONITOR) }
+}
+
+struct Command {
+    name: &'static str,
+    arguments: &'static str,
+    description: &'static str,
+    handler: fn(&mut Monitor, &mut Registers, &[&str]) -> Result<(), ()>,
+}
+
+const COMMANDS: [Command; 14] = [
+    Command {
+        name: "help",
+        arguments: "",
+        description: "Show this message",
+        handler: help_command,
+    },
+    Command {
+        name: "pause",
+        arguments: "",
+        description: "Stop the VM",
+        handler: pause_command,
+    },
+    Command {
+        name: "resume",
+        arguments: "",
+        description: "Restart the VM",
+        handler: resume_command,
+    },
+    Command {
+        name: "regs",
+        arguments: "",
+        description: "Dump the registers of the vCPU",
+        handler: regs_command,
+    },
+    Command {
+        name: "stage2",
+        arguments: "",
+        description: "Dump the stage 2 mappings",
+        handler: stage2_command,
+    },
+    Command {
+        name: "read",
+        arguments: "<address> [length]",
+        description: "Dump the guest physical memory",
+        handler: read_command,
+    },
+    Command {
+        name: "write",
+        arguments: "<address> <value> [1|2|4|8]",
+        description: "Write the guest physical memory",
+        handler: write_command,
+    },
+    Command {
+        name: "devices",
+        arguments: "[reset|remove <id>]",
+        description: "List the MMIO devices and their state, reset them, or unmap one",
+        handler: devices_command,
+    },
+    Command {
+        name: "balloon",
+        arguments: "[<pages>|stats|hint]",
+        description: "Show or set the balloon target, or request the statistics or free page hints",
+        handler: balloon_command,
+    },
+    Command {
+        name: "inject",
+        arguments: "<irq|fiq|serror>",
+        description: "Make the guest take the exception to test its handler",
+        handler: inject_command,
+    },
+    Command {
+        name: "stats",
+        arguments: "[reset]",
+        description: "Show or clear the trap statistics",
+        handler: stats_command,
+    },
+    Command {
+        name: "trace",
+        arguments: "[on|off|clear|dump]",
+        description: "Control the exit trace, dump prints each record in hex",
+        handler: trace_command,
+    },
+    Command {
+        name: "log",
+        arguments: "[dump|clear|<module> <level>|<module> reset]",
+        description: "Show or set the log levels, module may be \"default\" or \"console\"",
+        handler: log_command,
+    },
+    Command {
+        name: "console",
+        arguments: "[uefi|pl011|memory|dump|clear]",
+        description: "Show or switch the console backend, dump prints the memory backend",
+        handler: console_command,
+    },
+];
+
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+/// Parse the number, hexadecimal if prefixed with "0x"
+fn parse_number(s: &str) -> Result<u64, ()> {
+    match s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).or(Err(())),
+        None => s.parse::<u64>().or(Err(())),
+    }
+}
+
+/// Process the input of the monitor
+///
+/// This is called on each VM exit by the physical interrupt.
+pub fn poll(registers: &mut Registers) {
+    let m = monitor();
+    m.process_input(registers);
+    while m.is_paused {
+        mux::poll_input();
+        m.process_input(registers);
+        core::hint::spin_loop();
+    }
+}
+
+impl Monitor {
+    fn process_input(&mut self, registers: &mut Registers) {
+        let is_focused = mux::get_focused_console() == HYPERVISOR_CONSOLE_ID;
+        if is_focused && !self.is_focused {
+            print!("{}", PROMPT);
+        }
+        self.is_focused = is_focused;
+
+        let mut c = [0u8; 1];
+        while mux::read(HYPERVISOR_CONSOLE_ID, &mut c) == 1 {
+            match c[0] {
+                b'\r' | b'\n' => {
+                    println!("");
+                    self.execute_line(registers);
+                    self.line_length = 0;
+                    print!("{}", PROMPT);
+                }
+                BACKSPACE | DELETE if self.line_length > 0 => {
+                    self.line_length -= 1;
+                    print!("\x08 \x08");
+                }
+                c if (c.is_ascii_graphic() || c == b' ') && self.line_length < LINE_BUFFER_SIZE => {
+                    self.line[self.line_length] = c;
+                    self.line_length += 1;
+                    print!("{}", c as char);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn execute_line(&mut self, registers: &mut Registers) {
+        let mut line = [0u8; LINE_BUFFER_SIZE];
+        let length = self.line_length;
+        line[..length].copy_from_slice(&self.line[..length]);
+        /* Only the printable characters are stored */
+        let line = core::str::from_utf8(&line[..length]).unwrap_or("");
+
+        let mut words = line.split_ascii_whitespace();
+        let Some(name) = words.next() else {
+            return;
+        };
+        let mut arguments = [""; MAX_ARGUMENTS];
+        let mut number_of_arguments = 0;
+        for word in words {
+            if number_of_arguments == MAX_ARGUMENTS {
+                println!("Too many arguments");
+                return;
+            }
+            arguments[number_of_arguments] = word;
+            number_of_arguments += 1;
+        }
+        let Some(command) = find_command(name) else {
+            println!("Unknown command: {}, type \"help\"", name);
+            return;
+        };
+        if (command.handler)(self, registers, &arguments[..number_of_arguments]).is_err() {
+            println!("Usage: {} {}", command.name, command.arguments);
+        }
+    }
+}
+
+fn help_command(_: &mut Monitor, _: &mut Registers, _: &[&str]) -> Result<(), ()> {
+    for c in COMMANDS.iter() {
+        println!("{:<8} {:<28} {}", c.name, c.arguments, c.description);
+    }
+    Ok(())
+}
+
+fn pause_command(m: &mut Monitor, _: &mut Registers, _: &[&str]) -> Result<(), ()> {
+    if !m.is_paused {
+        m.is_paused = true;
+        println!("The VM is paused.");
+    }
+    Ok(())
+}
+
+fn resume_command(m: &mut Monitor, _: &mut Registers, _: &[&str]) -> Result<(), ()> {
+    if m.is_paused {
+        m.is_paused = false;
+        println!("The VM is resumed.");
+    }
+    Ok(())
+}
+
+fn regs_command(_: &mut Monitor, registers: &mut Registers, _: &[&str]) -> Result<(), ()> {
+    exception::dump_guest_registers(registers);
+    Ok(())
+}
+
+fn stage2_command(_: &mut Monitor, _: &mut Registers, _: &[&str]) -> Result<(), ()> {
+    /* The contiguous descriptors with the same permission are shown as one range */
+    let mut range: Option<(usize, usize, usize, u64)> = None;
+    let print_range = |(ipa, pa, size, permission): (usize, usize, usize, u64)| {
+        println!(
+            "{:#014X} - {:#014X} => {:#014X} {}{}",
+            ipa,
+            ipa + size - 1,
+            pa,
+            if (permission & 0b01) != 0 { "r" } else { "-" },
+            if (permission & 0b10) != 0 { "w" } else { "-" }
+        )
+    };
+    paging::walk_stage2_mappings(&mut |ipa, pa, size, permission| match range.as_mut() {
+        Some((r_ipa, r_pa, r_size, r_permission))
+            if *r_ipa + *r_size == ipa && *r_pa + *r_size == pa && *r_permission == permission =>
+        {
+            *r_size += size;
+        }
+        _ => {
+            if let Some(r) = range.replace((ipa, pa, size, permission)) {
+                print_range(r);
+            }
+        }
+    });
+    if let Some(r) = range {
+        print_range(r);
+    }
+    Ok(())
+}
+
+fn read_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    let (address, length) = match arguments {
+        [address] => (parse_number(address)? as usize, DEFAULT_DUMP_LENGTH),
+        [address, length] => (
+            parse_number(address)? as usize,
+            (parse_number(length)? as usize).min(MAX_DUMP_LENGTH),
+        ),
+        _ => return Err(()),
+    };
+    let Some(end_address) = address.checked_add(length) else {
+        println!("{:#X} + {:#X} is out of the address space", address, length);
+        return Ok(());
+    };
+    for line_address in (address..end_address).step_by(16) {
+        print!("{:#014X}:", line_address);
+        for i in line_address..line_address.saturating_add(16).min(end_address) {
+            let Ok((physical_address, _)) =
+                paging::convert_intermediate_physical_address_to_physical_address(i)
+            else {
+                println!(" (not mapped)");
+                return Ok(());
+            };
+            print!(" {:02X}", unsafe {
+                core::ptr::read_volatile(physical_address as *const u8)
+            });
+        }
+        println!("");
+    }
+    Ok(())
+}
+
+fn write_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    let (address, value, size) = match arguments {
+        [address, value] => (parse_number(address)? as usize, parse_number(value)?, 4),
+        [address, value, size] => (
+            parse_number(address)? as usize,
+            parse_number(value)?,
+            parse_number(size)? as usize,
+        ),
+        _ => return Err(()),
+    };
+    if !matches!(size, 1 | 2 | 4 | 8) || (address & (size - 1)) != 0 {
+        println!("The size must be 1, 2, 4 or 8 and the address must be aligned");
+        return Ok(());
+    }
+    let Ok((physical_address, _)) =
+        paging::convert_intermediate_physical_address_to_physical_address(address)
+    else {
+        println!("{:#X} is not mapped", address);
+        return Ok(());
+    };
+    unsafe {
+        match size {
+            1 => core::ptr::write_volatile(physical_address as *mut u8, value as u8),
+            2 => core::ptr::write_volatile(physical_address as *mut u16, value as u16),
+            4 => core::ptr::write_volatile(physical_address as *mut u32, value as u32),
+            _ => core::ptr::write_volatile(physical_address as *mut u64, value),
+        }
+    }
+    Ok(())
+}
+
+fn devices_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    let bus = &mut vm::current().mmio_bus;
+    match arguments {
+        [] => {}
+        ["reset"] => {
+            bus.reset_devices();
+            return Ok(());
+        }
+        ["remove", id] => {
+            if bus.remove_device(parse_number(id)? as usize).is_none() {
+                println!("No device: {}", id);
+            }
+            return Ok(());
+        }
+        _ => return Err(()),
+    }
+    bus.for_each_device(&mut |id, base_address, size, device| {
+        println!(
+            "{:>2}: {:#014X} - {:#014X} {}",
+            id,
+            base_address,
+            base_address + size - 1,
+            device.name()
+        );
+        device.dump_state();
+    });
+    Ok(())
+}
+
+fn balloon_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    match arguments {
+        [] => {}
+        ["stats"] => {
+            if virtio_balloon::request_memory_statistics().is_err() {
+                println!("The driver has not queued the statistics buffer");
+            }
+            return Ok(());
+        }
+        ["hint"] => {
+            match virtio_balloon::request_free_page_hints() {
+                Ok(command_id) => println!("Requested the free page hints: {}", command_id),
+                Err(_) => println!("The driver does not support the free page hints"),
+            }
+            return Ok(());
+        }
+        [pages] => {
+            let pages = u32::try_from(parse_number(pages)?).or(Err(()))?;
+            if virtio_balloon::set_balloon_target(pages).is_err() {
+                println!("virtio-balloon is not available");
+            }
+            return Ok(());
+        }
+        _ => return Err(()),
+    }
+    let Some(s) = virtio_balloon::get_balloon_statistics() else {
+        println!("virtio-balloon is not available");
+        return Ok(());
+    };
+    println!(
+        "Target: {} pages, Actual: {} pages, Inflated: {} pages, Failed: {} pages",
+        s.target_pages, s.actual_pages, s.inflated_pages, s.failed_pages
+    );
+    println!(
+        "Free Page Hints: {} pages (Completed Command: {})",
+        s.hinted_pages, s.completed_hint_command_id
+    );
+    println!("Free Frames: {}", frame_pool::get_number_of_free_frames());
+    for (tag, value) in s.memory_statistics.iter().enumerate() {
+        if let Some(value) = value {
+            println!(
+                "  {}: {}",
+                virtio_balloon::get_memory_statistics_name(tag as u16),
+                value
+            );
+        }
+    }
+    Ok(())
+}
+
+fn inject_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    let result = match arguments {
+        ["irq"] => inject::inject_irq(),
+        ["fiq"] => inject::inject_fiq(),
+        ["serror"] => inject::inject_serror(inject::SERROR_ISS_UNCONTAINABLE),
+        _ => return Err(()),
+    };
+    if result.is_err() {
+        println!("The exception is masked by the guest");
+    }
+    Ok(())
+}
+
+fn stats_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    match arguments {
+        [] => {}
+        ["reset"] => {
+            stats::reset_statistics();
+            return Ok(());
+        }
+        _ => return Err(()),
+    }
+    let s = stats::get_exit_statistics();
+    println!("Counter Frequency: {} Hz", cpu::get_cntfrq_el0());
+    println!("Total Exits: {}", s.total_exits);
+    println!("IRQ: {} ({} ticks)", s.irq_exits, s.irq_exit_ticks);
+    for (ec, count) in s.synchronous_exits.iter().enumerate() {
+        if *count != 0 {
+            println!(
+                "{:#04X} {}: {} ({} ticks)",
+                ec,
+                ExceptionClass::from_ec(ec as u8).name(),
+                count,
+                s.synchronous_exit_ticks[ec]
+            );
+        }
+    }
+    println!("MMIO:");
+    for c in stats::get_mmio_access_counters().iter().flatten() {
+        println!(
+            "  {:#014X} + {:#06X}: R {} W {} ({} ticks)",
+            c.base_address, c.offset, c.reads, c.writes, c.ticks
+        );
+    }
+    println!("System Registers:");
+    for c in stats::get_system_register_counters().iter().flatten() {
+        println!(
+            "  {}: R {} W {} ({} ticks)",
+            c.register, c.reads, c.writes, c.ticks
+        );
+    }
+    Ok(())
+}
+
+fn trace_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    match arguments {
+        ["on"] => stats::set_trace_enabled(true),
+        ["off"] => stats::set_trace_enabled(false),
+        ["clear"] => stats::clear_trace(),
+        ["dump"] => {
+            /* The bytes of the records in memory order, `xxd -r -p` restores the binary */
+            stats::for_each_trace_record(&mut |record| {
+                let bytes = unsafe {
+                    core::slice::from_raw_parts(
+                        record as *const _ as *const u8,
+                        stats::TRACE_RECORD_SIZE,
+                    )
+                };
+                for b in bytes {
+                    print!("{:02x}", b);
+                }
+                println!("");
+            });
+        }
+        [] => {}
+        _ => return Err(()),
+    }
+    let (length, lost_records) = stats::get_trace_status();
+    println!(
+        "Trace: {}, {} records, {} lost",
+        if stats::is_trace_enabled() {
+            "on"
+        } else {
+            "off"
+        },
+        length,
+        lost_records
+    );
+    Ok(())
+}
+
+fn log_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    match arguments {
+        [] => log::for_each_filter(&mut |module, level| println!("{}: {}", module, level.name())),
+        ["dump"] => log::dump_log_buffer(),
+        ["clear"] => log::clear_log_buffer(),
+        [module, "reset"] => {
+            if log::remove_module_level(module).is_err() {
+                println!("No filter for {}", module);
+            }
+        }
+        ["default", level] => log::set_default_level(LogLevel::from_name(level).ok_or(())?),
+        ["console", level] => log::set_console_level(LogLevel::from_name(level).ok_or(())?),
+        [module, level] => {
+            if log::set_module_level(module, LogLevel::from_name(level).ok_or(())?).is_err() {
+                println!("Too many filters or too long module name");
+            }
+        }
+        _ => return Err(()),
+    }
+    Ok(())
+}
+
+fn console_command(_: &mut Monitor, _: &mut Registers, arguments: &[&str]) -> Result<(), ()> {
+    match arguments {
+        [] => println!("{}", console::get_backend_name(console::get_backend())),
+        ["dump"] => {
+            if console::dump_memory_console().is_err() {
+                println!("The memory backend is selected now");
+            }
+        }
+        ["clear"] => console::clear_memory_console(),
+        [name] => {
+            if console::set_backend(ConsoleBackendType::from_name(name).ok_or(())?).is_err() {
+                println!("{} is not available", name);
+            }
+        }
+        _ => return Err(()),
+    }
+    Ok(())
+}
@@ -0,0 +1,253 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Software Ethernet Switch
+//!
+//! The virtual NICs of the guests are connected to this learning switch.
+//! The unicast frames to a learned MAC address are forwarded to its port, and the others are
+//! flooded to all ports except the source.
+//! The forwarded frames are queued to the port and the port is notified, then the port takes
+//! them by [`receive`] when it is not processing the other ports.
+//!
+
+pub const MAX_SWITCH_PORTS: usize = 8;
+const MAX_FORWARDING_ENTRIES: usize = 64;
+pub const ETHERNET_ADDRESS_LENGTH: usize = 6;
+const ETHERNET_HEADER_LENGTH: usize = 14;
+/// Ethernet frame with a VLAN tag, without FCS
+pub const MAX_FRAME_LENGTH: usize = 1518;
+/// The frames which each port can hold until they are received
+const MAX_QUEUED_FRAMES: usize = 8;
+
+pub type MacAddress = [u8; ETHERNET_ADDRESS_LENGTH];
+
+/// The checksum which the sender left to be calculated
+///
+/// The field at `start + offset` holds the partial checksum (of the pseudo header), and the
+/// checksum from `start` to the end of the frame must be added to it.
+#[derive(Clone, Copy, Debug)]
+pub struct PartialChecksum {
+    pub start: u16,
+    pub offset: u16,
+}
+
+/// Notify the port that the frames were queued
+///
+/// This is called while the sender is transmitting, so the port must not receive the frames
+/// in this function, but request to call [`receive`] later.
+///
+/// # Arguments
+/// * `port_id` - the id returned by [`register_switch_port`]
+pub type SwitchPortNotifier = fn(port_id: usize);
+
+/// The result of passing the queued frame to the port by [`receive`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReceiveStatus {
+    Received,
+    /// The port could not accept the frame, it is discarded
+    Dropped,
+    /// The port has no room now, the frame is kept in the queue
+    NoBuffer,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SwitchPortStatistics {
+    pub transmitted_frames: u64,
+    pub received_frames: u64,
+    pub dropped_frames: u64,
+}
+
+#[derive(Clone, Copy)]
+struct QueuedFrame {
+    data: [u8; MAX_FRAME_LENGTH],
+    length: usize,
+    checksum: Option<PartialChecksum>,
+}
+
+struct SwitchPort {
+    notifier: SwitchPortNotifier,
+    statistics: SwitchPortStatistics,
+    queued_frames: [Option<QueuedFrame>; MAX_QUEUED_FRAMES],
+    /// The index of the oldest queued frame
+    queue_head: usize,
+}
+
+#[derive(Clone, Copy)]
+struct ForwardingEntry {
+    mac_address: MacAddress,
+    port_id: usize,
+}
+
+struct Switch {
+    ports: [Option<SwitchPort>; MAX_SWITCH_PORTS],
+    forwarding_table: [Option<ForwardingEntry>; MAX_FORWARDING_ENTRIES],
+    next_victim: usize,
+}
+
+static mut SWITCH: Switch = Switch {
+    ports: [const { None }; MAX_SWITCH_PORTS],
+    forwarding_table: [None; MAX_FORWARDING_ENTRIES],
+    next_victim: 0,
+};
+
+fn switch() -> &'static mut Switch {
+    unsafe { &mut *core::ptr::addr_of_mut!(SWITCH) }
+}
+
+/// Connect the port to the switch
+///
+/// # Result
+/// If succeeded, Ok(port id), otherwise Err(())
+pub fn register_switch_port(notifier: SwitchPortNotifier) -> Result<usize, ()> {
+    let (port_id, entry) = switch()
+        .ports
+        .iter_mut()
+        .enumerate()
+        .find(|(_, p)| p.is_none())
+        .ok_or(())?;
+    *entry = Some(SwitchPort {
+        notifier,
+        statistics: SwitchPortStatistics::default(),
+        queued_frames: [None; MAX_QUEUED_FRAMES],
+        queue_head: 0,
+    });
+    Ok(port_id)
+}
+
+pub fn get_switch_port_statistics(port_id: usize) -> Option<SwitchPortStatistics> {
+    switch().ports.get(port_id)?.as_ref().map(|p| p.statistics)
+}
+
+fn learn(source: &MacAddress, port_id: usize) {
+    if (source[0] & 1) != 0 {
+        /* Multicast address must not be the source */
+        return;
+    }
+    let s = switch();
+    if let Some(e) = s
+        .forwarding_table
+        .iter_mut()
+        .flatten()
+        .find(|e| e.mac_address == *source)
+    {
+        e.port_id = port_id;
+        return;
+    }
+    let entry = if let Some(e) = s.forwarding_table.iter_mut().find(|e| e.is_none()) {
+        e
+    } else {
+        let victim = s.next_victim;
+        s.next_victim = (victim + 1) % MAX_FORWARDING_ENTRIES;
+        &mut s.forwarding_table[victim]
+    };
+    *entry = Some(ForwardingEntry {
+        mac_address: *source,
+        port_id,
+    });
+}
+
+fn lookup(destination: &MacAddress) -> Option<usize> {
+    if (destination[0] & 1) != 0 {
+        return None;
+    }
+    switch()
+        .forwarding_table
+        .iter()
+        .flatten()
+        .find(|e| e.mac_address == *destination)
+        .map(|e| e.port_id)
+}
+
+/// Queue the frame to the port and notify it
+fn deliver(port_id: usize, frame: &[u8], checksum: Option<PartialChecksum>) {
+    let Some(Some(port)) = switch().ports.get_mut(port_id) else {
+        return;
+    };
+    let Some(entry) = (0..MAX_QUEUED_FRAMES)
+        .map(|i| (port.queue_head + i) % MAX_QUEUED_FRAMES)
+        .find(|i| port.queued_frames[*i].is_none())
+    else {
+        port.statistics.dropped_frames += 1;
+        return;
+    };
+    let mut queued_frame = QueuedFrame {
+        data: [0; MAX_FRAME_LENGTH],
+        length: frame.len(),
+        checksum,
+    };
+    queued_frame.data[..frame.len()].copy_from_slice(frame);
+    port.queued_frames[entry] = Some(queued_frame);
+    let notifier = port.notifier;
+    notifier(port_id);
+}
+
+/// Pass the queued frames to the port from the oldest
+///
+/// # Arguments
+/// * `port_id` - the port which receives the frames
+/// * `receiver` - called with each frame and the checksum which is not calculated yet,
+///   the frames are passed until it returns [`ReceiveStatus::NoBuffer`]
+pub fn receive(
+    port_id: usize,
+    receiver: &mut dyn FnMut(&[u8], Option<PartialChecksum>) -> ReceiveStatus,
+) {
+    let Some(Some(port)) = switch().ports.get_mut(port_id) else {
+        return;
+    };
+    while let Some(frame) = &port.queued_frames[port.queue_head] {
+        match receiver(&frame.data[..frame.length], frame.checksum) {
+            ReceiveStatus::Received => port.statistics.received_frames += 1,
+            ReceiveStatus::Dropped => port.statistics.dropped_frames += 1,
+            ReceiveStatus::NoBuffer => break,
+        }
+        port.queued_frames[port.queue_head] = None;
+        port.queue_head = (port.queue_head + 1) % MAX_QUEUED_FRAMES;
+    }
+}
+
+/// Discard the frames queued to the port
+pub fn flush_port(port_id: usize) {
+    if let Some(Some(port)) = switch().ports.get_mut(port_id) {
+        port.queued_frames = [None; MAX_QUEUED_FRAMES];
+        port.queue_head = 0;
+    }
+}
+
+/// Send the frame from the port
+///
+/// # Arguments
+/// * `source_port_id` - the port which sends the frame
+/// * `frame` - the Ethernet frame without FCS
+/// * `checksum` - the checksum which is not calculated yet
+pub fn transmit(source_port_id: usize, frame: &[u8], checksum: Option<PartialChecksum>) {
+    let Some(Some(port)) = switch().ports.get_mut(source_port_id) else {
+        return;
+    };
+    if frame.len() < ETHERNET_HEADER_LENGTH || frame.len() > MAX_FRAME_LENGTH {
+        port.statistics.dropped_frames += 1;
+        return;
+    }
+    port.statistics.transmitted_frames += 1;
+    let destination: MacAddress = frame[0..ETHERNET_ADDRESS_LENGTH].try_into().unwrap();
+    let source: MacAddress = frame[ETHERNET_ADDRESS_LENGTH..(2 * ETHERNET_ADDRESS_LENGTH)]
+        .try_into()
+        .unwrap();
+    learn(&source, source_port_id);
+
+    match lookup(&destination) {
+        Some(port_id) if port_id == source_port_id => {}
+        Some(port_id) => deliver(port_id, frame, checksum),
+        None => {
+            for port_id in 0..MAX_SWITCH_PORTS {
+                if port_id != source_port_id {
+                    deliver(port_id, frame, checksum);
+                }
+            }
+        }
+    }
+}
@@ -64,8 +64,8 @@ pub const MEMORY_PERMISSION_EXECUTABLE_BIT: u8 = 2;
 pub const VTTBR_BADDR: u64 = ((1 << 47) - 1) & !1;
 
 impl TableEntry {
-    const TABLE_ADDRESS_MASK: u64 = ((1 << 52) - 1) & (PAGE_SIZE as u64 - 1);
-    const OUTPUT_ADDRESS_MASK: u64 = ((1 << 50) - 1) & (PAGE_SIZE as u64 - 1);
+    const TABLE_ADDRESS_MASK: u64 = ((1 << 48) - 1) & !(PAGE_SIZE as u64 - 1);
+    const OUTPUT_ADDRESS_MASK: u64 = ((1 << 48) - 1) & !(PAGE_SIZE as u64 - 1);
     const AF_OFFSET: u64 = 10;
     const AF: u64 = 1 << Self::AF_OFFSET;
     const SH_OFFSET: u64 = 8;
@@ -116,6 +116,14 @@ impl TableEntry {
         (self.0 & Self::TABLE_ADDRESS_MASK) as usize
     }
 
+    pub const fn get_output_address(&self) -> usize {
+        (self.0 & Self::OUTPUT_ADDRESS_MASK) as usize
+    }
+
+    pub const fn get_permission(&self) -> u64 {
+        (self.0 & Self::S2AP) >> Self::S2AP_OFFSET
+    }
+
     pub fn set_output_address(&mut self, output_address: usize) {
         self.0 = (self.0 & !Self::OUTPUT_ADDRESS_MASK) | (output_address as u64) | Self::AF;
     }
@@ -151,21 +159,24 @@ fn _map_address_stage2(
     num_of_entries: usize,
 ) -> Result<(), ()> {
     let shift_level = 12 + 9 * (3 - table_level as usize);
-    println!("table address: {:#X}", table_address);
-    println!("shift_level: {shift_level}");
+    trace!(
+        "table address: {:#X}, shift_level: {shift_level}",
+        table_address
+    );
     let table_index = (*virtual_address >> shift_level) & (num_of_entries - 1);
     let table = unsafe {
         &mut *core::ptr::slice_from_raw_parts_mut(table_address as *mut TableEntry, num_of_entries)
     };
 
     if table_level == 3 {
-        println!("mapping: {:#X} ~ {:#X} => {:#X} ~ {:#X}",
-            virtual_address, 
-            *virtual_address as usize + *remaining_size, 
-            physical_address, 
+        debug!(
+            "mapping: {:#X} ~ {:#X} => {:#X} ~ {:#X}",
+            virtual_address,
+            *virtual_address as usize + *remaining_size,
+            physical_address,
             *physical_address as usize + *remaining_size,
         );
-        println!("level 3: {:#X}", table_address);
+        trace!("level 3: {:#X}", table_address);
         for e in table[table_index..num_of_entries].iter_mut() {
             e.init();
             e.set_output_address(*physical_address);
@@ -191,7 +202,7 @@ fn _map_address_stage2(
             && (*virtual_address & mask) == 0
         {
             /* ブロックエントリ */
-            println!(
+            debug!(
                 "block entry: {:#X} ~ {:#X} => {:#X} ~ {:#X}",
                 *virtual_address,
                 *virtual_address as u64 + block_size as u64,
@@ -215,7 +226,7 @@ fn _map_address_stage2(
         let mut next_table_address = e.get_next_table_address();
         if !e.is_table_descriptor() {
             next_table_address = allocate_memory(1, Some(12))?;
-            println!("next table address: {:#X}", next_table_address);
+            trace!("next table address: {:#X}", next_table_address);
             for n in unsafe {
                 &mut *core::ptr::slice_from_raw_parts_mut(
                     next_table_address as *mut TableEntry,
@@ -228,9 +239,13 @@ fn _map_address_stage2(
             e.set_output_address(next_table_address);
             e.validate_as_table_descriptor();
         }
-        println!("table index: {:#X}\nlevel: {:#X}", table_index, table_level);
-        println!("num_of_entries: {:#X}", num_of_entries);
-        println!("next table {:#X}", next_table_address);
+        trace!(
+            "table index: {:#X}, level: {:#X}, num_of_entries: {:#X}, next table: {:#X}",
+            table_index,
+            table_level,
+            num_of_entries,
+            next_table_address
+        );
         _map_address_stage2(
             physical_address,
             virtual_address,
@@ -255,7 +270,7 @@ pub fn map_address_stage2(
     is_writable: bool,
 ) -> Result<(), ()> {
     if (map_size & ((1usize << PAGE_SHIFT) - 1)) != 0 {
-        println!("Map size is not aligned.");
+        error!("Map size is not aligned.");
         return Err(());
     }
     let page_table_address = (get_vttbr_el2() & VTTBR_BADDR) as usize;
@@ -269,8 +284,8 @@ pub fn map_address_stage2(
         0b11 => 3,
         _ => unreachable!(),
     };
-    println!("get table level {:#X}", table_level);
-    println!(
+    trace!("get table level {:#X}", table_level);
+    trace!(
         "first num_of_entries: {:#X}",
         number_of_concatenated_page_tables(t0sz, table_level) * 512
     );
@@ -287,6 +302,247 @@ pub fn map_address_stage2(
     Ok(())
 }
 
+/// Get the first level and the number of entries of the stage 2 translation table
+fn get_stage2_first_level() -> (usize, i8, usize) {
+    let page_table_address = (get_vttbr_el2() & VTTBR_BADDR) as usize;
+    let vtcr_el2 = get_vtcr_el2();
+    let sl0 = ((vtcr_el2 & VTCR_EL2_SL0) >> VTCR_EL2_SL0_BITS_OFFSET) as u8;
+    let t0sz = ((vtcr_el2 & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET) as u8;
+    let table_level: i8 = match sl0 {
+        0b00 => 2,
+        0b01 => 1,
+        0b10 => 0,
+        0b11 => 3,
+        _ => unreachable!(),
+    };
+    (
+        page_table_address,
+        table_level,
+        number_of_concatenated_page_tables(t0sz, table_level) * 512,
+    )
+}
+
+/// Check if the address is inside the input address range of the stage 2 translation
+///
+/// The index of the first level table is masked by the number of the entries, so the address
+/// beyond the range must be rejected before walking, otherwise it aliases the lower address.
+fn is_valid_stage2_input_address(intermediate_physical_address: usize) -> bool {
+    let t0sz = (get_vtcr_el2() & VTCR_EL2_T0SZ) >> VTCR_EL2_T0SZ_BITS_OFFSET;
+    (intermediate_physical_address as u64) >> (64 - t0sz) == 0
+}
+
+/// Convert the intermediate physical address of EL1 to the physical address
+///
+/// This function walks the stage 2 translation table by software.
+///
+/// # Arguments
+/// * `intermediate_physical_address` - the address to convert
+///
+/// # Result
+/// If mapped, returns Ok((physical_address, permission)), otherwise Err(())
+/// `permission` is the value of S2AP (bit 0: readable, bit 1: writable)
+pub fn convert_intermediate_physical_address_to_physical_address(
+    intermediate_physical_address: usize,
+) -> Result<(usize, u64), ()> {
+    if !is_valid_stage2_input_address(intermediate_physical_address) {
+        return Err(());
+    }
+    let (mut table_address, mut table_level, mut num_of_entries) = get_stage2_first_level();
+    loop {
+        let shift_level = 12 + 9 * (3 - table_level as usize);
+        let table_index = (intermediate_physical_address >> shift_level) & (num_of_entries - 1);
+        let entry = unsafe { &*(table_address as *const TableEntry).add(table_index) };
+        if !entry.is_validated() {
+            return Err(());
+        }
+        if table_level == 3 || entry.is_block_descriptor() {
+            let offset = intermediate_physical_address & ((1 << shift_level) - 1);
+            return Ok((entry.get_output_address() + offset, entry.get_permission()));
+        }
+        table_address = entry.get_next_table_address();
+        table_level += 1;
+        num_of_entries = 512;
+    }
+}
+
+/// Walk the stage 2 translation table
+///
+/// # Arguments
+/// * `f` - called with (intermediate physical address, physical address, size, permission)
+///   of each valid page or block descriptor
+pub fn walk_stage2_mappings(f: &mut dyn FnMut(usize, usize, usize, u64)) {
+    let (table_address, table_level, num_of_entries) = get_stage2_first_level();
+    walk_stage2_table(table_address, table_level, num_of_entries, 0, f);
+}
+
+fn walk_stage2_table(
+    table_address: usize,
+    table_level: i8,
+    num_of_entries: usize,
+    base_address: usize,
+    f: &mut dyn FnMut(usize, usize, usize, u64),
+) {
+    let shift_level = 12 + 9 * (3 - table_level as usize);
+    for table_index in 0..num_of_entries {
+        let entry = unsafe { &*(table_address as *const TableEntry).add(table_index) };
+        if !entry.is_validated() {
+            continue;
+        }
+        let intermediate_physical_address = base_address + (table_index << shift_level);
+        if table_level == 3 || entry.is_block_descriptor() {
+            f(
+                intermediate_physical_address,
+                entry.get_output_address(),
+                1 << shift_level,
+                entry.get_permission(),
+            );
+        } else {
+            walk_stage2_table(
+                entry.get_next_table_address(),
+                table_level + 1,
+                512,
+                intermediate_physical_address,
+                f,
+            );
+        }
+    }
+}
+
+/// Replace the block descriptor with the table which maps the same range by the next level
+fn split_stage2_block(entry: &mut TableEntry, table_level: i8) -> Result<(), ()> {
+    let next_table_address = crate::frame_pool::allocate_frame()?;
+    let child_size = 1usize << (12 + 9 * (3 - (table_level + 1) as usize));
+    let attributes = entry.0 & !TableEntry::OUTPUT_ADDRESS_MASK & !0b11;
+    let output_address = entry.get_output_address();
+    for (i, e) in unsafe {
+        &mut *core::ptr::slice_from_raw_parts_mut(next_table_address as *mut TableEntry, 512)
+    }
+    .iter_mut()
+    .enumerate()
+    {
+        e.0 = attributes | (output_address + i * child_size) as u64;
+        if table_level + 1 == 3 {
+            e.validate_as_level3_descriptor();
+        } else {
+            e.validate_as_block_descriptor();
+        }
+    }
+    /* Break-before-make */
+    entry.init();
+    flush_tlb_el1();
+    entry.set_output_address(next_table_address);
+    entry.validate_as_table_descriptor();
+    Ok(())
+}
+
+/// Get the level 3 descriptor of the intermediate physical address
+///
+/// The block descriptors on the way are split, and the missing tables are allocated.
+/// The tables are taken from [`crate::frame_pool`], so that this can be used after
+/// ExitBootServices.
+fn get_stage2_level3_entry(
+    intermediate_physical_address: usize,
+) -> Result<&'static mut TableEntry, ()> {
+    if !is_valid_stage2_input_address(intermediate_physical_address) {
+        return Err(());
+    }
+    let (mut table_address, mut table_level, mut num_of_entries) = get_stage2_first_level();
+    loop {
+        let shift_level = 12 + 9 * (3 - table_level as usize);
+        let table_index = (intermediate_physical_address >> shift_level) & (num_of_entries - 1);
+        let entry = unsafe { &mut *(table_address as *mut TableEntry).add(table_index) };
+        if table_level == 3 {
+            return Ok(entry);
+        }
+        if entry.is_block_descriptor() {
+            split_stage2_block(entry, table_level)?;
+        } else if !entry.is_validated() {
+            let next_table_address = crate::frame_pool::allocate_frame()?;
+            entry.init();
+            entry.set_output_address(next_table_address);
+            entry.validate_as_table_descriptor();
+        }
+        table_address = entry.get_next_table_address();
+        table_level += 1;
+        num_of_entries = 512;
+    }
+}
+
+/// Remove the page from the stage 2 translation
+///
+/// # Result
+/// If the page was mapped, Ok(physical address of the page), otherwise Err(())
+pub fn unmap_page_stage2(intermediate_physical_address: usize) -> Result<usize, ()> {
+    let entry = get_stage2_level3_entry(intermediate_physical_address & PAGE_MASK)?;
+    if !entry.is_validated() {
+        return Err(());
+    }
+    let physical_address = entry.get_output_address();
+    entry.init();
+    flush_tlb_el1();
+    Ok(physical_address)
+}
+
+/// Map the page by the stage 2 translation, this can be used after ExitBootServices
+///
+/// The page must not be mapped currently.
+///
+/// # Arguments
+/// * `physical_address` - the page to map
+/// * `intermediate_physical_address` - the address seen from EL1
+/// * `permission` - the value of S2AP (bit 0: readable, bit 1: writable)
+pub fn map_page_stage2(
+    physical_address: usize,
+    intermediate_physical_address: usize,
+    permission: u64,
+) -> Result<(), ()> {
+    let entry = get_stage2_level3_entry(intermediate_physical_address & PAGE_MASK)?;
+    if entry.is_validated() {
+        /* The frame mapped currently would be leaked */
+        return Err(());
+    }
+    entry.set_output_address(physical_address & PAGE_MASK);
+    entry.set_permission(permission);
+    entry.set_memory_attribute_write_back();
+    entry.set_shareability(Shareability::InterShareable);
+    entry.validate_as_level3_descriptor();
+    flush_tlb_el1();
+    Ok(())
+}
+
+/// Translate the intermediate physical address range which must be mapped contiguously
+///
+/// # Arguments
+/// * `intermediate_physical_address` - the start of the range
+/// * `size` - the size of the range
+/// * `need_write` - check that the range is writable
+///
+/// # Result
+/// The physical address of `intermediate_physical_address`
+pub fn convert_intermediate_physical_address_range_to_physical_address(
+    intermediate_physical_address: usize,
+    size: usize,
+    need_write: bool,
+) -> Result<usize, ()> {
+    let (physical_address, _) =
+        convert_intermediate_physical_address_to_physical_address(intermediate_physical_address)?;
+    let first_page = intermediate_physical_address >> PAGE_SHIFT;
+    let last_page = intermediate_physical_address
+        .checked_add(size.max(1) - 1)
+        .ok_or(())?
+        >> PAGE_SHIFT;
+    for page in first_page..=last_page {
+        let (p, permission) =
+            convert_intermediate_physical_address_to_physical_address(page << PAGE_SHIFT)?;
+        if p != ((physical_address >> PAGE_SHIFT) + (page - first_page)) << PAGE_SHIFT
+            || (need_write && (permission & (1 << MEMORY_PERMISSION_WRITABLE_BIT)) == 0)
+        {
+            return Err(());
+        }
+    }
+    Ok(physical_address)
+}
+
 pub fn setup_stage_2_translation() -> Result<(), ()> {
     let ps = get_id_aa64mmfr0_el1() & ID_AA64MMFR0_EL1_PARANGE;
     let (t0sz, table_level) = match ps {
@@ -320,7 +576,7 @@ pub fn setup_stage_2_translation() -> Result<(), ()> {
         | (0b11 << VTCR_EL2_IRG0_BITS_OFFSET)
         | (sl0 << VTCR_EL2_SL0_BITS_OFFSET)
         | (t0sz << VTCR_EL2_T0SZ_BITS_OFFSET);
-    println!("set table address: {:#X}", table_address);
+    debug!("set table address: {:#X}", table_address);
     unsafe {
         set_vtcr_el2(vtcr_el2);
         set_vttbr_el2(table_address as u64);
@@ -348,7 +604,7 @@ fn allocate_page_table_for_stage_2(
     match allocate_memory(number_of_tables as usize, Some(alignment)) {
         Ok(address) => Ok(address),
         Err(err) => {
-            println!("Failed to allocate the page table: {:?}", err);
+            error!("Failed to allocate the page table: {:?}", err);
             Err(())
         }
     }
@@ -0,0 +1,147 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Random Number Generator
+//!
+//! RNDR is used if FEAT_RNG is implemented. Otherwise, the bytes are generated by
+//! the ChaCha20 based DRBG which replaces its key after each request (fast key erasure).
+//!
+
+use crate::cpu::{get_cntpct_el0, get_id_aa64isar0_el1, get_rndr, ID_AA64ISAR0_EL1_RNDR};
+
+const CHACHA20_KEY_WORDS: usize = 8;
+const CHACHA20_BLOCK_SIZE: usize = 64;
+/// "expand 32-byte k"
+const CHACHA20_CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+/// The number of RNDR retries before falling back to DRBG
+const MAX_RNDR_RETRIES: usize = 10;
+
+struct Drbg {
+    key: [u32; CHACHA20_KEY_WORDS],
+    counter: u64,
+    is_seeded: bool,
+}
+
+static mut IS_RNDR_AVAILABLE: bool = false;
+static mut DRBG: Drbg = Drbg {
+    key: [0; CHACHA20_KEY_WORDS],
+    counter: 0,
+    is_seeded: false,
+};
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u32; CHACHA20_KEY_WORDS], counter: u64) -> [u8; CHACHA20_BLOCK_SIZE] {
+    let mut input = [0u32; 16];
+    input[0..4].copy_from_slice(&CHACHA20_CONSTANTS);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter as u32;
+    input[13] = (counter >> 32) as u32;
+    let mut state = input;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    let mut output = [0u8; CHACHA20_BLOCK_SIZE];
+    for (i, word) in state.iter().enumerate() {
+        output[(i * 4)..(i * 4 + 4)].copy_from_slice(&word.wrapping_add(input[i]).to_le_bytes());
+    }
+    output
+}
+
+impl Drbg {
+    fn next_block(&mut self) -> [u8; CHACHA20_BLOCK_SIZE] {
+        let block = chacha20_block(&self.key, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        block
+    }
+
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        for (i, k) in self.key.iter_mut().enumerate() {
+            *k = u32::from_le_bytes(block[(i * 4)..(i * 4 + 4)].try_into().unwrap());
+        }
+    }
+
+    fn seed(&mut self, seed: &[u8]) {
+        for (i, b) in seed.iter().enumerate() {
+            let word = &mut self.key[(i / 4) % CHACHA20_KEY_WORDS];
+            *word ^= (*b as u32) << ((i % 4) * 8);
+        }
+        /* The jitter of the counter is mixed even if the seed is empty */
+        for (i, word) in self.key.iter_mut().enumerate() {
+            *word ^= get_cntpct_el0().rotate_left(i as u32 * 7) as u32;
+        }
+        self.rekey();
+        self.is_seeded = true;
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        if !self.is_seeded {
+            self.seed(&[]);
+        }
+        for chunk in buffer.chunks_mut(CHACHA20_BLOCK_SIZE) {
+            let block = self.next_block();
+            chunk.copy_from_slice(&block[..chunk.len()]);
+        }
+        self.rekey();
+    }
+}
+
+fn drbg() -> &'static mut Drbg {
+    unsafe { &mut *core::ptr::addr_of_mut!(DRBG) }
+}
+
+/// Detect RNDR and seed DRBG
+///
+/// # Arguments
+/// * `seed` - the entropy for DRBG, e.g. from EFI_RNG_PROTOCOL
+pub fn init(seed: &[u8]) {
+    let is_rndr_available = (get_id_aa64isar0_el1() & ID_AA64ISAR0_EL1_RNDR) != 0;
+    unsafe { IS_RNDR_AVAILABLE = is_rndr_available };
+    drbg().seed(seed);
+}
+
+pub fn is_rndr_available() -> bool {
+    unsafe { IS_RNDR_AVAILABLE }
+}
+
+fn get_rndr_with_retry() -> Option<u64> {
+    (0..MAX_RNDR_RETRIES).find_map(|_| get_rndr())
+}
+
+/// Fill the buffer with the random bytes
+pub fn fill_random_bytes(buffer: &mut [u8]) {
+    if is_rndr_available() {
+        for chunk in buffer.chunks_mut(core::mem::size_of::<u64>()) {
+            let Some(r) = get_rndr_with_retry() else {
+                /* The entropy source is exhausted */
+                drbg().fill(chunk);
+                continue;
+            };
+            chunk.copy_from_slice(&r.to_le_bytes()[..chunk.len()]);
+        }
+    } else {
+        drbg().fill(buffer);
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Secure Monitor Call Dispatcher
+//!
+//! SMC from EL1 is trapped by HCR_EL2.TSC. Each call is emulated, forwarded to EL3,
+//! or denied according to the policy of its SMCCC service range.
+//!
+
+use crate::cpu::{get_mpidr_el1, secure_monitor_call};
+use crate::exception::Registers;
+use crate::smccc::*;
+use crate::vcpu;
+
+/* Arm Architecture Calls */
+pub const SMCCC_VERSION: u32 = 0x80000000;
+pub const SMCCC_ARCH_FEATURES: u32 = 0x80000001;
+pub const SMCCC_ARCH_WORKAROUND_1: u32 = 0x80008000;
+pub const SMCCC_ARCH_WORKAROUND_2: u32 = 0x80007FFF;
+pub const SMCCC_ARCH_WORKAROUND_3: u32 = 0x80003FFF;
+/// SMCCC v1.2
+pub const SMCCC_VERSION_1_2: u64 = 0x10002;
+
+/* PSCI (ARM DEN 0022) */
+pub const PSCI_VERSION: u32 = 0x84000000;
+pub const PSCI_CPU_SUSPEND_32: u32 = 0x84000001;
+pub const PSCI_CPU_SUSPEND_64: u32 = 0xC4000001;
+pub const PSCI_CPU_OFF: u32 = 0x84000002;
+pub const PSCI_CPU_ON_32: u32 = 0x84000003;
+pub const PSCI_CPU_ON_64: u32 = 0xC4000003;
+pub const PSCI_AFFINITY_INFO_32: u32 = 0x84000004;
+pub const PSCI_AFFINITY_INFO_64: u32 = 0xC4000004;
+pub const PSCI_MIGRATE_INFO_TYPE: u32 = 0x84000006;
+pub const PSCI_SYSTEM_OFF: u32 = 0x84000008;
+pub const PSCI_SYSTEM_RESET: u32 = 0x84000009;
+pub const PSCI_FEATURES: u32 = 0x8400000A;
+/// PSCI v1.1
+pub const PSCI_VERSION_1_1: u64 = 0x10001;
+
+pub const PSCI_NOT_SUPPORTED: u64 = -1i64 as u64;
+pub const PSCI_INVALID_PARAMETERS: u64 = -2i64 as u64;
+pub const PSCI_DENIED: u64 = -3i64 as u64;
+pub const PSCI_AFFINITY_INFO_ON: u64 = 0;
+pub const PSCI_AFFINITY_INFO_OFF: u64 = 1;
+/// Trusted OS is not present or does not require migration
+pub const PSCI_MIGRATE_INFO_TYPE_NOT_REQUIRED: u64 = 2;
+
+const MPIDR_AFFINITY_MASK: u64 = 0xFF_00FF_FFFF;
+
+/// How to handle SMC in the service range
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SmcPolicy {
+    /// Handle the call in the hypervisor, calls which cannot be emulated return NOT_SUPPORTED
+    Emulate,
+    /// Pass the call to EL3 with x0~x17 and return the results to EL1
+    Forward,
+    /// Return NOT_SUPPORTED
+    Deny,
+}
+
+static mut SMC_POLICIES: [SmcPolicy; ServiceRange::NUMBER_OF_RANGES] = [
+    SmcPolicy::Emulate, /* Arm Architecture */
+    SmcPolicy::Deny,    /* CPU */
+    SmcPolicy::Deny,    /* SiP */
+    SmcPolicy::Deny,    /* OEM */
+    SmcPolicy::Emulate, /* Standard Secure(PSCI) */
+    SmcPolicy::Deny,    /* Standard Hypervisor */
+    SmcPolicy::Deny,    /* Vendor Hypervisor */
+    SmcPolicy::Deny,    /* Vendor EL3 Monitor */
+    SmcPolicy::Deny,    /* Trusted Application */
+    SmcPolicy::Deny,    /* Trusted OS */
+    SmcPolicy::Deny,    /* Reserved */
+];
+
+/// Set the policy of the service range
+pub fn set_smc_policy(range: ServiceRange, policy: SmcPolicy) {
+    unsafe { SMC_POLICIES[range as usize] = policy };
+}
+
+pub fn get_smc_policy(range: ServiceRange) -> SmcPolicy {
+    unsafe { SMC_POLICIES[range as usize] }
+}
+
+/// Handle SMC from EL1
+///
+/// ELR_EL2 points the SMC instruction, it is advanced in this function.
+pub fn smc_handler(registers: &mut Registers, imm16: u16) {
+    let function_id = FunctionId::new(registers.x0);
+    if imm16 != 0 {
+        registers.x0 = SMCCC_NOT_SUPPORTED;
+    } else {
+        match get_smc_policy(function_id.service_range()) {
+            SmcPolicy::Emulate => emulate_smc(registers, function_id),
+            SmcPolicy::Forward => forward_smc(registers),
+            SmcPolicy::Deny => registers.x0 = SMCCC_NOT_SUPPORTED,
+        }
+    }
+    unsafe { crate::exception::advance_elr_el2() };
+}
+
+/// Execute SMC at EL2 with the registers of EL1
+pub fn forward_smc(registers: &mut Registers) {
+    let Registers {
+        x0,
+        x1,
+        x2,
+        x3,
+        x4,
+        x5,
+        x6,
+        x7,
+        x8,
+        x9,
+        x10,
+        x11,
+        x12,
+        x13,
+        x14,
+        x15,
+        x16,
+        x17,
+        ..
+    } = registers;
+    secure_monitor_call(
+        x0, x1, x2, x3, x4, x5, x6, x7, x8, x9, x10, x11, x12, x13, x14, x15, x16, x17,
+    );
+}
+
+fn emulate_smc(registers: &mut Registers, function_id: FunctionId) {
+    match function_id.service_range() {
+        ServiceRange::ArmArchitecture => emulate_arm_architecture_call(registers, function_id),
+        ServiceRange::StandardSecure => emulate_psci(registers, function_id),
+        _ => registers.x0 = SMCCC_NOT_SUPPORTED,
+    }
+}
+
+fn emulate_arm_architecture_call(registers: &mut Registers, function_id: FunctionId) {
+    registers.x0 = match function_id.0 {
+        SMCCC_VERSION => SMCCC_VERSION_1_2,
+        SMCCC_ARCH_FEATURES => match registers.x1 as u32 {
+            SMCCC_VERSION | SMCCC_ARCH_FEATURES => SMCCC_SUCCESS,
+            SMCCC_ARCH_WORKAROUND_1 | SMCCC_ARCH_WORKAROUND_2 | SMCCC_ARCH_WORKAROUND_3 => {
+                /* Ask EL3 whether the firmware mitigation is available */
+                forward_smc(registers);
+                registers.x0
+            }
+            _ => SMCCC_NOT_SUPPORTED,
+        },
+        SMCCC_ARCH_WORKAROUND_1 | SMCCC_ARCH_WORKAROUND_2 | SMCCC_ARCH_WORKAROUND_3 => {
+            forward_smc(registers);
+            registers.x0
+        }
+        _ => SMCCC_NOT_SUPPORTED,
+    };
+}
+
+/// Emulate PSCI for the guest which has only one vCPU
+fn emulate_psci(registers: &mut Registers, function_id: FunctionId) {
+    let own_affinity = get_mpidr_el1() & MPIDR_AFFINITY_MASK;
+    registers.x0 = match function_id.0 {
+        PSCI_VERSION => PSCI_VERSION_1_1,
+        PSCI_FEATURES => match registers.x1 as u32 {
+            PSCI_VERSION
+            | PSCI_FEATURES
+            | PSCI_CPU_SUSPEND_32
+            | PSCI_CPU_SUSPEND_64
+            | PSCI_CPU_OFF
+            | PSCI_AFFINITY_INFO_32
+            | PSCI_AFFINITY_INFO_64
+            | PSCI_MIGRATE_INFO_TYPE
+            | PSCI_SYSTEM_OFF
+            | PSCI_SYSTEM_RESET
+            | SMCCC_VERSION => SMCCC_SUCCESS,
+            _ => PSCI_NOT_SUPPORTED,
+        },
+        PSCI_CPU_SUSPEND_32 | PSCI_CPU_SUSPEND_64 => {
+            /* Treat any power state as standby */
+            vcpu::current().block(None);
+            SMCCC_SUCCESS
+        }
+        PSCI_AFFINITY_INFO_32 | PSCI_AFFINITY_INFO_64 => {
+            if registers.x2 != 0 {
+                /* Only the affinity level 0 is supported since PSCI 1.0 */
+                PSCI_INVALID_PARAMETERS
+            } else if (registers.x1 & MPIDR_AFFINITY_MASK) == own_affinity {
+                PSCI_AFFINITY_INFO_ON
+            } else {
+                PSCI_AFFINITY_INFO_OFF
+            }
+        }
+        PSCI_MIGRATE_INFO_TYPE => PSCI_MIGRATE_INFO_TYPE_NOT_REQUIRED,
+        /* Only one vCPU is available */
+        PSCI_CPU_ON_32 | PSCI_CPU_ON_64 => PSCI_DENIED,
+        PSCI_CPU_OFF | PSCI_SYSTEM_OFF | PSCI_SYSTEM_RESET => {
+            forward_smc(registers);
+            registers.x0
+        }
+        _ => PSCI_NOT_SUPPORTED,
+    };
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! SMC Calling Convention
+//!
+//! (ARM DEN 0028 SMC Calling Convention)
+//!
+
+/* Function Identifier */
+pub const SMCCC_FAST_CALL: u32 = 1 << 31;
+pub const SMCCC_64BIT_CALL: u32 = 1 << 30;
+pub const SMCCC_OWNING_ENTITY_BITS_OFFSET: u32 = 24;
+pub const SMCCC_OWNING_ENTITY: u32 = 0b111111 << SMCCC_OWNING_ENTITY_BITS_OFFSET;
+pub const SMCCC_FUNCTION_NUMBER: u32 = 0xFFFF;
+
+/* Return Codes */
+pub const SMCCC_SUCCESS: u64 = 0;
+pub const SMCCC_NOT_SUPPORTED: u64 = -1i64 as u64;
+pub const SMCCC_INVALID_PARAMETER: u64 = -3i64 as u64;
+
+/* General Service Queries, common to all service ranges */
+pub const SMCCC_CALL_COUNT: u32 = 0xFF00;
+pub const SMCCC_CALL_UID: u32 = 0xFF01;
+pub const SMCCC_REVISION: u32 = 0xFF03;
+
+/// Service ranges identified by Owning Entity Number
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServiceRange {
+    ArmArchitecture,
+    Cpu,
+    SiliconPartner,
+    Oem,
+    StandardSecure,
+    StandardHypervisor,
+    VendorHypervisor,
+    VendorEl3Monitor,
+    TrustedApplication,
+    TrustedOs,
+    Reserved,
+}
+
+impl ServiceRange {
+    pub const NUMBER_OF_RANGES: usize = 11;
+
+    pub const fn from_owning_entity_number(oen: u8) -> Self {
+        match oen {
+            0 => Self::ArmArchitecture,
+            1 => Self::Cpu,
+            2 => Self::SiliconPartner,
+            3 => Self::Oem,
+            4 => Self::StandardSecure,
+            5 => Self::StandardHypervisor,
+            6 => Self::VendorHypervisor,
+            7 => Self::VendorEl3Monitor,
+            48..=49 => Self::TrustedApplication,
+            50..=63 => Self::TrustedOs,
+            _ => Self::Reserved,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::ArmArchitecture => "Arm Architecture Calls",
+            Self::Cpu => "CPU Service Calls",
+            Self::SiliconPartner => "SiP Service Calls",
+            Self::Oem => "OEM Service Calls",
+            Self::StandardSecure => "Standard Secure Service Calls",
+            Self::StandardHypervisor => "Standard Hypervisor Service Calls",
+            Self::VendorHypervisor => "Vendor Specific Hypervisor Service Calls",
+            Self::VendorEl3Monitor => "Vendor Specific EL3 Monitor Calls",
+            Self::TrustedApplication => "Trusted Application Calls",
+            Self::TrustedOs => "Trusted OS Calls",
+            Self::Reserved => "Reserved",
+        }
+    }
+}
+
+/// SMCCC Function Identifier
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FunctionId(pub u32);
+
+impl FunctionId {
+    pub const fn new(function_id: u64) -> Self {
+        Self(function_id as u32)
+    }
+
+    pub const fn is_fast_call(&self) -> bool {
+        (self.0 & SMCCC_FAST_CALL) != 0
+    }
+
+    pub const fn is_64bit_call(&self) -> bool {
+        (self.0 & SMCCC_64BIT_CALL) != 0
+    }
+
+    pub const fn owning_entity_number(&self) -> u8 {
+        ((self.0 & SMCCC_OWNING_ENTITY) >> SMCCC_OWNING_ENTITY_BITS_OFFSET) as u8
+    }
+
+    pub const fn service_range(&self) -> ServiceRange {
+        ServiceRange::from_owning_entity_number(self.owning_entity_number())
+    }
+
+    pub const fn function_number(&self) -> u32 {
+        self.0 & SMCCC_FUNCTION_NUMBER
+    }
+}
@@ -0,0 +1,327 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! VM Exit Statistics
+//!
+//! Every VM exit is counted by its reason with the time spent in the handler, measured by
+//! CNTPCT_EL0. The MMIO accesses and the system register traps are also counted per device
+//! offset and per register.
+//!
+//! When the trace is enabled, each exit is also stored into the ring buffer as [`TraceRecord`].
+//! The records can be dumped by the monitor or read by the hypercall, and converted into
+//! the flame graph offline.
+//!
+
+use crate::cpu::get_cntpct_el0;
+use crate::sysreg::SystemRegister;
+
+pub const NUMBER_OF_EXCEPTION_CLASSES: usize = 64;
+pub const MAX_MMIO_COUNTERS: usize = 64;
+pub const MAX_SYSTEM_REGISTER_COUNTERS: usize = 64;
+pub const TRACE_BUFFER_SIZE: usize = 4096;
+
+/// [`TraceRecord::reason`] of the exit by the physical interrupt, out of the range of EC
+pub const TRACE_REASON_IRQ: u32 = 0x100;
+
+pub struct ExitStatistics {
+    pub total_exits: u64,
+    pub irq_exits: u64,
+    pub synchronous_exits: [u64; NUMBER_OF_EXCEPTION_CLASSES],
+    /// The ticks of CNTPCT_EL0 spent in the handlers
+    pub irq_exit_ticks: u64,
+    pub synchronous_exit_ticks: [u64; NUMBER_OF_EXCEPTION_CLASSES],
+}
+
+#[derive(Clone, Copy)]
+pub struct MmioAccessCounter {
+    pub base_address: usize,
+    pub offset: usize,
+    pub reads: u64,
+    pub writes: u64,
+    pub ticks: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct SystemRegisterCounter {
+    pub register: SystemRegister,
+    pub reads: u64,
+    pub writes: u64,
+    pub ticks: u64,
+}
+
+/// The record of the trace buffer, the layout is fixed for the offline tools
+///
+/// All fields are little endian.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TraceRecord {
+    /// CNTPCT_EL0 at the entry of the handler
+    pub timestamp: u64,
+    /// ELR_EL2 at the entry of the handler
+    pub pc: u64,
+    /// The faulting IPA for Data Abort, ISS for other synchronous exceptions, 0 for IRQ
+    pub detail: u64,
+    /// The ticks spent in the handler
+    pub ticks: u32,
+    /// ESR_EL2.EC, or [`TRACE_REASON_IRQ`]
+    pub reason: u32,
+}
+
+pub const TRACE_RECORD_SIZE: usize = core::mem::size_of::<TraceRecord>();
+
+struct TraceBuffer {
+    records: [TraceRecord; TRACE_BUFFER_SIZE],
+    /// The index of the oldest record
+    head: usize,
+    length: usize,
+    is_enabled: bool,
+    /// The number of the records overwritten before read
+    lost_records: u64,
+}
+
+static mut EXIT_STATISTICS: ExitStatistics = ExitStatistics {
+    total_exits: 0,
+    irq_exits: 0,
+    synchronous_exits: [0; NUMBER_OF_EXCEPTION_CLASSES],
+    irq_exit_ticks: 0,
+    synchronous_exit_ticks: [0; NUMBER_OF_EXCEPTION_CLASSES],
+};
+static mut MMIO_ACCESS_COUNTERS: [Option<MmioAccessCounter>; MAX_MMIO_COUNTERS] =
+    [None; MAX_MMIO_COUNTERS];
+static mut SYSTEM_REGISTER_COUNTERS: [Option<SystemRegisterCounter>; MAX_SYSTEM_REGISTER_COUNTERS] =
+    [None; MAX_SYSTEM_REGISTER_COUNTERS];
+static mut TRACE_BUFFER: TraceBuffer = TraceBuffer {
+    records: [TraceRecord {
+        timestamp: 0,
+        pc: 0,
+        detail: 0,
+        ticks: 0,
+        reason: 0,
+    }; TRACE_BUFFER_SIZE],
+    head: 0,
+    length: 0,
+    is_enabled: false,
+    lost_records: 0,
+};
+
+pub fn get_exit_statistics() -> &'static ExitStatistics {
+    unsafe { &*core::ptr::addr_of!(EXIT_STATISTICS) }
+}
+
+fn exit_statistics() -> &'static mut ExitStatistics {
+    unsafe { &mut *core::ptr::addr_of_mut!(EXIT_STATISTICS) }
+}
+
+pub fn get_mmio_access_counters() -> &'static [Option<MmioAccessCounter>] {
+    unsafe { &*core::ptr::addr_of!(MMIO_ACCESS_COUNTERS) }
+}
+
+pub fn get_system_register_counters() -> &'static [Option<SystemRegisterCounter>] {
+    unsafe { &*core::ptr::addr_of!(SYSTEM_REGISTER_COUNTERS) }
+}
+
+fn trace_buffer() -> &'static mut TraceBuffer {
+    unsafe { &mut *core::ptr::addr_of_mut!(TRACE_BUFFER) }
+}
+
+/// Get the current time for [`record_synchronous_exit`] and others
+#[inline(always)]
+pub fn get_timestamp() -> u64 {
+    get_cntpct_el0()
+}
+
+fn elapsed_ticks(start: u64) -> u64 {
+    get_timestamp().wrapping_sub(start)
+}
+
+/// Count the exit by the synchronous exception, called at the end of the handler
+///
+/// # Arguments
+/// * `exception_class` - ESR_EL2.EC
+/// * `pc` - ELR_EL2 at the entry
+/// * `detail` - see [`TraceRecord::detail`]
+/// * `start` - [`get_timestamp`] at the entry
+pub fn record_synchronous_exit(exception_class: u8, pc: u64, detail: u64, start: u64) {
+    let ticks = elapsed_ticks(start);
+    let ec = exception_class as usize % NUMBER_OF_EXCEPTION_CLASSES;
+    let s = exit_statistics();
+    s.total_exits += 1;
+    s.synchronous_exits[ec] += 1;
+    s.synchronous_exit_ticks[ec] += ticks;
+    add_trace_record(TraceRecord {
+        timestamp: start,
+        pc,
+        detail,
+        ticks: ticks.min(u32::MAX as u64) as u32,
+        reason: ec as u32,
+    });
+}
+
+/// Count the exit by the physical interrupt, called at the end of the handler
+///
+/// # Arguments
+/// * `pc` - ELR_EL2 at the entry
+/// * `start` - [`get_timestamp`] at the entry
+pub fn record_irq_exit(pc: u64, start: u64) {
+    let ticks = elapsed_ticks(start);
+    let s = exit_statistics();
+    s.total_exits += 1;
+    s.irq_exits += 1;
+    s.irq_exit_ticks += ticks;
+    add_trace_record(TraceRecord {
+        timestamp: start,
+        pc,
+        detail: 0,
+        ticks: ticks.min(u32::MAX as u64) as u32,
+        reason: TRACE_REASON_IRQ,
+    });
+}
+
+/// Count the access to the MMIO device
+///
+/// If the table is full, the access to a new offset is not counted.
+///
+/// # Arguments
+/// * `base_address` - the base address of the device
+/// * `offset` - the offset from `base_address`
+/// * `is_write` - true if the access is write
+/// * `start` - [`get_timestamp`] before the device was called
+pub fn record_mmio_access(base_address: usize, offset: usize, is_write: bool, start: u64) {
+    let ticks = elapsed_ticks(start);
+    let counters = unsafe { &mut *core::ptr::addr_of_mut!(MMIO_ACCESS_COUNTERS) };
+    let counter = if let Some(c) = counters
+        .iter_mut()
+        .flatten()
+        .find(|c| c.base_address == base_address && c.offset == offset)
+    {
+        c
+    } else if let Some(e) = counters.iter_mut().find(|c| c.is_none()) {
+        e.insert(MmioAccessCounter {
+            base_address,
+            offset,
+            reads: 0,
+            writes: 0,
+            ticks: 0,
+        })
+    } else {
+        return;
+    };
+    if is_write {
+        counter.writes += 1;
+    } else {
+        counter.reads += 1;
+    }
+    counter.ticks += ticks;
+}
+
+/// Count the trapped access to the system register
+///
+/// If the table is full, the access to a new register is not counted.
+///
+/// # Arguments
+/// * `register` - the accessed register
+/// * `is_read` - true if the access is MRS
+/// * `start` - [`get_timestamp`] before the register was emulated
+pub fn record_system_register_access(register: SystemRegister, is_read: bool, start: u64) {
+    let ticks = elapsed_ticks(start);
+    let counters = unsafe { &mut *core::ptr::addr_of_mut!(SYSTEM_REGISTER_COUNTERS) };
+    let counter = if let Some(c) = counters
+        .iter_mut()
+        .flatten()
+        .find(|c| c.register == register)
+    {
+        c
+    } else if let Some(e) = counters.iter_mut().find(|c| c.is_none()) {
+        e.insert(SystemRegisterCounter {
+            register,
+            reads: 0,
+            writes: 0,
+            ticks: 0,
+        })
+    } else {
+        return;
+    };
+    if is_read {
+        counter.reads += 1;
+    } else {
+        counter.writes += 1;
+    }
+    counter.ticks += ticks;
+}
+
+/// Clear all counters, the trace buffer is not changed
+pub fn reset_statistics() {
+    let s = exit_statistics();
+    s.total_exits = 0;
+    s.irq_exits = 0;
+    s.synchronous_exits = [0; NUMBER_OF_EXCEPTION_CLASSES];
+    s.irq_exit_ticks = 0;
+    s.synchronous_exit_ticks = [0; NUMBER_OF_EXCEPTION_CLASSES];
+    unsafe {
+        *core::ptr::addr_of_mut!(MMIO_ACCESS_COUNTERS) = [None; MAX_MMIO_COUNTERS];
+        *core::ptr::addr_of_mut!(SYSTEM_REGISTER_COUNTERS) = [None; MAX_SYSTEM_REGISTER_COUNTERS];
+    }
+}
+
+fn add_trace_record(record: TraceRecord) {
+    let t = trace_buffer();
+    if !t.is_enabled {
+        return;
+    }
+    if t.length == TRACE_BUFFER_SIZE {
+        t.records[t.head] = record;
+        t.head = (t.head + 1) % TRACE_BUFFER_SIZE;
+        t.lost_records += 1;
+    } else {
+        t.records[(t.head + t.length) % TRACE_BUFFER_SIZE] = record;
+        t.length += 1;
+    }
+}
+
+/// Start or stop storing the exits into the trace buffer
+pub fn set_trace_enabled(is_enabled: bool) {
+    trace_buffer().is_enabled = is_enabled;
+}
+
+pub fn is_trace_enabled() -> bool {
+    trace_buffer().is_enabled
+}
+
+/// Discard all records in the trace buffer
+pub fn clear_trace() {
+    let t = trace_buffer();
+    t.head = 0;
+    t.length = 0;
+    t.lost_records = 0;
+}
+
+/// Get the number of the stored records and the number of the overwritten records
+pub fn get_trace_status() -> (usize, u64) {
+    let t = trace_buffer();
+    (t.length, t.lost_records)
+}
+
+/// Take the oldest record out of the trace buffer
+pub fn pop_trace_record() -> Option<TraceRecord> {
+    let t = trace_buffer();
+    if t.length == 0 {
+        return None;
+    }
+    let record = t.records[t.head];
+    t.head = (t.head + 1) % TRACE_BUFFER_SIZE;
+    t.length -= 1;
+    Some(record)
+}
+
+/// Call `f` with each record from the oldest without removing them
+pub fn for_each_trace_record(f: &mut dyn FnMut(&TraceRecord)) {
+    let t = trace_buffer();
+    for i in 0..t.length {
+        f(&t.records[(t.head + i) % TRACE_BUFFER_SIZE]);
+    }
+}
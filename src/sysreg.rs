@@ -0,0 +1,411 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Trapped System Register Access Emulation
+//!
+//! The accesses to the system registers trapped by HCR_EL2.TID*/TACR/TSW and MDCR_EL2
+//! are dispatched to the handlers registered with the encoding (op0, op1, CRn, CRm, op2).
+//!
+//! The debug registers written by the guest are shadowed. The breakpoints, the watchpoints and
+//! MDSCR_EL1 are also written to the physical registers unless the debugger owns them, and
+//! they are restored when the debugger releases them by [`set_debugger_attached`].
+//!
+
+mod pmu;
+
+use crate::cpu::*;
+use crate::exception::Registers;
+
+use core::arch::asm;
+use core::fmt;
+
+/// The encoding of the system register, or the system instruction if op0 is 1
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct SystemRegister {
+    pub op0: u8,
+    pub op1: u8,
+    pub crn: u8,
+    pub crm: u8,
+    pub op2: u8,
+}
+
+impl SystemRegister {
+    pub const fn new(op0: u8, op1: u8, crn: u8, crm: u8, op2: u8) -> Self {
+        Self {
+            op0,
+            op1,
+            crn,
+            crm,
+            op2,
+        }
+    }
+
+    /// Debug registers trapped by MDCR_EL2.TDA/TDOSA/TDRA
+    pub const fn is_debug_register(&self) -> bool {
+        self.op0 == 2
+    }
+}
+
+impl fmt::Display for SystemRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "S{}_{}_C{}_C{}_{}",
+            self.op0, self.op1, self.crn, self.crm, self.op2
+        )
+    }
+}
+
+impl fmt::Debug for SystemRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+pub const ACTLR_EL1: SystemRegister = SystemRegister::new(3, 0, 1, 0, 1);
+pub const ID_AA64PFR0_EL1: SystemRegister = SystemRegister::new(3, 0, 0, 4, 0);
+pub const ID_AA64PFR1_EL1: SystemRegister = SystemRegister::new(3, 0, 0, 4, 1);
+pub const ID_AA64ZFR0_EL1: SystemRegister = SystemRegister::new(3, 0, 0, 4, 4);
+pub const ID_AA64SMFR0_EL1: SystemRegister = SystemRegister::new(3, 0, 0, 4, 5);
+pub const DC_ISW: SystemRegister = SystemRegister::new(1, 0, 7, 6, 2);
+pub const DC_CSW: SystemRegister = SystemRegister::new(1, 0, 7, 10, 2);
+pub const DC_CISW: SystemRegister = SystemRegister::new(1, 0, 7, 14, 2);
+pub const MDSCR_EL1: SystemRegister = SystemRegister::new(2, 0, 0, 2, 2);
+
+/// Read handler, returns the value to pass to the guest
+pub type SystemRegisterReadHandler = fn(register: SystemRegister) -> u64;
+/// Write handler, receives the value written by the guest
+pub type SystemRegisterWriteHandler = fn(register: SystemRegister, value: u64);
+
+#[derive(Clone, Copy)]
+pub struct SystemRegisterTrap {
+    pub register: SystemRegister,
+    /// If None, the read access is treated as read-as-zero
+    pub read: Option<SystemRegisterReadHandler>,
+    /// If None, the write access is ignored
+    pub write: Option<SystemRegisterWriteHandler>,
+}
+
+/// Which traps should be enabled
+#[derive(Clone, Copy)]
+pub struct TrapConfiguration {
+    /// HCR_EL2.TID1: REVIDR_EL1, AIDR_EL1
+    pub trap_id_group1: bool,
+    /// HCR_EL2.TID2: CTR_EL0, CCSIDR_EL1, CLIDR_EL1, CSSELR_EL1
+    pub trap_id_group2: bool,
+    /// HCR_EL2.TID3: ID_AA64*, ID_PFR*, ...
+    pub trap_id_group3: bool,
+    /// HCR_EL2.TACR: ACTLR_EL1
+    pub trap_auxiliary_control: bool,
+    /// HCR_EL2.TSW: DC ISW/CSW/CISW
+    pub trap_set_way: bool,
+    /// MDCR_EL2.TDA/TDOSA/TDRA: debug registers
+    pub trap_debug: bool,
+    /// MDCR_EL2.TPM/TPMCR: performance monitors
+    pub trap_pmu: bool,
+}
+
+pub const DEFAULT_TRAP_CONFIGURATION: TrapConfiguration = TrapConfiguration {
+    trap_id_group1: false,
+    trap_id_group2: false,
+    trap_id_group3: true,
+    trap_auxiliary_control: true,
+    trap_set_way: true,
+    trap_debug: true,
+    trap_pmu: true,
+};
+
+const MAX_SYSTEM_REGISTER_TRAPS: usize = 128;
+const MAX_DEBUG_REGISTERS: usize = 80;
+
+static mut SYSTEM_REGISTER_TRAPS: [Option<SystemRegisterTrap>; MAX_SYSTEM_REGISTER_TRAPS] =
+    [None; MAX_SYSTEM_REGISTER_TRAPS];
+
+/// The values written to the debug registers by the guest
+static mut DEBUG_REGISTERS: [Option<(SystemRegister, u64)>; MAX_DEBUG_REGISTERS] =
+    [None; MAX_DEBUG_REGISTERS];
+
+/// True while the debugger owns the physical debug registers
+static mut IS_DEBUGGER_ATTACHED: bool = false;
+
+/// Register the handlers for the system register
+///
+/// If the handlers for the same register are already registered, they will be replaced.
+///
+/// # Result
+/// If the table is full, returns Err(())
+pub fn register_system_register_trap(trap: SystemRegisterTrap) -> Result<(), ()> {
+    let traps = unsafe { &mut *core::ptr::addr_of_mut!(SYSTEM_REGISTER_TRAPS) };
+    if let Some(e) = traps
+        .iter_mut()
+        .find(|e| matches!(e, Some(t) if t.register == trap.register))
+    {
+        *e = Some(trap);
+        return Ok(());
+    }
+    match traps.iter_mut().find(|e| e.is_none()) {
+        Some(e) => {
+            *e = Some(trap);
+            Ok(())
+        }
+        None => Err(()),
+    }
+}
+
+fn find_system_register_trap(register: SystemRegister) -> Option<SystemRegisterTrap> {
+    unsafe { &*core::ptr::addr_of!(SYSTEM_REGISTER_TRAPS) }
+        .iter()
+        .flatten()
+        .find(|t| t.register == register)
+        .copied()
+}
+
+/// Register the default handlers and enable the traps
+///
+/// MDCR_EL2 is set in this function.
+///
+/// # Result
+/// The bits to set into HCR_EL2
+pub fn setup_system_register_trap(configuration: &TrapConfiguration) -> u64 {
+    let default_traps = [
+        SystemRegisterTrap {
+            register: ID_AA64PFR0_EL1,
+            read: Some(|_| get_id_aa64pfr0_el1() & !ID_AA64PFR0_EL1_SVE),
+            write: None,
+        },
+        SystemRegisterTrap {
+            register: ID_AA64PFR1_EL1,
+            read: Some(|_| get_id_aa64pfr1_el1() & !(ID_AA64PFR1_EL1_MTE | ID_AA64PFR1_EL1_SME)),
+            write: None,
+        },
+        SystemRegisterTrap {
+            register: ID_AA64ZFR0_EL1,
+            read: None,
+            write: None,
+        },
+        SystemRegisterTrap {
+            register: ID_AA64SMFR0_EL1,
+            read: None,
+            write: None,
+        },
+        SystemRegisterTrap {
+            register: ACTLR_EL1,
+            read: Some(|_| {
+                let actlr_el1: u64;
+                unsafe { asm!("mrs {:x}, actlr_el1", out(reg) actlr_el1) };
+                actlr_el1
+            }),
+            write: None,
+        },
+        SystemRegisterTrap {
+            register: DC_ISW,
+            read: None,
+            write: Some(set_way_handler),
+        },
+        SystemRegisterTrap {
+            register: DC_CSW,
+            read: None,
+            write: Some(set_way_handler),
+        },
+        SystemRegisterTrap {
+            register: DC_CISW,
+            read: None,
+            write: Some(set_way_handler),
+        },
+    ];
+    for trap in default_traps {
+        register_system_register_trap(trap).expect("Failed to register the system register trap");
+    }
+
+    let mut hcr_el2 = 0;
+    if configuration.trap_id_group1 {
+        hcr_el2 |= HCR_EL2_TID1;
+    }
+    if configuration.trap_id_group2 {
+        hcr_el2 |= HCR_EL2_TID2;
+    }
+    if configuration.trap_id_group3 {
+        hcr_el2 |= HCR_EL2_TID3;
+    }
+    if configuration.trap_auxiliary_control {
+        hcr_el2 |= HCR_EL2_TACR;
+    }
+    if configuration.trap_set_way {
+        hcr_el2 |= HCR_EL2_TSW;
+    }
+
+    let mut mdcr_el2 = get_mdcr_el2()
+        & !(MDCR_EL2_TDRA | MDCR_EL2_TDOSA | MDCR_EL2_TDA | MDCR_EL2_TPM | MDCR_EL2_TPMCR);
+    if configuration.trap_debug {
+        mdcr_el2 |= MDCR_EL2_TDRA | MDCR_EL2_TDOSA | MDCR_EL2_TDA;
+    }
+    if configuration.trap_pmu {
+        pmu::setup_virtual_pmu().expect("Failed to register the PMU traps");
+        mdcr_el2 |= MDCR_EL2_TPM | MDCR_EL2_TPMCR;
+    }
+    set_mdcr_el2(mdcr_el2);
+
+    hcr_el2
+}
+
+/// Handle trapped MSR/MRS/System instruction
+///
+/// # Arguments
+/// * `registers` - the registers of EL1
+/// * `register` - the encoding of the accessed system register
+/// * `register_number` - the general purpose register number of the transfer(Rt)
+/// * `is_read` - true if MRS, otherwise MSR or System instruction
+///
+/// # Result
+/// If the access is emulated, returns Ok(()) and ELR_EL2 is advanced,
+/// otherwise returns Err(()) and ELR_EL2 is not changed
+pub fn system_register_trap_handler(
+    registers: &mut Registers,
+    register: SystemRegister,
+    register_number: usize,
+    is_read: bool,
+) -> Result<(), ()> {
+    if let Some(trap) = find_system_register_trap(register) {
+        if is_read {
+            registers.write(register_number, trap.read.map_or(0, |read| read(register)));
+        } else if let Some(write) = trap.write {
+            write(register, registers.read(register_number));
+        }
+    } else if register.is_debug_register() {
+        if is_read {
+            registers.write(register_number, read_debug_register(register));
+        } else {
+            write_debug_register(register, registers.read(register_number))?;
+        }
+    } else if is_read {
+        /* ID registers: pass through the physical value */
+        registers.write(register_number, read_physical_id_register(register).ok_or(())?);
+    } else if read_physical_id_register(register).is_some() {
+        /* ID registers are read only, ignore the write */
+    } else {
+        return Err(());
+    }
+    advance_elr_el2();
+    Ok(())
+}
+
+/// Emulate set/way cache maintenance by cleaning the whole data cache
+///
+/// The guest cannot know the physical cache topology correctly, so the operation by set/way
+/// is replaced by the operation to all caches.
+fn set_way_handler(_register: SystemRegister, _value: u64) {
+    clean_data_cache_all();
+}
+
+fn read_debug_register(register: SystemRegister) -> u64 {
+    unsafe { &*core::ptr::addr_of!(DEBUG_REGISTERS) }
+        .iter()
+        .flatten()
+        .find(|(r, _)| *r == register)
+        .map_or(0, |(_, v)| *v)
+}
+
+fn write_debug_register(register: SystemRegister, value: u64) -> Result<(), ()> {
+    let debug_registers = unsafe { &mut *core::ptr::addr_of_mut!(DEBUG_REGISTERS) };
+    if let Some((_, v)) = debug_registers
+        .iter_mut()
+        .flatten()
+        .find(|(r, _)| *r == register)
+    {
+        *v = value;
+    } else {
+        *debug_registers.iter_mut().find(|e| e.is_none()).ok_or(())? = Some((register, value));
+    }
+    if !unsafe { *core::ptr::addr_of!(IS_DEBUGGER_ATTACHED) } {
+        apply_debug_register(register, value);
+    }
+    Ok(())
+}
+
+/// Write the debug register of the guest into the physical register
+///
+/// The other debug registers, like the OS lock, are only shadowed.
+fn apply_debug_register(register: SystemRegister, value: u64) {
+    let dfr0 = get_id_aa64dfr0_el1();
+    let breakpoints = ((dfr0 & ID_AA64DFR0_EL1_BRPS) >> ID_AA64DFR0_EL1_BRPS_BITS_OFFSET) + 1;
+    let watchpoints = ((dfr0 & ID_AA64DFR0_EL1_WRPS) >> ID_AA64DFR0_EL1_WRPS_BITS_OFFSET) + 1;
+    let n = register.crm as usize;
+    match (register.op0, register.op1, register.crn, register.op2) {
+        (2, 0, 0, 4) if (n as u64) < breakpoints => set_dbgbvr_el1(n, value),
+        (2, 0, 0, 5) if (n as u64) < breakpoints => set_dbgbcr_el1(n, value),
+        (2, 0, 0, 6) if (n as u64) < watchpoints => set_dbgwvr_el1(n, value),
+        (2, 0, 0, 7) if (n as u64) < watchpoints => set_dbgwcr_el1(n, value),
+        _ if register == MDSCR_EL1 => set_mdscr_el1(value),
+        _ => {}
+    }
+}
+
+/// Give the physical debug registers to the debugger, or return them to the guest
+///
+/// While attached, the writes of the guest are only shadowed. When released, the values
+/// written by the guest are written back into the physical registers.
+pub fn set_debugger_attached(is_attached: bool) {
+    unsafe { *core::ptr::addr_of_mut!(IS_DEBUGGER_ATTACHED) = is_attached };
+    if is_attached {
+        return;
+    }
+    for (register, value) in unsafe { &*core::ptr::addr_of!(DEBUG_REGISTERS) }
+        .iter()
+        .flatten()
+    {
+        apply_debug_register(*register, *value);
+    }
+    isb();
+}
+
+/// Read the physical ID register by the encoding
+///
+/// MRS needs the encoding as an immediate value, therefore all candidates are listed.
+macro_rules! mrs_by_encoding {
+    ($register:expr; $(($op0:literal, $op1:literal, $crn:literal, $crm:literal, $op2:literal)),* $(,)?) => {
+        match ($register.op0, $register.op1, $register.crn, $register.crm, $register.op2) {
+            $(
+                ($op0, $op1, $crn, $crm, $op2) => {
+                    let value: u64;
+                    unsafe {
+                        asm!(
+                            concat!("mrs {:x}, S", $op0, "_", $op1, "_C", $crn, "_C", $crm, "_", $op2),
+                            out(reg) value
+                        )
+                    };
+                    Some(value)
+                }
+            )*
+            _ => None,
+        }
+    };
+}
+
+fn read_physical_id_register(register: SystemRegister) -> Option<u64> {
+    mrs_by_encoding!(register;
+        /* TID1 */
+        (3, 0, 0, 0, 6), (3, 1, 0, 0, 7),
+        /* TID2 */
+        (3, 3, 0, 0, 1), (3, 1, 0, 0, 0), (3, 1, 0, 0, 1), (3, 2, 0, 0, 0),
+        /* TID3 */
+        (3, 0, 0, 1, 0), (3, 0, 0, 1, 1), (3, 0, 0, 1, 2), (3, 0, 0, 1, 3),
+        (3, 0, 0, 1, 4), (3, 0, 0, 1, 5), (3, 0, 0, 1, 6), (3, 0, 0, 1, 7),
+        (3, 0, 0, 2, 0), (3, 0, 0, 2, 1), (3, 0, 0, 2, 2), (3, 0, 0, 2, 3),
+        (3, 0, 0, 2, 4), (3, 0, 0, 2, 5), (3, 0, 0, 2, 6), (3, 0, 0, 2, 7),
+        (3, 0, 0, 3, 0), (3, 0, 0, 3, 1), (3, 0, 0, 3, 2), (3, 0, 0, 3, 3),
+        (3, 0, 0, 3, 4), (3, 0, 0, 3, 5), (3, 0, 0, 3, 6), (3, 0, 0, 3, 7),
+        (3, 0, 0, 4, 0), (3, 0, 0, 4, 1), (3, 0, 0, 4, 2), (3, 0, 0, 4, 3),
+        (3, 0, 0, 4, 4), (3, 0, 0, 4, 5), (3, 0, 0, 4, 6), (3, 0, 0, 4, 7),
+        (3, 0, 0, 5, 0), (3, 0, 0, 5, 1), (3, 0, 0, 5, 2), (3, 0, 0, 5, 3),
+        (3, 0, 0, 5, 4), (3, 0, 0, 5, 5), (3, 0, 0, 5, 6), (3, 0, 0, 5, 7),
+        (3, 0, 0, 6, 0), (3, 0, 0, 6, 1), (3, 0, 0, 6, 2), (3, 0, 0, 6, 3),
+        (3, 0, 0, 6, 4), (3, 0, 0, 6, 5), (3, 0, 0, 6, 6), (3, 0, 0, 6, 7),
+        (3, 0, 0, 7, 0), (3, 0, 0, 7, 1), (3, 0, 0, 7, 2), (3, 0, 0, 7, 3),
+        (3, 0, 0, 7, 4), (3, 0, 0, 7, 5), (3, 0, 0, 7, 6), (3, 0, 0, 7, 7),
+    )
+}
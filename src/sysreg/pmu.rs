@@ -0,0 +1,184 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtual Performance Monitors
+//!
+//! The guest sees a PMU which has only the cycle counter (PMCR_EL0.N = 0).
+//! The cycle counter is emulated with the physical counter(CNTPCT_EL0).
+//!
+
+use super::{register_system_register_trap, SystemRegister, SystemRegisterTrap};
+use crate::cpu::*;
+
+const PMCR_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 0);
+const PMCNTENSET_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 1);
+const PMCNTENCLR_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 2);
+const PMOVSCLR_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 3);
+const PMSWINC_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 4);
+const PMSELR_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 5);
+const PMCEID0_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 6);
+const PMCEID1_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 12, 7);
+const PMCCNTR_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 13, 0);
+const PMXEVTYPER_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 13, 1);
+const PMXEVCNTR_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 13, 2);
+const PMUSERENR_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 14, 0);
+const PMINTENSET_EL1: SystemRegister = SystemRegister::new(3, 0, 9, 14, 1);
+const PMINTENCLR_EL1: SystemRegister = SystemRegister::new(3, 0, 9, 14, 2);
+const PMOVSSET_EL0: SystemRegister = SystemRegister::new(3, 3, 9, 14, 3);
+const PMCCFILTR_EL0: SystemRegister = SystemRegister::new(3, 3, 14, 15, 7);
+
+/// The number of PMEVCNTR<n>_EL0/PMEVTYPER<n>_EL0 in the architecture
+const MAX_EVENT_COUNTERS: u8 = 31;
+
+/// PMEVCNTR<n>_EL0: CRm = 0b10:n[4:3], op2 = n[2:0]
+const fn pmevcntr_el0(n: u8) -> SystemRegister {
+    SystemRegister::new(3, 3, 14, 0b1000 | (n >> 3), n & 0b111)
+}
+
+/// PMEVTYPER<n>_EL0: CRm = 0b11:n[4:3], op2 = n[2:0]
+const fn pmevtyper_el0(n: u8) -> SystemRegister {
+    SystemRegister::new(3, 3, 14, 0b1100 | (n >> 3), n & 0b111)
+}
+
+/// The bit of the cycle counter in PMCNTEN*, PMINTEN*, PMOVS*
+const CYCLE_COUNTER_BIT: u64 = 1 << 31;
+
+struct VirtualPmu {
+    pmcr_el0: u64,
+    counter_enable: u64,
+    interrupt_enable: u64,
+    overflow_status: u64,
+    user_enable: u64,
+    cycle_counter_filter: u64,
+    /// The value of the cycle counter when it was stopped or written
+    cycle_counter_base: u64,
+    /// CNTPCT_EL0 when the cycle counter was started or written
+    cycle_counter_start: u64,
+}
+
+static mut VIRTUAL_PMU: VirtualPmu = VirtualPmu {
+    pmcr_el0: 0,
+    counter_enable: 0,
+    interrupt_enable: 0,
+    overflow_status: 0,
+    user_enable: 0,
+    cycle_counter_filter: 0,
+    cycle_counter_base: 0,
+    cycle_counter_start: 0,
+};
+
+impl VirtualPmu {
+    fn is_cycle_counter_running(&self) -> bool {
+        (self.pmcr_el0 & PMCR_EL0_E) != 0 && (self.counter_enable & CYCLE_COUNTER_BIT) != 0
+    }
+
+    fn read_cycle_counter(&self) -> u64 {
+        if self.is_cycle_counter_running() {
+            self.cycle_counter_base
+                .wrapping_add(get_cntpct_el0().wrapping_sub(self.cycle_counter_start))
+        } else {
+            self.cycle_counter_base
+        }
+    }
+
+    fn write_cycle_counter(&mut self, value: u64) {
+        self.cycle_counter_base = value;
+        self.cycle_counter_start = get_cntpct_el0();
+    }
+
+    /// Update the state with keeping the current value of the cycle counter
+    fn update(&mut self, f: impl FnOnce(&mut Self)) {
+        let current = self.read_cycle_counter();
+        f(self);
+        self.write_cycle_counter(current);
+    }
+}
+
+fn pmu() -> &'static mut VirtualPmu {
+    unsafe { &mut *core::ptr::addr_of_mut!(VIRTUAL_PMU) }
+}
+
+fn read_handler(register: SystemRegister) -> u64 {
+    let pmu = pmu();
+    match register {
+        PMCR_EL0 => {
+            /* N = 0: no event counter is implemented */
+            (get_pmcr_el0() & PMCR_EL0_IMP) | (pmu.pmcr_el0 & (PMCR_EL0_LC | PMCR_EL0_E))
+        }
+        PMCNTENSET_EL0 | PMCNTENCLR_EL0 => pmu.counter_enable,
+        PMINTENSET_EL1 | PMINTENCLR_EL1 => pmu.interrupt_enable,
+        PMOVSSET_EL0 | PMOVSCLR_EL0 => pmu.overflow_status,
+        PMUSERENR_EL0 => pmu.user_enable,
+        PMCCFILTR_EL0 => pmu.cycle_counter_filter,
+        PMCCNTR_EL0 => pmu.read_cycle_counter(),
+        /* No common event and no event counter are implemented */
+        _ => 0,
+    }
+}
+
+fn write_handler(register: SystemRegister, value: u64) {
+    let pmu = pmu();
+    match register {
+        PMCR_EL0 => {
+            pmu.update(|p| p.pmcr_el0 = value & (PMCR_EL0_LC | PMCR_EL0_E));
+            /* After update, which writes back the value before the write */
+            if (value & PMCR_EL0_C) != 0 {
+                pmu.write_cycle_counter(0);
+            }
+        }
+        PMCNTENSET_EL0 => pmu.update(|p| p.counter_enable |= value & CYCLE_COUNTER_BIT),
+        PMCNTENCLR_EL0 => pmu.update(|p| p.counter_enable &= !value),
+        PMINTENSET_EL1 => pmu.interrupt_enable |= value & CYCLE_COUNTER_BIT,
+        PMINTENCLR_EL1 => pmu.interrupt_enable &= !value,
+        PMOVSSET_EL0 => pmu.overflow_status |= value & CYCLE_COUNTER_BIT,
+        PMOVSCLR_EL0 => pmu.overflow_status &= !value,
+        PMUSERENR_EL0 => pmu.user_enable = value & 0b1111,
+        PMCCFILTR_EL0 => pmu.cycle_counter_filter = value,
+        PMCCNTR_EL0 => pmu.write_cycle_counter(value),
+        /* PMSWINC_EL0, PMSELR_EL0, PMXEV*, PMEV*: no event counter is implemented */
+        _ => {}
+    }
+}
+
+pub(super) fn setup_virtual_pmu() -> Result<(), ()> {
+    for register in [
+        PMCR_EL0,
+        PMCNTENSET_EL0,
+        PMCNTENCLR_EL0,
+        PMOVSCLR_EL0,
+        PMSWINC_EL0,
+        PMSELR_EL0,
+        PMCEID0_EL0,
+        PMCEID1_EL0,
+        PMCCNTR_EL0,
+        PMXEVTYPER_EL0,
+        PMXEVCNTR_EL0,
+        PMUSERENR_EL0,
+        PMINTENSET_EL1,
+        PMINTENCLR_EL1,
+        PMOVSSET_EL0,
+        PMCCFILTR_EL0,
+    ] {
+        register_system_register_trap(SystemRegisterTrap {
+            register,
+            read: Some(read_handler),
+            write: Some(write_handler),
+        })?;
+    }
+    /* The event counters read as zero and ignore the writes, as PMCR_EL0.N is 0 */
+    for n in 0..MAX_EVENT_COUNTERS {
+        for register in [pmevcntr_el0(n), pmevtyper_el0(n)] {
+            register_system_register_trap(SystemRegisterTrap {
+                register,
+                read: None,
+                write: None,
+            })?;
+        }
+    }
+    Ok(())
+}
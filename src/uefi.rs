@@ -14,7 +14,9 @@
 #![allow(dead_code)]
 
 pub mod boot_service;
+pub mod file;
 pub mod output;
+pub mod rng;
 
 pub type EfiHandle = usize;
 
@@ -0,0 +1,271 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! EFI Simple File System Protocol and EFI File Protocol
+//!
+
+use super::boot_service::{EfiBootServices, EFI_OPEN_PROTOCOL_GET_PROTOCOL};
+use super::{EfiHandle, EfiStatus, EfiTime, Guid};
+
+const EFI_LOADED_IMAGE_PROTOCOL_GUID: Guid = Guid {
+    d1: 0x5b1b31a1,
+    d2: 0x9562,
+    d3: 0x11d2,
+    d4: [0x8e, 0x3f, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+const EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: Guid = Guid {
+    d1: 0x964e5b22,
+    d2: 0x6459,
+    d3: 0x11d2,
+    d4: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+const EFI_FILE_INFO_ID: Guid = Guid {
+    d1: 0x09576e92,
+    d2: 0x6d3f,
+    d3: 0x11d2,
+    d4: [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b],
+};
+
+pub const EFI_FILE_MODE_READ: u64 = 0x0000000000000001;
+pub const EFI_FILE_MODE_WRITE: u64 = 0x0000000000000002;
+pub const EFI_FILE_MODE_CREATE: u64 = 0x8000000000000000;
+
+const MAX_FILE_NAME_LENGTH: usize = 256;
+
+#[repr(C)]
+struct EfiLoadedImageProtocol {
+    revision: u32,
+    parent_handle: EfiHandle,
+    system_table: usize,
+    device_handle: EfiHandle,
+    file_path: usize,
+    reserved: usize,
+    load_options_size: u32,
+    load_options: usize,
+    image_base: usize,
+    image_size: u64,
+    image_code_type: u32,
+    image_data_type: u32,
+    unload: usize,
+}
+
+#[repr(C)]
+struct EfiSimpleFileSystemProtocol {
+    revision: u64,
+    open_volume: extern "efiapi" fn(
+        this: *const EfiSimpleFileSystemProtocol,
+        root: *mut *const EfiFileProtocol,
+    ) -> EfiStatus,
+}
+
+#[repr(C)]
+pub struct EfiFileProtocol {
+    revision: u64,
+    open: extern "efiapi" fn(
+        this: *const EfiFileProtocol,
+        new_handle: *mut *const EfiFileProtocol,
+        file_name: *const u16,
+        open_mode: u64,
+        attributes: u64,
+    ) -> EfiStatus,
+    close: extern "efiapi" fn(this: *const EfiFileProtocol) -> EfiStatus,
+    delete: usize,
+    read: extern "efiapi" fn(
+        this: *const EfiFileProtocol,
+        buffer_size: *mut usize,
+        buffer: *mut u8,
+    ) -> EfiStatus,
+    write: usize,
+    get_position: usize,
+    set_position: extern "efiapi" fn(this: *const EfiFileProtocol, position: u64) -> EfiStatus,
+    get_info: extern "efiapi" fn(
+        this: *const EfiFileProtocol,
+        information_type: *const Guid,
+        buffer_size: *mut usize,
+        buffer: *mut u8,
+    ) -> EfiStatus,
+    set_info: usize,
+    flush: usize,
+}
+
+#[repr(C)]
+struct EfiFileInfo {
+    size: u64,
+    file_size: u64,
+    physical_size: u64,
+    create_time: EfiTime,
+    last_access_time: EfiTime,
+    modification_time: EfiTime,
+    attribute: u64,
+    file_name: [u16; MAX_FILE_NAME_LENGTH],
+}
+
+fn get_loaded_image(
+    image_handle: EfiHandle,
+    b_s: &EfiBootServices,
+) -> Result<&'static EfiLoadedImageProtocol, EfiStatus> {
+    let mut loaded_image: *const usize = core::ptr::null();
+    let status = (b_s.open_protocol)(
+        image_handle,
+        &EFI_LOADED_IMAGE_PROTOCOL_GUID,
+        &mut loaded_image,
+        image_handle,
+        0,
+        EFI_OPEN_PROTOCOL_GET_PROTOCOL,
+    );
+    if status != EfiStatus::EfiSuccess {
+        return Err(status);
+    }
+    Ok(unsafe { &*(loaded_image as *const EfiLoadedImageProtocol) })
+}
+
+/// Get the load options of this image, the command line given by the shell or the boot option
+///
+/// # Arguments
+/// * `image_handle` - EfiHandle passed to efi_main
+/// * `b_s` - EfiBootServices
+///
+/// # Result
+/// The options as UCS-2 characters, the terminating null character is removed
+pub fn get_load_options(
+    image_handle: EfiHandle,
+    b_s: &EfiBootServices,
+) -> Result<&'static [u16], EfiStatus> {
+    let loaded_image = get_loaded_image(image_handle, b_s)?;
+    if loaded_image.load_options == 0 {
+        return Ok(&[]);
+    }
+    let options = unsafe {
+        core::slice::from_raw_parts(
+            loaded_image.load_options as *const u16,
+            loaded_image.load_options_size as usize / core::mem::size_of::<u16>(),
+        )
+    };
+    Ok(options
+        .iter()
+        .position(|c| *c == 0)
+        .map(|length| &options[..length])
+        .unwrap_or(options))
+}
+
+/// Get the memory range which this image was loaded into
+///
+/// # Arguments
+/// * `image_handle` - EfiHandle passed to efi_main
+/// * `b_s` - EfiBootServices
+///
+/// # Result
+/// If succeeded, Ok((image_base, image_size))
+pub fn get_image_range(
+    image_handle: EfiHandle,
+    b_s: &EfiBootServices,
+) -> Result<(usize, usize), EfiStatus> {
+    let loaded_image = get_loaded_image(image_handle, b_s)?;
+    Ok((loaded_image.image_base, loaded_image.image_size as usize))
+}
+
+/// Open the root directory of the volume which this image was loaded from
+///
+/// # Arguments
+/// * `image_handle` - EfiHandle passed to efi_main
+/// * `b_s` - EfiBootServices
+pub fn open_root_dir(
+    image_handle: EfiHandle,
+    b_s: &EfiBootServices,
+) -> Result<&'static EfiFileProtocol, EfiStatus> {
+    let device_handle = get_loaded_image(image_handle, b_s)?.device_handle;
+
+    let mut file_system: *const usize = core::ptr::null();
+    let status = (b_s.open_protocol)(
+        device_handle,
+        &EFI_SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
+        &mut file_system,
+        image_handle,
+        0,
+        EFI_OPEN_PROTOCOL_GET_PROTOCOL,
+    );
+    if status != EfiStatus::EfiSuccess {
+        return Err(status);
+    }
+    let file_system = file_system as *const EfiSimpleFileSystemProtocol;
+
+    let mut root: *const EfiFileProtocol = core::ptr::null();
+    let status = (unsafe { &*file_system }.open_volume)(file_system, &mut root);
+    if status != EfiStatus::EfiSuccess {
+        return Err(status);
+    }
+    Ok(unsafe { &*root })
+}
+
+impl EfiFileProtocol {
+    /// Open the file for reading
+    ///
+    /// # Arguments
+    /// * `file_name` - the path from this directory, `\` is the separator
+    pub fn open_file(&self, file_name: &str) -> Result<&'static Self, EfiStatus> {
+        let mut buf = [0u16; MAX_FILE_NAME_LENGTH];
+        for (i, c) in file_name.encode_utf16().enumerate() {
+            if i >= buf.len() - 1 {
+                return Err(EfiStatus::EfiInvalidParameter);
+            }
+            buf[i] = c;
+        }
+        let mut file: *const Self = core::ptr::null();
+        let status = (self.open)(self, &mut file, buf.as_ptr(), EFI_FILE_MODE_READ, 0);
+        if status != EfiStatus::EfiSuccess {
+            return Err(status);
+        }
+        Ok(unsafe { &*file })
+    }
+
+    /// Read the file from the current position
+    ///
+    /// # Result
+    /// The number of read bytes, 0 means the end of the file
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, EfiStatus> {
+        let mut size = buffer.len();
+        let status = (self.read)(self, &mut size, buffer.as_mut_ptr());
+        if status != EfiStatus::EfiSuccess {
+            return Err(status);
+        }
+        Ok(size)
+    }
+
+    pub fn seek(&self, position: u64) -> Result<(), EfiStatus> {
+        let status = (self.set_position)(self, position);
+        if status != EfiStatus::EfiSuccess {
+            return Err(status);
+        }
+        Ok(())
+    }
+
+    pub fn get_file_size(&self) -> Result<u64, EfiStatus> {
+        let mut info = core::mem::MaybeUninit::<EfiFileInfo>::uninit();
+        let mut size = core::mem::size_of::<EfiFileInfo>();
+        let status = (self.get_info)(
+            self,
+            &EFI_FILE_INFO_ID,
+            &mut size,
+            info.as_mut_ptr() as *mut u8,
+        );
+        if status != EfiStatus::EfiSuccess {
+            return Err(status);
+        }
+        Ok(unsafe { info.assume_init_ref() }.file_size)
+    }
+
+    pub fn close(&self) -> Result<(), EfiStatus> {
+        let status = (self.close)(self);
+        if status != EfiStatus::EfiSuccess {
+            return Err(status);
+        }
+        Ok(())
+    }
+}
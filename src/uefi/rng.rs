@@ -0,0 +1,53 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! EFI Random Number Generator Protocol
+//!
+
+use super::boot_service::EfiBootServices;
+use super::{EfiStatus, Guid};
+
+const EFI_RNG_PROTOCOL_GUID: Guid = Guid {
+    d1: 0x3152bca5,
+    d2: 0xeade,
+    d3: 0x433d,
+    d4: [0x86, 0x2e, 0xc0, 0x1c, 0xdc, 0x29, 0x1f, 0x44],
+};
+
+#[repr(C)]
+struct EfiRngProtocol {
+    get_info: usize,
+    get_rng: extern "efiapi" fn(
+        this: *const EfiRngProtocol,
+        rng_algorithm: *const Guid,
+        rng_value_length: usize,
+        rng_value: *mut u8,
+    ) -> EfiStatus,
+}
+
+/// Fill the buffer with the default algorithm of EFI_RNG_PROTOCOL
+///
+/// This must be called before ExitBootServices.
+pub fn get_random_bytes(b_s: &EfiBootServices, buffer: &mut [u8]) -> Result<(), EfiStatus> {
+    let mut protocol: *const usize = core::ptr::null();
+    let status = (b_s.locate_protocol)(&EFI_RNG_PROTOCOL_GUID, core::ptr::null(), &mut protocol);
+    if status != EfiStatus::EfiSuccess {
+        return Err(status);
+    }
+    let protocol = protocol as *const EfiRngProtocol;
+    let status = (unsafe { &*protocol }.get_rng)(
+        protocol,
+        core::ptr::null(),
+        buffer.len(),
+        buffer.as_mut_ptr(),
+    );
+    if status != EfiStatus::EfiSuccess {
+        return Err(status);
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Frame Pointer based Stack Unwinder
+//!
+//! Walk the frame records linked by x29 and print the return addresses.
+//! The frame record is `[x29] = previous x29, [x29 + 8] = x30(return address)`.
+//!
+
+use crate::cpu::convert_virtual_address_to_intermediate_physical_address_el1_read;
+use crate::exception::Registers;
+use crate::paging::convert_intermediate_physical_address_to_physical_address;
+
+use core::arch::asm;
+
+mod symbols {
+    include!(concat!(env!("OUT_DIR"), "/symbols.rs"));
+}
+
+/// The maximum number of frames to print
+pub const MAX_BACKTRACE_DEPTH: usize = 32;
+
+/// Print the backtrace of the hypervisor from the caller of this function
+#[inline(never)]
+pub fn print_backtrace() {
+    let frame_pointer: usize;
+    unsafe { asm!("mov {:x}, x29", out(reg) frame_pointer) };
+    println!("Backtrace:");
+    walk_frame_chain(frame_pointer, 0, print_frame, |address| {
+        if address & 0b111 != 0 {
+            return None;
+        }
+        Some(unsafe { *(address as *const usize) })
+    });
+}
+
+/// Print the backtrace of EL1 from the registers saved by the exception vectors
+///
+/// The frame chain is read through the stage 1 translation of EL1 and the stage 2 translation,
+/// the walk stops at the frame which is not mapped.
+/// The addresses are not symbolized, the symbol table is of the hypervisor.
+pub fn print_guest_backtrace(registers: &Registers, elr_el2: u64) {
+    println!("EL1 Backtrace:");
+    print_guest_frame(0, elr_el2 as usize);
+    print_guest_frame(1, registers.x30 as usize);
+    walk_frame_chain(registers.x29 as usize, 2, print_guest_frame, |address| {
+        if address & 0b111 != 0 {
+            return None;
+        }
+        let ipa = convert_virtual_address_to_intermediate_physical_address_el1_read(address).ok()?;
+        let (physical_address, _) =
+            convert_intermediate_physical_address_to_physical_address(ipa).ok()?;
+        Some(unsafe { *(physical_address as *const usize) })
+    });
+}
+
+/// Walk the frame records from `frame_pointer`
+///
+/// # Arguments
+/// * `frame_pointer` - the value of x29 of the first frame
+/// * `first_depth` - the frame number of the first frame, the frames already printed
+/// * `print` - print the frame number and the return address
+/// * `read` - read one word from the address, returns None if the address is not accessible
+fn walk_frame_chain(
+    mut frame_pointer: usize,
+    first_depth: usize,
+    print: fn(usize, usize),
+    read: impl Fn(usize) -> Option<usize>,
+) {
+    for depth in first_depth..MAX_BACKTRACE_DEPTH {
+        if frame_pointer == 0 {
+            return;
+        }
+        let (Some(next_frame_pointer), Some(return_address)) =
+            (read(frame_pointer), read(frame_pointer + 8))
+        else {
+            println!("  <invalid frame pointer: {:#X}>", frame_pointer);
+            return;
+        };
+        if return_address == 0 {
+            return;
+        }
+        print(depth, return_address);
+        /* The stack grows downwards, the caller's frame must be placed at the higher address */
+        if next_frame_pointer <= frame_pointer {
+            return;
+        }
+        frame_pointer = next_frame_pointer;
+    }
+    println!("  ...");
+}
+
+fn print_frame(depth: usize, address: usize) {
+    match symbolize(address) {
+        Some((name, offset)) => println!("  #{:02} {:#X} <{}+{:#X}>", depth, address, name, offset),
+        None => println!("  #{:02} {:#X}", depth, address),
+    }
+}
+
+fn print_guest_frame(depth: usize, address: usize) {
+    println!("  #{:02} {:#X}", depth, address);
+}
+
+/// Look up the symbol which contains `address`
+///
+/// The symbol table holds the addresses at link time, UEFI relocates the image.
+/// The difference is calculated from the address of `efi_main`.
+///
+/// # Result
+/// If found, returns Some((symbol_name, offset_from_symbol)), otherwise None
+pub fn symbolize(address: usize) -> Option<(&'static str, usize)> {
+    let table = &symbols::SYMBOLS;
+    let link_address_of_efi_main = table.iter().find(|(_, name)| *name == "efi_main")?.0;
+    let link_address = address
+        .wrapping_sub(crate::efi_main as *const fn() as usize)
+        .wrapping_add(link_address_of_efi_main);
+
+    let index = match table.binary_search_by_key(&link_address, |(a, _)| *a) {
+        Ok(i) => i,
+        Err(0) => return None,
+        Err(i) => i - 1,
+    };
+    let (symbol_address, name) = table[index];
+    Some((name, link_address - symbol_address))
+}
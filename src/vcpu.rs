@@ -0,0 +1,63 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtual CPU
+//!
+
+use crate::cpu::*;
+use crate::vgic;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum VCpuState {
+    /// Executing EL1
+    Running,
+    /// Waiting for an interrupt by WFI
+    Blocked,
+}
+
+pub struct VCpu {
+    pub state: VCpuState,
+}
+
+static mut BOOT_VCPU: VCpu = VCpu {
+    state: VCpuState::Running,
+};
+
+/// Get the vCPU running on this physical CPU
+pub fn current() -> &'static mut VCpu {
+    unsafe { &mut *core::ptr::addr_of_mut!(BOOT_VCPU) }
+}
+
+impl VCpu {
+    /// Check whether an interrupt for this vCPU is pending
+    ///
+    /// The virtual interrupts in the list registers and the physical interrupts which will be
+    /// forwarded after returning to EL1 are checked.
+    pub fn has_pending_interrupt(&self) -> bool {
+        vgic::has_pending_interrupt() || (get_isr_el1() & (ISR_EL1_I | ISR_EL1_F)) != 0
+    }
+
+    /// Block this vCPU until an interrupt becomes pending or the deadline passes
+    ///
+    /// Currently, only one vCPU runs on the physical CPU, so the physical CPU idles by WFI.
+    ///
+    /// # Arguments
+    /// * `deadline` - the virtual counter value to wake up, if None, wait only for the interrupt
+    pub fn block(&mut self, deadline: Option<u64>) {
+        self.state = VCpuState::Blocked;
+        while !self.has_pending_interrupt() {
+            match deadline {
+                Some(d) if get_cntvct_el0() >= d => break,
+                /* The timer of EL1 is not available for EL2, poll the counter */
+                Some(_) => core::hint::spin_loop(),
+                None => unsafe { core::arch::asm!("wfi") },
+            }
+        }
+        self.state = VCpuState::Running;
+    }
+}
@@ -0,0 +1,242 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtual GIC CPU Interface
+//!
+//! Deliver the virtual interrupts to EL1 by GICv3 List Registers.
+//! The physical IRQs are taken to EL2 (HCR_EL2.IMO) and forwarded to EL1
+//! as the hardware interrupts, the deactivation by EL1 deactivates the physical interrupt.
+//!
+
+use crate::cpu::HCR_EL2_IMO;
+
+use core::arch::asm;
+
+/* ICH_HCR_EL2 */
+pub const ICH_HCR_EL2_EN: u64 = 1 << 0;
+
+/* ICH_VTR_EL2 */
+pub const ICH_VTR_EL2_LIST_REGS: u64 = 0b11111;
+
+/* ICH_LR<n>_EL2 */
+pub const ICH_LR_EL2_STATE_BITS_OFFSET: u64 = 62;
+pub const ICH_LR_EL2_STATE_PENDING: u64 = 0b01 << ICH_LR_EL2_STATE_BITS_OFFSET;
+pub const ICH_LR_EL2_HW: u64 = 1 << 61;
+pub const ICH_LR_EL2_GROUP: u64 = 1 << 60;
+pub const ICH_LR_EL2_PRIORITY_BITS_OFFSET: u64 = 48;
+pub const ICH_LR_EL2_PINTID_BITS_OFFSET: u64 = 32;
+pub const ICH_LR_EL2_VINTID: u64 = u32::MAX as u64;
+
+/* ICC_CTLR_EL1 */
+pub const ICC_CTLR_EL1_EOI_MODE: u64 = 1 << 1;
+
+/* ICC_IAR1_EL1 */
+pub const ICC_IAR1_EL1_INTID: u64 = 0xFFFFFF;
+
+pub const GIC_SPURIOUS_INTERRUPT_START: u32 = 1020;
+pub const GIC_SPURIOUS_INTERRUPT_END: u32 = 1023;
+pub const DEFAULT_INTERRUPT_PRIORITY: u8 = 0xa0;
+
+const MAX_PENDING_INTERRUPTS: usize = 32;
+
+#[derive(Clone, Copy)]
+struct PendingInterrupt {
+    virtual_interrupt_id: u32,
+    physical_interrupt_id: Option<u32>,
+    priority: u8,
+}
+
+/// The interrupts waiting for a free list register
+static mut PENDING_INTERRUPTS: [Option<PendingInterrupt>; MAX_PENDING_INTERRUPTS] =
+    [None; MAX_PENDING_INTERRUPTS];
+
+macro_rules! ich_lr_access {
+    ($index:expr, $op:ident $(, $value:expr)?) => {
+        ich_lr_access!(@arms $index, $op $(, $value)?;
+            0 => "S3_4_C12_C12_0", 1 => "S3_4_C12_C12_1", 2 => "S3_4_C12_C12_2", 3 => "S3_4_C12_C12_3",
+            4 => "S3_4_C12_C12_4", 5 => "S3_4_C12_C12_5", 6 => "S3_4_C12_C12_6", 7 => "S3_4_C12_C12_7",
+            8 => "S3_4_C12_C13_0", 9 => "S3_4_C12_C13_1", 10 => "S3_4_C12_C13_2", 11 => "S3_4_C12_C13_3",
+            12 => "S3_4_C12_C13_4", 13 => "S3_4_C12_C13_5", 14 => "S3_4_C12_C13_6", 15 => "S3_4_C12_C13_7"
+        )
+    };
+    (@arms $index:expr, read; $($n:literal => $name:literal),*) => {
+        match $index {
+            $($n => {
+                let value: u64;
+                unsafe { asm!(concat!("mrs {:x}, ", $name), out(reg) value) };
+                value
+            })*
+            _ => unreachable!(),
+        }
+    };
+    (@arms $index:expr, write, $value:expr; $($n:literal => $name:literal),*) => {
+        match $index {
+            $($n => unsafe { asm!(concat!("msr ", $name, ", {:x}"), in(reg) $value) },)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+fn get_ich_lr_el2(index: usize) -> u64 {
+    ich_lr_access!(index, read)
+}
+
+fn set_ich_lr_el2(index: usize, value: u64) {
+    ich_lr_access!(index, write, value)
+}
+
+fn get_ich_vtr_el2() -> u64 {
+    let ich_vtr_el2: u64;
+    unsafe { asm!("mrs {:x}, S3_4_C12_C11_1", out(reg) ich_vtr_el2) };
+    ich_vtr_el2
+}
+
+fn get_ich_hcr_el2() -> u64 {
+    let ich_hcr_el2: u64;
+    unsafe { asm!("mrs {:x}, S3_4_C12_C11_0", out(reg) ich_hcr_el2) };
+    ich_hcr_el2
+}
+
+fn set_ich_hcr_el2(ich_hcr_el2: u64) {
+    unsafe { asm!("msr S3_4_C12_C11_0, {:x}", in(reg) ich_hcr_el2) };
+}
+
+fn get_ich_elrsr_el2() -> u64 {
+    let ich_elrsr_el2: u64;
+    unsafe { asm!("mrs {:x}, S3_4_C12_C11_5", out(reg) ich_elrsr_el2) };
+    ich_elrsr_el2
+}
+
+fn number_of_list_registers() -> usize {
+    ((get_ich_vtr_el2() & ICH_VTR_EL2_LIST_REGS) + 1) as usize
+}
+
+/// Enable the virtual CPU interface
+///
+/// # Result
+/// The bits to set into HCR_EL2
+pub fn init() -> u64 {
+    for i in 0..number_of_list_registers() {
+        set_ich_lr_el2(i, 0);
+    }
+    /* Split the priority drop and the deactivation to forward the physical interrupts */
+    let icc_ctlr_el1: u64;
+    unsafe { asm!("mrs {:x}, icc_ctlr_el1", out(reg) icc_ctlr_el1) };
+    unsafe { asm!("msr icc_ctlr_el1, {:x}", in(reg) icc_ctlr_el1 | ICC_CTLR_EL1_EOI_MODE) };
+    set_ich_hcr_el2(get_ich_hcr_el2() | ICH_HCR_EL2_EN);
+    /* FIQs are not routed to EL2, EL2 has no FIQ handler and Group 0 is not forwarded */
+    HCR_EL2_IMO
+}
+
+fn write_list_register(interrupt: &PendingInterrupt) -> Result<(), ()> {
+    let empty = get_ich_elrsr_el2();
+    let number_of_list_registers = number_of_list_registers();
+    /* Avoid to inject the same interrupt twice */
+    for i in 0..number_of_list_registers {
+        if (empty & (1 << i)) == 0
+            && (get_ich_lr_el2(i) & ICH_LR_EL2_VINTID) as u32 == interrupt.virtual_interrupt_id
+            && (get_ich_lr_el2(i) & ICH_LR_EL2_STATE_PENDING) != 0
+        {
+            return Ok(());
+        }
+    }
+    let index = (0..number_of_list_registers)
+        .find(|i| (empty & (1 << i)) != 0)
+        .ok_or(())?;
+    let mut lr = ICH_LR_EL2_STATE_PENDING
+        | ICH_LR_EL2_GROUP
+        | ((interrupt.priority as u64) << ICH_LR_EL2_PRIORITY_BITS_OFFSET)
+        | interrupt.virtual_interrupt_id as u64;
+    if let Some(physical_interrupt_id) = interrupt.physical_interrupt_id {
+        lr |= ICH_LR_EL2_HW | ((physical_interrupt_id as u64) << ICH_LR_EL2_PINTID_BITS_OFFSET);
+    }
+    set_ich_lr_el2(index, lr);
+    Ok(())
+}
+
+fn inject(interrupt: PendingInterrupt) -> Result<(), ()> {
+    if write_list_register(&interrupt).is_ok() {
+        return Ok(());
+    }
+    let pending_interrupts = unsafe { &mut *core::ptr::addr_of_mut!(PENDING_INTERRUPTS) };
+    if pending_interrupts
+        .iter()
+        .flatten()
+        .any(|p| p.virtual_interrupt_id == interrupt.virtual_interrupt_id)
+    {
+        return Ok(());
+    }
+    *pending_interrupts
+        .iter_mut()
+        .find(|e| e.is_none())
+        .ok_or(())? = Some(interrupt);
+    Ok(())
+}
+
+/// Make the virtual interrupt pending on the vCPU
+///
+/// If no list register is free, the interrupt will be injected by [`flush_pending_interrupts`].
+///
+/// # Arguments
+/// * `interrupt_id` - the virtual INTID
+/// * `priority` - the priority of the interrupt
+pub fn inject_interrupt(interrupt_id: u32, priority: u8) -> Result<(), ()> {
+    inject(PendingInterrupt {
+        virtual_interrupt_id: interrupt_id,
+        physical_interrupt_id: None,
+        priority,
+    })
+}
+
+/// Move the interrupts waiting for a free list register into the list registers
+///
+/// This should be called before returning to EL1.
+pub fn flush_pending_interrupts() {
+    for e in unsafe { &mut *core::ptr::addr_of_mut!(PENDING_INTERRUPTS) }.iter_mut() {
+        if let Some(interrupt) = e {
+            if write_list_register(interrupt).is_err() {
+                return;
+            }
+            *e = None;
+        }
+    }
+}
+
+/// Check whether an interrupt is pending for the vCPU
+pub fn has_pending_interrupt() -> bool {
+    let empty = get_ich_elrsr_el2();
+    (0..number_of_list_registers())
+        .any(|i| (empty & (1 << i)) == 0 && (get_ich_lr_el2(i) & ICH_LR_EL2_STATE_PENDING) != 0)
+        || unsafe { &*core::ptr::addr_of!(PENDING_INTERRUPTS) }
+            .iter()
+            .any(|e| e.is_some())
+}
+
+/// Acknowledge the physical interrupt and forward it to EL1
+///
+/// The interrupt is not deactivated here, EL1 deactivates it through the list register.
+pub fn handle_physical_interrupt() {
+    let icc_iar1_el1: u64;
+    unsafe { asm!("mrs {:x}, icc_iar1_el1", out(reg) icc_iar1_el1) };
+    let interrupt_id = (icc_iar1_el1 & ICC_IAR1_EL1_INTID) as u32;
+    if (GIC_SPURIOUS_INTERRUPT_START..=GIC_SPURIOUS_INTERRUPT_END).contains(&interrupt_id) {
+        return;
+    }
+    /* Priority drop */
+    unsafe { asm!("msr icc_eoir1_el1, {:x}", in(reg) icc_iar1_el1) };
+
+    let result = inject(PendingInterrupt {
+        virtual_interrupt_id: interrupt_id,
+        physical_interrupt_id: Some(interrupt_id),
+        priority: DEFAULT_INTERRUPT_PRIORITY,
+    });
+    if result.is_err() {
+        println!("Failed to forward the interrupt: {}", interrupt_id);
+        unsafe { asm!("msr icc_dir_el1, {:x}", in(reg) icc_iar1_el1) };
+    }
+}
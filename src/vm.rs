@@ -0,0 +1,58 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! Virtual Machine
+//!
+
+use crate::mmio::bus::MmioBus;
+
+/// The behavior on the guest access to the intermediate physical address
+/// which is neither mapped by stage 2 nor handled by the MMIO devices
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnmappedAccessPolicy {
+    /// Inject Synchronous External abort like the bus error of the real hardware
+    ///
+    /// The firmware accesses the platform devices which are not mapped by stage 2,
+    /// like GIC, RTC and fw_cfg, therefore this is not the default.
+    InjectAbort,
+    /// Reads return zero and writes are discarded, each access is logged
+    ReadAsZeroWriteIgnore,
+    /// Dump the state of the guest and stop it
+    StopVm,
+}
+
+impl UnmappedAccessPolicy {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::InjectAbort => "abort",
+            Self::ReadAsZeroWriteIgnore => "ignore",
+            Self::StopVm => "stop",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::InjectAbort, Self::ReadAsZeroWriteIgnore, Self::StopVm]
+            .into_iter()
+            .find(|p| p.name() == name)
+    }
+}
+
+pub struct Vm {
+    pub mmio_bus: MmioBus,
+    pub unmapped_access_policy: UnmappedAccessPolicy,
+}
+
+static mut VM: Vm = Vm {
+    mmio_bus: MmioBus::new(),
+    unmapped_access_policy: UnmappedAccessPolicy::ReadAsZeroWriteIgnore,
+};
+
+/// Get the VM running on this physical CPU
+pub fn current() -> &'static mut Vm {
+    unsafe { &mut *core::ptr::addr_of_mut!(VM) }
+}
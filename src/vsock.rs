@@ -0,0 +1,316 @@
+// Copyright (c) 2022 RIKEN
+// Copyright (c) 2022 National Institute of Advanced Industrial Science and Technology (AIST)
+// All rights reserved.
+//
+// This software is released under the MIT License.
+// http://opensource.org/licenses/mit-license.php
+
+//!
+//! VM Sockets Endpoint of the Hypervisor
+//!
+//! The hypervisor listens on CID 2 (the host) and services the connections with the
+//! request/response protocols registered by [`register_vsock_service`].
+//! Each RW packet from the guest is one request, and the response is sent back as one RW packet.
+//!
+
+/// The well-known CID of the host
+pub const VMADDR_CID_HOST: u64 = 2;
+
+pub const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+/* Operations */
+pub const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+pub const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+pub const VIRTIO_VSOCK_OP_RST: u16 = 3;
+pub const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VIRTIO_VSOCK_OP_RW: u16 = 5;
+pub const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The maximum size of the request and the response of the services
+pub const VSOCK_MAX_MESSAGE_SIZE: usize = 256;
+const MAX_VSOCK_SERVICES: usize = 8;
+const MAX_VSOCK_CONNECTIONS: usize = 32;
+/// The receive buffer size advertised to the guests, the requests are consumed immediately
+const VSOCK_BUFFER_SIZE: u32 = 0x10000;
+
+/// struct virtio_vsock_hdr
+#[derive(Clone, Copy, Default, Debug)]
+pub struct VsockHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub r#type: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+}
+
+impl VsockHeader {
+    pub const SIZE: usize = 44;
+
+    pub fn from_bytes(b: &[u8; Self::SIZE]) -> Self {
+        Self {
+            src_cid: u64::from_le_bytes(b[0..8].try_into().unwrap()),
+            dst_cid: u64::from_le_bytes(b[8..16].try_into().unwrap()),
+            src_port: u32::from_le_bytes(b[16..20].try_into().unwrap()),
+            dst_port: u32::from_le_bytes(b[20..24].try_into().unwrap()),
+            len: u32::from_le_bytes(b[24..28].try_into().unwrap()),
+            r#type: u16::from_le_bytes(b[28..30].try_into().unwrap()),
+            op: u16::from_le_bytes(b[30..32].try_into().unwrap()),
+            flags: u32::from_le_bytes(b[32..36].try_into().unwrap()),
+            buf_alloc: u32::from_le_bytes(b[36..40].try_into().unwrap()),
+            fwd_cnt: u32::from_le_bytes(b[40..44].try_into().unwrap()),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        b[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        b[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        b[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        b[24..28].copy_from_slice(&self.len.to_le_bytes());
+        b[28..30].copy_from_slice(&self.r#type.to_le_bytes());
+        b[30..32].copy_from_slice(&self.op.to_le_bytes());
+        b[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        b[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        b[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        b
+    }
+
+    /// Create the header of the packet sent back to the source without payload
+    pub fn reply(&self, op: u16) -> Self {
+        Self {
+            src_cid: self.dst_cid,
+            dst_cid: self.src_cid,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            len: 0,
+            r#type: self.r#type,
+            op,
+            flags: 0,
+            buf_alloc: 0,
+            fwd_cnt: 0,
+        }
+    }
+}
+
+/// Handle one request of the service
+///
+/// # Arguments
+/// * `guest_cid` - the CID of the client
+/// * `request` - the payload of the RW packet
+/// * `response` - the buffer to store the response
+///
+/// # Result
+/// If succeeded, Ok(length of the response), otherwise Err(()) and the connection is reset
+pub type VsockService =
+    fn(guest_cid: u64, request: &[u8], response: &mut [u8]) -> Result<usize, ()>;
+
+/// Send the packet to the guest
+pub type VsockSender<'a> = &'a mut dyn FnMut(&VsockHeader, &[u8]);
+
+struct Connection {
+    guest_cid: u64,
+    guest_port: u32,
+    local_port: u32,
+    /// The bytes received from the guest
+    received_bytes: u32,
+    /// The bytes sent to the guest
+    transmitted_bytes: u32,
+    peer_buffer_size: u32,
+    peer_forwarded_bytes: u32,
+}
+
+impl Connection {
+    fn peer_free_space(&self) -> u32 {
+        self.peer_buffer_size.wrapping_sub(
+            self.transmitted_bytes
+                .wrapping_sub(self.peer_forwarded_bytes),
+        )
+    }
+}
+
+struct Endpoint {
+    services: [Option<(u32, VsockService)>; MAX_VSOCK_SERVICES],
+    connections: [Option<Connection>; MAX_VSOCK_CONNECTIONS],
+}
+
+static mut ENDPOINT: Endpoint = Endpoint {
+    services: [None; MAX_VSOCK_SERVICES],
+    connections: [const { None }; MAX_VSOCK_CONNECTIONS],
+};
+
+fn endpoint() -> &'static mut Endpoint {
+    unsafe { &mut *core::ptr::addr_of_mut!(ENDPOINT) }
+}
+
+/// Listen on the port of the host CID
+pub fn register_vsock_service(port: u32, service: VsockService) -> Result<(), ()> {
+    let e = endpoint();
+    if e.services.iter().flatten().any(|(p, _)| *p == port) {
+        return Err(());
+    }
+    let entry = e.services.iter_mut().find(|s| s.is_none()).ok_or(())?;
+    *entry = Some((port, service));
+    Ok(())
+}
+
+/// The service which returns the request as it is
+pub fn echo_service(_guest_cid: u64, request: &[u8], response: &mut [u8]) -> Result<usize, ()> {
+    let length = request.len().min(response.len());
+    response[..length].copy_from_slice(&request[..length]);
+    Ok(length)
+}
+
+/// Close all connections from the guest, used when its device is reset
+pub fn reset_connections(guest_cid: u64) {
+    for c in endpoint().connections.iter_mut() {
+        if c.as_ref().is_some_and(|c| c.guest_cid == guest_cid) {
+            *c = None;
+        }
+    }
+}
+
+fn find_connection(header: &VsockHeader) -> Option<&'static mut Option<Connection>> {
+    endpoint().connections.iter_mut().find(|c| {
+        c.as_ref().is_some_and(|c| {
+            c.guest_cid == header.src_cid
+                && c.guest_port == header.src_port
+                && c.local_port == header.dst_port
+        })
+    })
+}
+
+fn send_with_credit(
+    connection: &mut Connection,
+    mut header: VsockHeader,
+    payload: &[u8],
+    send: VsockSender,
+) {
+    header.len = payload.len() as u32;
+    header.buf_alloc = VSOCK_BUFFER_SIZE;
+    header.fwd_cnt = connection.received_bytes;
+    connection.transmitted_bytes = connection
+        .transmitted_bytes
+        .wrapping_add(payload.len() as u32);
+    send(&header, payload);
+}
+
+fn send_reset(header: &VsockHeader, send: VsockSender) {
+    if header.op != VIRTIO_VSOCK_OP_RST {
+        send(&header.reply(VIRTIO_VSOCK_OP_RST), &[]);
+    }
+}
+
+/// Handle the packet sent to [`VMADDR_CID_HOST`]
+///
+/// # Arguments
+/// * `header` - the header whose `src_cid` is validated by the transport
+/// * `payload` - the payload of the packet
+/// * `send` - the function to send the packets to the guest
+pub fn receive(header: &VsockHeader, payload: &[u8], send: VsockSender) {
+    if header.r#type != VIRTIO_VSOCK_TYPE_STREAM {
+        send_reset(header, send);
+        return;
+    }
+    if header.op == VIRTIO_VSOCK_OP_REQUEST {
+        let e = endpoint();
+        let is_listening = e
+            .services
+            .iter()
+            .flatten()
+            .any(|(p, _)| *p == header.dst_port);
+        let entry = if let Some(c) = find_connection(header) {
+            /* The guest reuses the port without closing */
+            Some(c)
+        } else {
+            e.connections.iter_mut().find(|c| c.is_none())
+        };
+        let (true, Some(entry)) = (is_listening, entry) else {
+            send_reset(header, send);
+            return;
+        };
+        let connection = entry.insert(Connection {
+            guest_cid: header.src_cid,
+            guest_port: header.src_port,
+            local_port: header.dst_port,
+            received_bytes: 0,
+            transmitted_bytes: 0,
+            peer_buffer_size: header.buf_alloc,
+            peer_forwarded_bytes: header.fwd_cnt,
+        });
+        send_with_credit(
+            connection,
+            header.reply(VIRTIO_VSOCK_OP_RESPONSE),
+            &[],
+            send,
+        );
+        return;
+    }
+
+    let Some(entry) = find_connection(header) else {
+        send_reset(header, send);
+        return;
+    };
+    let connection = entry.as_mut().unwrap();
+    connection.peer_buffer_size = header.buf_alloc;
+    connection.peer_forwarded_bytes = header.fwd_cnt;
+
+    match header.op {
+        VIRTIO_VSOCK_OP_RW => {
+            connection.received_bytes =
+                connection.received_bytes.wrapping_add(payload.len() as u32);
+            let Some((_, service)) = endpoint()
+                .services
+                .iter()
+                .flatten()
+                .find(|(p, _)| *p == connection.local_port)
+            else {
+                *entry = None;
+                send_reset(header, send);
+                return;
+            };
+            let mut response = [0u8; VSOCK_MAX_MESSAGE_SIZE];
+            match service(header.src_cid, payload, &mut response) {
+                Ok(length) if (length as u32) <= connection.peer_free_space() => {
+                    send_with_credit(
+                        connection,
+                        header.reply(VIRTIO_VSOCK_OP_RW),
+                        &response[..length],
+                        send,
+                    );
+                }
+                _ => {
+                    *entry = None;
+                    send_reset(header, send);
+                }
+            }
+        }
+        VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+            send_with_credit(
+                connection,
+                header.reply(VIRTIO_VSOCK_OP_CREDIT_UPDATE),
+                &[],
+                send,
+            );
+        }
+        VIRTIO_VSOCK_OP_CREDIT_UPDATE => {}
+        VIRTIO_VSOCK_OP_SHUTDOWN => {
+            /* The connection is closed immediately because no data remains to be sent */
+            *entry = None;
+            send_reset(header, send);
+        }
+        VIRTIO_VSOCK_OP_RST => {
+            *entry = None;
+        }
+        _ => {
+            *entry = None;
+            send_reset(header, send);
+        }
+    }
+}